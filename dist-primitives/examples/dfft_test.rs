@@ -2,14 +2,14 @@ use ark_bls12_377::Fr;
 use ark_ff::{FftField, PrimeField};
 use ark_poly::{EvaluationDomain, Radix2EvaluationDomain};
 use dist_primitives::{
-    channel::MpcSerNet,
+    channel::{MpcSerNet, WireType},
     dfft::{d_fft, fft_in_place_rearrange},
     utils::pack::transpose,
 };
 use mpc_net::{LocalTestNet as Net, MpcNet, MultiplexedStreamID};
 use secret_sharing::pss::PackedSharingParams;
 
-pub async fn d_fft_test<F: FftField + PrimeField, Net: MpcNet>(
+pub async fn d_fft_test<F: FftField + PrimeField + WireType, Net: MpcNet>(
     pp: &PackedSharingParams<F>,
     dom: &Radix2EvaluationDomain<F>,
     net: &Net,
@@ -48,14 +48,14 @@ pub async fn d_fft_test<F: FftField + PrimeField, Net: MpcNet>(
         dom,
         pp,
         net,
-        MultiplexedStreamID::One,
+        MultiplexedStreamID::ONE,
     )
     .await
     .unwrap();
 
     // Send to king who reconstructs and checks the answer
     let result = net
-        .send_to_king(&peval_share, MultiplexedStreamID::One)
+        .send_to_king(&peval_share, MultiplexedStreamID::ONE)
         .await
         .unwrap();
     if let Some(peval_shares) = result {
@@ -81,6 +81,22 @@ pub async fn main() {
             let pp = PackedSharingParams::<Fr>::new(2);
             let dom = Radix2EvaluationDomain::<Fr>::new(1024).unwrap();
             d_fft_test::<Fr, _>(&pp, &dom, &net).await;
+
+            #[cfg(feature = "bls12-381")]
+            {
+                use ark_bls12_381::Fr;
+                let pp = PackedSharingParams::<Fr>::new(2);
+                let dom = Radix2EvaluationDomain::<Fr>::new(1024).unwrap();
+                d_fft_test::<Fr, _>(&pp, &dom, &net).await;
+            }
+
+            #[cfg(feature = "bn254")]
+            {
+                use ark_bn254::Fr;
+                let pp = PackedSharingParams::<Fr>::new(2);
+                let dom = Radix2EvaluationDomain::<Fr>::new(1024).unwrap();
+                d_fft_test::<Fr, _>(&pp, &dom, &net).await;
+            }
         })
         .await;
 }
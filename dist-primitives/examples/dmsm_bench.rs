@@ -2,11 +2,12 @@ use ark_bls12_377::Fr;
 use ark_ec::CurveGroup;
 use ark_poly::{EvaluationDomain, Radix2EvaluationDomain};
 use ark_std::{UniformRand, Zero};
+use dist_primitives::channel::WireType;
 use dist_primitives::dmsm::d_msm;
 use mpc_net::{LocalTestNet as Net, MpcNet, MultiplexedStreamID};
 use secret_sharing::pss::PackedSharingParams;
 
-pub async fn d_msm_test<G: CurveGroup, Net: MpcNet>(
+pub async fn d_msm_test<G: CurveGroup + WireType, Net: MpcNet>(
     pp: &PackedSharingParams<G::ScalarField>,
     dom: &Radix2EvaluationDomain<G::ScalarField>,
     net: &Net,
@@ -29,7 +30,7 @@ pub async fn d_msm_test<G: CurveGroup, Net: MpcNet>(
     let x_share_aff: Vec<G::Affine> =
         x_share.iter().map(|s| (*s).into()).collect();
 
-    d_msm::<G, _>(&x_share_aff, &y_share, pp, net, MultiplexedStreamID::One)
+    d_msm::<G, _>(&x_share_aff, &y_share, pp, net, MultiplexedStreamID::ONE)
         .await
         .unwrap();
 }
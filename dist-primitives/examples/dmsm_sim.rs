@@ -0,0 +1,83 @@
+// Predicts how long d_msm takes on a WAN, by running it over simulated links
+use ark_bls12_377::{Fr, G1Affine, G1Projective};
+use ark_std::UniformRand;
+use dist_primitives::dmsm::d_msm;
+use mpc_net::memory::MemoryTestNet;
+use mpc_net::sim::{LinkProfile, NetworkProfile, SimNet};
+use mpc_net::MultiplexedStreamID;
+use secret_sharing::pss::PackedSharingParams;
+use std::time::Duration;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "Simulated d_msm benchmark")]
+struct Opt {
+    /// Packing factor, the cluster has 4 * l parties
+    #[structopt(short, long, default_value = "2")]
+    l: usize,
+
+    /// Log2 of the MSM size
+    #[structopt(short, long, default_value = "14")]
+    log_size: usize,
+
+    /// One-way latency of every link, in milliseconds
+    #[structopt(long, default_value = "50")]
+    latency_ms: u64,
+
+    /// Jitter of every link, in milliseconds
+    #[structopt(long, default_value = "0")]
+    jitter_ms: u64,
+
+    /// Outgoing bandwidth of every party, in Mbit/s
+    #[structopt(long, default_value = "100")]
+    bandwidth_mbps: u64,
+}
+
+#[tokio::main]
+async fn main() {
+    env_logger::builder().format_timestamp(None).init();
+    let opt = Opt::from_args();
+
+    let link = LinkProfile {
+        jitter: Duration::from_millis(opt.jitter_ms),
+        ..LinkProfile::new(
+            Duration::from_millis(opt.latency_ms),
+            opt.bandwidth_mbps * 1_000_000,
+        )
+    };
+    let mut profile = NetworkProfile::uniform(link);
+    profile.uplink = link.bandwidth;
+
+    let l = opt.l;
+    let size = 1 << opt.log_size;
+    let elapsed = MemoryTestNet::new(4 * l)
+        .simulate_network_round(profile, move |net, profile| async move {
+            let net = SimNet::new(net, profile);
+            let pp = PackedSharingParams::<Fr>::new(l);
+
+            let rng = &mut ark_std::test_rng();
+            let x_share = (0..size / l)
+                .map(|_| G1Affine::rand(rng))
+                .collect::<Vec<_>>();
+            let y_share =
+                (0..size / l).map(|_| Fr::rand(rng)).collect::<Vec<_>>();
+
+            let _msm = d_msm::<G1Projective, _>(
+                &x_share,
+                &y_share,
+                &pp,
+                &net,
+                MultiplexedStreamID::ONE,
+            )
+            .await
+            .unwrap();
+            net.elapsed()
+        })
+        .await;
+
+    println!(
+        "l = {l}, m = 2^{}: simulated time {:?}",
+        opt.log_size,
+        elapsed.into_iter().max().unwrap()
+    );
+}
@@ -2,12 +2,13 @@ use ark_bls12_377::Fr;
 use ark_ec::CurveGroup;
 use ark_poly::{EvaluationDomain, Radix2EvaluationDomain};
 use ark_std::UniformRand;
+use dist_primitives::channel::WireType;
 use dist_primitives::dmsm::d_msm;
 use dist_primitives::dmsm::packexp_from_public;
 use mpc_net::{LocalTestNet as Net, MpcNet, MultiplexedStreamID};
 use secret_sharing::pss::PackedSharingParams;
 
-pub async fn d_msm_test<G: CurveGroup, Net: MpcNet>(
+pub async fn d_msm_test<G: CurveGroup + WireType, Net: MpcNet>(
     pp: &PackedSharingParams<G::ScalarField>,
     dom: &Radix2EvaluationDomain<G::ScalarField>,
     net: &Net,
@@ -54,7 +55,7 @@ pub async fn d_msm_test<G: CurveGroup, Net: MpcNet>(
         &y_share,
         pp,
         net,
-        MultiplexedStreamID::One,
+        MultiplexedStreamID::ONE,
     )
     .await
     .unwrap();
@@ -75,6 +76,23 @@ async fn main() {
             let pp = PackedSharingParams::<Fr>::new(2);
             let dom = Radix2EvaluationDomain::<Fr>::new(32768).unwrap();
             d_msm_test::<ark_bls12_377::G1Projective, _>(&pp, &dom, &net).await;
+
+            #[cfg(feature = "bls12-381")]
+            {
+                use ark_bls12_381::{Fr, G1Projective, G2Projective};
+                let pp = PackedSharingParams::<Fr>::new(2);
+                let dom = Radix2EvaluationDomain::<Fr>::new(32768).unwrap();
+                d_msm_test::<G1Projective, _>(&pp, &dom, &net).await;
+                d_msm_test::<G2Projective, _>(&pp, &dom, &net).await;
+            }
+
+            #[cfg(feature = "bn254")]
+            {
+                use ark_bn254::{Fr, G1Projective};
+                let pp = PackedSharingParams::<Fr>::new(2);
+                let dom = Radix2EvaluationDomain::<Fr>::new(32768).unwrap();
+                d_msm_test::<G1Projective, _>(&pp, &dom, &net).await;
+            }
         })
         .await;
 }
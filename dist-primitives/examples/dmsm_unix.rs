@@ -0,0 +1,68 @@
+// Runs d_msm between parties connected by Unix socket pairs instead of TCP
+
+#[cfg(unix)]
+#[tokio::main]
+async fn main() {
+    use ark_bls12_377::{Fr, G1Projective};
+    use ark_ec::{CurveGroup, VariableBaseMSM};
+    use ark_poly::{EvaluationDomain, Radix2EvaluationDomain};
+    use ark_std::UniformRand;
+    use dist_primitives::dmsm::d_msm;
+    use dist_primitives::dmsm::packexp_from_public;
+    use mpc_net::{LocalTestNet as Net, MpcNet, MultiplexedStreamID};
+    use secret_sharing::pss::PackedSharingParams;
+
+    env_logger::builder().format_timestamp(None).init();
+
+    let network = Net::new_local_unix_testnet(8).await.unwrap();
+
+    network
+        .simulate_network_round((), |net, _| async move {
+            let pp = PackedSharingParams::<Fr>::new(2);
+            let dom = Radix2EvaluationDomain::<Fr>::new(4096).unwrap();
+            let rng = &mut ark_std::test_rng();
+
+            let y_pub: Vec<Fr> =
+                (0..dom.size()).map(|_| Fr::rand(rng)).collect();
+            let x_pub: Vec<G1Projective> =
+                (0..dom.size()).map(|_| G1Projective::rand(rng)).collect();
+
+            let x_share: Vec<_> = x_pub
+                .chunks(pp.l)
+                .map(|s| {
+                    packexp_from_public(s, &pp)[net.party_id() as usize]
+                        .into_affine()
+                })
+                .collect();
+            let y_share: Vec<Fr> = y_pub
+                .chunks(pp.l)
+                .map(|s| {
+                    pp.pack_from_public(s.to_vec())[net.party_id() as usize]
+                })
+                .collect();
+
+            let output = d_msm::<G1Projective, _>(
+                &x_share,
+                &y_share,
+                &pp,
+                &net,
+                MultiplexedStreamID::ONE,
+            )
+            .await
+            .unwrap();
+
+            if net.is_king() {
+                let x_pub_aff = G1Projective::normalize_batch(&x_pub);
+                let should_be_output =
+                    G1Projective::msm(&x_pub_aff, &y_pub).unwrap();
+                assert_eq!(should_be_output, output);
+                println!("d_msm over Unix sockets matches the local msm");
+            }
+        })
+        .await;
+}
+
+#[cfg(not(unix))]
+fn main() {
+    eprintln!("Unix sockets are not available on this platform");
+}
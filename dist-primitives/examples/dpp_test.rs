@@ -2,14 +2,14 @@ use ark_bls12_377::Fr;
 use ark_ff::{FftField, PrimeField};
 use ark_poly::{EvaluationDomain, Radix2EvaluationDomain};
 use dist_primitives::{
-    channel::MpcSerNet,
+    channel::{MpcSerNet, WireType},
     dpp::d_pp,
     utils::pack::{pack_vec, transpose},
 };
 use mpc_net::{LocalTestNet as Net, MpcNet, MultiplexedStreamID};
 use secret_sharing::pss::PackedSharingParams;
 
-pub async fn d_pp_test<F: FftField + PrimeField, Net: MpcNet>(
+pub async fn d_pp_test<F: FftField + PrimeField + WireType, Net: MpcNet>(
     pp: &PackedSharingParams<F>,
     dom: &Radix2EvaluationDomain<F>,
     net: &Net,
@@ -33,13 +33,13 @@ pub async fn d_pp_test<F: FftField + PrimeField, Net: MpcNet>(
         px_share.clone(),
         pp,
         net,
-        MultiplexedStreamID::One,
+        MultiplexedStreamID::ONE,
     )
     .await
     .unwrap();
 
     // Send to king who reconstructs and checks the answer
-    net.send_to_king(&pp_px_share, MultiplexedStreamID::One)
+    net.send_to_king(&pp_px_share, MultiplexedStreamID::ONE)
         .await
         .unwrap()
         .map(|pp_px_shares| {
@@ -1,60 +1,511 @@
-use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
+use ark_serialize::{
+    CanonicalDeserialize, CanonicalSerialize, Compress, Validate,
+};
 use async_trait::async_trait;
 
-use mpc_net::{MpcNet, MpcNetError, MultiplexedStreamID};
+use mpc_net::session::TAG_ROOM;
+use mpc_net::{MpcNet, MpcNetError, MultiplexedStreamID, WireCodec};
+
+/// Header prepended to every value exchanged with the king.
+///
+/// It carries the identity of the serialized type and the length of the
+/// payload so that the receiver can reject a malformed message from a single
+/// party before it reaches `transpose` or `unpack`.
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, CanonicalSerialize, CanonicalDeserialize,
+)]
+pub struct RoundHeader {
+    /// [`WireType::TAG`] of the payload type
+    pub type_tag: u64,
+    /// Number of payload bytes following the header
+    pub payload_len: u64,
+    /// Whether the payload was serialized in compressed form
+    pub compressed: bool,
+}
+
+impl RoundHeader {
+    pub fn new<T: WireType>(payload_len: usize, compressed: bool) -> Self {
+        Self {
+            type_tag: T::TAG,
+            payload_len: payload_len as u64,
+            compressed,
+        }
+    }
+}
+
+fn compress_mode(compressed: bool) -> Compress {
+    if compressed {
+        Compress::Yes
+    } else {
+        Compress::No
+    }
+}
+
+/// A type that can go over the wire. Its tag identifies it to the receiver,
+/// so that the king can tell a value of the wrong type from a malformed one.
+///
+/// Tags are part of the wire format, so they must never change and no two
+/// types may share one. The fields and groups of the supported curves, a few
+/// integers and vectors of those have a tag; other types need an impl before
+/// they can be sent.
+pub trait WireType: 'static {
+    const TAG: u64;
+}
+
+/// Tags of vectors end in this byte, which no other tag does
+const VEC_TAG: u64 = 0xff;
+
+impl<T: WireType> WireType for Vec<T> {
+    const TAG: u64 = (T::TAG << 8) | VEC_TAG;
+}
+
+macro_rules! wire_types {
+    ($tags:ident; $($ty:ty => $tag:expr),* $(,)?) => {
+        $(
+            impl WireType for $ty {
+                const TAG: u64 = $tag;
+            }
+        )*
+
+        #[cfg(test)]
+        const $tags: &[u64] = &[$($tag),*];
+    };
+}
+
+wire_types!(INTEGER_TAGS; u8 => 0x01, u32 => 0x02, u64 => 0x03);
+
+#[cfg(feature = "bls12-377")]
+wire_types!(BLS12_377_TAGS;
+    ark_bls12_377::Fr => 0x0101,
+    ark_bls12_377::Fq => 0x0102,
+    ark_bls12_377::G1Projective => 0x0103,
+    ark_bls12_377::G1Affine => 0x0104,
+    ark_bls12_377::G2Projective => 0x0105,
+    ark_bls12_377::G2Affine => 0x0106,
+);
+
+#[cfg(feature = "bls12-381")]
+wire_types!(BLS12_381_TAGS;
+    ark_bls12_381::Fr => 0x0201,
+    ark_bls12_381::Fq => 0x0202,
+    ark_bls12_381::G1Projective => 0x0203,
+    ark_bls12_381::G1Affine => 0x0204,
+    ark_bls12_381::G2Projective => 0x0205,
+    ark_bls12_381::G2Affine => 0x0206,
+);
+
+#[cfg(feature = "bn254")]
+wire_types!(BN254_TAGS;
+    ark_bn254::Fr => 0x0301,
+    ark_bn254::Fq => 0x0302,
+    ark_bn254::G1Projective => 0x0303,
+    ark_bn254::G1Affine => 0x0304,
+    ark_bn254::G2Projective => 0x0305,
+    ark_bn254::G2Affine => 0x0306,
+);
+
+/// Serializes `value` with `codec` behind a [`RoundHeader`], straight into a
+/// buffer of the right size. It leaves room for the network to seal the
+/// message in place.
+pub fn encode_round_message<T: CanonicalSerialize + WireType>(
+    value: &T,
+    codec: WireCodec,
+) -> Result<Vec<u8>, MpcNetError> {
+    let mode = compress_mode(codec.compress);
+    let payload_len = value.serialized_size(mode);
+    let header = RoundHeader::new::<T>(payload_len, codec.compress);
+    let mut bytes =
+        Vec::with_capacity(header.compressed_size() + payload_len + TAG_ROOM);
+    header.serialize_compressed(&mut bytes)?;
+    value.serialize_with_mode(&mut bytes, mode)?;
+    Ok(bytes)
+}
+
+/// Validates the [`RoundHeader`] sent by `party` and deserializes the payload.
+///
+/// The payload is decoded in the form announced by the sender; whether it is
+/// checked is up to the receiver's `codec`. If `reference` is given, the
+/// payload must serialize to as many bytes as it does.
+pub fn decode_round_message<
+    T: CanonicalDeserialize + CanonicalSerialize + WireType,
+>(
+    bytes: &[u8],
+    party: u32,
+    codec: WireCodec,
+    reference: Option<&T>,
+) -> Result<T, MpcNetError> {
+    let protocol_err = |err: String| MpcNetError::Protocol { err, party };
+
+    let mut reader = bytes;
+    let header = RoundHeader::deserialize_compressed(&mut reader)
+        .map_err(|err| protocol_err(format!("Malformed header: {err}")))?;
+
+    if header.type_tag != T::TAG {
+        return Err(protocol_err(format!(
+            "Sent a message of the wrong type, expected {}",
+            std::any::type_name::<T>()
+        )));
+    }
+
+    if header.payload_len != reader.len() as u64 {
+        return Err(protocol_err(format!(
+            "Header announces {} payload bytes but {} were sent",
+            header.payload_len,
+            reader.len()
+        )));
+    }
+
+    if let Some(reference) = reference {
+        let expected_len =
+            reference.serialized_size(compress_mode(header.compressed));
+        if reader.len() != expected_len {
+            return Err(protocol_err(format!(
+                "Sent {} payload bytes, expected {}",
+                reader.len(),
+                expected_len
+            )));
+        }
+    }
+
+    let validate = if codec.validate {
+        Validate::Yes
+    } else {
+        Validate::No
+    };
+
+    T::deserialize_with_mode(reader, compress_mode(header.compressed), validate)
+        .map_err(|err| protocol_err(format!("Malformed payload: {err}")))
+}
+
+/// Decodes the message each party sent, in party order
+fn decode_from_all<T, B>(
+    bytes_in: &[B],
+    codec: WireCodec,
+) -> Result<Vec<T>, MpcNetError>
+where
+    T: CanonicalDeserialize + CanonicalSerialize + WireType,
+    B: AsRef<[u8]>,
+{
+    bytes_in
+        .iter()
+        .enumerate()
+        .map(|(party, b)| {
+            decode_round_message(b.as_ref(), party as u32, codec, None)
+        })
+        .collect()
+}
 
 #[async_trait]
 pub trait MpcSerNet: MpcNet {
-    async fn send_to_king<T: CanonicalDeserialize + CanonicalSerialize>(
+    /// Every party sends `out` to the king. The king checks that each message
+    /// has the same type and length as its own before deserializing it, and
+    /// aborts the session if one does not.
+    async fn send_to_king<
+        T: CanonicalDeserialize + CanonicalSerialize + WireType + Send,
+    >(
         &self,
         out: &T,
         sid: MultiplexedStreamID,
     ) -> Result<Option<Vec<T>>, MpcNetError> {
-        let mut bytes_out = Vec::new();
-        out.serialize_compressed(&mut bytes_out).unwrap();
-        let bytes_in =
-            self.client_send_or_king_receive(&bytes_out, sid).await?;
+        self.send_to_king_with_codec(out, sid, self.wire_codec())
+            .await
+    }
+
+    /// Like [`MpcSerNet::send_to_king`], with an explicit codec for this call
+    async fn send_to_king_with_codec<
+        T: CanonicalDeserialize + CanonicalSerialize + WireType + Send,
+    >(
+        &self,
+        out: &T,
+        sid: MultiplexedStreamID,
+        codec: WireCodec,
+    ) -> Result<Option<Vec<T>>, MpcNetError> {
+        let bytes_out = encode_round_message(out, codec)?;
+        let bytes_in = self
+            .client_send_or_king_receive_bytes(bytes_out.into(), sid)
+            .await?;
 
         if let Some(bytes_in) = bytes_in {
-            let results: Vec<Result<T, MpcNetError>> = bytes_in
-                .into_iter()
-                .map(|b| {
-                    T::deserialize_compressed(&b[..])
-                        .map_err(|err| MpcNetError::Generic(err.to_string()))
+            let ret = bytes_in
+                .iter()
+                .enumerate()
+                .map(|(party, b)| {
+                    decode_round_message(b, party as u32, codec, Some(out))
                 })
-                .collect();
+                .collect::<Result<Vec<_>, _>>();
 
-            let mut ret = Vec::new();
-            for result in results {
-                ret.push(result?);
+            // The clients are waiting on the king, so tell them it gave up
+            if let Err(err) = &ret {
+                self.abort(&format!("{err:?}")).await;
             }
-
-            Ok(Some(ret))
+            ret.map(Some)
         } else {
             Ok(None)
         }
     }
 
     async fn recv_from_king<
-        T: CanonicalDeserialize + CanonicalSerialize + Send,
+        T: CanonicalDeserialize + CanonicalSerialize + WireType + Send,
     >(
         &self,
         out: Option<Vec<T>>,
         sid: MultiplexedStreamID,
     ) -> Result<T, MpcNetError> {
-        let bytes = out.map(|outs| {
-            outs.iter()
-                .map(|out| {
-                    let mut bytes_out = Vec::new();
-                    out.serialize_compressed(&mut bytes_out).unwrap();
-                    bytes_out.into()
-                })
-                .collect()
-        });
+        self.recv_from_king_with_codec(out, sid, self.wire_codec())
+            .await
+    }
+
+    /// Like [`MpcSerNet::recv_from_king`], with an explicit codec for this call
+    async fn recv_from_king_with_codec<
+        T: CanonicalDeserialize + CanonicalSerialize + WireType + Send,
+    >(
+        &self,
+        out: Option<Vec<T>>,
+        sid: MultiplexedStreamID,
+        codec: WireCodec,
+    ) -> Result<T, MpcNetError> {
+        let bytes = match out {
+            Some(outs) => Some(
+                outs.iter()
+                    .map(|out| encode_round_message(out, codec).map(Into::into))
+                    .collect::<Result<Vec<_>, _>>()?,
+            ),
+            None => None,
+        };
 
         let bytes_in = self.client_receive_or_king_send(bytes, sid).await?;
-        Ok(T::deserialize_compressed(&bytes_in[..])?)
+        decode_round_message(&bytes_in, self.king_id(), codec, None)
+    }
+
+    /// Every party sends `out` to every other party, and receives what each
+    /// of them sent, in party order. See [`MpcNet::broadcast`].
+    async fn broadcast_ser<
+        T: CanonicalDeserialize + CanonicalSerialize + WireType + Send,
+    >(
+        &self,
+        out: &T,
+        sid: MultiplexedStreamID,
+    ) -> Result<Vec<T>, MpcNetError> {
+        let codec = self.wire_codec();
+        let bytes_out = encode_round_message(out, codec)?;
+        let bytes_in = self.broadcast(&bytes_out, sid).await?;
+        let ret = decode_from_all(&bytes_in, codec);
+        if let Err(err) = &ret {
+            self.abort(&format!("{err:?}")).await;
+        }
+        ret
+    }
+
+    /// Every party sends `outs[i]` to party `i`, and receives what each party
+    /// sent it, in party order. See [`MpcNet::all_to_all`].
+    async fn all_to_all_ser<
+        T: CanonicalDeserialize + CanonicalSerialize + WireType + Send,
+    >(
+        &self,
+        outs: &[T],
+        sid: MultiplexedStreamID,
+    ) -> Result<Vec<T>, MpcNetError> {
+        let codec = self.wire_codec();
+        let bytes_out = outs
+            .iter()
+            .map(|out| encode_round_message(out, codec).map(Into::into))
+            .collect::<Result<Vec<_>, _>>()?;
+        let bytes_in = self.all_to_all(bytes_out, sid).await?;
+        let ret = decode_from_all(&bytes_in, codec);
+        if let Err(err) = &ret {
+            self.abort(&format!("{err:?}")).await;
+        }
+        ret
+    }
+
+    /// `sender` sends `out` to every party, who check with each other that
+    /// they got the same value. See [`MpcNet::reliable_broadcast`].
+    /// Provide a value iff you're the sender!
+    async fn reliable_broadcast_ser<
+        T: CanonicalDeserialize + CanonicalSerialize + WireType + Send,
+    >(
+        &self,
+        sender: u32,
+        out: Option<&T>,
+        sid: MultiplexedStreamID,
+    ) -> Result<T, MpcNetError> {
+        let codec = self.wire_codec();
+        let bytes_out = match out {
+            Some(out) => Some(encode_round_message(out, codec)?.into()),
+            None => None,
+        };
+        let bytes_in = self.reliable_broadcast(sender, bytes_out, sid).await?;
+        let ret = decode_round_message(&bytes_in, sender, codec, None);
+        if let Err(err) = &ret {
+            self.abort(&format!("{err:?}")).await;
+        }
+        ret
     }
 }
 
 impl<N: MpcNet> MpcSerNet for N {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ark_std::UniformRand;
+    use mpc_net::LocalTestNet;
+
+    const N_PARTIES: usize = 4;
+    const LEN: usize = 8;
+
+    #[tokio::test]
+    async fn send_to_king_round_trip() {
+        let net = LocalTestNet::new_local_testnet(N_PARTIES).await.unwrap();
+
+        net.simulate_network_round((), |net, _| async move {
+            let rng = &mut ark_std::test_rng();
+            let share = (0..LEN).map(|_| u64::rand(rng)).collect::<Vec<_>>();
+            let received = net
+                .send_to_king(&share, MultiplexedStreamID::ZERO)
+                .await
+                .unwrap();
+
+            let king_answer = received.inspect(|shares| {
+                assert_eq!(shares.len(), N_PARTIES);
+                assert!(shares.iter().all(|s| *s == share));
+            });
+            let got = net
+                .recv_from_king(king_answer, MultiplexedStreamID::ZERO)
+                .await
+                .unwrap();
+            assert_eq!(got, share);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn parties_exchange_values_with_each_other() {
+        let net = LocalTestNet::new_local_testnet(N_PARTIES).await.unwrap();
+
+        net.simulate_network_round((), |net, _| async move {
+            let sid = MultiplexedStreamID::ONE;
+            let id = net.party_id() as u64;
+            let ids = net.broadcast_ser(&id, sid).await.unwrap();
+            assert_eq!(ids, (0..N_PARTIES as u64).collect::<Vec<_>>());
+
+            // Party i sends party j the value N_PARTIES * i + j
+            let outs = (0..N_PARTIES as u64)
+                .map(|to| N_PARTIES as u64 * id + to)
+                .collect::<Vec<_>>();
+            let got = net.all_to_all_ser(&outs, sid).await.unwrap();
+            let expected = (0..N_PARTIES as u64)
+                .map(|from| N_PARTIES as u64 * from + id)
+                .collect::<Vec<_>>();
+            assert_eq!(got, expected);
+
+            let value = 42u64;
+            let out = (net.party_id() == 1).then_some(&value);
+            let got = net.reliable_broadcast_ser(1, out, sid).await.unwrap();
+            assert_eq!(got, value);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn king_rejects_short_share_vector() {
+        let net = LocalTestNet::new_local_testnet(N_PARTIES).await.unwrap();
+
+        net.simulate_network_round((), |net, _| async move {
+            let len = if net.party_id() == 2 { LEN - 1 } else { LEN };
+            let share = vec![1u64; len];
+            let result =
+                net.send_to_king(&share, MultiplexedStreamID::ZERO).await;
+
+            if net.is_king() {
+                match result {
+                    Err(MpcNetError::Protocol { party, .. }) => {
+                        assert_eq!(party, 2)
+                    }
+                    other => panic!("Expected a protocol error, got {other:?}"),
+                }
+            } else {
+                assert!(result.unwrap().is_none());
+            }
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn king_rejects_wrong_type() {
+        let net = LocalTestNet::new_local_testnet(N_PARTIES).await.unwrap();
+
+        net.simulate_network_round((), |net, _| async move {
+            let sid = MultiplexedStreamID::ZERO;
+            if net.party_id() == 1 {
+                // Same number of bytes as the other shares, but the wrong type
+                let share = vec![0u32; LEN * 2];
+                assert!(net.send_to_king(&share, sid).await.unwrap().is_none());
+                return;
+            }
+
+            let share = vec![1u64; LEN];
+            let result = net.send_to_king(&share, sid).await;
+            if net.is_king() {
+                match result {
+                    Err(MpcNetError::Protocol { party, .. }) => {
+                        assert_eq!(party, 1)
+                    }
+                    other => panic!("Expected a protocol error, got {other:?}"),
+                }
+            }
+        })
+        .await;
+    }
+
+    #[test]
+    fn wire_types_have_distinct_tags() {
+        let mut tags = INTEGER_TAGS.to_vec();
+        #[cfg(feature = "bls12-377")]
+        tags.extend_from_slice(BLS12_377_TAGS);
+        #[cfg(feature = "bls12-381")]
+        tags.extend_from_slice(BLS12_381_TAGS);
+        #[cfg(feature = "bn254")]
+        tags.extend_from_slice(BN254_TAGS);
+
+        assert!(tags.iter().all(|tag| tag & 0xff != VEC_TAG));
+        let mut unique = tags.clone();
+        unique.sort_unstable();
+        unique.dedup();
+        assert_eq!(tags.len(), unique.len());
+        assert_eq!(<Vec<u64>>::TAG, (0x03 << 8) | VEC_TAG);
+    }
+
+    #[cfg(feature = "bls12-377")]
+    #[tokio::test]
+    async fn points_round_trip_with_any_codec() {
+        use ark_bls12_377::G1Projective as G1;
+
+        let mut net = LocalTestNet::new_local_testnet(N_PARTIES).await.unwrap();
+        net.set_wire_codec(WireCodec::TRUSTED);
+
+        net.simulate_network_round((), |net, _| async move {
+            let rng = &mut ark_std::test_rng();
+            let share = (0..LEN).map(|_| G1::rand(rng)).collect::<Vec<_>>();
+            // Senders may pick a different codec per call
+            let codec = if net.party_id() == 3 {
+                WireCodec::COMPRESSED
+            } else {
+                net.wire_codec()
+            };
+            let received = net
+                .send_to_king_with_codec(
+                    &share,
+                    MultiplexedStreamID::ZERO,
+                    codec,
+                )
+                .await
+                .unwrap();
+
+            if let Some(shares) = received {
+                assert!(shares.iter().all(|s| *s == share));
+            }
+        })
+        .await;
+    }
+}
@@ -1,5 +1,5 @@
 use crate::{
-    channel::MpcSerNet,
+    channel::{MpcSerNet, WireType},
     utils::pack::{pack_vec, transpose},
 };
 use ark_ff::{FftField, PrimeField};
@@ -15,7 +15,7 @@ use std::mem;
 /// pad: whether or not to pad output shares with zeros
 /// degree2: whether or not to do degree reduction n the input shares
 pub async fn d_fft<
-    F: FftField + PrimeField,
+    F: FftField + PrimeField + WireType,
     D: EvaluationDomain<F>,
     Net: MpcSerNet,
 >(
@@ -36,6 +36,8 @@ pub async fn d_fft<
         dom.size()
     );
 
+    net.set_protocol_label(sid, "d_fft");
+
     // Parties apply FFT1 locally
     fft1_in_place(&mut pcoeff_share, dom, pp, dom.group_gen(), &net);
     // King applies FFT2 and parties receive shares of evals
@@ -54,7 +56,7 @@ pub async fn d_fft<
 }
 
 pub async fn d_ifft<
-    F: FftField + PrimeField,
+    F: FftField + PrimeField + WireType,
     D: EvaluationDomain<F>,
     Net: MpcSerNet,
 >(
@@ -75,6 +77,8 @@ pub async fn d_ifft<
         dom.size()
     );
 
+    net.set_protocol_label(sid, "d_ifft");
+
     peval_share.iter_mut().for_each(|x| *x *= dom.size_inv());
 
     // Parties apply FFT1 locally
@@ -96,7 +100,7 @@ pub async fn d_ifft<
 
 ////////////////////////////////////////////////////////////////////////////////////////////////////
 fn fft1_in_place<
-    F: FftField + PrimeField,
+    F: FftField + PrimeField + WireType,
     D: EvaluationDomain<F>,
     Net: MpcSerNet,
 >(
@@ -140,7 +144,7 @@ fn fft1_in_place<
 }
 
 fn fft2_in_place<
-    F: FftField + PrimeField,
+    F: FftField + PrimeField + WireType,
     D: EvaluationDomain<F>,
     Net: MpcSerNet,
 >(
@@ -183,7 +187,7 @@ fn fft2_in_place<
 
 /// Send shares after fft1 to king who finishes the protocol and returns packed shares
 async fn fft2_with_rearrange_pad<
-    F: FftField + PrimeField,
+    F: FftField + PrimeField + WireType,
     D: EvaluationDomain<F>,
     Net: MpcSerNet,
 >(
@@ -273,17 +277,16 @@ pub fn fft_in_place_rearrange<F: FftField + PrimeField>(data: &mut Vec<F>) {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use ark_bls12_377::Fr as F;
     use ark_poly::Radix2EvaluationDomain;
-    use ark_std::UniformRand;
+    use mpc_net::adversary::{check_common_faults, run_with_faults, Fault};
     use mpc_net::LocalTestNet;
     use mpc_net::MpcNet;
 
     const L: usize = 2;
     const M: usize = L * 4;
+    const N: usize = L * 4;
 
-    #[tokio::test]
-    async fn d_ifft_works() {
+    async fn d_ifft_works<F: FftField + PrimeField + WireType>() {
         let rng = &mut ark_std::test_rng();
         let pp = PackedSharingParams::<F>::new(L);
         let degree2 = false;
@@ -333,7 +336,7 @@ mod tests {
                         &constraint,
                         &pp,
                         &net,
-                        MultiplexedStreamID::Zero,
+                        MultiplexedStreamID::ZERO,
                     )
                     .await
                     .unwrap()
@@ -373,8 +376,7 @@ mod tests {
         assert_eq!(actual_x_evals, computed_x_evals);
     }
 
-    #[tokio::test]
-    async fn d_fft_works() {
+    async fn d_fft_works<F: FftField + PrimeField + WireType>() {
         let rng = &mut ark_std::test_rng();
         let pp = PackedSharingParams::<F>::new(L);
         let degree2 = false;
@@ -420,7 +422,7 @@ mod tests {
                         &constraint,
                         &pp,
                         &net,
-                        MultiplexedStreamID::Zero,
+                        MultiplexedStreamID::ZERO,
                     )
                     .await
                     .unwrap()
@@ -458,8 +460,7 @@ mod tests {
         assert_eq!(actual_x_coeff, computed_x_coeff);
     }
 
-    #[tokio::test]
-    async fn d_ifftxd_fft_works() {
+    async fn d_ifftxd_fft_works<F: FftField + PrimeField + WireType>() {
         let rng = &mut ark_std::test_rng();
         let pp = PackedSharingParams::<F>::new(L);
         let degree2 = false;
@@ -505,7 +506,7 @@ mod tests {
                         &constraint,
                         &pp,
                         &net,
-                        MultiplexedStreamID::Zero,
+                        MultiplexedStreamID::ZERO,
                     )
                     .await
                     .unwrap();
@@ -517,7 +518,7 @@ mod tests {
                         &constraint,
                         &pp,
                         &net,
-                        MultiplexedStreamID::Zero,
+                        MultiplexedStreamID::ZERO,
                     )
                     .await
                     .unwrap()
@@ -554,4 +555,80 @@ mod tests {
 
         assert_eq!(expected_x, computed_x);
     }
-}
\ No newline at end of file
+
+    /// Runs [`d_fft`] on random shares, with each fault injected into its
+    /// party
+    async fn d_fft_with_faults<F: FftField + PrimeField + WireType>(
+        network: LocalTestNet,
+        faults: Vec<(u32, Fault)>,
+    ) -> Vec<Result<Vec<F>, MpcNetError>> {
+        let rng = &mut ark_std::test_rng();
+        let pp = PackedSharingParams::<F>::new(L);
+        let constraint = Radix2EvaluationDomain::<F>::new(M).unwrap();
+        let x = (0..M).map(|_| F::rand(rng)).collect::<Vec<_>>();
+        let pcoeff = pack_vec(&x, &pp);
+
+        run_with_faults(
+            network,
+            faults,
+            (pcoeff, pp, constraint),
+            |net, (pcoeff, pp, constraint)| async move {
+                let idx = net.party_id() as usize;
+                let pcoeff_share =
+                    pcoeff.iter().map(|x| x[idx]).collect::<Vec<_>>();
+                d_fft(
+                    pcoeff_share,
+                    false,
+                    1,
+                    false,
+                    &constraint,
+                    &pp,
+                    &net,
+                    MultiplexedStreamID::ZERO,
+                )
+                .await
+            },
+        )
+        .await
+    }
+
+    async fn d_fft_common_faults<F: FftField + PrimeField + WireType>() {
+        check_common_faults(N, d_fft_with_faults::<F>).await;
+    }
+
+    /// Instantiates the generic tests above once per enabled curve
+    macro_rules! curve_tests {
+        ($($curve:ident: $feature:literal;)*) => {$(
+            #[cfg(feature = $feature)]
+            mod $curve {
+                use ::$curve::Fr;
+
+                #[tokio::test]
+                async fn d_ifft_works() {
+                    super::d_ifft_works::<Fr>().await;
+                }
+
+                #[tokio::test]
+                async fn d_fft_works() {
+                    super::d_fft_works::<Fr>().await;
+                }
+
+                #[tokio::test]
+                async fn d_ifftxd_fft_works() {
+                    super::d_ifftxd_fft_works::<Fr>().await;
+                }
+
+                #[tokio::test]
+                async fn d_fft_common_faults() {
+                    super::d_fft_common_faults::<Fr>().await;
+                }
+            }
+        )*};
+    }
+
+    curve_tests! {
+        ark_bls12_377: "bls12-377";
+        ark_bls12_381: "bls12-381";
+        ark_bn254: "bn254";
+    }
+}
@@ -1,9 +1,20 @@
-use crate::channel::MpcSerNet;
+use crate::channel::{MpcSerNet, WireType};
 use ark_ec::{CurveGroup, Group};
+use ark_ff::Field;
 use ark_poly::EvaluationDomain;
 use mpc_net::{MpcNetError, MultiplexedStreamID};
 use secret_sharing::pss::PackedSharingParams;
 
+/// Recovers the secrets packed in `shares`.
+///
+/// # Panics
+///
+/// In debug builds, if the shares do not lie on a polynomial of the right
+/// degree. Release builds don't check, and return whatever the shares
+/// interpolate to.
+#[deprecated(
+    note = "use `try_unpackexp`, which reports a share off the polynomial"
+)]
 pub fn unpackexp<G: Group, Net: MpcSerNet>(
     mut shares: Vec<G>,
     degree2: bool,
@@ -13,40 +24,92 @@ pub fn unpackexp<G: Group, Net: MpcSerNet>(
     // interpolate shares
     pp.share.ifft_in_place(&mut shares);
 
-    // Simplified this assertion using a zero check in the last n - d - 1 entries
-
     #[cfg(debug_assertions)]
-    {
-        let n = _net.n_parties();
-        let d: usize = if degree2 {
-            2 * (pp.t + pp.l)
-        } else {
-            pp.t + pp.l
-        };
+    if let Err(err) = check_degree(&shares, degree2, pp) {
+        panic!("Can not unpack the shares: {err:?}");
+    }
 
-        for share in shares.iter().take(n).skip(d + 1) {
-            debug_assert!(
-                share.is_zero(),
-                "Polynomial has degree > degree bound {})",
-                d
-            );
-        }
+    secrets_of(shares, degree2, pp)
+}
+
+/// Recovers the secrets packed in `shares`, checking that the shares lie on a
+/// polynomial of the right degree, so that a party sending a wrong share is
+/// caught
+pub fn try_unpackexp<G: Group>(
+    mut shares: Vec<G>,
+    degree2: bool,
+    pp: &PackedSharingParams<G::ScalarField>,
+) -> Result<Vec<G>, MpcNetError> {
+    // interpolate shares
+    pp.share.ifft_in_place(&mut shares);
+    check_degree(&shares, degree2, pp)?;
+    Ok(secrets_of(shares, degree2, pp))
+}
+
+/// Checks the degree of the polynomial with coefficients `coeffs`, with a
+/// zero check in the last n - d - 1 entries
+fn check_degree<G: Group>(
+    coeffs: &[G],
+    degree2: bool,
+    pp: &PackedSharingParams<G::ScalarField>,
+) -> Result<(), MpcNetError> {
+    let d = if degree2 {
+        2 * (pp.t + pp.l)
+    } else {
+        pp.t + pp.l
+    };
+    let spare = &coeffs[d + 1..];
+    if spare.iter().all(|coeff| coeff.is_zero()) {
+        return Ok(());
     }
+    Err(match faulty_party(spare, pp) {
+        Some(party) => MpcNetError::Protocol {
+            err: format!(
+                "Sent a share that is not on the polynomial of degree {d}"
+            ),
+            party,
+        },
+        None => MpcNetError::Generic(format!(
+            "Shares do not lie on a polynomial of degree {d}, and there are too few to tell whose share is wrong"
+        )),
+    })
+}
 
-    // Evaluate the polynomial on the coset to recover secrets
+/// Evaluates the polynomial with coefficients `coeffs` on the coset to
+/// recover the secrets
+fn secrets_of<G: Group>(
+    mut coeffs: Vec<G>,
+    degree2: bool,
+    pp: &PackedSharingParams<G::ScalarField>,
+) -> Vec<G> {
     if degree2 {
-        pp.secret2.fft_in_place(&mut shares);
-        shares[0..pp.l * 2]
-            .iter()
-            .step_by(2)
-            .copied()
-            .collect::<Vec<_>>()
+        pp.secret2.fft_in_place(&mut coeffs);
+        coeffs[0..pp.l * 2].iter().step_by(2).copied().collect()
     } else {
-        pp.secret.fft_in_place(&mut shares);
-        shares[0..pp.l].to_vec()
+        pp.secret.fft_in_place(&mut coeffs);
+        coeffs[0..pp.l].to_vec()
     }
 }
 
+/// The party whose share alone puts the coefficients above the degree bound,
+/// `spare`, off zero. A wrong share of the party at point `x` adds a multiple
+/// of `x^-k` to the coefficient of `x^k`, so each spare coefficient is the
+/// one before times `x^-1`. It takes two spare coefficients to tell.
+fn faulty_party<G: Group>(
+    spare: &[G],
+    pp: &PackedSharingParams<G::ScalarField>,
+) -> Option<u32> {
+    if spare.len() < 2 {
+        return None;
+    }
+    (0..pp.n)
+        .find(|&party| {
+            let step = pp.share.element(party).inverse().unwrap();
+            spare.windows(2).all(|pair| pair[0] * step == pair[1])
+        })
+        .map(|party| party as u32)
+}
+
 pub fn packexp_from_public_in_place<G: Group>(
     secrets: &mut Vec<G>,
     pp: &PackedSharingParams<G::ScalarField>,
@@ -67,7 +130,7 @@ pub fn packexp_from_public<G: Group>(
     result
 }
 
-pub async fn d_msm<G: CurveGroup, Net: MpcSerNet>(
+pub async fn d_msm<G: CurveGroup + WireType, Net: MpcSerNet>(
     bases: &[G::Affine],
     scalars: &[G::ScalarField],
     pp: &PackedSharingParams<G::ScalarField>,
@@ -77,6 +140,8 @@ pub async fn d_msm<G: CurveGroup, Net: MpcSerNet>(
     // Using affine is important because we don't want to create an extra vector for converting Projective to Affine.
     // Eventually we do have to convert to Projective but this will be pp.l group elements instead of m()
 
+    net.set_protocol_label(sid, "d_msm");
+
     // First round of local computation done by parties
     log::debug!("bases: {}, scalars: {}", bases.len(), scalars.len());
     let c_share = G::msm(bases, scalars)?;
@@ -85,110 +150,296 @@ pub async fn d_msm<G: CurveGroup, Net: MpcSerNet>(
     // Should be randomized. First convert to projective share.
 
     let n_parties = net.n_parties();
-    let king_answer: Option<Vec<G>> = net
-        .send_to_king(&c_share, sid)
-        .await?
-        .map(|shares: Vec<G>| {
-            // TODO: Mask with random values.
-            let output: G = unpackexp(shares, true, pp, &net).iter().sum();
-            vec![output; n_parties]
-        });
+    let king_answer: Option<Vec<G>> =
+        match net.send_to_king(&c_share, sid).await? {
+            Some(shares) => match try_unpackexp(shares, true, pp) {
+                // TODO: Mask with random values.
+                Ok(output) => Some(vec![output.iter().sum(); n_parties]),
+                Err(err) => {
+                    net.abort(&format!("{err:?}")).await;
+                    return Err(err);
+                }
+            },
+            None => None,
+        };
 
     net.recv_from_king(king_answer, sid).await
 }
 
 #[cfg(test)]
 mod tests {
-    use ark_ec::bls12::Bls12Config;
     use ark_ec::CurveGroup;
-    use ark_ec::Group;
-    use ark_ec::VariableBaseMSM;
     use ark_std::UniformRand;
-    use ark_std::Zero;
+    use mpc_net::adversary::{
+        assert_clients_aborted, check_common_faults, run_with_faults, Fault,
+    };
+    use mpc_net::{
+        LocalTestNet, MpcNet, MpcNetError, MultiplexedStreamID, WireCodec,
+    };
     use secret_sharing::pss::PackedSharingParams;
 
-    use ark_bls12_377::G1Affine;
-    use ark_bls12_377::G1Projective as G1P;
-    use mpc_net::LocalTestNet;
-
-    type F = <ark_ec::short_weierstrass::Projective<
-        <ark_bls12_377::Config as Bls12Config>::G1Config,
-    > as Group>::ScalarField;
-
-    use crate::dmsm::packexp_from_public;
-    use crate::dmsm::unpackexp;
-    use crate::utils::pack::transpose;
+    use crate::channel::{encode_round_message, WireType};
+    use crate::dmsm::{d_msm, packexp_from_public, try_unpackexp};
+    use crate::utils::pack::{pack_vec, transpose};
 
     const L: usize = 2;
     const N: usize = L * 4;
     // const T:usize = N/2 - L - 1;
     const M: usize = 1 << 8;
 
-    #[tokio::test]
-    async fn pack_unpack_test() {
-        println!("pack_unpack_test");
-        let net = LocalTestNet::new_local_testnet(4).await.unwrap();
+    fn pack_unpack_test<G: CurveGroup>() {
+        let pp = PackedSharingParams::<G::ScalarField>::new(L);
+        let rng = &mut ark_std::test_rng();
+        let secrets = (0..L).map(|_| G::rand(rng)).collect::<Vec<_>>();
+
+        let shares = packexp_from_public(&secrets, &pp);
+        let result = try_unpackexp(shares, false, &pp).unwrap();
+        assert_eq!(secrets, result);
+    }
+
+    fn pack_unpack2_test<G: CurveGroup>() {
+        let pp = PackedSharingParams::<G::ScalarField>::new(L);
+        let rng = &mut ark_std::test_rng();
+
+        let gsecrets = vec![G::rand(rng); M];
+        let fsecrets = vec![G::ScalarField::from(1_u32); M];
+
+        ///////////////////////////////////////
+        let gsecrets_aff = G::normalize_batch(&gsecrets);
+        let expected = G::msm(&gsecrets_aff, &fsecrets).unwrap();
+        ///////////////////////////////////////
+        let gshares: Vec<Vec<G>> = gsecrets
+            .chunks(L)
+            .map(|s| packexp_from_public(s, &pp))
+            .collect();
+
+        let fshares: Vec<Vec<G::ScalarField>> = fsecrets
+            .chunks(L)
+            .map(|s| pp.pack_from_public(s.to_vec()))
+            .collect();
+
+        let gshares = transpose(gshares);
+        let fshares = transpose(fshares);
 
-        println!("net init done");
+        let mut result = vec![G::zero(); N];
 
-        net.simulate_network_round((), |net, _| async move {
-            let pp = PackedSharingParams::<F>::new(L);
-            let rng = &mut ark_std::test_rng();
-            let secrets: [G1P; L] = UniformRand::rand(rng);
-            let secrets = secrets.to_vec();
+        for i in 0..N {
+            let temp_aff = G::normalize_batch(&gshares[i]);
+            result[i] = G::msm(&temp_aff, &fshares[i]).unwrap();
+        }
+        let result: G = try_unpackexp(result, true, &pp).unwrap().iter().sum();
+        assert_eq!(expected, result);
+    }
+
+    /// The expected output of an MSM of `M` random points, and each party's
+    /// shares of its bases and scalars
+    #[allow(clippy::type_complexity)]
+    fn d_msm_inputs<G: CurveGroup>(
+        pp: &PackedSharingParams<G::ScalarField>,
+    ) -> (G, Vec<Vec<G>>, Vec<Vec<G::ScalarField>>) {
+        let rng = &mut ark_std::test_rng();
+
+        let x_pub = (0..M).map(|_| G::rand(rng)).collect::<Vec<_>>();
+        let y_pub = (0..M)
+            .map(|_| G::ScalarField::rand(rng))
+            .collect::<Vec<_>>();
+        let expected = G::msm(&G::normalize_batch(&x_pub), &y_pub).unwrap();
+
+        // Party i holds the i-th share of every packed chunk
+        let x_shares = transpose(
+            x_pub
+                .chunks(L)
+                .map(|s| packexp_from_public(s, pp))
+                .collect(),
+        );
+        let y_shares = transpose(pack_vec(&y_pub, pp));
+        (expected, x_shares, y_shares)
+    }
+
+    /// Runs [`d_msm`], with each fault injected into its party
+    async fn d_msm_with_faults<G: CurveGroup + WireType>(
+        net: LocalTestNet,
+        faults: Vec<(u32, Fault)>,
+    ) -> (G, Vec<Result<G, MpcNetError>>) {
+        let pp = PackedSharingParams::<G::ScalarField>::new(L);
+        let (expected, x_shares, y_shares) = d_msm_inputs::<G>(&pp);
 
-            let shares = packexp_from_public(&secrets, &pp);
-            let result = unpackexp(shares, false, &pp, &net);
-            assert_eq!(secrets, result);
+        let results = run_with_faults(
+            net,
+            faults,
+            (x_shares, y_shares, pp),
+            |net, (x_shares, y_shares, pp)| async move {
+                let idx = net.party_id() as usize;
+                let bases = G::normalize_batch(&x_shares[idx]);
+                d_msm::<G, _>(
+                    &bases,
+                    &y_shares[idx],
+                    &pp,
+                    &net,
+                    MultiplexedStreamID::ZERO,
+                )
+                .await
+            },
+        )
+        .await;
+        (expected, results)
+    }
+
+    async fn d_msm_test<G: CurveGroup + WireType>() {
+        let pp = PackedSharingParams::<G::ScalarField>::new(L);
+        let net = LocalTestNet::new_local_testnet(pp.n).await.unwrap();
+        let (expected, x_shares, y_shares) = d_msm_inputs::<G>(&pp);
+
+        let result = net
+            .simulate_network_round(
+                (x_shares, y_shares, pp),
+                |net, (x_shares, y_shares, pp)| async move {
+                    let idx = net.party_id() as usize;
+                    let bases = G::normalize_batch(&x_shares[idx]);
+                    d_msm::<G, _>(
+                        &bases,
+                        &y_shares[idx],
+                        &pp,
+                        &net,
+                        MultiplexedStreamID::ZERO,
+                    )
+                    .await
+                    .unwrap()
+                },
+            )
+            .await;
+
+        // The king hands the same reconstructed output to every party
+        for output in result {
+            assert_eq!(expected, output);
+        }
+    }
+
+    async fn d_msm_common_faults_test<G: CurveGroup + WireType>() {
+        check_common_faults(N, |net, faults| async move {
+            d_msm_with_faults::<G>(net, faults).await.1
         })
         .await;
     }
 
-    #[tokio::test]
-    async fn pack_unpack2_test() {
-        let net = LocalTestNet::new_local_testnet(4).await.unwrap();
+    fn random_share_test<G: CurveGroup>() {
+        let pp = PackedSharingParams::<G::ScalarField>::new(L);
+        let rng = &mut ark_std::test_rng();
+        let secrets = (0..L).map(|_| G::rand(rng)).collect::<Vec<_>>();
 
-        net.simulate_network_round((), |net, _| async move {
-            let pp = PackedSharingParams::<F>::new(L);
-            let rng = &mut ark_std::test_rng();
+        // A well-formed share that is not on the sharing polynomial can only
+        // be caught by the degree check
+        let mut shares = packexp_from_public(&secrets, &pp);
+        assert_eq!(try_unpackexp(shares.clone(), false, &pp).unwrap(), secrets);
+        shares[5] = G::rand(rng);
+        assert!(matches!(
+            try_unpackexp(shares, false, &pp),
+            Err(MpcNetError::Protocol { party: 5, .. })
+        ));
+    }
 
-            let gsecrets: [G1P; M] = [G1P::rand(rng); M];
-            let gsecrets = gsecrets.to_vec();
+    async fn d_msm_random_share_test<G: CurveGroup + WireType>() {
+        // A well-formed share that is not on the sharing polynomial can only
+        // be caught by the king's degree check. A product has one share to
+        // spare, too few to tell whose share is wrong.
+        let random_share = Fault::replace(|_, _| {
+            let share = G::rand(&mut ark_std::test_rng());
+            encode_round_message(&share, WireCodec::default())
+                .unwrap()
+                .into()
+        });
+        let net = LocalTestNet::new_local_testnet(N).await.unwrap();
+        let (_, results) =
+            d_msm_with_faults::<G>(net, vec![(5, random_share)]).await;
 
-            let fsecrets: [F; M] = [F::from(1_u32); M];
-            let fsecrets = fsecrets.to_vec();
+        assert!(matches!(results[0], Err(MpcNetError::Generic(_))));
+        assert_clients_aborted(&results);
+    }
 
-            ///////////////////////////////////////
-            let gsecrets_aff: Vec<G1Affine> =
-                gsecrets.iter().map(|s| (*s).into()).collect();
-            let expected = G1P::msm(&gsecrets_aff, &fsecrets).unwrap();
-            ///////////////////////////////////////
-            let gshares: Vec<Vec<G1P>> = gsecrets
-                .chunks(L)
-                .map(|s| packexp_from_public(s, &pp))
-                .collect();
+    async fn d_msm_king_equivocates_test<G: CurveGroup + WireType>() {
+        // The king hands parties 1 and 2 another output than the rest
+        let forged = Fault::equivocate(&[1, 2], |_, _| {
+            let output = G::rand(&mut ark_std::test_rng());
+            encode_round_message(&output, WireCodec::default())
+                .unwrap()
+                .into()
+        });
+        let net = LocalTestNet::new_local_testnet(N).await.unwrap();
+        let (expected, results) =
+            d_msm_with_faults::<G>(net, vec![(0, forged)]).await;
 
-            let fshares: Vec<Vec<F>> = fsecrets
-                .chunks(L)
-                .map(|s| pp.pack_from_public(s.to_vec()))
-                .collect();
+        // Nothing in d_msm checks that the king sent everyone the same
+        // output, so the parties it lied to are left with a wrong one
+        for (party, result) in results.into_iter().enumerate() {
+            let lied_to = party == 1 || party == 2;
+            assert_eq!(result.unwrap() != expected, lied_to, "party {party}");
+        }
+    }
+
+    /// Instantiates the generic tests above once per enabled curve
+    macro_rules! curve_tests {
+        ($($curve:ident: $feature:literal;)*) => {$(
+            #[cfg(feature = $feature)]
+            mod $curve {
+                use super::*;
+                use ::$curve::{G1Projective as G1P, G2Projective as G2P};
+
+                #[test]
+                fn pack_unpack_g1() {
+                    pack_unpack_test::<G1P>();
+                }
+
+                #[test]
+                fn pack_unpack_g2() {
+                    pack_unpack_test::<G2P>();
+                }
+
+                #[test]
+                fn pack_unpack2_g1() {
+                    pack_unpack2_test::<G1P>();
+                }
 
-            let gshares = transpose(gshares);
-            let fshares = transpose(fshares);
+                #[test]
+                fn pack_unpack2_g2() {
+                    pack_unpack2_test::<G2P>();
+                }
 
-            let mut result = vec![G1P::zero(); N];
+                #[tokio::test]
+                async fn d_msm_g1() {
+                    d_msm_test::<G1P>().await;
+                }
 
-            for i in 0..N {
-                let temp_aff: Vec<
-                    <ark_ec::short_weierstrass::Projective<
-                        <ark_bls12_377::Config as Bls12Config>::G1Config,
-                    > as CurveGroup>::Affine,
-                > = gshares[i].iter().map(|s| (*s).into()).collect();
-                result[i] = G1P::msm(&temp_aff, &fshares[i]).unwrap();
+                #[tokio::test]
+                async fn d_msm_g2() {
+                    d_msm_test::<G2P>().await;
+                }
+
+                #[tokio::test]
+                async fn d_msm_common_faults() {
+                    d_msm_common_faults_test::<G1P>().await;
+                }
+
+                #[test]
+                fn random_share() {
+                    random_share_test::<G1P>();
+                }
+
+                #[tokio::test]
+                async fn d_msm_random_share() {
+                    d_msm_random_share_test::<G1P>().await;
+                }
+
+                #[tokio::test]
+                async fn d_msm_king_equivocates() {
+                    d_msm_king_equivocates_test::<G1P>().await;
+                }
             }
-            let result: G1P = unpackexp(result, true, &pp, &net).iter().sum();
-            assert_eq!(expected, result);
-        })
-        .await;
+        )*};
+    }
+
+    curve_tests! {
+        ark_bls12_377: "bls12-377";
+        ark_bls12_381: "bls12-381";
+        ark_bn254: "bn254";
     }
-}
\ No newline at end of file
+}
@@ -2,7 +2,7 @@
 // Given x1, x2, .., xn, output x1, x1*x2, x1*x2*x3, .., x1*x2*..*xn
 
 use crate::{
-    channel::MpcSerNet,
+    channel::{MpcSerNet, WireType},
     utils::{
         deg_red::deg_red,
         pack::{pack_vec, transpose},
@@ -14,13 +14,18 @@ use secret_sharing::pss::PackedSharingParams;
 
 // Given pre-processed randomness [s], [s^-1]
 // Partial products of [num] and [den] are computed
-pub async fn d_pp<F: FftField + PrimeField + Field, Net: MpcSerNet>(
+pub async fn d_pp<
+    F: FftField + PrimeField + Field + WireType,
+    Net: MpcSerNet,
+>(
     num: Vec<F>,
     den: Vec<F>,
     pp: &PackedSharingParams<F>,
     net: &Net,
     sid: MultiplexedStreamID,
 ) -> Result<Vec<F>, MpcNetError> {
+    net.set_protocol_label(sid, "d_pp");
+
     // using some dummy randomness
     let s = F::from(1_u32);
     let sinv = s.inverse().unwrap();
@@ -2,17 +2,19 @@ use ark_ff::{FftField, PrimeField};
 use mpc_net::{MpcNetError, MultiplexedStreamID};
 use secret_sharing::pss::PackedSharingParams;
 
-use crate::channel::MpcSerNet;
+use crate::channel::{MpcSerNet, WireType};
 
 use super::pack::transpose;
 
 /// Reduces the degree of a poylnomial with the help of king
-pub async fn deg_red<F: FftField + PrimeField, Net: MpcSerNet>(
+pub async fn deg_red<F: FftField + PrimeField + WireType, Net: MpcSerNet>(
     px: Vec<F>,
     pp: &PackedSharingParams<F>,
     net: &Net,
     sid: MultiplexedStreamID,
 ) -> Result<Vec<F>, MpcNetError> {
+    net.set_protocol_label(sid, "deg_red");
+
     let received_shares = net.send_to_king(&px, sid).await?;
     let king_answer: Option<Vec<Vec<F>>> =
         received_shares.map(|px_shares: Vec<Vec<F>>| {
@@ -10,6 +10,7 @@ use ark_relations::r1cs::{ConstraintSynthesizer, ConstraintSystem};
 use ark_std::{cfg_chunks, cfg_into_iter, end_timer, start_timer, Zero};
 use std::sync::Arc;
 
+use dist_primitives::channel::WireType;
 use groth16::qap::qap;
 use groth16::{ext_wit, qap};
 use log::debug;
@@ -36,6 +37,9 @@ async fn dsha256<E, Net>(
 ) -> (E::G1, E::G2, E::G1)
 where
     E: Pairing,
+    E::ScalarField: WireType,
+    E::G1: WireType,
+    E::G2: WireType,
     Net: MpcNet,
 {
     let h_share = ext_wit::h(qap_share, pp, &net).await.unwrap();
@@ -50,7 +54,7 @@ where
         S: &crs_share.s,
         a: a_share,
     }
-    .compute(net, MultiplexedStreamID::Zero)
+    .compute(net, MultiplexedStreamID::ZERO)
     .await
     .unwrap();
     end_timer!(compute_a);
@@ -64,7 +68,7 @@ where
         V: &crs_share.v,
         a: a_share,
     }
-    .compute(net, MultiplexedStreamID::Zero)
+    .compute(net, MultiplexedStreamID::ZERO)
     .await
     .unwrap();
     end_timer!(compute_b);
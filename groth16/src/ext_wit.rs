@@ -2,7 +2,7 @@ use ark_ff::{FftField, PrimeField};
 use ark_poly::EvaluationDomain;
 use ark_relations::r1cs::SynthesisError;
 use ark_std::cfg_into_iter;
-use dist_primitives::channel::MpcSerNet;
+use dist_primitives::channel::{MpcSerNet, WireType};
 use dist_primitives::dfft::{d_fft, d_ifft};
 use dist_primitives::utils::pack::{pack_vec, transpose};
 use mpc_net::{MpcNetError, MultiplexedStreamID};
@@ -14,7 +14,7 @@ use crate::qap::PackedQAPShare;
 use rayon::prelude::*;
 
 pub async fn h<
-    F: FftField + PrimeField,
+    F: FftField + PrimeField + WireType,
     D: EvaluationDomain<F>,
     Net: MpcSerNet,
 >(
@@ -22,9 +22,16 @@ pub async fn h<
     pp: &PackedSharingParams<F>,
     net: &Net,
 ) -> Result<Vec<F>, MpcNetError> {
-    const CHANNEL0: MultiplexedStreamID = MultiplexedStreamID::Zero;
-    const CHANNEL1: MultiplexedStreamID = MultiplexedStreamID::One;
-    const CHANNEL2: MultiplexedStreamID = MultiplexedStreamID::Two;
+    const CHANNEL0: MultiplexedStreamID = MultiplexedStreamID::ZERO;
+    const CHANNEL1: MultiplexedStreamID = MultiplexedStreamID::ONE;
+    const CHANNEL2: MultiplexedStreamID = MultiplexedStreamID::TWO;
+
+    // The three FFTs run concurrently, each on its own stream
+    if net.n_streams() < 3 {
+        return Err(MpcNetError::BadInput {
+            err: "Computing h needs at least 3 streams",
+        });
+    }
 
     let domain = qap_share.domain;
     let m = domain.size();
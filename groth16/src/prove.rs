@@ -1,6 +1,7 @@
 #![allow(non_snake_case, clippy::too_many_arguments)]
 
 use ark_ec::pairing::Pairing;
+use dist_primitives::channel::WireType;
 use dist_primitives::dmsm::d_msm;
 use mpc_net::{MpcNet, MpcNetError, MultiplexedStreamID};
 use secret_sharing::pss::PackedSharingParams;
@@ -16,7 +17,10 @@ pub struct A<'a, E: Pairing> {
     pub a: &'a [E::ScalarField],
 }
 
-impl<'a, E: Pairing> A<'a, E> {
+impl<'a, E: Pairing> A<'a, E>
+where
+    E::G1: WireType,
+{
     /// Computes A
     pub async fn compute<Net: MpcNet>(
         self,
@@ -57,7 +61,10 @@ pub struct B<'a, E: Pairing> {
     pub a: &'a [E::ScalarField],
 }
 
-impl<'a, E: Pairing> B<'a, E> {
+impl<'a, E: Pairing> B<'a, E>
+where
+    E::G2: WireType,
+{
     /// Computes B
     pub async fn compute<Net: MpcNet>(
         self,
@@ -101,7 +108,10 @@ pub struct C<'a, E: Pairing> {
     pub h: &'a [E::ScalarField],
 }
 
-impl<'a, E: Pairing> C<'a, E> {
+impl<'a, E: Pairing> C<'a, E>
+where
+    E::G1: WireType,
+{
     /// Computes C
     pub async fn compute<Net: MpcNet>(
         self,
@@ -111,9 +121,16 @@ impl<'a, E: Pairing> C<'a, E> {
         // to denote elements in G1. We also assume that all the servers computing the proof
         // get A, M, s, r and h in the clear and only receive packed shares of the remaining elements.
 
-        const CHANNEL0: MultiplexedStreamID = MultiplexedStreamID::Zero;
-        const CHANNEL1: MultiplexedStreamID = MultiplexedStreamID::One;
-        const CHANNEL2: MultiplexedStreamID = MultiplexedStreamID::Two;
+        const CHANNEL0: MultiplexedStreamID = MultiplexedStreamID::ZERO;
+        const CHANNEL1: MultiplexedStreamID = MultiplexedStreamID::ONE;
+        const CHANNEL2: MultiplexedStreamID = MultiplexedStreamID::TWO;
+
+        // The three MSMs run concurrently, each on its own stream
+        if net.n_streams() < 3 {
+            return Err(MpcNetError::BadInput {
+                err: "Computing C needs at least 3 streams",
+            });
+        }
 
         // Calculate ∏{i∈[l+1,m]}(W_i)^a_i using dmsm
         let w = d_msm::<E::G1, _>(self.W, self.ax, self.pp, net, CHANNEL0);
@@ -135,3 +152,164 @@ impl<'a, E: Pairing> C<'a, E> {
         Ok(C)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use ark_bn254::{Bn254, Fr, G1Projective as G1, G2Projective as G2};
+    use ark_poly::{EvaluationDomain, Radix2EvaluationDomain};
+    use ark_std::{UniformRand, Zero};
+    use dist_primitives::channel::{
+        decode_round_message, encode_round_message,
+    };
+    use dist_primitives::utils::pack::{pack_vec, transpose};
+    use mpc_net::adversary::{
+        assert_clients_aborted, assert_king_timed_out, check_common_faults,
+        run_with_faults, Fault,
+    };
+    use mpc_net::{LocalTestNet, WireCodec};
+    use std::time::Duration;
+
+    use super::*;
+    use crate::ext_wit::h;
+    use crate::proving_key::PackedProvingKeyShare;
+    use crate::qap::{PackedQAPShare, QAP};
+
+    const L: usize = 2;
+    const M: usize = 1 << 6;
+
+    type ProofShare = (G1, G2, G1);
+
+    /// Runs every step of the prover, like the API does
+    async fn prove<Net: MpcNet>(
+        pp: &PackedSharingParams<Fr>,
+        crs_share: &PackedProvingKeyShare<Bn254>,
+        qap_share: PackedQAPShare<Fr, Radix2EvaluationDomain<Fr>>,
+        a_share: &[Fr],
+        net: &Net,
+    ) -> Result<ProofShare, MpcNetError> {
+        let h_share = h(qap_share, pp, net).await?;
+        let pi_a_share = A::<Bn254> {
+            L: Default::default(),
+            N: Default::default(),
+            r: Fr::zero(),
+            pp,
+            S: &crs_share.s,
+            a: a_share,
+        }
+        .compute(net, MultiplexedStreamID::ZERO)
+        .await?;
+        let pi_b_share = B::<Bn254> {
+            Z: Default::default(),
+            K: Default::default(),
+            s: Fr::zero(),
+            pp,
+            V: &crs_share.v,
+            a: a_share,
+        }
+        .compute(net, MultiplexedStreamID::ZERO)
+        .await?;
+        let pi_c_share = C::<Bn254> {
+            W: &crs_share.w,
+            U: &crs_share.u,
+            A: pi_a_share,
+            M: Default::default(),
+            r: Fr::zero(),
+            s: Fr::zero(),
+            pp,
+            H: &crs_share.h,
+            a: a_share,
+            ax: a_share,
+            h: &h_share,
+        }
+        .compute(net)
+        .await?;
+        Ok((pi_a_share, pi_b_share, pi_c_share))
+    }
+
+    /// Proves a random QAP and witness, with each fault injected into its
+    /// party
+    async fn prove_with_faults(
+        network: LocalTestNet,
+        faults: Vec<(u32, Fault)>,
+    ) -> Vec<Result<ProofShare, MpcNetError>> {
+        let rng = &mut ark_std::test_rng();
+        let pp = PackedSharingParams::<Fr>::new(L);
+
+        let a = (0..M).map(|_| Fr::rand(rng)).collect::<Vec<_>>();
+        let b = (0..M).map(|_| Fr::rand(rng)).collect::<Vec<_>>();
+        let c = a.iter().zip(&b).map(|(a, b)| *a * b).collect();
+        let qap = QAP {
+            num_inputs: 1,
+            num_constraints: M - 1,
+            a,
+            b,
+            c,
+            domain: Radix2EvaluationDomain::<Fr>::new(M).unwrap(),
+        };
+        let qap_shares = qap.pss(&pp);
+        // Every party gets the same key, which shares it with a constant
+        // polynomial
+        let crs_share = PackedProvingKeyShare::<Bn254>::rand(rng, M, &pp);
+        let witness = (0..M).map(|_| Fr::rand(rng)).collect::<Vec<_>>();
+        let a_shares = transpose(pack_vec(&witness, &pp));
+
+        run_with_faults(
+            network,
+            faults,
+            (pp, crs_share, qap_shares, a_shares),
+            |net, (pp, crs_share, qap_shares, a_shares)| async move {
+                let idx = net.party_id() as usize;
+                let qap_share = qap_shares[idx].clone();
+                prove(&pp, &crs_share, qap_share, &a_shares[idx], &net).await
+            },
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn prover_completes_without_faults() {
+        let network = LocalTestNet::new_local_testnet(L * 4).await.unwrap();
+        let results = prove_with_faults(network, vec![]).await;
+
+        let king = results[0].as_ref().unwrap();
+        for result in &results {
+            assert_eq!(result.as_ref().unwrap(), king);
+        }
+    }
+
+    #[tokio::test]
+    async fn prover_copes_with_common_faults() {
+        check_common_faults(L * 4, prove_with_faults).await;
+    }
+
+    #[tokio::test]
+    async fn prover_catches_shares_off_the_polynomial() {
+        // Well-formed MSM shares that are not on the sharing polynomial, so
+        // that only the king's degree check can tell
+        let random_msm_shares = Fault::replace(|_, bytes| {
+            let codec = WireCodec::default();
+            if decode_round_message::<G1>(bytes, 2, codec, None).is_err() {
+                return bytes.clone();
+            }
+            let share = G1::rand(&mut ark_std::test_rng());
+            encode_round_message(&share, codec).unwrap().into()
+        });
+        let network = LocalTestNet::new_local_testnet(L * 4).await.unwrap();
+        let results =
+            prove_with_faults(network, vec![(2, random_msm_shares)]).await;
+
+        // The king can't tell who sent the share, only that one is wrong
+        assert!(matches!(results[0], Err(MpcNetError::Generic(_))));
+        assert_clients_aborted(&results);
+    }
+
+    #[tokio::test]
+    async fn prover_times_out_on_a_party_going_silent_midway() {
+        let mut network = LocalTestNet::new_local_testnet(L * 4).await.unwrap();
+        network.set_op_timeout(Duration::from_secs(2));
+        let results =
+            prove_with_faults(network, vec![(5, Fault::stop().nth(4))]).await;
+
+        assert_king_timed_out(&results, 5);
+    }
+}
@@ -194,4 +194,32 @@ mod tests {
                 &pk, pp_g1, pp_g2,
             );
     }
+
+    #[cfg(feature = "bls12-381")]
+    #[test]
+    fn packed_pk_share_round_trip_bls12_381() {
+        use ark_bls12_381::Bls12_381;
+
+        let rng = &mut ark_std::test_rng();
+        let pp = PackedSharingParams::new(L);
+        let share = PackedProvingKeyShare::<Bls12_381>::rand(rng, 1 << 6, &pp);
+
+        let mut bytes = Vec::new();
+        share.serialize_compressed(&mut bytes).unwrap();
+        let decoded =
+            PackedProvingKeyShare::<Bls12_381>::deserialize_compressed(
+                &bytes[..],
+            )
+            .unwrap();
+        assert_eq!(share, decoded);
+
+        let mut bytes = Vec::new();
+        share.serialize_uncompressed(&mut bytes).unwrap();
+        let decoded =
+            PackedProvingKeyShare::<Bls12_381>::deserialize_uncompressed(
+                &bytes[..],
+            )
+            .unwrap();
+        assert_eq!(share, decoded);
+    }
 }
@@ -2,6 +2,7 @@ use ark_ec::CurveGroup;
 use ark_poly::Radix2EvaluationDomain;
 use axum::extract::DefaultBodyLimit;
 use axum::extract::Path as AxumPath;
+use axum::extract::State;
 use common::dto::CreateProofWithNaiveMpcResponse;
 use common::dto::CreateProofWithoutMpcRequest;
 use common::dto::CreateProofWithoutMpcResponse;
@@ -9,6 +10,7 @@ use common::dto::CustomError;
 use common::dto::GetCircuitFilesResponse;
 use common::dto::SaveCircuitRequest;
 use common::dto::SaveCircuitResponse;
+use dist_primitives::channel::WireType;
 use groth16::ext_wit;
 use groth16::proving_key::PackedProvingKeyShare;
 use groth16::qap;
@@ -38,7 +40,8 @@ use common::dto::VerifyProofResponse;
 use common::utils::arkworks_helpers::InputVec;
 use common::utils::file::find_latest_file_with_extension;
 use log::{debug, error, info};
-use mpc_net::{LocalTestNet as Net, MpcNet, MultiplexedStreamID};
+use mpc_net::cluster::LocalCluster;
+use mpc_net::{MpcNet, MpcNetError, MultiplexedStreamID};
 use rand::SeedableRng;
 use secret_sharing::pss::PackedSharingParams;
 use std::collections::HashMap;
@@ -49,13 +52,22 @@ use std::{
     env,
     // fs::{self},
     path::Path,
-    time::Instant,
+    time::{Duration, Instant},
 };
 use tokio::fs;
 use tokio::fs::File;
 use tokio::io::AsyncWriteExt; // for `write_all`
 use tokio::io::BufWriter;
 
+/// How long the parties of an MPC proof may take before the request fails
+const MPC_PROOF_TIMEOUT: Duration = Duration::from_secs(600);
+/// Packing factor of the MPC proofs, which are run by `4 * l` parties
+const MPC_PACKING_FACTOR: usize = 2;
+/// Streams each MPC proof uses, one per MSM it runs concurrently
+const MPC_STREAMS_PER_PROOF: usize = 3;
+/// How many MPC proofs run at once unless `MPC_PROOF_SLOTS` says otherwise
+const MPC_PROOF_SLOTS: usize = 4;
+
 /// Save a circuit
 ///
 /// # Inputs
@@ -422,6 +434,7 @@ async fn create_proof_without_mpc(
 
 #[axum::debug_handler]
 async fn create_proof_with_naive_mpc(
+    State(cluster): State<Arc<LocalCluster>>,
     mut multipart: Multipart,
 ) -> Result<Json<CreateProofWithNaiveMpcResponse>, CustomError> {
     let start = Instant::now();
@@ -557,7 +570,7 @@ async fn create_proof_with_naive_mpc(
     )
     .unwrap();
 
-    let pp = PackedSharingParams::new(2);
+    let pp = PackedSharingParams::new(MPC_PACKING_FACTOR);
     let qap_shares = qap.pss(&pp);
     let pp_g1 = PackedSharingParams::new(pp.l);
     let pp_g2 = PackedSharingParams::new(pp.l);
@@ -571,7 +584,6 @@ async fn create_proof_with_naive_mpc(
     let ax_shares = pack_from_witness::<Bn254>(&pp, aux_assignment.to_vec());
     let a_shares =
         pack_from_witness::<Bn254>(&pp, full_assignment[1..].to_vec());
-    let network = Net::new_local_testnet(pp.n).await.unwrap();
 
     // Log information about the circuit
     info!("Number of inputs: {}", num_inputs);
@@ -580,8 +592,8 @@ async fn create_proof_with_naive_mpc(
     debug!("------------");
     debug!("Start creating proof with MPC");
     let mpc_proof_time = start_timer!(|| "MPC Proof");
-    let result = network
-        .simulate_network_round(
+    let result = cluster
+        .run_job(
             (crs_shares, pp, a_shares, ax_shares, qap_shares),
             |net, (crs_shares, pp, a_shares, ax_shares, qap_shares)| async move {
                 let idx = net.party_id() as usize;
@@ -590,14 +602,24 @@ async fn create_proof_with_naive_mpc(
                 let a_share = &a_shares[idx];
                 let ax_share = &ax_shares[idx];
                 let qap_share = qap_shares[idx].clone();
-                dsha256(&pp, crs_share, qap_share, a_share, ax_share, &net).await
+                dsha256(&pp, crs_share, qap_share, a_share, ax_share, &net)
+                    .await
             },
         )
         .await;
     end_timer!(mpc_proof_time);
     debug!("End creating proof with MPC");
 
-    let (mut a, mut b, c) = result[0];
+    // The king's share comes first
+    let king_share = result.and_then(|shares| {
+        shares.into_iter().next().unwrap_or_else(|| {
+            Err(MpcNetError::Generic("No party ran the job".to_string()))
+        })
+    });
+    let (mut a, mut b, c) = king_share.map_err(|err| {
+        error!("MPC proof failed: {:?}", err);
+        CustomError::new(std::io::ErrorKind::Other, "MPC proof failed")
+    })?;
     // These elements are needed to construct the full proof, they are part of the proving key.
     // however, we can just send these values to the client, not the full proving key.
     a += pk.a_query[0] + vk.alpha_g1;
@@ -695,12 +717,15 @@ async fn dsha256<E, Net>(
     a_share: &[E::ScalarField],
     ax_share: &[E::ScalarField],
     net: &Net,
-) -> (E::G1, E::G2, E::G1)
+) -> Result<(E::G1, E::G2, E::G1), MpcNetError>
 where
     E: Pairing,
+    E::ScalarField: WireType,
+    E::G1: WireType,
+    E::G2: WireType,
     Net: MpcNet,
 {
-    let h_share = ext_wit::h(qap_share, pp, &net).await.unwrap();
+    let h_share = ext_wit::h(qap_share, pp, &net).await?;
     let msm_section = start_timer!(|| "MSM operations");
     // Compute msm while dropping the base vectors as they are not used again
     let compute_a = start_timer!(|| "Compute A");
@@ -712,9 +737,8 @@ where
         S: &crs_share.s,
         a: a_share,
     }
-    .compute(net, MultiplexedStreamID::Zero)
-    .await
-    .unwrap();
+    .compute(net, MultiplexedStreamID::ZERO)
+    .await?;
     end_timer!(compute_a);
 
     let compute_b = start_timer!(|| "Compute B");
@@ -726,9 +750,8 @@ where
         V: &crs_share.v,
         a: a_share,
     }
-    .compute(net, MultiplexedStreamID::Zero)
-    .await
-    .unwrap();
+    .compute(net, MultiplexedStreamID::ZERO)
+    .await?;
     end_timer!(compute_b);
 
     let compute_c = start_timer!(|| "Compute C");
@@ -746,14 +769,13 @@ where
         h: &h_share,
     }
     .compute(net)
-    .await
-    .unwrap();
+    .await?;
     end_timer!(compute_c);
 
     end_timer!(msm_section);
 
     // Send pi_a_share, pi_b_share, pi_c_share to client
-    (pi_a_share, pi_b_share, pi_c_share)
+    Ok((pi_a_share, pi_b_share, pi_c_share))
 }
 
 fn pack_from_witness<E: Pairing>(
@@ -782,8 +804,25 @@ fn pack_from_witness<E: Pairing>(
         .collect::<Vec<_>>()
 }
 
+/// How many MPC proofs run at once, from `MPC_PROOF_SLOTS` if it is set
+fn mpc_proof_slots() -> Result<usize, CustomError> {
+    let slots = match env::var("MPC_PROOF_SLOTS") {
+        Ok(slots) => slots,
+        Err(_) => return Ok(MPC_PROOF_SLOTS),
+    };
+    match slots.parse() {
+        Ok(n_slots) if n_slots > 0 => Ok(n_slots),
+        _ => Err(CustomError::new(
+            std::io::ErrorKind::InvalidInput,
+            &format!(
+                "MPC_PROOF_SLOTS must be a positive number, not {slots:?}"
+            ),
+        )),
+    }
+}
+
 #[tokio::main]
-async fn main() {
+async fn main() -> Result<(), CustomError> {
     color_backtrace::install();
     // initialize the logger
     env_logger::init();
@@ -791,6 +830,16 @@ async fn main() {
     // Get the port from the environment variable or use the default value of 8000
     let port = env::var("PORT").unwrap_or_else(|_| "8000".to_string());
 
+    // Connect the parties of the MPC proofs once, and run every proof on
+    // the same connections
+    let n_slots = mpc_proof_slots()?;
+    let n_parties = PackedSharingParams::<Bn254Fr>::new(MPC_PACKING_FACTOR).n;
+    let mut cluster =
+        LocalCluster::new(n_parties, MPC_STREAMS_PER_PROOF, n_slots)
+            .await
+            .unwrap();
+    cluster.set_job_timeout(MPC_PROOF_TIMEOUT);
+
     // build our application with a single route
     let app = Router::new()
         .route("/", get(|| async { "Hello, World!" }))
@@ -802,7 +851,8 @@ async fn main() {
         )
         .route("/verify_proof", post(verify_proof))
         .route("/get_circuit_files/:circuit_id", get(get_circuit_files))
-        .layer(DefaultBodyLimit::max(104857600)); // 100MB
+        .layer(DefaultBodyLimit::max(104857600)) // 100MB
+        .with_state(Arc::new(cluster));
 
     // run our app with hyper, listening globally on the specified port
     let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", port))
@@ -810,4 +860,5 @@ async fn main() {
         .unwrap();
     println!("Listening on port {}", port);
     axum::serve(listener, app).await.unwrap();
+    Ok(())
 }
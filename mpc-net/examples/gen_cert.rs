@@ -1,38 +1,46 @@
-use std::fs::File;
-use std::io::Write;
+use mpc_net::identity::{self, DEFAULT_VALIDITY};
+use mpc_net::prod::RustlsCertificate;
 use std::path::PathBuf;
+use std::time::Duration;
 use structopt::StructOpt;
 
 #[derive(Debug, StructOpt)]
-#[structopt(name = "Add IDs Example using ProdNet")]
+#[structopt(name = "Generate a certificate and private key")]
 struct Opt {
+    /// Where to write the certificate, as PEM if it ends in .pem or as DER
+    /// otherwise
     #[structopt(parse(from_os_str))]
     output_cert: PathBuf,
+    /// Where to write the private key, encoded like the certificate
     #[structopt(parse(from_os_str))]
     output_priv: PathBuf,
     #[structopt()]
     alt_name: String,
+    /// How many days the certificate is valid for
+    #[structopt(long)]
+    days: Option<u64>,
 }
 
 fn main() {
     let opts: Opt = Opt::from_args();
 
-    let output_cert = &opts.output_cert;
-    let output_priv = &opts.output_priv;
-
-    let mut file_cert = File::create(output_cert).unwrap();
-    let mut file_priv = File::create(output_priv).unwrap();
-
-    let self_signed_cert =
-        rcgen::generate_simple_self_signed(vec![opts.alt_name.clone()])
+    let validity = opts.days.map_or(DEFAULT_VALIDITY, |days| {
+        Duration::from_secs(days * 24 * 60 * 60)
+    });
+    let identity =
+        RustlsCertificate::generate(vec![opts.alt_name.clone()], validity)
             .unwrap();
-    let bytes_cert = self_signed_cert.serialize_der().unwrap();
-    let bytes_priv = self_signed_cert.serialize_private_key_der();
+    identity.save(&opts.output_cert, &opts.output_priv).unwrap();
 
-    file_cert.write_all(&bytes_cert).unwrap();
-    file_priv.write_all(&bytes_priv).unwrap();
+    let expiry = identity::expiry(&identity.cert).unwrap();
     println!(
-        "Generated certificate and private key for {}",
-        opts.alt_name
+        "Generated certificate and private key for {}, fingerprint {}, valid for {} more days",
+        opts.alt_name,
+        identity.fingerprint(),
+        expiry
+            .duration_since(std::time::SystemTime::now())
+            .unwrap_or_default()
+            .as_secs()
+            / (24 * 60 * 60)
     );
 }
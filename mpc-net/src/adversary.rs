@@ -0,0 +1,456 @@
+//! A wrapper that makes a party misbehave, to test how protocols detect it.
+//!
+//! [`AdversarialNet`] wraps any [`MpcNet`] and applies scripted [`Fault`]s to
+//! the messages the party sends: it can corrupt them, replace them, e.g. with
+//! random shares, send them late, tell different peers different things, or
+//! stop sending altogether. Receiving is left alone, so a misbehaving party
+//! still learns when the others give up on it.
+//!
+//! [`run_with_faults`] and [`check_common_faults`] run a protocol on a
+//! [`LocalTestNet`] with faults injected, so that the tests of each protocol
+//! only need to cover the faults particular to it.
+
+use std::fmt::Debug;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use tokio::net::TcpStream;
+use tokio_util::bytes::Bytes;
+
+use crate::multi::MpcNetConnection;
+use crate::{
+    LocalTestNet, MpcNet, MpcNetError, MultiplexedStreamID, NetStats, WireCodec,
+};
+
+/// Computes the message to send to a peer instead of the honest one
+pub type Replacement = Arc<dyn Fn(u32, &Bytes) -> Bytes + Send + Sync>;
+
+/// What happens to a message a fault applies to
+#[derive(Clone)]
+pub enum Action {
+    /// Flip every bit of the message
+    Corrupt,
+    /// Send something else instead
+    Replace(Replacement),
+    /// Send the message late
+    Delay(Duration),
+    /// Stop sending this message and every later one, like a party that
+    /// hangs or was cut off
+    Stop,
+}
+
+/// Which messages to misbehave on, and how. A fault applies to every message
+/// sent unless narrowed down.
+#[derive(Clone)]
+pub struct Fault {
+    action: Action,
+    nth: Option<usize>,
+    peers: Option<Vec<u32>>,
+    sid: Option<MultiplexedStreamID>,
+}
+
+impl Fault {
+    pub fn new(action: Action) -> Self {
+        Self {
+            action,
+            nth: None,
+            peers: None,
+            sid: None,
+        }
+    }
+
+    pub fn corrupt() -> Self {
+        Self::new(Action::Corrupt)
+    }
+
+    pub fn replace(
+        f: impl Fn(u32, &Bytes) -> Bytes + Send + Sync + 'static,
+    ) -> Self {
+        Self::new(Action::Replace(Arc::new(f)))
+    }
+
+    pub fn delay(delay: Duration) -> Self {
+        Self::new(Action::Delay(delay))
+    }
+
+    pub fn stop() -> Self {
+        Self::new(Action::Stop)
+    }
+
+    /// Send `peers` something else than the other peers get
+    pub fn equivocate(
+        peers: &[u32],
+        f: impl Fn(u32, &Bytes) -> Bytes + Send + Sync + 'static,
+    ) -> Self {
+        Self::replace(f).to(peers)
+    }
+
+    /// Only apply to the `n`th matching message, counting from zero
+    pub fn nth(mut self, n: usize) -> Self {
+        self.nth = Some(n);
+        self
+    }
+
+    /// Only apply to messages sent to `peers`
+    pub fn to(mut self, peers: &[u32]) -> Self {
+        self.peers = Some(peers.to_vec());
+        self
+    }
+
+    /// Only apply to messages sent on `sid`
+    pub fn on(mut self, sid: MultiplexedStreamID) -> Self {
+        self.sid = Some(sid);
+        self
+    }
+
+    fn matches(&self, peer: u32, sid: MultiplexedStreamID) -> bool {
+        self.peers
+            .as_ref()
+            .is_none_or(|peers| peers.contains(&peer))
+            && self.sid.is_none_or(|only| only == sid)
+    }
+}
+
+/// Wraps a network and applies [`Fault`]s to the messages sent through it
+pub struct AdversarialNet<N> {
+    inner: N,
+    /// Each with how many matching messages it has seen
+    faults: Vec<(Fault, AtomicUsize)>,
+    stopped: AtomicBool,
+}
+
+impl<N: MpcNet> AdversarialNet<N> {
+    /// A party that behaves honestly until given a fault
+    pub fn new(inner: N) -> Self {
+        Self {
+            inner,
+            faults: Vec::new(),
+            stopped: AtomicBool::new(false),
+        }
+    }
+
+    pub fn with_fault(mut self, fault: Fault) -> Self {
+        self.faults.push((fault, AtomicUsize::new(0)));
+        self
+    }
+
+    /// Adds `fault` if this is party `party`, so that every party of a test
+    /// network can be wrapped the same way
+    pub fn with_fault_for(self, party: u32, fault: Fault) -> Self {
+        if self.inner.party_id() == party {
+            self.with_fault(fault)
+        } else {
+            self
+        }
+    }
+
+    pub fn inner(&self) -> &N {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> N {
+        self.inner
+    }
+}
+
+#[async_trait]
+impl<N: MpcNet> MpcNet for AdversarialNet<N> {
+    fn n_parties(&self) -> usize {
+        self.inner.n_parties()
+    }
+
+    fn party_id(&self) -> u32 {
+        self.inner.party_id()
+    }
+
+    fn king_id(&self) -> u32 {
+        self.inner.king_id()
+    }
+
+    fn is_init(&self) -> bool {
+        self.inner.is_init()
+    }
+
+    fn n_streams(&self) -> usize {
+        self.inner.n_streams()
+    }
+
+    fn wire_codec(&self) -> WireCodec {
+        self.inner.wire_codec()
+    }
+
+    fn set_protocol_label(&self, sid: MultiplexedStreamID, label: &str) {
+        self.inner.set_protocol_label(sid, label)
+    }
+
+    fn stats(&self) -> NetStats {
+        self.inner.stats()
+    }
+
+    async fn recv_from(
+        &self,
+        id: u32,
+        sid: MultiplexedStreamID,
+    ) -> Result<Bytes, MpcNetError> {
+        self.inner.recv_from(id, sid).await
+    }
+
+    async fn send_to(
+        &self,
+        id: u32,
+        mut bytes: Bytes,
+        sid: MultiplexedStreamID,
+    ) -> Result<(), MpcNetError> {
+        for (fault, seen) in &self.faults {
+            if !fault.matches(id, sid) {
+                continue;
+            }
+            let n = seen.fetch_add(1, Ordering::SeqCst);
+            if fault.nth.is_some_and(|nth| nth != n) {
+                continue;
+            }
+
+            match &fault.action {
+                Action::Corrupt => {
+                    bytes = bytes.iter().map(|b| !b).collect::<Vec<_>>().into()
+                }
+                Action::Replace(f) => bytes = f(id, &bytes),
+                Action::Delay(delay) => tokio::time::sleep(*delay).await,
+                Action::Stop => self.stopped.store(true, Ordering::SeqCst),
+            }
+        }
+
+        if self.stopped.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        self.inner.send_to(id, bytes, sid).await
+    }
+
+    async fn abort(&self, reason: &str) {
+        if !self.stopped.load(Ordering::SeqCst) {
+            self.inner.abort(reason).await
+        }
+    }
+
+    async fn shutdown(&self) -> Result<(), MpcNetError> {
+        self.inner.shutdown().await
+    }
+}
+
+/// Runs `protocol` on every party of `network`, each wrapped in an
+/// [`AdversarialNet`] with the faults meant for it
+pub async fn run_with_faults<U, K, F>(
+    network: LocalTestNet,
+    faults: Vec<(u32, Fault)>,
+    user_data: U,
+    protocol: impl Fn(AdversarialNet<MpcNetConnection<TcpStream>>, U) -> F
+        + Send
+        + Sync
+        + Clone
+        + 'static,
+) -> Vec<K>
+where
+    F: Future<Output = K> + Send,
+    K: Send + Sync + 'static,
+    U: Clone + Send + Sync + 'static,
+{
+    network
+        .simulate_network_round(
+            (user_data, faults),
+            move |net, (user_data, faults)| {
+                let net = faults
+                    .into_iter()
+                    .fold(AdversarialNet::new(net), |net, (party, fault)| {
+                        net.with_fault_for(party, fault)
+                    });
+                protocol(net, user_data)
+            },
+        )
+        .await
+}
+
+/// Checks that a protocol run through the king copes with the faults every
+/// such protocol has to: a party that sends garbage is blamed by the king, one
+/// that goes silent is timed out, and one that is slow changes nothing. `run`
+/// runs the protocol on the network it is given, with each fault injected
+/// into its party.
+pub async fn check_common_faults<R, F>(
+    n_parties: usize,
+    run: impl Fn(LocalTestNet, Vec<(u32, Fault)>) -> F,
+) where
+    F: Future<Output = Vec<Result<R, MpcNetError>>>,
+    R: PartialEq + Debug,
+{
+    let last = n_parties as u32 - 1;
+
+    let network = LocalTestNet::new_local_testnet(n_parties).await.unwrap();
+    let results = run(network, vec![(1, Fault::corrupt())]).await;
+    assert_king_blamed(&results, 1);
+
+    let mut network = LocalTestNet::new_local_testnet(n_parties).await.unwrap();
+    network.set_op_timeout(Duration::from_secs(2));
+    let results = run(network, vec![(last, Fault::stop())]).await;
+    assert_king_timed_out(&results, last);
+
+    let network = LocalTestNet::new_local_testnet(n_parties).await.unwrap();
+    let expected = run(network, vec![]).await;
+    let network = LocalTestNet::new_local_testnet(n_parties).await.unwrap();
+    let delay = Fault::delay(Duration::from_millis(100));
+    let results = run(network, vec![(last / 2, delay)]).await;
+    for (result, expected) in results.into_iter().zip(expected) {
+        assert_eq!(result.unwrap(), expected.unwrap());
+    }
+}
+
+/// Asserts that the king blamed `culprit` for what it sent, and told every
+/// other party to stop
+pub fn assert_king_blamed<R: Debug>(
+    results: &[Result<R, MpcNetError>],
+    culprit: u32,
+) {
+    assert!(
+        matches!(
+            &results[0],
+            Err(MpcNetError::Protocol { party, .. }) if *party == culprit
+        ),
+        "{:?}",
+        results[0]
+    );
+    assert_clients_aborted(results);
+}
+
+/// Asserts that the king told every other party to stop
+pub fn assert_clients_aborted<R: Debug>(results: &[Result<R, MpcNetError>]) {
+    for result in &results[1..] {
+        assert!(
+            matches!(result, Err(MpcNetError::Aborted { party: 0, .. })),
+            "{result:?}"
+        );
+    }
+}
+
+/// Asserts that the king gave up waiting for `culprit`, and every other party
+/// gave up on the king
+pub fn assert_king_timed_out<R: Debug>(
+    results: &[Result<R, MpcNetError>],
+    culprit: u32,
+) {
+    assert!(
+        matches!(
+            &results[0],
+            Err(MpcNetError::Timeout { party }) if *party == culprit
+        ),
+        "{:?}",
+        results[0]
+    );
+    // The king aborts, unless a client's own timeout fires first
+    for result in &results[1..] {
+        assert!(
+            matches!(
+                result,
+                Err(MpcNetError::Aborted { party: 0, .. })
+                    | Err(MpcNetError::Timeout { party: 0 })
+            ),
+            "{result:?}"
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryTestNet;
+
+    const N_PARTIES: usize = 4;
+    const SID: MultiplexedStreamID = MultiplexedStreamID::ZERO;
+
+    /// Every party sends its ID to the king twice, and the king sends each
+    /// party back what it got from it
+    async fn echo_twice<N: MpcNet>(
+        net: N,
+    ) -> Result<Vec<Option<Vec<Bytes>>>, MpcNetError> {
+        let mut rounds = Vec::new();
+        for _ in 0..2 {
+            let from_all = net
+                .client_send_or_king_receive(&[net.party_id() as u8], SID)
+                .await?;
+            let echo = net.client_receive_or_king_send(from_all.clone(), SID);
+            if echo.await?[..] != [net.party_id() as u8] {
+                return Err(MpcNetError::Generic("Wrong echo".to_string()));
+            }
+            rounds.push(from_all);
+        }
+        Ok(rounds)
+    }
+
+    #[tokio::test]
+    async fn test_only_the_nth_message_is_corrupted() {
+        let results = MemoryTestNet::new(N_PARTIES)
+            .simulate_network_round((), |net, _| async move {
+                let fault = Fault::corrupt().nth(1);
+                echo_twice(AdversarialNet::new(net).with_fault_for(2, fault))
+                    .await
+            })
+            .await;
+
+        // The king sees the honest first message and then the corrupted one,
+        // and has to send it back to party 2, who notices
+        let king = results[0].as_ref().unwrap();
+        assert_eq!(&king[0].as_ref().unwrap()[2][..], &[2]);
+        assert_eq!(&king[1].as_ref().unwrap()[2][..], &[!2]);
+        assert!(results[2].is_err());
+    }
+
+    #[tokio::test]
+    async fn test_stopped_party_times_out() {
+        let mut network = MemoryTestNet::new(N_PARTIES);
+        network.set_op_timeout(Duration::from_millis(100));
+        let results = network
+            .simulate_network_round((), |net, _| async move {
+                let net = AdversarialNet::new(net)
+                    .with_fault_for(3, Fault::stop().nth(1));
+                echo_twice(net).await
+            })
+            .await;
+
+        assert_king_timed_out(&results, 3);
+    }
+
+    #[tokio::test]
+    async fn test_delayed_messages_still_arrive() {
+        let results = MemoryTestNet::new(N_PARTIES)
+            .simulate_network_round((), |net, _| async move {
+                let fault = Fault::delay(Duration::from_millis(50));
+                echo_twice(AdversarialNet::new(net).with_fault_for(1, fault))
+                    .await
+            })
+            .await;
+
+        assert!(results.iter().all(Result::is_ok));
+    }
+
+    #[tokio::test]
+    async fn test_king_equivocates() {
+        let results = MemoryTestNet::new(N_PARTIES)
+            .simulate_network_round((), |net, _| async move {
+                let fault =
+                    Fault::equivocate(&[1, 2], |_, _| Bytes::from_static(&[7]));
+                let net = AdversarialNet::new(net).with_fault_for(0, fault);
+                let from_all =
+                    net.client_send_or_king_receive(&[0], SID).await?;
+                let shared =
+                    from_all.map(|_| vec![Bytes::from_static(&[1]); N_PARTIES]);
+                net.client_receive_or_king_send(shared, SID).await
+            })
+            .await;
+
+        let received = results
+            .into_iter()
+            .map(|result| result.unwrap()[0])
+            .collect::<Vec<_>>();
+        assert_eq!(received, vec![1, 7, 7, 1]);
+    }
+}
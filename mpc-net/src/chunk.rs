@@ -0,0 +1,209 @@
+//! Splits messages into chunks that fit the length-delimited codec of
+//! [`crate::multi::wrap_stream`], and puts them back together.
+//!
+//! Every chunk starts with how many bytes of the message follow it, as a
+//! u64, so the receiver learns the size of the message from its first chunk.
+//! The top bit of that header marks the first chunk of a message, so that a
+//! message whose sender or receiver was dropped half way is discarded rather
+//! than read as the start of the next one. Messages longer than
+//! [`MAX_MESSAGE_LEN`] are refused on both ends.
+
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use tokio_util::bytes::{Buf, BufMut, Bytes, BytesMut};
+
+use crate::MpcNetError;
+
+/// The most bytes of a message sent in one chunk. This stays well below the
+/// 8 MiB frames the codec accepts by default.
+pub const MAX_CHUNK_LEN: usize = 4 << 20;
+
+/// The longest message that is sent or put back together
+pub const MAX_MESSAGE_LEN: u64 = 1 << 30;
+
+const HEADER_LEN: usize = 8;
+
+/// Set in the header of the first chunk of every message
+const FIRST_CHUNK: u64 = 1 << 63;
+
+/// The most the receiver allocates up front, however large the sender says
+/// the message is
+const MAX_PREALLOC: u64 = 64 << 20;
+
+/// Sends `prefix` followed by `body` as one message, in as many chunks as
+/// needed
+pub async fn send_chunked<S>(
+    sink: &mut S,
+    mut prefix: &[u8],
+    mut body: &[u8],
+) -> Result<(), MpcNetError>
+where
+    S: Sink<Bytes> + Unpin,
+    S::Error: ToString,
+{
+    let mut remaining = prefix.len() + body.len();
+    if remaining as u64 > MAX_MESSAGE_LEN {
+        return Err(MpcNetError::BadInput {
+            err: "Message is too long to send",
+        });
+    }
+    let mut first = FIRST_CHUNK;
+    loop {
+        let len = remaining.min(MAX_CHUNK_LEN);
+        remaining -= len;
+
+        let mut chunk = BytesMut::with_capacity(HEADER_LEN + len);
+        chunk.put_u64(first | remaining as u64);
+        first = 0;
+        let from_prefix = len.min(prefix.len());
+        chunk.put_slice(&prefix[..from_prefix]);
+        prefix = &prefix[from_prefix..];
+        let from_body = len - from_prefix;
+        chunk.put_slice(&body[..from_body]);
+        body = &body[from_body..];
+
+        if remaining == 0 {
+            sink.send(chunk.freeze()).await?;
+            return Ok(());
+        }
+        sink.feed(chunk.freeze()).await?;
+    }
+}
+
+/// Receives one message sent with [`send_chunked`]. Chunks left over from a
+/// message that was cut short are skipped.
+pub async fn recv_chunked<S, E>(stream: &mut S) -> Result<Bytes, MpcNetError>
+where
+    S: Stream<Item = Result<BytesMut, E>> + Unpin,
+    E: ToString,
+{
+    // The rest of a message whose receiver gave up on it half way
+    let (mut message, mut remaining) = loop {
+        let (chunk, first, remaining) = next_chunk(stream).await?;
+        if first {
+            break start_message(chunk, remaining)?;
+        }
+    };
+
+    while remaining > 0 {
+        let (next, first, left) = next_chunk(stream).await?;
+        // The sender gave up on the message half way and sent another one
+        if first {
+            (message, remaining) = start_message(next, left)?;
+            continue;
+        }
+        if next.len() as u64 + left != remaining {
+            return Err(MpcNetError::Generic(
+                "Chunk does not continue the message".to_string(),
+            ));
+        }
+        remaining = left;
+        message.extend_from_slice(&next);
+    }
+    Ok(message.freeze())
+}
+
+/// The buffer a message that starts with `chunk` is put back together in
+fn start_message(
+    chunk: BytesMut,
+    remaining: u64,
+) -> Result<(BytesMut, u64), MpcNetError> {
+    if remaining == 0 {
+        return Ok((chunk, 0));
+    }
+
+    let total = chunk.len() as u64 + remaining;
+    if total > MAX_MESSAGE_LEN {
+        return Err(MpcNetError::Generic(format!(
+            "Message of {total} bytes is longer than the limit"
+        )));
+    }
+    let mut message = BytesMut::with_capacity(total.min(MAX_PREALLOC) as usize);
+    message.extend_from_slice(&chunk);
+    Ok((message, remaining))
+}
+
+/// The next chunk, without its header, whether it starts a message, and how
+/// many bytes follow it
+async fn next_chunk<S, E>(
+    stream: &mut S,
+) -> Result<(BytesMut, bool, u64), MpcNetError>
+where
+    S: Stream<Item = Result<BytesMut, E>> + Unpin,
+    E: ToString,
+{
+    let mut chunk = stream
+        .next()
+        .await
+        .ok_or_else(|| MpcNetError::Generic("Stream died".to_string()))??;
+    if chunk.len() < HEADER_LEN {
+        return Err(MpcNetError::Generic(
+            "Chunk is missing its header".to_string(),
+        ));
+    }
+    let header = chunk.get_u64();
+    Ok((chunk, header & FIRST_CHUNK != 0, header & !FIRST_CHUNK))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::multi::wrap_stream;
+
+    #[tokio::test]
+    async fn test_messages_larger_than_a_frame_are_chunked() {
+        let (a, b) = tokio::io::duplex(1 << 16);
+        let (mut a, mut b) = (wrap_stream(a), wrap_stream(b));
+
+        // 20 MiB would not fit a single frame of the codec
+        let body = (0..20 << 20).map(|i| i as u8).collect::<Vec<_>>();
+        let sender = async {
+            send_chunked(&mut a, &[7], &body).await.unwrap();
+            send_chunked(&mut a, &[], &[]).await.unwrap();
+        };
+        let receiver = async {
+            let message = recv_chunked(&mut b).await.unwrap();
+            assert_eq!(message.len(), body.len() + 1);
+            assert_eq!(message[0], 7);
+            assert!(message[1..] == body[..]);
+            assert!(recv_chunked(&mut b).await.unwrap().is_empty());
+        };
+        tokio::join!(sender, receiver);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_messages_are_refused() {
+        let (a, b) = tokio::io::duplex(1 << 16);
+        let (mut a, mut b) = (wrap_stream(a), wrap_stream(b));
+
+        // Claims far more bytes than the limit, which are never sent
+        let mut chunk = BytesMut::new();
+        chunk.put_u64(FIRST_CHUNK | MAX_MESSAGE_LEN);
+        chunk.put_slice(&[1, 2, 3]);
+        a.send(chunk.freeze()).await.unwrap();
+
+        assert!(matches!(
+            recv_chunked(&mut b).await,
+            Err(MpcNetError::Generic(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_messages_cut_short_are_discarded() {
+        let (a, b) = tokio::io::duplex(1 << 16);
+        let (mut a, mut b) = (wrap_stream(a), wrap_stream(b));
+
+        // The end of a message whose receiver gave up on it
+        let mut leftover = BytesMut::new();
+        leftover.put_u64(0);
+        leftover.put_slice(&[9]);
+        a.send(leftover.freeze()).await.unwrap();
+        // The start of a message whose sender gave up on it
+        let mut cut = BytesMut::new();
+        cut.put_u64(FIRST_CHUNK | 4);
+        cut.put_slice(&[1, 2]);
+        a.send(cut.freeze()).await.unwrap();
+        send_chunked(&mut a, &[], &[7, 7]).await.unwrap();
+
+        assert_eq!(&recv_chunked(&mut b).await.unwrap()[..], &[7, 7]);
+    }
+}
@@ -0,0 +1,592 @@
+//! Long-lived clusters that run many jobs over the same connections.
+//!
+//! Connecting a cluster takes a while, so a [`Cluster`] keeps one party's
+//! network open and runs each job, e.g. a proof, in a [`SubSession`] of its
+//! own. The streams of the network are split into slots, and jobs on
+//! different slots run concurrently, one at a time on each. A sub-session
+//! tags its frames with its job ID and tracks its own rounds, timeouts and
+//! abort, so a job that fails leaves the others alone. Networks that tag
+//! their own frames send those of a sub-session as they are, so every frame
+//! is only tagged once.
+//!
+//! [`LocalCluster`] does the bookkeeping for a cluster whose parties all run
+//! in this process, like a [`LocalTestNet`].
+
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use parking_lot::Mutex;
+use tokio::net::TcpStream;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tokio_util::bytes::Bytes;
+
+use crate::multi::MpcNetConnection;
+use crate::session::ABORT_TIMEOUT;
+use crate::{
+    LocalTestNet, MpcNet, MpcNetError, MultiplexedStreamID, NetStats,
+    SessionTracker, WireCodec,
+};
+
+/// One party's long-lived network, shared by the jobs it runs
+#[derive(Debug)]
+pub struct Cluster<N> {
+    net: Arc<N>,
+    streams_per_job: usize,
+    slots: Arc<Mutex<Vec<Slot>>>,
+}
+
+/// The job a slot runs, if any, and the last job it ran
+#[derive(Debug, Default, Clone, Copy)]
+struct Slot {
+    busy: bool,
+    last_job: Option<u64>,
+}
+
+impl<N: MpcNet> Cluster<N> {
+    /// Splits the streams of `net` into slots of `streams_per_job` streams
+    pub fn new(net: N, streams_per_job: usize) -> Result<Self, MpcNetError> {
+        if streams_per_job == 0 || streams_per_job > net.n_streams() {
+            return Err(MpcNetError::BadInput {
+                err: "A job needs between one and all of the streams",
+            });
+        }
+        if net.n_streams() % streams_per_job != 0 {
+            return Err(MpcNetError::BadInput {
+                err: "The streams must split evenly into jobs",
+            });
+        }
+        let n_slots = net.n_streams() / streams_per_job;
+        Ok(Self {
+            net: Arc::new(net),
+            streams_per_job,
+            slots: Arc::new(Mutex::new(vec![Slot::default(); n_slots])),
+        })
+    }
+
+    /// How many jobs can run at once
+    pub fn n_slots(&self) -> usize {
+        self.net.n_streams() / self.streams_per_job
+    }
+
+    pub fn net(&self) -> &N {
+        &self.net
+    }
+
+    /// Opens the sub-session of job `job_id` on `slot`, which must not run
+    /// another job. Every party must open the same job on the same slot. The
+    /// job IDs on a slot must go up, so that frames left over from an
+    /// aborted job can be told apart and skipped, and a job ID must not be
+    /// in use on another slot or be the session ID of the network
+    /// underneath.
+    pub fn job(
+        &self,
+        slot: usize,
+        job_id: u64,
+    ) -> Result<SubSession<N>, MpcNetError> {
+        let mut slots = self.slots.lock();
+        if slot >= slots.len() {
+            return Err(MpcNetError::BadInput {
+                err: "Slot is out of range",
+            });
+        }
+        if slots[slot].busy {
+            return Err(MpcNetError::BadInput {
+                err: "Slot is in use",
+            });
+        }
+        if slots[slot].last_job.is_some_and(|last| job_id <= last) {
+            return Err(MpcNetError::BadInput {
+                err: "Job IDs must go up on every slot",
+            });
+        }
+        if slots.iter().any(|s| s.busy && s.last_job == Some(job_id)) {
+            return Err(MpcNetError::BadInput {
+                err: "Job ID is in use on another slot",
+            });
+        }
+        slots[slot] = Slot {
+            busy: true,
+            last_job: Some(job_id),
+        };
+
+        Ok(SubSession {
+            net: self.net.clone(),
+            slot,
+            first_sid: (slot * self.streams_per_job) as u32,
+            n_streams: self.streams_per_job,
+            session: SessionTracker::new(job_id),
+            slots: self.slots.clone(),
+        })
+    }
+
+    /// Shuts down the network underneath every job
+    pub async fn shutdown(&self) -> Result<(), MpcNetError> {
+        self.net.shutdown().await
+    }
+}
+
+/// The network of a single job. Its streams are numbered from zero, and
+/// aborting it only ends this job. Shutting it down does nothing, since the
+/// connections belong to the [`Cluster`]. Its king is the king of the
+/// cluster's network, so a job whose king is lost can only be aborted, not
+/// moved to another king.
+#[derive(Debug)]
+pub struct SubSession<N> {
+    net: Arc<N>,
+    slot: usize,
+    first_sid: u32,
+    n_streams: usize,
+    session: SessionTracker,
+    slots: Arc<Mutex<Vec<Slot>>>,
+}
+
+impl<N> Drop for SubSession<N> {
+    fn drop(&mut self) {
+        self.slots.lock()[self.slot].busy = false;
+    }
+}
+
+impl<N: MpcNet> SubSession<N> {
+    pub fn job_id(&self) -> u64 {
+        self.session.session_id()
+    }
+
+    /// Bound every single send and receive of the job by `timeout`
+    pub fn set_op_timeout(&mut self, timeout: Duration) {
+        self.session.set_op_timeout(Some(timeout));
+    }
+
+    /// Fail every send and receive of the job once `timeout` has passed
+    pub fn set_session_timeout(&mut self, timeout: Duration) {
+        self.session
+            .set_deadline(Some(tokio::time::Instant::now() + timeout));
+    }
+
+    /// The stream of the cluster's network that `sid` maps to
+    fn cluster_sid(
+        &self,
+        sid: MultiplexedStreamID,
+    ) -> Result<MultiplexedStreamID, MpcNetError> {
+        if sid.index() >= self.n_streams {
+            return Err(MpcNetError::BadInput {
+                err: "Stream is out of the job's range",
+            });
+        }
+        Ok(MultiplexedStreamID::new(self.first_sid + sid.0))
+    }
+
+    /// Whether `frame` belongs to a job that ran on the slot before
+    fn is_stale(&self, frame: &[u8]) -> bool {
+        SessionTracker::session_of(frame)
+            .is_some_and(|job_id| job_id < self.job_id())
+    }
+}
+
+#[async_trait]
+impl<N: MpcNet> MpcNet for SubSession<N> {
+    fn n_parties(&self) -> usize {
+        self.net.n_parties()
+    }
+
+    fn party_id(&self) -> u32 {
+        self.net.party_id()
+    }
+
+    fn king_id(&self) -> u32 {
+        self.net.king_id()
+    }
+
+    fn is_init(&self) -> bool {
+        self.net.is_init()
+    }
+
+    fn n_streams(&self) -> usize {
+        self.n_streams
+    }
+
+    fn wire_codec(&self) -> WireCodec {
+        self.net.wire_codec()
+    }
+
+    fn set_protocol_label(&self, sid: MultiplexedStreamID, label: &str) {
+        self.session.set_label(sid, label)
+    }
+
+    fn stats(&self) -> NetStats {
+        self.session.stats(self.party_id())
+    }
+
+    async fn recv_from(
+        &self,
+        id: u32,
+        sid: MultiplexedStreamID,
+    ) -> Result<Bytes, MpcNetError> {
+        let cluster_sid = self.cluster_sid(sid)?;
+        loop {
+            let frame = self
+                .session
+                .bounded_recv(id, sid, self.net.recv_sealed(id, cluster_sid))
+                .await?;
+            // Left over from an aborted job on this slot
+            if self.is_stale(&frame) {
+                continue;
+            }
+            return self.session.open(self.party_id(), id, sid, frame);
+        }
+    }
+
+    async fn send_to(
+        &self,
+        id: u32,
+        bytes: Bytes,
+        sid: MultiplexedStreamID,
+    ) -> Result<(), MpcNetError> {
+        let cluster_sid = self.cluster_sid(sid)?;
+        let frame = self.session.seal(self.party_id(), id, sid, bytes)?;
+        self.session
+            .bounded(id, self.net.send_sealed(id, frame, cluster_sid))
+            .await
+    }
+
+    async fn abort(&self, reason: &str) {
+        let abort = match self.session.start_abort(self.party_id(), reason) {
+            Some(abort) => abort,
+            None => return,
+        };
+
+        for peer in 0..self.n_parties() as u32 {
+            if peer == self.party_id() || peer == abort.party {
+                continue;
+            }
+            for sid in (0..self.n_streams as u32).map(MultiplexedStreamID::new)
+            {
+                let frame = match self.session.seal_abort(
+                    self.party_id(),
+                    peer,
+                    sid,
+                    &abort,
+                ) {
+                    Ok(frame) => frame,
+                    Err(_) => continue,
+                };
+                let cluster_sid =
+                    MultiplexedStreamID::new(self.first_sid + sid.0);
+                // Best effort, the peer may be gone already
+                let _ = tokio::time::timeout(
+                    ABORT_TIMEOUT,
+                    self.net.send_sealed(peer, frame, cluster_sid),
+                )
+                .await;
+            }
+        }
+    }
+}
+
+/// Hands a slot back once its job is done, even if the job panicked
+struct SlotGuard<'a> {
+    slot: usize,
+    free_slots: &'a Mutex<Vec<usize>>,
+}
+
+impl Drop for SlotGuard<'_> {
+    fn drop(&mut self) {
+        self.free_slots.lock().push(self.slot);
+    }
+}
+
+/// A [`LocalTestNet`] that stays connected and runs many jobs, as many at
+/// once as it has slots
+#[derive(Debug)]
+pub struct LocalCluster {
+    nodes: Vec<Cluster<MpcNetConnection<TcpStream>>>,
+    free_slots: Mutex<Vec<usize>>,
+    slots: Semaphore,
+    next_job_id: AtomicU64,
+    job_timeout: Option<Duration>,
+}
+
+impl LocalCluster {
+    /// Connects `n_parties` parties, with room for `n_slots` concurrent jobs
+    /// of `streams_per_job` streams each
+    pub async fn new(
+        n_parties: usize,
+        streams_per_job: usize,
+        n_slots: usize,
+    ) -> Result<Self, MpcNetError> {
+        let net = LocalTestNet::new_local_testnet_with_streams(
+            n_parties,
+            streams_per_job * n_slots,
+        )
+        .await?;
+        let nodes = net
+            .into_nodes()
+            .into_iter()
+            .map(|node| Cluster::new(node, streams_per_job))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self {
+            nodes,
+            free_slots: Mutex::new((0..n_slots).rev().collect()),
+            slots: Semaphore::new(n_slots),
+            next_job_id: AtomicU64::new(1),
+            job_timeout: None,
+        })
+    }
+
+    /// Fail every send and receive of a job once `timeout` has passed since
+    /// it started
+    pub fn set_job_timeout(&mut self, timeout: Duration) {
+        self.job_timeout = Some(timeout);
+    }
+
+    /// Runs `f` for every party in a new job, like
+    /// [`LocalTestNet::simulate_network_round`], and returns the results in
+    /// party order. Waits for a free slot first if every slot is busy. If a
+    /// party panics, the other parties are stopped and the job fails.
+    pub async fn run_job<
+        F: Future<Output = K> + Send,
+        K: Send + Sync + 'static,
+        U: Clone + Send + Sync + 'static,
+    >(
+        &self,
+        user_data: U,
+        f: impl Fn(SubSession<MpcNetConnection<TcpStream>>, U) -> F
+            + Send
+            + Sync
+            + Clone
+            + 'static,
+    ) -> Result<Vec<K>, MpcNetError> {
+        let _permit = self
+            .slots
+            .acquire()
+            .await
+            .expect("The slots are never closed");
+        let slot = SlotGuard {
+            slot: self
+                .free_slots
+                .lock()
+                .pop()
+                .expect("Every permit comes with a free slot"),
+            free_slots: &self.free_slots,
+        };
+        let job_id = self.next_job_id.fetch_add(1, Ordering::SeqCst);
+
+        // Dropped before the slot, which aborts the parties if we are
+        // cancelled, so the next job on the slot has it to itself
+        let mut parties = JoinSet::new();
+        for (idx, node) in self.nodes.iter().enumerate() {
+            let mut net = node
+                .job(slot.slot, job_id)
+                .expect("Every node has the same slots");
+            if let Some(timeout) = self.job_timeout {
+                net.set_session_timeout(timeout);
+            }
+            let f = f.clone();
+            let user_data = user_data.clone();
+            parties.spawn(async move { (idx, f(net, user_data).await) });
+        }
+
+        let mut results = Vec::with_capacity(self.nodes.len());
+        while let Some(result) = parties.join_next().await {
+            match result {
+                Ok(result) => results.push(result),
+                Err(err) => {
+                    parties.abort_all();
+                    while parties.join_next().await.is_some() {}
+                    return Err(MpcNetError::Generic(format!(
+                        "A party of job {job_id} failed: {err}"
+                    )));
+                }
+            }
+        }
+        results.sort_by_key(|(idx, _)| *idx);
+        Ok(results.into_iter().map(|(_, result)| result).collect())
+    }
+
+    /// Shuts down the connections of every party
+    pub async fn shutdown(&self) -> Result<(), MpcNetError> {
+        let results = futures::future::join_all(
+            self.nodes.iter().map(|node| node.shutdown()),
+        )
+        .await;
+        results.into_iter().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const N_PARTIES: usize = 4;
+    const STREAMS_PER_JOB: usize = 2;
+    const SID: MultiplexedStreamID = MultiplexedStreamID::ONE;
+
+    /// Every party sends its ID plus `offset`, the king sends back the sum
+    async fn sum_ids<N: MpcNet>(
+        net: &N,
+        offset: u8,
+    ) -> Result<u8, MpcNetError> {
+        let from_all = net
+            .client_send_or_king_receive(&[net.party_id() as u8 + offset], SID)
+            .await?
+            .map(|ids| {
+                let sum = ids.iter().map(|id| id[0]).sum::<u8>();
+                vec![Bytes::from(vec![sum]); ids.len()]
+            });
+        let sum = net.client_receive_or_king_send(from_all, SID).await?;
+        Ok(sum[0])
+    }
+
+    #[tokio::test]
+    async fn test_jobs_reuse_the_connections() {
+        let cluster = LocalCluster::new(N_PARTIES, STREAMS_PER_JOB, 1)
+            .await
+            .unwrap();
+
+        let mut job_ids = Vec::new();
+        for offset in 0..3 {
+            let results = cluster
+                .run_job(offset, |net, offset| async move {
+                    (net.job_id(), sum_ids(&net, offset).await.unwrap())
+                })
+                .await
+                .unwrap();
+            let expected = 6 + 4 * offset;
+            assert!(results.iter().all(|(_, sum)| *sum == expected));
+            job_ids.push(results[0].0);
+        }
+        assert_eq!(job_ids, vec![1, 2, 3]);
+        cluster.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_job_ids_are_used_once() {
+        let net = LocalTestNet::new_local_testnet_with_streams(2, 2)
+            .await
+            .unwrap()
+            .into_nodes()
+            .remove(0);
+        let cluster = Cluster::new(net, 1).unwrap();
+
+        let job = cluster.job(0, 1).unwrap();
+        assert!(cluster.job(1, 1).is_err());
+        drop(job);
+        assert!(cluster.job(0, 1).is_err());
+        assert!(cluster.job(0, 2).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_a_slot_runs_one_job_at_a_time() {
+        let net = LocalTestNet::new_local_testnet_with_streams(2, 2)
+            .await
+            .unwrap()
+            .into_nodes()
+            .remove(0);
+        let cluster = Cluster::new(net, 1).unwrap();
+
+        let job = cluster.job(0, 1).unwrap();
+        assert!(matches!(
+            cluster.job(0, 2),
+            Err(MpcNetError::BadInput {
+                err: "Slot is in use"
+            })
+        ));
+        let other = cluster.job(1, 2).unwrap();
+        drop(job);
+        assert!(cluster.job(0, 3).is_ok());
+        drop(other);
+    }
+
+    #[tokio::test]
+    async fn test_an_aborted_job_leaves_the_others_alone() {
+        let cluster = LocalCluster::new(N_PARTIES, STREAMS_PER_JOB, 2)
+            .await
+            .unwrap();
+
+        let aborted = cluster.run_job((), |net, _| async move {
+            if net.is_king() {
+                net.abort("Giving up").await;
+            }
+            sum_ids(&net, 0).await
+        });
+        let finished =
+            cluster.run_job((), |net, _| async move { sum_ids(&net, 0).await });
+        let (aborted, finished) = tokio::join!(aborted, finished);
+
+        for result in aborted.unwrap() {
+            assert!(matches!(
+                result,
+                Err(MpcNetError::Aborted { party: 0, .. })
+            ));
+        }
+        assert!(finished.unwrap().into_iter().all(|sum| sum.unwrap() == 6));
+    }
+
+    #[tokio::test]
+    async fn test_frames_left_by_an_aborted_job_are_skipped() {
+        let cluster = LocalCluster::new(N_PARTIES, STREAMS_PER_JOB, 1)
+            .await
+            .unwrap();
+
+        // The king gives up without reading what the clients sent it
+        cluster
+            .run_job((), |net, _| async move {
+                if !net.is_king() {
+                    net.send_to(0, Bytes::from_static(&[1]), SID)
+                        .await
+                        .unwrap();
+                }
+            })
+            .await
+            .unwrap();
+
+        let sums = cluster
+            .run_job((), |net, _| async move { sum_ids(&net, 0).await })
+            .await
+            .unwrap();
+        assert!(sums.into_iter().all(|sum| sum.unwrap() == 6));
+    }
+
+    #[tokio::test]
+    async fn test_a_panicking_party_fails_only_its_job() {
+        let cluster = LocalCluster::new(N_PARTIES, STREAMS_PER_JOB, 1)
+            .await
+            .unwrap();
+
+        let failed = cluster
+            .run_job((), |net, _| async move {
+                if net.party_id() == 2 {
+                    panic!("Party 2 gives up");
+                }
+                sum_ids(&net, 0).await
+            })
+            .await;
+        assert!(matches!(failed, Err(MpcNetError::Generic(_))));
+
+        // The other parties were stopped, so the slot is free for the next
+        let sums = cluster
+            .run_job((), |net, _| async move { sum_ids(&net, 0).await })
+            .await
+            .unwrap();
+        assert!(sums.into_iter().all(|sum| sum.unwrap() == 6));
+    }
+
+    #[tokio::test]
+    async fn test_streams_must_split_evenly_into_jobs() {
+        let net = LocalTestNet::new_local_testnet_with_streams(2, 5)
+            .await
+            .unwrap()
+            .into_nodes()
+            .remove(0);
+        assert!(matches!(
+            Cluster::new(net, 2),
+            Err(MpcNetError::BadInput {
+                err: "The streams must split evenly into jobs"
+            })
+        ));
+    }
+}
@@ -0,0 +1,152 @@
+//! Optional compression of frame payloads.
+//!
+//! Every frame tells the receiver which algorithms its sender can decompress,
+//! so parties learn what their peers support from the first frame they get.
+//! Until then, and for payloads below the threshold or that don't shrink,
+//! payloads are sent as they are.
+
+use serde::{Deserialize, Serialize};
+
+use crate::MpcNetError;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Algorithm {
+    /// Fast, with a modest ratio. Needs the `lz4` feature.
+    Lz4,
+    /// Slower, with a better ratio. Needs the `zstd` feature.
+    Zstd,
+}
+
+impl Algorithm {
+    /// Whether this build can compress and decompress with the algorithm
+    pub fn is_supported(self) -> bool {
+        match self {
+            Algorithm::Lz4 => cfg!(feature = "lz4"),
+            Algorithm::Zstd => cfg!(feature = "zstd"),
+        }
+    }
+
+    /// Every algorithm this build supports
+    pub fn supported() -> Vec<Algorithm> {
+        [Algorithm::Lz4, Algorithm::Zstd]
+            .iter()
+            .copied()
+            .filter(|algorithm| algorithm.is_supported())
+            .collect()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CompressionConfig {
+    pub algorithm: Algorithm,
+    /// Payloads smaller than this are not worth compressing
+    pub threshold: usize,
+    /// Compression level, for zstd
+    pub level: i32,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            algorithm: Algorithm::Lz4,
+            threshold: 4096,
+            level: 3,
+        }
+    }
+}
+
+pub(crate) fn compress(
+    config: &CompressionConfig,
+    payload: &[u8],
+) -> Result<Vec<u8>, MpcNetError> {
+    match config.algorithm {
+        #[cfg(feature = "lz4")]
+        Algorithm::Lz4 => Ok(lz4_flex::compress_prepend_size(payload)),
+        #[cfg(feature = "zstd")]
+        Algorithm::Zstd => Ok(zstd::bulk::compress(payload, config.level)?),
+        #[allow(unreachable_patterns)]
+        algorithm => Err(unsupported(algorithm)),
+    }
+}
+
+/// Decompresses `payload`, refusing anything that would grow past `max_len`
+/// before allocating for it
+pub(crate) fn decompress(
+    algorithm: Algorithm,
+    payload: &[u8],
+    max_len: u64,
+) -> Result<Vec<u8>, MpcNetError> {
+    match algorithm {
+        #[cfg(feature = "lz4")]
+        Algorithm::Lz4 => {
+            let len = payload
+                .get(..4)
+                .map(|prefix| {
+                    u32::from_le_bytes([
+                        prefix[0], prefix[1], prefix[2], prefix[3],
+                    ])
+                })
+                .ok_or_else(|| {
+                    MpcNetError::Generic(
+                        "Compressed payload is missing its size".to_string(),
+                    )
+                })?;
+            if len as u64 > max_len {
+                return Err(too_long(max_len));
+            }
+            Ok(lz4_flex::decompress_size_prepended(payload)?)
+        }
+        #[cfg(feature = "zstd")]
+        Algorithm::Zstd => {
+            use std::io::Read;
+
+            let mut decompressed = vec![];
+            zstd::stream::Decoder::new(payload)?
+                .take(max_len + 1)
+                .read_to_end(&mut decompressed)?;
+            if decompressed.len() as u64 > max_len {
+                return Err(too_long(max_len));
+            }
+            Ok(decompressed)
+        }
+        #[allow(unreachable_patterns)]
+        algorithm => Err(unsupported(algorithm)),
+    }
+}
+
+#[allow(dead_code)]
+fn too_long(max_len: u64) -> MpcNetError {
+    MpcNetError::Generic(format!(
+        "Compressed payload grows past {max_len} bytes"
+    ))
+}
+
+#[allow(dead_code)]
+fn unsupported(algorithm: Algorithm) -> MpcNetError {
+    MpcNetError::Generic(format!("{algorithm:?} compression is not supported"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_oversized_payloads_are_refused() {
+        let payload = vec![0; 1 << 16];
+        for algorithm in Algorithm::supported() {
+            let config = CompressionConfig {
+                algorithm,
+                ..Default::default()
+            };
+            let compressed = compress(&config, &payload).unwrap();
+
+            let decompressed =
+                decompress(algorithm, &compressed, payload.len() as u64);
+            assert_eq!(decompressed.unwrap(), payload);
+            assert!(matches!(
+                decompress(algorithm, &compressed, payload.len() as u64 - 1),
+                Err(MpcNetError::Generic(_))
+            ));
+        }
+    }
+}
@@ -0,0 +1,1021 @@
+//! The topology of a cluster, shared by every party as a TOML or JSON file.
+//!
+//! ```toml
+//! king = 0
+//! n_streams = 3
+//!
+//! [pss]
+//! l = 1
+//!
+//! [[parties]]
+//! id = 0
+//! address = "127.0.0.1:12344"
+//! certificate = "public_0.cert.der"
+//!
+//! [[parties]]
+//! id = 1
+//! address = "127.0.0.1:12345"
+//! fingerprint = "sha256:5d1c..."
+//! ```
+//!
+//! The topology holds no secrets. Each party keeps its ID and the paths to
+//! its private keys in a local file of its own, a [`LocalConfig`]:
+//!
+//! ```toml
+//! id = 0
+//! private_key = "private_0.key.der"
+//! ```
+//!
+//! Relative paths are resolved against the directory of the file.
+//!
+//! With a `[failover]` section, e.g. `backups = [1, 2]`, parties that can't
+//! reach the king within `timeout_ms` try the backups in turn, and the first
+//! backup that can't reach any coordinator before it takes over as the king.
+//! It still waits for every party in the roster, the old king included, which
+//! joins it as a peer once it is back. A coordinator that leads steps down
+//! unless most parties join it within `timeout_ms`, so that two coordinators
+//! which can't reach each other don't both wait for the cluster.
+//!
+//! With `resume = true`, a party that loses its connection to the king dials
+//! it again and resumes the session where it left off. Every party then keeps
+//! the frames it sent until they are acknowledged, to replay them.
+
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use log::{info, warn};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsStream;
+
+use crate::identity;
+use crate::multi::MULTIPLEXED_STREAMS;
+use crate::prod::{
+    PeerIdentity, PeerRoster, ProdNet, Quorum, ResumeConfig, RustlsCertificate,
+};
+use crate::MpcNetError;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ClusterConfig {
+    /// Party ID of the king
+    #[serde(default)]
+    pub king: u32,
+    /// Streams multiplexed over each connection
+    #[serde(default = "default_n_streams")]
+    pub n_streams: usize,
+    /// Parameters of the packed secret sharing the cluster runs, if any
+    #[serde(default)]
+    pub pss: Option<PssConfig>,
+    /// Who takes over if the king is unreachable, if anyone
+    #[serde(default)]
+    pub failover: Option<FailoverConfig>,
+    /// Whether parties reconnect and resume the session when a connection
+    /// to the king is lost
+    #[serde(default)]
+    pub resume: bool,
+    /// Every party, including the king
+    pub parties: Vec<PartyConfig>,
+}
+
+/// Coordinators to fall back on when the king is unreachable
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct FailoverConfig {
+    /// Parties that take over as the king, in order of preference
+    pub backups: Vec<u32>,
+    /// How long to keep dialing a coordinator before moving on to the next.
+    /// Should be longer than it takes every party to start.
+    #[serde(default = "default_failover_timeout_ms")]
+    pub timeout_ms: u64,
+    /// How long a coordinator looks for another one that already leads
+    #[serde(default = "default_probe_timeout_ms")]
+    pub probe_timeout_ms: u64,
+}
+
+impl FailoverConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+
+    pub fn probe_timeout(&self) -> Duration {
+        Duration::from_millis(self.probe_timeout_ms)
+    }
+}
+
+/// Packed secret sharing with `l` secrets per share, among `4 * l` parties
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PssConfig {
+    pub l: usize,
+}
+
+impl PssConfig {
+    pub fn n_parties(&self) -> usize {
+        self.l * 4
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct PartyConfig {
+    pub id: u32,
+    /// Where the party listens, as `host:port`
+    pub address: String,
+    /// The party's certificate, in PEM or DER
+    #[serde(default)]
+    pub certificate: Option<PathBuf>,
+    /// The SHA-256 fingerprint of the party's certificate, as `sha256:<hex>`.
+    /// Pins the certificate, or identifies the party if there is none.
+    #[serde(default)]
+    pub fingerprint: Option<String>,
+    /// The party's Noise static public key, as `x25519:<hex>`. Identifies the
+    /// party on Noise transports.
+    #[serde(default)]
+    pub static_key: Option<String>,
+}
+
+/// What only one party knows, read from a file that is not shared with the
+/// others
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct LocalConfig {
+    /// Our party ID
+    pub id: u32,
+    /// Our private key, in PEM or DER
+    #[serde(default)]
+    pub private_key: Option<PathBuf>,
+    /// Our certificate, in PEM or DER, if the cluster config only lists
+    /// its fingerprint
+    #[serde(default)]
+    pub certificate: Option<PathBuf>,
+    /// Our raw 32-byte Noise private key
+    #[serde(default)]
+    pub noise_private_key: Option<PathBuf>,
+}
+
+fn default_n_streams() -> usize {
+    MULTIPLEXED_STREAMS
+}
+
+fn default_failover_timeout_ms() -> u64 {
+    10_000
+}
+
+fn default_probe_timeout_ms() -> u64 {
+    500
+}
+
+/// Reads a config from a `.json` file, or a TOML file otherwise
+fn read_config<T: DeserializeOwned>(path: &Path) -> Result<T, MpcNetError> {
+    let contents = std::fs::read_to_string(path).map_err(|err| {
+        MpcNetError::Generic(format!("Error reading {path:?}: {err}"))
+    })?;
+
+    if path.extension().is_some_and(|e| e == "json") {
+        serde_json::from_str(&contents).map_err(|err| {
+            MpcNetError::Generic(format!("Malformed config {path:?}: {err}"))
+        })
+    } else {
+        toml::from_str(&contents).map_err(|err| {
+            MpcNetError::Generic(format!("Malformed config {path:?}: {err}"))
+        })
+    }
+}
+
+/// Makes `path` relative to `dir` if it is relative
+fn resolve_path(path: &mut PathBuf, dir: &Path) {
+    if path.is_relative() {
+        *path = dir.join(&*path);
+    }
+}
+
+impl ClusterConfig {
+    /// Reads a config from a `.json` file, or a TOML file otherwise
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, MpcNetError> {
+        let path = path.as_ref();
+        let mut config: Self = read_config(path)?;
+        if let Some(dir) = path.parent() {
+            config.resolve_paths(dir);
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_toml(contents: &str) -> Result<Self, MpcNetError> {
+        toml::from_str(contents).map_err(|err| {
+            MpcNetError::Generic(format!("Malformed cluster config: {err}"))
+        })
+    }
+
+    pub fn from_json(contents: &str) -> Result<Self, MpcNetError> {
+        serde_json::from_str(contents).map_err(|err| {
+            MpcNetError::Generic(format!("Malformed cluster config: {err}"))
+        })
+    }
+
+    /// Makes relative certificate paths relative to `dir`
+    pub fn resolve_paths(&mut self, dir: &Path) {
+        for party in &mut self.parties {
+            if let Some(path) = &mut party.certificate {
+                resolve_path(path, dir);
+            }
+        }
+    }
+
+    /// Checks that the parties are numbered 0 to n-1, that they match the
+    /// PSS parameters if given, and that the king and its backups are
+    /// distinct parties
+    pub fn validate(&self) -> Result<(), MpcNetError> {
+        let mut ids = self.parties.iter().map(|p| p.id).collect::<Vec<_>>();
+        ids.sort_unstable();
+        if ids != (0..self.parties.len() as u32).collect::<Vec<_>>() {
+            return Err(MpcNetError::BadInput {
+                err: "Parties must be numbered 0 to n-1",
+            });
+        }
+
+        if let Some(pss) = &self.pss {
+            if pss.n_parties() != self.n_parties() {
+                return Err(MpcNetError::Generic(format!(
+                    "PSS with l = {} needs {} parties, but {} are listed",
+                    pss.l,
+                    pss.n_parties(),
+                    self.n_parties()
+                )));
+            }
+        }
+
+        if self.party(self.king).is_err() {
+            return Err(MpcNetError::BadInput {
+                err: "The king is not one of the parties",
+            });
+        }
+
+        let coordinators = self.coordinators();
+        for (i, id) in coordinators.iter().enumerate() {
+            if self.party(*id).is_err() || coordinators[..i].contains(id) {
+                return Err(MpcNetError::BadInput {
+                    err: "Backup kings must be distinct parties other than the king",
+                });
+            }
+        }
+
+        if self.n_streams == 0 {
+            return Err(MpcNetError::BadInput {
+                err: "Must open at least one stream per connection",
+            });
+        }
+
+        Ok(())
+    }
+
+    /// The king followed by its backups, in the order they take over
+    pub fn coordinators(&self) -> Vec<u32> {
+        let backups = self.failover.iter().flat_map(|f| f.backups.iter());
+        std::iter::once(self.king).chain(backups.copied()).collect()
+    }
+
+    /// The same cluster, with `king` as the king
+    pub fn with_king(&self, king: u32) -> Self {
+        Self {
+            king,
+            ..self.clone()
+        }
+    }
+
+    pub fn n_parties(&self) -> usize {
+        self.parties.len()
+    }
+
+    pub fn party(&self, id: u32) -> Result<&PartyConfig, MpcNetError> {
+        self.parties.iter().find(|p| p.id == id).ok_or_else(|| {
+            MpcNetError::Generic(format!("Party {id} is not in the config"))
+        })
+    }
+
+    /// The king's roster of every other party
+    pub fn roster(&self) -> Result<PeerRoster, MpcNetError> {
+        let mut roster = PeerRoster::new();
+        for party in self.parties.iter().filter(|p| p.id != self.king) {
+            match (party.load_certificate()?, &party.fingerprint) {
+                (Some(cert), _) => roster.add_certificate(party.id, cert)?,
+                (None, Some(fingerprint)) => roster
+                    .insert(parse_fingerprint(fingerprint)?, party.id)?,
+                (None, None) => {
+                    return Err(MpcNetError::Generic(format!(
+                        "Party {} needs a certificate or a fingerprint to connect over TLS",
+                        party.id
+                    )))
+                }
+            }
+        }
+        Ok(roster)
+    }
+
+    /// The king's roster of every other party, by their Noise static keys
+    pub fn noise_roster(&self) -> Result<PeerRoster, MpcNetError> {
+        let mut roster = PeerRoster::new();
+        for party in self.parties.iter().filter(|p| p.id != self.king) {
+            roster.insert(party.noise_identity()?, party.id)?;
+        }
+        Ok(roster)
+    }
+}
+
+impl PartyConfig {
+    /// Loads the certificate, checking it against the fingerprint if both are
+    /// given
+    pub fn load_certificate(
+        &self,
+    ) -> Result<Option<rustls::Certificate>, MpcNetError> {
+        let path = match &self.certificate {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let cert = identity::load_certificate(path)?;
+
+        if let Some(fingerprint) = &self.fingerprint {
+            let identity = PeerIdentity::from_certificate(&cert);
+            if identity != parse_fingerprint(fingerprint)? {
+                return Err(MpcNetError::Generic(format!(
+                    "Certificate of party {} has fingerprint {identity}, expected {fingerprint}",
+                    self.id
+                )));
+            }
+        }
+
+        Ok(Some(cert))
+    }
+
+    /// The identity the party authenticates with, preferring its
+    /// certificate over its static key
+    pub fn identity(&self) -> Result<PeerIdentity, MpcNetError> {
+        match (&self.fingerprint, self.load_certificate()?) {
+            (_, Some(cert)) => Ok(PeerIdentity::from_certificate(&cert)),
+            (Some(fingerprint), None) => parse_fingerprint(fingerprint),
+            (None, None) if self.static_key.is_some() => self.noise_identity(),
+            (None, None) => Err(MpcNetError::Generic(format!(
+                "Party {} has neither a certificate, a fingerprint nor a static key",
+                self.id
+            ))),
+        }
+    }
+
+    /// The static key the party authenticates with on Noise transports
+    pub fn noise_identity(&self) -> Result<PeerIdentity, MpcNetError> {
+        Ok(PeerIdentity::StaticKey(self.noise_static_key()?))
+    }
+
+    /// The raw X25519 public key behind [`PartyConfig::noise_identity`]
+    pub fn noise_static_key(&self) -> Result<[u8; 32], MpcNetError> {
+        let key = self.static_key.as_ref().ok_or_else(|| {
+            MpcNetError::Generic(format!("Party {} has no static key", self.id))
+        })?;
+        parse_static_key(key)
+    }
+}
+
+impl LocalConfig {
+    /// Reads a config from a `.json` file, or a TOML file otherwise
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, MpcNetError> {
+        let path = path.as_ref();
+        let mut config: Self = read_config(path)?;
+        if let Some(dir) = path.parent() {
+            config.resolve_paths(dir);
+        }
+        Ok(config)
+    }
+
+    pub fn from_toml(contents: &str) -> Result<Self, MpcNetError> {
+        toml::from_str(contents).map_err(|err| {
+            MpcNetError::Generic(format!("Malformed local config: {err}"))
+        })
+    }
+
+    /// Makes relative key paths relative to `dir`
+    pub fn resolve_paths(&mut self, dir: &Path) {
+        for path in self
+            .private_key
+            .iter_mut()
+            .chain(self.certificate.iter_mut())
+            .chain(self.noise_private_key.iter_mut())
+        {
+            resolve_path(path, dir);
+        }
+    }
+
+    /// Loads our certificate, from `cluster` unless we have our own, and our
+    /// private key. Our own certificate must match what `cluster` lists for
+    /// us.
+    pub fn load_tls_identity(
+        &self,
+        cluster: &ClusterConfig,
+    ) -> Result<RustlsCertificate, MpcNetError> {
+        let me = cluster.party(self.id)?;
+        let cert = match &self.certificate {
+            Some(path) => {
+                let cert = identity::load_certificate(path)?;
+                let identity = PeerIdentity::from_certificate(&cert);
+                if me.certificate.is_none() && me.fingerprint.is_none() {
+                    return Err(MpcNetError::Generic(format!(
+                        "Party {} is not known by a certificate",
+                        self.id
+                    )));
+                }
+                if identity != me.identity()? {
+                    return Err(MpcNetError::Generic(format!(
+                        "Our certificate has fingerprint {identity}, which is not the one party {} has",
+                        self.id
+                    )));
+                }
+                cert
+            }
+            None => me.load_certificate()?.ok_or_else(|| {
+                MpcNetError::Generic(format!(
+                    "Party {} has no certificate",
+                    self.id
+                ))
+            })?,
+        };
+        let path = self.private_key.as_ref().ok_or_else(|| {
+            MpcNetError::Generic(format!(
+                "Party {} has no private key",
+                self.id
+            ))
+        })?;
+        Ok(RustlsCertificate {
+            cert,
+            private_key: identity::load_private_key(path)?,
+        })
+    }
+
+    /// Loads our Noise key pair, checking it against our static key in
+    /// `cluster` if it lists one
+    #[cfg(feature = "noise")]
+    pub fn load_noise_keypair(
+        &self,
+        cluster: &ClusterConfig,
+    ) -> Result<crate::noise::NoiseKeypair, MpcNetError> {
+        let path = self.noise_private_key.as_ref().ok_or_else(|| {
+            MpcNetError::Generic(format!(
+                "Party {} has no Noise private key",
+                self.id
+            ))
+        })?;
+        let private_key = std::fs::read(path).map_err(|err| {
+            MpcNetError::Generic(format!("Error reading {path:?}: {err}"))
+        })?;
+        if private_key.len() != 32 {
+            return Err(MpcNetError::Generic(format!(
+                "{path:?} is not a 32-byte key"
+            )));
+        }
+        let mut key = [0u8; 32];
+        key.copy_from_slice(&private_key);
+        let keypair = crate::noise::NoiseKeypair::from_private_key(key)?;
+
+        let me = cluster.party(self.id)?;
+        if me.static_key.is_some() && me.noise_identity()? != keypair.identity()
+        {
+            return Err(MpcNetError::Generic(format!(
+                "Noise private key of party {} does not match its static key",
+                self.id
+            )));
+        }
+        Ok(keypair)
+    }
+}
+
+/// Parses a fingerprint as printed by [`PeerIdentity`]'s `Display`
+pub fn parse_fingerprint(
+    fingerprint: &str,
+) -> Result<PeerIdentity, MpcNetError> {
+    let bytes = parse_hex_key(fingerprint, "sha256:").ok_or_else(|| {
+        MpcNetError::Generic(format!("Malformed fingerprint {fingerprint}"))
+    })?;
+    Ok(PeerIdentity::CertificateFingerprint(bytes))
+}
+
+/// Parses a Noise static key as printed by [`PeerIdentity`]'s `Display`
+pub fn parse_static_key(key: &str) -> Result<[u8; 32], MpcNetError> {
+    parse_hex_key(key, "x25519:").ok_or_else(|| {
+        MpcNetError::Generic(format!("Malformed static key {key}"))
+    })
+}
+
+/// Parses `<prefix><64 hex digits>` into 32 bytes
+fn parse_hex_key(key: &str, prefix: &str) -> Option<[u8; 32]> {
+    let hex = key.strip_prefix(prefix)?;
+    if hex.len() != 64 {
+        return None;
+    }
+
+    let mut bytes = [0u8; 32];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(2 * i..2 * i + 2)?, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+impl ProdNet<TlsStream<TcpStream>> {
+    /// Boots the party described by the local config file at `local`, in
+    /// the cluster described by the config file at `path`. Returns when all
+    /// the parties have connected.
+    pub async fn from_config<P: AsRef<Path>, Q: AsRef<Path>>(
+        path: P,
+        local: Q,
+    ) -> Result<Self, MpcNetError> {
+        let config = ClusterConfig::load(path)?;
+        let local = LocalConfig::load(local)?;
+        Self::from_cluster_config(&config, &local).await
+    }
+
+    /// Like [`ProdNet::from_config`], for configs that are already loaded
+    pub async fn from_cluster_config(
+        config: &ClusterConfig,
+        local: &LocalConfig,
+    ) -> Result<Self, MpcNetError> {
+        config.validate()?;
+        match &config.failover {
+            Some(failover) => {
+                elect(
+                    config,
+                    local.id,
+                    failover,
+                    |config, timeout| async move {
+                        Self::connect_tls(&config, local, Some(timeout)).await
+                    },
+                )
+                .await
+            }
+            None => Self::connect_tls(config, local, None).await,
+        }
+    }
+
+    /// With a `timeout`, as during an election, a peer keeps dialing the king
+    /// until it has passed, and the king gives up unless most parties join
+    /// it by then
+    async fn connect_tls(
+        config: &ClusterConfig,
+        local: &LocalConfig,
+        timeout: Option<Duration>,
+    ) -> Result<Self, MpcNetError> {
+        let my_id = local.id;
+        let me = config.party(my_id)?;
+        let identity = local.load_tls_identity(config)?;
+        let resume = config.resume.then(ResumeConfig::default);
+
+        if my_id == config.king {
+            let quorum = timeout
+                .map(|timeout| Quorum::majority(config.n_parties(), timeout));
+            Self::new_king_tls_with_quorum(
+                my_id,
+                tokio::net::TcpListener::bind(me.address.as_str()).await?,
+                identity,
+                config.roster()?,
+                config.n_streams,
+                quorum,
+                resume,
+            )
+            .await
+        } else {
+            let king = config.party(config.king)?;
+            let king_cert = king.load_certificate()?.ok_or_else(|| {
+                MpcNetError::Generic(
+                    "The king needs a certificate for TLS".to_string(),
+                )
+            })?;
+            let mut king_store = rustls::RootCertStore::empty();
+            king_store.add(&king_cert)?;
+
+            Self::new_peer_tls_with_king(
+                my_id,
+                config.king,
+                king.address.as_str(),
+                identity,
+                king_store,
+                config.n_parties(),
+                timeout,
+                resume,
+            )
+            .await
+        }
+    }
+}
+
+#[cfg(feature = "noise")]
+impl ProdNet<crate::noise::NoiseStream<TcpStream>> {
+    /// Boots the party described by `local` in the cluster described by
+    /// `config` over Noise, identifying every party by its static key.
+    /// Returns when all the parties have connected.
+    pub async fn from_noise_config(
+        config: &ClusterConfig,
+        local: &LocalConfig,
+    ) -> Result<Self, MpcNetError> {
+        config.validate()?;
+        match &config.failover {
+            Some(failover) => {
+                elect(
+                    config,
+                    local.id,
+                    failover,
+                    |config, timeout| async move {
+                        Self::connect_noise(&config, local, Some(timeout)).await
+                    },
+                )
+                .await
+            }
+            None => Self::connect_noise(config, local, None).await,
+        }
+    }
+
+    /// Like [`ProdNet::connect_tls`], over Noise
+    async fn connect_noise(
+        config: &ClusterConfig,
+        local: &LocalConfig,
+        timeout: Option<Duration>,
+    ) -> Result<Self, MpcNetError> {
+        use crate::noise::NoiseStream;
+        use crate::prod::greet_peer;
+        use crate::session::HANDSHAKE_TIMEOUT;
+
+        let my_id = local.id;
+        let me = config.party(my_id)?;
+        let keypair = local.load_noise_keypair(config)?;
+
+        if my_id == config.king {
+            let roster = config.noise_roster()?;
+            let quorum = timeout
+                .map(|timeout| Quorum::majority(config.n_parties(), timeout));
+            let listener =
+                tokio::net::TcpListener::bind(me.address.as_str()).await?;
+            let mut greeted: Vec<(u32, NoiseStream<TcpStream>)> = vec![];
+            while greeted.len() < roster.len() {
+                let joined = greeted.len() + 1;
+                let (stream, addr) =
+                    Quorum::accept(quorum, my_id, joined, listener.accept())
+                        .await??;
+                let known =
+                    greeted.iter().map(|(peer, _)| *peer).collect::<Vec<_>>();
+                let handshake = async {
+                    let mut stream =
+                        NoiseStream::accept(stream, &keypair).await?;
+                    let peer_id = greet_peer(
+                        &mut stream,
+                        my_id,
+                        config.n_parties(),
+                        config.n_streams,
+                        Some(&roster),
+                        &known,
+                    )
+                    .await?;
+                    Ok::<_, MpcNetError>((peer_id, stream))
+                };
+                // A party that fails to connect, or that is not in the roster,
+                // must not keep the others out
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake).await {
+                    Ok(Ok(peer)) => greeted.push(peer),
+                    Ok(Err(err)) => {
+                        warn!("Rejected a connection from {addr}: {err:?}")
+                    }
+                    Err(_) => warn!("Handshake with {addr} timed out"),
+                }
+            }
+            Self::new_from_greeted_peers(
+                my_id,
+                config.n_parties(),
+                greeted,
+                Some(&roster),
+                config.n_streams,
+            )
+            .await
+        } else {
+            let king = config.party(config.king)?;
+            let king_key = king.noise_static_key()?;
+            let stream = dial_king(&king.address, config.king, timeout).await?;
+            let stream =
+                NoiseStream::connect(stream, &keypair, Some(&king_key)).await?;
+            Self::new_from_connections(
+                my_id,
+                config.king,
+                config.n_parties(),
+                vec![stream],
+                None,
+                config.n_streams,
+            )
+            .await
+        }
+    }
+}
+
+/// Tries to connect with the king and then with each backup in turn, until
+/// one of them is reachable. A backup only takes over as the king once every
+/// coordinator before it turned out to be unreachable, and then waits for
+/// every other party, the coordinators before it included.
+///
+/// A coordinator first looks for another one that already leads, e.g. a
+/// backup that took over while it was down, and joins it as a peer instead
+/// of competing with it. When some parties can reach one coordinator but not
+/// another, two of them may lead at once. Only one of them can have most of
+/// the parties join it within the timeout, so the other one steps down and
+/// tries the coordinators after it like any other party.
+async fn elect<N, F, Fut>(
+    config: &ClusterConfig,
+    my_id: u32,
+    failover: &FailoverConfig,
+    connect: F,
+) -> Result<N, MpcNetError>
+where
+    F: Fn(ClusterConfig, Duration) -> Fut,
+    Fut: Future<Output = Result<N, MpcNetError>>,
+{
+    let coordinators = config.coordinators();
+    if coordinators.contains(&my_id) {
+        for king in coordinators.iter().filter(|king| **king != my_id) {
+            if let Ok(net) =
+                connect(config.with_king(*king), failover.probe_timeout()).await
+            {
+                info!("Party {king} already leads, joining it");
+                return Ok(net);
+            }
+        }
+    }
+
+    let mut last_err = MpcNetError::NotConnected;
+    for king in coordinators {
+        if king == my_id && king != config.king {
+            info!("No coordinator before us is reachable, taking over");
+        }
+        match connect(config.with_king(king), failover.timeout()).await {
+            Ok(net) => return Ok(net),
+            Err(err) if king == my_id => {
+                warn!("Stepping down as the coordinator: {err:?}");
+                last_err = err;
+            }
+            Err(err) => {
+                warn!("Coordinator {king} is unreachable: {err:?}");
+                last_err = err;
+            }
+        }
+    }
+    Err(last_err)
+}
+
+/// Connects to the king at `address`. With a `dial_timeout`, keeps trying
+/// until it has passed, in case the king is not listening yet.
+#[cfg(feature = "noise")]
+async fn dial_king(
+    address: &str,
+    king: u32,
+    dial_timeout: Option<Duration>,
+) -> Result<TcpStream, MpcNetError> {
+    let dial_timeout = match dial_timeout {
+        Some(dial_timeout) => dial_timeout,
+        None => return Ok(TcpStream::connect(address).await?),
+    };
+
+    let deadline = tokio::time::Instant::now() + dial_timeout;
+    loop {
+        match tokio::time::timeout_at(deadline, TcpStream::connect(address))
+            .await
+        {
+            Ok(Ok(stream)) => return Ok(stream),
+            Ok(Err(_)) if tokio::time::Instant::now() < deadline => {
+                tokio::time::sleep(crate::mesh::DIAL_RETRY_DELAY).await
+            }
+            Ok(Err(err)) => return Err(err.into()),
+            Err(_) => return Err(MpcNetError::Timeout { party: king }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+        [pss]
+        l = 1
+
+        [[parties]]
+        id = 0
+        address = "127.0.0.1:12344"
+        certificate = "public_0.cert.der"
+
+        [[parties]]
+        id = 1
+        address = "127.0.0.1:12345"
+        fingerprint = "sha256:00000000000000000000000000000000000000000000000000000000000000ff"
+
+        [[parties]]
+        id = 3
+        address = "127.0.0.1:12347"
+
+        [[parties]]
+        id = 2
+        address = "127.0.0.1:12346"
+    "#;
+
+    #[test]
+    fn test_toml_and_json_agree() {
+        let mut config = ClusterConfig::from_toml(CONFIG).unwrap();
+        assert_eq!(config.king, 0);
+        assert_eq!(config.n_streams, MULTIPLEXED_STREAMS);
+        assert!(!config.resume);
+        config.validate().unwrap();
+
+        let json = serde_json::to_string(&config).unwrap();
+        assert_eq!(ClusterConfig::from_json(&json).unwrap(), config);
+
+        config.resolve_paths(Path::new("/etc/cluster"));
+        assert_eq!(
+            config.party(0).unwrap().certificate,
+            Some(PathBuf::from("/etc/cluster/public_0.cert.der"))
+        );
+    }
+
+    #[test]
+    fn test_local_paths_are_resolved() {
+        let mut local = LocalConfig::from_toml(
+            "id = 2\nprivate_key = \"private_2.key.der\"",
+        )
+        .unwrap();
+        assert_eq!(local.noise_private_key, None);
+
+        local.resolve_paths(Path::new("/etc/party"));
+        assert_eq!(
+            local.private_key,
+            Some(PathBuf::from("/etc/party/private_2.key.der"))
+        );
+    }
+
+    #[test]
+    fn test_invalid_topologies_are_rejected() {
+        let config = ClusterConfig::from_toml(CONFIG).unwrap();
+
+        let mut missing_party = config.clone();
+        missing_party.parties.pop();
+        assert!(missing_party.validate().is_err());
+
+        let mut bad_king = config.clone();
+        bad_king.king = 7;
+        assert!(bad_king.validate().is_err());
+
+        let mut bad_pss = config;
+        bad_pss.pss = Some(PssConfig { l: 2 });
+        assert!(bad_pss.validate().is_err());
+    }
+
+    #[test]
+    fn test_failover_coordinators() {
+        let toml = format!("king = 2\n[failover]\nbackups = [0, 1]\n{CONFIG}");
+        let config = ClusterConfig::from_toml(&toml).unwrap();
+        config.validate().unwrap();
+        assert_eq!(config.coordinators(), vec![2, 0, 1]);
+        assert_eq!(
+            config.failover.as_ref().unwrap().timeout(),
+            Duration::from_millis(default_failover_timeout_ms())
+        );
+        assert_eq!(config.with_king(1).king, 1);
+
+        let mut king_as_backup = config.clone();
+        king_as_backup.failover = Some(FailoverConfig {
+            backups: vec![2],
+            timeout_ms: 100,
+            probe_timeout_ms: 10,
+        });
+        assert!(king_as_backup.validate().is_err());
+
+        let mut missing_backup = config;
+        missing_backup.failover = Some(FailoverConfig {
+            backups: vec![7],
+            timeout_ms: 100,
+            probe_timeout_ms: 10,
+        });
+        assert!(missing_backup.validate().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_coordinator_without_a_majority_steps_down() {
+        let toml = format!(
+            "[failover]\nbackups = [1, 2]\nprobe_timeout_ms = 5\n{CONFIG}"
+        );
+        let config = ClusterConfig::from_toml(&toml).unwrap();
+        let failover = config.failover.clone().unwrap();
+        let (probe, timeout) = (failover.probe_timeout(), failover.timeout());
+        assert_eq!(probe, Duration::from_millis(5));
+
+        // Nobody leads yet, so party 1 takes over, but most parties join
+        // party 2 instead
+        let attempts = std::sync::Mutex::new(vec![]);
+        let king = elect(&config, 1, &failover, |config, time| {
+            attempts.lock().unwrap().push((config.king, time));
+            async move {
+                match config.king {
+                    2 if time == timeout => Ok(2),
+                    _ => Err(MpcNetError::NotConnected),
+                }
+            }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(king, 2);
+        assert_eq!(
+            attempts.into_inner().unwrap(),
+            vec![
+                (0, probe),
+                (2, probe),
+                (0, timeout),
+                (1, timeout),
+                (2, timeout)
+            ]
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_party_known_by_fingerprint_connects() {
+        use crate::{MpcNet, MultiplexedStreamID};
+        use std::convert::TryInto;
+        use tokio_util::bytes::Bytes;
+
+        let dir = std::env::temp_dir()
+            .join(format!("mpc-net-fingerprint-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let king_addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let mut toml = String::new();
+        let mut locals = vec![];
+        for id in 0..3u32 {
+            let identity = RustlsCertificate::generate(
+                vec!["127.0.0.1".to_string()],
+                identity::DEFAULT_VALIDITY,
+            )
+            .unwrap();
+            let cert = dir.join(format!("public_{id}.cert.der"));
+            let key = dir.join(format!("private_{id}.key.der"));
+            identity.save(&cert, &key).unwrap();
+
+            // Party 2 is only listed by fingerprint, and keeps its
+            // certificate to itself
+            let known_by = if id == 2 {
+                format!("fingerprint = \"{}\"", identity.fingerprint())
+            } else {
+                format!("certificate = {cert:?}")
+            };
+            toml += &format!(
+                "[[parties]]\nid = {id}\naddress = \"{king_addr}\"\n{known_by}\n"
+            );
+            locals.push(LocalConfig {
+                id,
+                private_key: Some(key),
+                certificate: (id == 2).then_some(cert),
+                noise_private_key: None,
+            });
+        }
+        let config = ClusterConfig::from_toml(&toml).unwrap();
+        assert!(config.party(2).unwrap().certificate.is_none());
+
+        let nets = futures::future::try_join_all(
+            locals
+                .iter()
+                .map(|local| ProdNet::from_cluster_config(&config, local)),
+        )
+        .await
+        .unwrap();
+        let sums =
+            futures::future::try_join_all(nets.iter().map(|net| async move {
+                let id = net.party_id().to_le_bytes();
+                net.king_compute(&id, MultiplexedStreamID::ZERO, |ids| {
+                    let sum = ids
+                        .iter()
+                        .map(|id| {
+                            u32::from_le_bytes(id[..].try_into().unwrap())
+                        })
+                        .sum::<u32>();
+                    vec![Bytes::copy_from_slice(&sum.to_le_bytes()); 3]
+                })
+                .await
+            }))
+            .await
+            .unwrap();
+        for sum in sums {
+            assert_eq!(sum[..], 3u32.to_le_bytes());
+        }
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_fingerprints_round_trip() {
+        let identity = PeerIdentity::CertificateFingerprint([0xab; 32]);
+        assert_eq!(parse_fingerprint(&identity.to_string()).unwrap(), identity);
+        assert!(parse_fingerprint("sha256:abcd").is_err());
+        assert!(parse_fingerprint(&"ab".repeat(32)).is_err());
+
+        let key = PeerIdentity::StaticKey([0x12; 32]);
+        assert_eq!(parse_static_key(&key.to_string()).unwrap(), [0x12; 32]);
+        assert!(parse_static_key(&identity.to_string()).is_err());
+    }
+}
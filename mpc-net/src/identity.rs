@@ -0,0 +1,468 @@
+//! Generating, loading and saving the certificates and private keys parties
+//! authenticate with, in PEM or DER.
+//!
+//! Certificates are generated with a limited validity, which is checked when
+//! they are loaded into a roster. A king can rotate its own certificate
+//! without restarting through a [`ReloadableIdentity`].
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use parking_lot::RwLock;
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+
+use crate::config::ClusterConfig;
+use crate::prod::{PeerIdentity, PeerRoster, RustlsCertificate};
+use crate::MpcNetError;
+
+/// How long generated certificates are valid by default
+pub const DEFAULT_VALIDITY: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+/// How certificates and keys are stored in files
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Pem,
+    Der,
+}
+
+impl Encoding {
+    /// PEM for `.pem` files, DER otherwise
+    pub fn from_path(path: &Path) -> Self {
+        if path.extension().is_some_and(|e| e == "pem") {
+            Encoding::Pem
+        } else {
+            Encoding::Der
+        }
+    }
+}
+
+impl RustlsCertificate {
+    /// Generates a self-signed certificate for `alt_names`, valid from now
+    /// for `validity`
+    pub fn generate(
+        alt_names: Vec<String>,
+        validity: Duration,
+    ) -> Result<Self, MpcNetError> {
+        let mut params = rcgen::CertificateParams::new(alt_names);
+        let now = time::OffsetDateTime::now_utc();
+        params.not_before = now;
+        params.not_after = now + validity;
+        let cert = rcgen::Certificate::from_params(params)?;
+
+        Ok(Self {
+            cert: rustls::Certificate(cert.serialize_der()?),
+            private_key: rustls::PrivateKey(cert.serialize_private_key_der()),
+        })
+    }
+
+    /// Loads a certificate and its private key, each in PEM or DER
+    pub fn load<P: AsRef<Path>, Q: AsRef<Path>>(
+        cert_path: P,
+        key_path: Q,
+    ) -> Result<Self, MpcNetError> {
+        Ok(Self {
+            cert: load_certificate(cert_path)?,
+            private_key: load_private_key(key_path)?,
+        })
+    }
+
+    /// Saves the certificate and private key, encoded according to the
+    /// extension of each path
+    pub fn save<P: AsRef<Path>, Q: AsRef<Path>>(
+        &self,
+        cert_path: P,
+        key_path: Q,
+    ) -> Result<(), MpcNetError> {
+        save_certificate(&self.cert, cert_path)?;
+        save_private_key(&self.private_key, key_path)
+    }
+
+    /// The identity peers know this certificate by
+    pub fn fingerprint(&self) -> PeerIdentity {
+        PeerIdentity::from_certificate(&self.cert)
+    }
+}
+
+/// Loads the first certificate of a PEM file, or a DER certificate
+pub fn load_certificate<P: AsRef<Path>>(
+    path: P,
+) -> Result<rustls::Certificate, MpcNetError> {
+    let path = path.as_ref();
+    let contents = read(path)?;
+    if !is_pem(&contents) {
+        return Ok(rustls::Certificate(contents));
+    }
+
+    rustls_pemfile::certs(&mut contents.as_slice())?
+        .into_iter()
+        .next()
+        .map(rustls::Certificate)
+        .ok_or_else(|| {
+            MpcNetError::Generic(format!("No certificate in {path:?}"))
+        })
+}
+
+/// Loads the first private key of a PEM file, or a DER private key
+pub fn load_private_key<P: AsRef<Path>>(
+    path: P,
+) -> Result<rustls::PrivateKey, MpcNetError> {
+    let path = path.as_ref();
+    let contents = read(path)?;
+    if !is_pem(&contents) {
+        return Ok(rustls::PrivateKey(contents));
+    }
+
+    rustls_pemfile::read_all(&mut contents.as_slice())?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(rustls::PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| {
+            MpcNetError::Generic(format!("No private key in {path:?}"))
+        })
+}
+
+/// Saves a certificate, as PEM if `path` ends in `.pem` or as DER otherwise
+pub fn save_certificate<P: AsRef<Path>>(
+    cert: &rustls::Certificate,
+    path: P,
+) -> Result<(), MpcNetError> {
+    write(path.as_ref(), "CERTIFICATE", &cert.0)
+}
+
+/// Saves a PKCS #8 private key, as PEM if `path` ends in `.pem` or as DER
+/// otherwise
+pub fn save_private_key<P: AsRef<Path>>(
+    key: &rustls::PrivateKey,
+    path: P,
+) -> Result<(), MpcNetError> {
+    write(path.as_ref(), "PRIVATE KEY", &key.0)
+}
+
+/// The time the certificate stops being valid
+pub fn expiry(cert: &rustls::Certificate) -> Result<SystemTime, MpcNetError> {
+    Ok(validity(cert)?.1)
+}
+
+/// Fails unless the certificate is valid at `time`
+pub fn check_validity(
+    cert: &rustls::Certificate,
+    time: SystemTime,
+) -> Result<(), MpcNetError> {
+    let (not_before, not_after) = validity(cert)?;
+    let fingerprint = PeerIdentity::from_certificate(cert);
+    if time < not_before {
+        return Err(MpcNetError::Generic(format!(
+            "Certificate {fingerprint} is not valid yet"
+        )));
+    }
+    if time > not_after {
+        return Err(MpcNetError::Generic(format!(
+            "Certificate {fingerprint} has expired"
+        )));
+    }
+    Ok(())
+}
+
+fn validity(
+    cert: &rustls::Certificate,
+) -> Result<(SystemTime, SystemTime), MpcNetError> {
+    let (_, parsed) =
+        x509_parser::parse_x509_certificate(&cert.0).map_err(|err| {
+            MpcNetError::Generic(format!("Malformed certificate: {err}"))
+        })?;
+    let validity = parsed.validity();
+    Ok((
+        to_system_time(validity.not_before.timestamp()),
+        to_system_time(validity.not_after.timestamp()),
+    ))
+}
+
+fn to_system_time(timestamp: i64) -> SystemTime {
+    let offset = Duration::from_secs(timestamp.unsigned_abs());
+    if timestamp >= 0 {
+        UNIX_EPOCH + offset
+    } else {
+        UNIX_EPOCH - offset
+    }
+}
+
+/// Loads a roster from the certificates in `dir` named
+/// `<name>_<party id>.cert.der` or `<name>_<party id>.cert.pem`, as written by
+/// the `gen_cert` example. The king's own certificate is skipped, and
+/// certificates that are not currently valid are rejected.
+pub fn load_roster_dir<P: AsRef<Path>>(
+    dir: P,
+    king: u32,
+) -> Result<PeerRoster, MpcNetError> {
+    let dir = dir.as_ref();
+    let mut certs = vec![];
+    for entry in std::fs::read_dir(dir).map_err(|err| {
+        MpcNetError::Generic(format!("Error reading {dir:?}: {err}"))
+    })? {
+        let path = entry?.path();
+        if let Some(party_id) = certificate_party_id(&path) {
+            certs.push((party_id, path));
+        }
+    }
+    // Report duplicates the same way whatever order the directory lists in
+    certs.sort();
+
+    let mut roster = PeerRoster::new();
+    let now = SystemTime::now();
+    for (party_id, path) in certs {
+        if party_id == king {
+            continue;
+        }
+        let cert = load_certificate(&path)?;
+        check_validity(&cert, now)?;
+        roster.add_certificate(party_id, cert)?;
+    }
+    Ok(roster)
+}
+
+/// Loads the roster of the cluster config at `path`, rejecting certificates
+/// that are not currently valid
+pub fn load_roster_config<P: AsRef<Path>>(
+    path: P,
+) -> Result<PeerRoster, MpcNetError> {
+    let config = ClusterConfig::load(path)?;
+    let now = SystemTime::now();
+    for party in config.parties.iter().filter(|p| p.id != config.king) {
+        if let Some(cert) = party.load_certificate()? {
+            check_validity(&cert, now)?;
+        }
+    }
+    config.roster()
+}
+
+/// The party ID in a file name like `public_3.cert.der`
+fn certificate_party_id(path: &Path) -> Option<u32> {
+    let name = path.file_name()?.to_str()?;
+    let stem = name
+        .strip_suffix(".cert.der")
+        .or_else(|| name.strip_suffix(".cert.pem"))?;
+    stem.rsplit('_').next()?.parse().ok()
+}
+
+/// The king's certificate and private key, reloaded from their files on
+/// demand. Connections accepted after a [`ReloadableIdentity::reload`]
+/// present the new certificate, so peers must already trust it. Only the
+/// king's own identity rotates, not the roster of its peers.
+pub struct ReloadableIdentity {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<(RustlsCertificate, Arc<CertifiedKey>)>,
+}
+
+impl ReloadableIdentity {
+    pub fn load<P: Into<PathBuf>, Q: Into<PathBuf>>(
+        cert_path: P,
+        key_path: Q,
+    ) -> Result<Arc<Self>, MpcNetError> {
+        let (cert_path, key_path) = (cert_path.into(), key_path.into());
+        let current = RwLock::new(Self::read(&cert_path, &key_path)?);
+        Ok(Arc::new(Self {
+            cert_path,
+            key_path,
+            current,
+        }))
+    }
+
+    /// Reads the files again. The old identity stays in place if they can't
+    /// be loaded, or if the key does not belong to the certificate, e.g.
+    /// when only one of the files has been replaced so far.
+    pub fn reload(&self) -> Result<(), MpcNetError> {
+        let reloaded = Self::read(&self.cert_path, &self.key_path)?;
+        *self.current.write() = reloaded;
+        Ok(())
+    }
+
+    /// The identity new connections are accepted with
+    pub fn current(&self) -> RustlsCertificate {
+        self.current.read().0.clone()
+    }
+
+    fn read(
+        cert_path: &Path,
+        key_path: &Path,
+    ) -> Result<(RustlsCertificate, Arc<CertifiedKey>), MpcNetError> {
+        let identity = RustlsCertificate::load(cert_path, key_path)?;
+        check_validity(&identity.cert, SystemTime::now())?;
+        check_key_matches(&identity)?;
+        let key = rustls::sign::any_supported_type(&identity.private_key)
+            .map_err(|err| {
+                MpcNetError::Generic(format!(
+                    "Unsupported private key in {key_path:?}: {err}"
+                ))
+            })?;
+        let certified = CertifiedKey::new(vec![identity.cert.clone()], key);
+        Ok((identity, Arc::new(certified)))
+    }
+}
+
+/// Fails unless the private key is the one the certificate was issued for
+fn check_key_matches(identity: &RustlsCertificate) -> Result<(), MpcNetError> {
+    let key_pair = rcgen::KeyPair::from_der(&identity.private_key.0)?;
+    let (_, parsed) = x509_parser::parse_x509_certificate(&identity.cert.0)
+        .map_err(|err| {
+            MpcNetError::Generic(format!("Malformed certificate: {err}"))
+        })?;
+    if parsed.public_key().subject_public_key.data != key_pair.public_key_raw()
+    {
+        return Err(MpcNetError::Generic(format!(
+            "Private key does not belong to certificate {}",
+            identity.fingerprint()
+        )));
+    }
+    Ok(())
+}
+
+impl ResolvesServerCert for ReloadableIdentity {
+    fn resolve(&self, _: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().1.clone())
+    }
+}
+
+fn is_pem(contents: &[u8]) -> bool {
+    let start = contents
+        .iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .unwrap_or(contents.len());
+    contents[start..].starts_with(b"-----BEGIN")
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, MpcNetError> {
+    std::fs::read(path).map_err(|err| {
+        MpcNetError::Generic(format!("Error reading {path:?}: {err}"))
+    })
+}
+
+fn write(path: &Path, tag: &str, der: &[u8]) -> Result<(), MpcNetError> {
+    let contents = match Encoding::from_path(path) {
+        Encoding::Pem => pem::encode(&pem::Pem::new(tag, der)).into_bytes(),
+        Encoding::Der => der.to_vec(),
+    };
+    std::fs::write(path, contents).map_err(|err| {
+        MpcNetError::Generic(format!("Error writing {path:?}: {err}"))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("mpc-net-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn generate() -> RustlsCertificate {
+        RustlsCertificate::generate(
+            vec!["127.0.0.1".to_string()],
+            DEFAULT_VALIDITY,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_pem_and_der_round_trip() {
+        let dir = temp_dir("identity-round-trip");
+        let identity = generate();
+
+        for (cert, key) in [("cert.pem", "key.pem"), ("cert.der", "key.der")] {
+            let (cert, key) = (dir.join(cert), dir.join(key));
+            identity.save(&cert, &key).unwrap();
+            let loaded = RustlsCertificate::load(&cert, &key).unwrap();
+            assert_eq!(loaded.cert, identity.cert);
+            assert_eq!(loaded.private_key, identity.private_key);
+            assert_eq!(loaded.fingerprint(), identity.fingerprint());
+        }
+        assert!(std::fs::read_to_string(dir.join("cert.pem"))
+            .unwrap()
+            .starts_with("-----BEGIN CERTIFICATE-----"));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_expiry_is_checked() {
+        let validity = Duration::from_secs(24 * 60 * 60);
+        let identity = RustlsCertificate::generate(
+            vec!["localhost".to_string()],
+            validity,
+        )
+        .unwrap();
+
+        let expiry = expiry(&identity.cert).unwrap();
+        let expected = SystemTime::now() + validity;
+        assert!(expiry <= expected);
+        assert!(expiry + Duration::from_secs(60) > expected);
+
+        check_validity(&identity.cert, SystemTime::now()).unwrap();
+        let later = SystemTime::now() + 2 * validity;
+        assert!(check_validity(&identity.cert, later).is_err());
+    }
+
+    #[test]
+    fn test_roster_is_loaded_from_a_directory() {
+        let dir = temp_dir("identity-roster");
+        let identities = (0..3).map(|_| generate()).collect::<Vec<_>>();
+        for (id, identity) in identities.iter().enumerate() {
+            save_certificate(
+                &identity.cert,
+                dir.join(format!("public_{id}.cert.pem")),
+            )
+            .unwrap();
+        }
+        std::fs::write(dir.join("README"), "not a certificate").unwrap();
+
+        let roster = load_roster_dir(&dir, 0).unwrap();
+        assert_eq!(roster.len(), 2);
+        for (id, identity) in identities.iter().enumerate().skip(1) {
+            assert_eq!(
+                roster.party_id(&identity.fingerprint()),
+                Some(id as u32)
+            );
+        }
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_rotated_identity_is_reloaded() {
+        let dir = temp_dir("identity-reload");
+        let (cert, key) = (dir.join("king.cert.pem"), dir.join("king.key.pem"));
+        let first = generate();
+        first.save(&cert, &key).unwrap();
+
+        let identity = ReloadableIdentity::load(&cert, &key).unwrap();
+        assert_eq!(identity.current().cert, first.cert);
+
+        let second = generate();
+        second.save(&cert, &key).unwrap();
+        identity.reload().unwrap();
+        assert_eq!(identity.current().cert, second.cert);
+
+        // A broken rotation leaves the current identity in place
+        std::fs::write(&key, "garbage").unwrap();
+        assert!(identity.reload().is_err());
+        assert_eq!(identity.current().cert, second.cert);
+
+        // So does a certificate that was rotated without its key
+        let third = generate();
+        third.save(&cert, dir.join("other.key.pem")).unwrap();
+        second.save(dir.join("other.cert.pem"), &key).unwrap();
+        assert!(identity.reload().is_err());
+        assert_eq!(identity.current().cert, second.cert);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
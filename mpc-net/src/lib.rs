@@ -1,12 +1,33 @@
+#[cfg(any(test, feature = "test-utils"))]
+pub mod adversary;
+pub mod chunk;
+pub mod cluster;
+pub mod compress;
+pub mod config;
+pub mod identity;
+pub mod local;
+pub mod memory;
+pub mod mesh;
 pub mod multi;
+#[cfg(feature = "noise")]
+pub mod noise;
 pub mod prod;
+#[cfg(feature = "quic")]
+pub mod quic;
+pub mod session;
+pub mod sim;
+pub mod stats;
+pub mod transcript;
 
 use async_trait::async_trait;
 use auto_impl::auto_impl;
+use futures::future::{try_join, try_join_all};
 use futures::stream::FuturesOrdered;
 use futures::TryStreamExt;
 pub use multi::LocalTestNet;
 use serde::{Deserialize, Serialize};
+pub use session::{FrameTag, ReplayLimit, SessionTracker};
+pub use stats::NetStats;
 use std::collections::HashMap;
 use std::fmt::Debug;
 use tokio_util::bytes::Bytes;
@@ -14,9 +35,33 @@ use tokio_util::bytes::Bytes;
 #[derive(Clone, Debug)]
 pub enum MpcNetError {
     Generic(String),
-    Protocol { err: String, party: u32 },
+    Protocol {
+        err: String,
+        party: u32,
+    },
     NotConnected,
-    BadInput { err: &'static str },
+    BadInput {
+        err: &'static str,
+    },
+    /// `party` did not answer in time
+    Timeout {
+        party: u32,
+    },
+    /// `party` aborted the session
+    Aborted {
+        err: String,
+        party: u32,
+    },
+    /// `party` shut down its side of the network cleanly
+    ShutDown {
+        party: u32,
+    },
+    /// Parties disagree on what was broadcast, and any of `suspects` may be
+    /// the one that lied
+    Equivocation {
+        err: String,
+        suspects: Vec<u32>,
+    },
 }
 
 impl<T: ToString> From<T> for MpcNetError {
@@ -25,20 +70,90 @@ impl<T: ToString> From<T> for MpcNetError {
     }
 }
 
+/// Identifies one of the streams multiplexed over the connection to a peer.
+/// A network opens [`MpcNet::n_streams`] of them, numbered from zero.
+#[derive(
+    Serialize,
+    Deserialize,
+    Clone,
+    Debug,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Copy,
+)]
+pub struct MultiplexedStreamID(pub u32);
+
+impl MultiplexedStreamID {
+    pub const ZERO: Self = Self(0);
+    pub const ONE: Self = Self(1);
+    pub const TWO: Self = Self(2);
+
+    pub fn new(id: u32) -> Self {
+        Self(id)
+    }
+
+    /// Position of this stream in a peer's list of streams
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+impl From<u32> for MultiplexedStreamID {
+    fn from(id: u32) -> Self {
+        Self(id)
+    }
+}
+
+/// How serialized values are laid out on the wire. This is interpreted by the
+/// serialization layer built on top of `MpcNet`.
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash, Copy)]
-pub enum MultiplexedStreamID {
-    Zero = 0,
-    One = 1,
-    Two = 2,
+pub struct WireCodec {
+    /// Send curve points in compressed form. Uncompressed points are twice as
+    /// large but avoid a square root per point when decoding.
+    pub compress: bool,
+    /// Check received values, e.g. subgroup membership of curve points. Only
+    /// disable this for authenticated peers.
+    pub validate: bool,
+}
+
+impl WireCodec {
+    /// Compressed and validated, the safe default
+    pub const COMPRESSED: Self = Self {
+        compress: true,
+        validate: true,
+    };
+    /// Uncompressed but still validated
+    pub const UNCOMPRESSED: Self = Self {
+        compress: false,
+        validate: true,
+    };
+    /// Uncompressed and unchecked, for trusted peers only
+    pub const TRUSTED: Self = Self {
+        compress: false,
+        validate: false,
+    };
+}
+
+impl Default for WireCodec {
+    fn default() -> Self {
+        Self::COMPRESSED
+    }
 }
 
 #[async_trait]
 #[auto_impl(&, &mut, Arc)]
 pub trait MpcNet: Send + Sync {
-    /// Am I the first party?
-
+    /// Am I the king?
     fn is_king(&self) -> bool {
-        self.party_id() == 0
+        self.party_id() == self.king_id()
+    }
+    /// Which party is the king, that the king-centric helpers gather at and
+    /// scatter from?
+    fn king_id(&self) -> u32 {
+        0
     }
     /// How many parties are there?
     fn n_parties(&self) -> usize;
@@ -46,6 +161,30 @@ pub trait MpcNet: Send + Sync {
     fn party_id(&self) -> u32;
     /// Is the network layer initalized?
     fn is_init(&self) -> bool;
+    /// How many streams are multiplexed over each connection?
+    fn n_streams(&self) -> usize;
+    /// The codec used when serializing values sent over this network
+    fn wire_codec(&self) -> WireCodec {
+        WireCodec::default()
+    }
+    /// Label the protocol that is about to run on `sid`. Networks that tag
+    /// their frames reject frames sent under a different label.
+    fn set_protocol_label(&self, _sid: MultiplexedStreamID, _label: &str) {}
+    /// Abort the session: tell every other party to stop, so that their
+    /// pending and future operations fail with [`MpcNetError::Aborted`]
+    async fn abort(&self, _reason: &str) {}
+    /// Tell every other party that we are leaving, after the frames sent so
+    /// far, and close the connections. Their operations with us then fail
+    /// with [`MpcNetError::ShutDown`] instead of a dead stream, and all of
+    /// ours fail from now on.
+    async fn shutdown(&self) -> Result<(), MpcNetError> {
+        Ok(())
+    }
+    /// Traffic and timing metrics so far. Networks that don't record them
+    /// report none.
+    fn stats(&self) -> NetStats {
+        NetStats::new(self.party_id())
+    }
     async fn recv_from(
         &self,
         id: u32,
@@ -57,45 +196,53 @@ pub trait MpcNet: Send + Sync {
         bytes: Bytes,
         sid: MultiplexedStreamID,
     ) -> Result<(), MpcNetError>;
+    /// Receive a frame that the caller opens itself, e.g. with a
+    /// [`SessionTracker`] of its own. Networks that tag their frames pass it
+    /// on without opening it. The others, and those that replay frames
+    /// after reconnecting, receive it like any message.
+    async fn recv_sealed(
+        &self,
+        id: u32,
+        sid: MultiplexedStreamID,
+    ) -> Result<Bytes, MpcNetError> {
+        self.recv_from(id, sid).await
+    }
+    /// Send a frame that the caller sealed itself. Networks that tag their
+    /// frames send it without tagging it again. The others, and those that
+    /// replay frames after reconnecting, send it like any message.
+    async fn send_sealed(
+        &self,
+        id: u32,
+        frame: Bytes,
+        sid: MultiplexedStreamID,
+    ) -> Result<(), MpcNetError> {
+        self.send_to(id, frame, sid).await
+    }
     /// All parties send bytes to the king. The king receives all the bytes
     async fn client_send_or_king_receive(
         &self,
         bytes: &[u8],
         sid: MultiplexedStreamID,
     ) -> Result<Option<Vec<Bytes>>, MpcNetError> {
-        let bytes_out = Bytes::copy_from_slice(bytes);
-        let own_id = self.party_id();
-
-        let r = if self.is_king() {
-            let mut r = FuturesOrdered::new();
-
-            for id in 0..self.n_parties() as u32 {
-                let bytes_out: Bytes = bytes_out.clone();
-                r.push_back(Box::pin(async move {
-                    let bytes_in = if id == own_id {
-                        bytes_out
-                    } else {
-                        self.recv_from(id, sid).await?
-                    };
-
-                    Ok::<_, MpcNetError>((id, bytes_in))
-                }));
-            }
-
-            let mut ret: HashMap<u32, Bytes> = r.try_collect().await?;
-            ret.entry(0).or_insert_with(|| bytes_out.clone());
-
-            let mut sorted_ret = Vec::new();
-            for x in 0..self.n_parties() {
-                sorted_ret.push(ret.remove(&(x as u32)).unwrap());
-            }
-
-            Ok(Some(sorted_ret))
-        } else {
-            self.send_to(0, bytes_out, sid).await?;
-            Ok(None)
-        };
-        r
+        self.client_send_or_king_receive_bytes(
+            Bytes::copy_from_slice(bytes),
+            sid,
+        )
+        .await
+    }
+    /// Like [`MpcNet::client_send_or_king_receive`], but takes the message as
+    /// [`Bytes`]. The king keeps its own message as is, while the other
+    /// parties still copy theirs into chunks when sending it.
+    async fn client_send_or_king_receive_bytes(
+        &self,
+        bytes: Bytes,
+        sid: MultiplexedStreamID,
+    ) -> Result<Option<Vec<Bytes>>, MpcNetError> {
+        let result = gather_at_king(self, bytes, sid).await;
+        if let Err(err) = &result {
+            self.abort(&format!("{err:?}")).await;
+        }
+        result
     }
     /// All parties recv bytes from the king.
     /// Provide bytes iff you're the king!
@@ -104,38 +251,11 @@ pub trait MpcNet: Send + Sync {
         bytes_out: Option<Vec<Bytes>>,
         sid: MultiplexedStreamID,
     ) -> Result<Bytes, MpcNetError> {
-        let own_id = self.party_id();
-
-        if let Some(bytes_out) = bytes_out {
-            if !self.is_king() {
-                return Err(MpcNetError::BadInput {
-                    err: "recv_from_king called with bytes_out when not king",
-                });
-            }
-
-            let m = bytes_out[0].len();
-
-            for id in (0..self.n_parties()).filter(|p| *p != own_id as usize) {
-                if bytes_out[id].len() != m {
-                    return Err(MpcNetError::Protocol {
-                        err: format!("Peer {} sent wrong number of bytes", id),
-                        party: id as u32,
-                    });
-                }
-
-                self.send_to(id as u32, bytes_out[id].clone(), sid).await?;
-            }
-
-            Ok(bytes_out[own_id as usize].clone())
-        } else {
-            if self.is_king() {
-                return Err(MpcNetError::BadInput {
-                    err: "recv_from_king called with no bytes_out when king",
-                });
-            }
-
-            self.recv_from(0, sid).await
+        let result = scatter_from_king(self, bytes_out, sid).await;
+        if let Err(err) = &result {
+            self.abort(&format!("{err:?}")).await;
         }
+        result
     }
 
     /// Everyone sends bytes to the king, who receives those bytes, runs a computation on them, and
@@ -153,4 +273,232 @@ pub trait MpcNet: Send + Sync {
             self.client_send_or_king_receive(bytes, sid).await?.map(f);
         self.client_receive_or_king_send(king_response, sid).await
     }
+
+    /// Every party sends `bytes` to every other party, and receives what each
+    /// of them sent, in party order. Like the other calls between clients,
+    /// this needs a connection between every pair of parties, so on a star
+    /// network it fails with [`MpcNetError::NotConnected`].
+    async fn broadcast(
+        &self,
+        bytes: &[u8],
+        sid: MultiplexedStreamID,
+    ) -> Result<Vec<Bytes>, MpcNetError> {
+        let bytes = Bytes::copy_from_slice(bytes);
+        self.all_to_all(vec![bytes; self.n_parties()], sid).await
+    }
+    /// Every party sends `bytes_out[i]` to party `i`, and receives what each
+    /// party sent it, in party order. Our own entry is kept as is.
+    async fn all_to_all(
+        &self,
+        bytes_out: Vec<Bytes>,
+        sid: MultiplexedStreamID,
+    ) -> Result<Vec<Bytes>, MpcNetError> {
+        let result = exchange(self, bytes_out, sid).await;
+        if let Err(err) = &result {
+            self.abort(&format!("{err:?}")).await;
+        }
+        result
+    }
+    /// `sender` sends `bytes` to every party, and then every party echoes
+    /// what it got to all the others. A sender that tells different parties
+    /// different things is caught, since the echoes then disagree. The
+    /// sender blames the party whose echo is wrong with
+    /// [`MpcNetError::Protocol`]. Any other party can't tell whether the
+    /// sender or the echo lied, and fails with [`MpcNetError::Equivocation`].
+    /// Provide bytes iff you're the sender!
+    async fn reliable_broadcast(
+        &self,
+        sender: u32,
+        bytes: Option<Bytes>,
+        sid: MultiplexedStreamID,
+    ) -> Result<Bytes, MpcNetError> {
+        let result = echo_broadcast(self, sender, bytes, sid).await;
+        if let Err(err) = &result {
+            self.abort(&format!("{err:?}")).await;
+        }
+        result
+    }
+}
+
+/// [`MpcNet::client_send_or_king_receive_bytes`], without the abort on failure
+async fn gather_at_king<N: MpcNet + ?Sized>(
+    net: &N,
+    bytes_out: Bytes,
+    sid: MultiplexedStreamID,
+) -> Result<Option<Vec<Bytes>>, MpcNetError> {
+    let own_id = net.party_id();
+    let king_id = net.king_id();
+
+    let r = if net.is_king() {
+        let mut r = FuturesOrdered::new();
+
+        for id in 0..net.n_parties() as u32 {
+            let bytes_out: Bytes = bytes_out.clone();
+            r.push_back(Box::pin(async move {
+                let bytes_in = if id == own_id {
+                    bytes_out
+                } else {
+                    net.recv_from(id, sid).await?
+                };
+
+                Ok::<_, MpcNetError>((id, bytes_in))
+            }));
+        }
+
+        let mut ret: HashMap<u32, Bytes> = r.try_collect().await?;
+        ret.entry(own_id).or_insert_with(|| bytes_out.clone());
+
+        let mut sorted_ret = Vec::new();
+        for x in 0..net.n_parties() {
+            sorted_ret.push(ret.remove(&(x as u32)).unwrap());
+        }
+
+        Ok(Some(sorted_ret))
+    } else {
+        net.send_to(king_id, bytes_out, sid).await?;
+        Ok(None)
+    };
+    r
+}
+
+/// [`MpcNet::client_receive_or_king_send`], without the abort on failure
+async fn scatter_from_king<N: MpcNet + ?Sized>(
+    net: &N,
+    bytes_out: Option<Vec<Bytes>>,
+    sid: MultiplexedStreamID,
+) -> Result<Bytes, MpcNetError> {
+    let own_id = net.party_id();
+
+    if let Some(bytes_out) = bytes_out {
+        if !net.is_king() {
+            return Err(MpcNetError::BadInput {
+                err: "recv_from_king called with bytes_out when not king",
+            });
+        }
+
+        if bytes_out.len() != net.n_parties() {
+            return Err(MpcNetError::BadInput {
+                err: "recv_from_king called with the wrong number of outputs",
+            });
+        }
+
+        let m = bytes_out[0].len();
+
+        for id in (0..net.n_parties()).filter(|p| *p != own_id as usize) {
+            if bytes_out[id].len() != m {
+                return Err(MpcNetError::Protocol {
+                    err: format!("Peer {} sent wrong number of bytes", id),
+                    party: id as u32,
+                });
+            }
+
+            net.send_to(id as u32, bytes_out[id].clone(), sid).await?;
+        }
+
+        Ok(bytes_out[own_id as usize].clone())
+    } else {
+        if net.is_king() {
+            return Err(MpcNetError::BadInput {
+                err: "recv_from_king called with no bytes_out when king",
+            });
+        }
+
+        net.recv_from(net.king_id(), sid).await
+    }
+}
+
+/// [`MpcNet::all_to_all`], without the abort on failure
+async fn exchange<N: MpcNet + ?Sized>(
+    net: &N,
+    bytes_out: Vec<Bytes>,
+    sid: MultiplexedStreamID,
+) -> Result<Vec<Bytes>, MpcNetError> {
+    let own_id = net.party_id();
+    if bytes_out.len() != net.n_parties() {
+        return Err(MpcNetError::BadInput {
+            err: "all_to_all called with the wrong number of outputs",
+        });
+    }
+
+    // Send and receive at once, so that large messages can't fill up the
+    // buffers of parties that are all still sending
+    let sends = bytes_out
+        .iter()
+        .enumerate()
+        .filter(|(id, _)| *id != own_id as usize)
+        .map(|(id, bytes)| net.send_to(id as u32, bytes.clone(), sid));
+    let recvs = (0..net.n_parties() as u32).map(|id| {
+        let own_bytes = bytes_out[own_id as usize].clone();
+        async move {
+            if id == own_id {
+                Ok(own_bytes)
+            } else {
+                net.recv_from(id, sid).await
+            }
+        }
+    });
+
+    let (_, bytes_in) =
+        try_join(try_join_all(sends), try_join_all(recvs)).await?;
+    Ok(bytes_in)
+}
+
+/// [`MpcNet::reliable_broadcast`], without the abort on failure
+async fn echo_broadcast<N: MpcNet + ?Sized>(
+    net: &N,
+    sender: u32,
+    bytes: Option<Bytes>,
+    sid: MultiplexedStreamID,
+) -> Result<Bytes, MpcNetError> {
+    let own_id = net.party_id();
+    if sender as usize >= net.n_parties() {
+        return Err(MpcNetError::BadInput {
+            err: "reliable_broadcast called with an unknown sender",
+        });
+    }
+
+    let received = match bytes {
+        Some(bytes) => {
+            if own_id != sender {
+                return Err(MpcNetError::BadInput {
+                    err: "reliable_broadcast called with bytes when not the sender",
+                });
+            }
+            let sends = (0..net.n_parties() as u32)
+                .filter(|id| *id != own_id)
+                .map(|id| net.send_to(id, bytes.clone(), sid));
+            try_join_all(sends).await?;
+            bytes
+        }
+        None => {
+            if own_id == sender {
+                return Err(MpcNetError::BadInput {
+                    err: "reliable_broadcast called with no bytes when the sender",
+                });
+            }
+            net.recv_from(sender, sid).await?
+        }
+    };
+
+    let echoes =
+        exchange(net, vec![received.clone(); net.n_parties()], sid).await?;
+    for (id, echo) in echoes.iter().enumerate() {
+        if *echo == received {
+            continue;
+        }
+        let err =
+            format!("Party {id} got a different message from party {sender}");
+        // The sender knows what it sent, so only the echo can be wrong
+        if own_id == sender {
+            return Err(MpcNetError::Protocol {
+                err,
+                party: id as u32,
+            });
+        }
+        return Err(MpcNetError::Equivocation {
+            err,
+            suspects: vec![sender, id as u32],
+        });
+    }
+    Ok(received)
 }
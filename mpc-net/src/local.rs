@@ -0,0 +1,337 @@
+//! Transports for parties on the same machine: Unix domain sockets, and
+//! vsock with the `vsock` feature, for an enclave and its host.
+//!
+//! Neither encrypts anything, so a [`ProdNet`] only accepts them wrapped in
+//! [`TrustedLocal`]. Wrapping a stream is the caller's promise that no one
+//! else can read or inject traffic on it, e.g. because the socket file is
+//! only accessible to the parties. Parties are not authenticated either: the
+//! king trusts the party ID each peer announces.
+use crate::mesh::DIAL_RETRY_DELAY;
+use crate::multi::MULTIPLEXED_STREAMS;
+use crate::prod::{HasPeerAddr, HasPeerIdentity, IsTransportEncrypted};
+use crate::prod::{IOStream, ProdNet};
+use crate::MpcNetError;
+use std::future::Future;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+#[cfg(feature = "vsock")]
+use tokio_vsock::{VsockListener, VsockStream};
+
+/// Local sockets have no IP address, so peers on them report this one
+pub(crate) const LOCAL_ADDR: SocketAddr =
+    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0));
+
+/// Listens on every context ID, see `VMADDR_CID_ANY` in `linux/vm_sockets.h`
+#[cfg(feature = "vsock")]
+pub const VMADDR_CID_ANY: u32 = u32::MAX;
+
+/// A stream that is trusted not to be read or tampered with by anyone but the
+/// parties, so that it can stand in for an encrypted transport
+#[derive(Debug)]
+pub struct TrustedLocal<S> {
+    inner: S,
+}
+
+impl<S> TrustedLocal<S> {
+    /// Marks `stream` as trusted. Only use this for streams that never leave
+    /// the machine, or the enclave and its host.
+    pub fn new(stream: S) -> Self {
+        Self { inner: stream }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for TrustedLocal<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for TrustedLocal<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+impl<S> HasPeerAddr for TrustedLocal<S> {
+    fn peer_addr(&self) -> Result<SocketAddr, MpcNetError> {
+        Ok(LOCAL_ADDR)
+    }
+}
+
+impl<S> HasPeerIdentity for TrustedLocal<S> {}
+
+impl<S> IsTransportEncrypted for TrustedLocal<S> {}
+
+#[cfg(unix)]
+impl ProdNet<TrustedLocal<UnixStream>> {
+    /// Listens on the Unix socket at `path` until the other `n_parties - 1`
+    /// parties have connected, as king `id`. A socket left behind at `path`
+    /// by an earlier run is replaced.
+    pub async fn new_king_unix<P: AsRef<Path>>(
+        id: u32,
+        path: P,
+        n_parties: usize,
+        n_streams: usize,
+    ) -> Result<Self, MpcNetError> {
+        remove_stale_socket(path.as_ref())?;
+        let listener = UnixListener::bind(path)?;
+        let mut conns = vec![];
+        while conns.len() + 1 < n_parties {
+            let (stream, _) = listener.accept().await?;
+            conns.push(TrustedLocal::new(stream));
+        }
+
+        ProdNet::new_from_connections(id, id, n_parties, conns, None, n_streams)
+            .await
+    }
+
+    /// Connects to king `king_id` listening at `path`. If `dial_timeout` is
+    /// given, keeps dialing until it has passed, in case the king is not
+    /// listening yet.
+    pub async fn new_peer_unix<P: AsRef<Path>>(
+        id: u32,
+        king_id: u32,
+        path: P,
+        n_parties: usize,
+        dial_timeout: Option<Duration>,
+    ) -> Result<Self, MpcNetError> {
+        let path: PathBuf = path.as_ref().into();
+        let stream =
+            dial(king_id, dial_timeout, || UnixStream::connect(path.clone()))
+                .await?;
+        new_peer(id, king_id, n_parties, stream).await
+    }
+}
+
+#[cfg(feature = "vsock")]
+impl ProdNet<TrustedLocal<VsockStream>> {
+    /// Listens on vsock port `port` until the other `n_parties - 1` parties
+    /// have connected, as king `id`
+    pub async fn new_king_vsock(
+        id: u32,
+        port: u32,
+        n_parties: usize,
+        n_streams: usize,
+    ) -> Result<Self, MpcNetError> {
+        let mut listener = VsockListener::bind(VMADDR_CID_ANY, port)?;
+        let mut conns = vec![];
+        while conns.len() + 1 < n_parties {
+            let (stream, _) = listener.accept().await?;
+            conns.push(TrustedLocal::new(stream));
+        }
+
+        ProdNet::new_from_connections(id, id, n_parties, conns, None, n_streams)
+            .await
+    }
+
+    /// Connects to king `king_id` listening on port `port` of context `cid`.
+    /// If `dial_timeout` is given, keeps dialing until it has passed.
+    pub async fn new_peer_vsock(
+        id: u32,
+        king_id: u32,
+        cid: u32,
+        port: u32,
+        n_parties: usize,
+        dial_timeout: Option<Duration>,
+    ) -> Result<Self, MpcNetError> {
+        let stream =
+            dial(king_id, dial_timeout, || VsockStream::connect(cid, port))
+                .await?;
+        new_peer(id, king_id, n_parties, stream).await
+    }
+}
+
+/// Removes the socket at `path` if there is one, but nothing else
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) -> Result<(), MpcNetError> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            Ok(std::fs::remove_file(path)?)
+        }
+        Ok(_) => Err(MpcNetError::BadInput {
+            err: "The socket path is taken by something other than a socket",
+        }),
+        Err(_) => Ok(()),
+    }
+}
+
+/// Dials until it succeeds, or until `dial_timeout` has passed
+async fn dial<S, F, Fut>(
+    king_id: u32,
+    dial_timeout: Option<Duration>,
+    connect: F,
+) -> Result<S, MpcNetError>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = io::Result<S>>,
+{
+    let dial_timeout = match dial_timeout {
+        Some(dial_timeout) => dial_timeout,
+        None => return Ok(connect().await?),
+    };
+
+    let deadline = tokio::time::Instant::now() + dial_timeout;
+    loop {
+        match tokio::time::timeout_at(deadline, connect()).await {
+            Ok(Ok(stream)) => return Ok(stream),
+            Ok(Err(_)) if tokio::time::Instant::now() < deadline => {
+                tokio::time::sleep(DIAL_RETRY_DELAY).await
+            }
+            Ok(Err(err)) => return Err(err.into()),
+            Err(_) => return Err(MpcNetError::Timeout { party: king_id }),
+        }
+    }
+}
+
+async fn new_peer<S>(
+    id: u32,
+    king_id: u32,
+    n_parties: usize,
+    stream: S,
+) -> Result<ProdNet<TrustedLocal<S>>, MpcNetError>
+where
+    TrustedLocal<S>: IOStream,
+{
+    // The king tells us how many streams to open
+    ProdNet::new_from_connections(
+        id,
+        king_id,
+        n_parties,
+        vec![TrustedLocal::new(stream)],
+        None,
+        MULTIPLEXED_STREAMS,
+    )
+    .await
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::{LocalTestNet, MpcNet, MultiplexedStreamID};
+    use tokio_util::bytes::Bytes;
+
+    #[tokio::test]
+    async fn test_unix_testnet_gathers_and_scatters() {
+        const N_PARTIES: usize = 4;
+        let testnet = LocalTestNet::new_local_unix_testnet(N_PARTIES)
+            .await
+            .unwrap();
+
+        let sums = testnet
+            .simulate_network_round((), |conn, _| async move {
+                let sid = MultiplexedStreamID::ONE;
+                let id = [conn.party_id() as u8];
+                let from_all = conn
+                    .client_send_or_king_receive(&id, sid)
+                    .await
+                    .unwrap()
+                    .map(|ids| {
+                        let sum = ids.iter().map(|id| id[0]).sum::<u8>();
+                        vec![Bytes::from(vec![sum]); N_PARTIES]
+                    });
+                conn.client_receive_or_king_send(from_all, sid)
+                    .await
+                    .unwrap()
+            })
+            .await;
+        assert!(sums.iter().all(|sum| sum[..] == [6]));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_prod_net_over_a_unix_socket() {
+        const N_PARTIES: usize = 3;
+        const KING: u32 = 1;
+        let dir = std::env::temp_dir()
+            .join(format!("mpc-net-unix-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("king.sock");
+
+        let king = tokio::spawn(ProdNet::new_king_unix(
+            KING,
+            path.clone(),
+            N_PARTIES,
+            2,
+        ));
+        let peers = [0, 2].map(|id| {
+            tokio::spawn(ProdNet::new_peer_unix(
+                id,
+                KING,
+                path.clone(),
+                N_PARTIES,
+                Some(Duration::from_secs(5)),
+            ))
+        });
+
+        let mut nets = vec![king.await.unwrap().unwrap()];
+        for peer in peers {
+            nets.push(peer.await.unwrap().unwrap());
+        }
+        // Spawned all at once, since the king waits for the peers
+        let rounds = nets.into_iter().map(|net| {
+            tokio::spawn(async move {
+                assert_eq!(net.n_streams(), 2);
+                let sid = MultiplexedStreamID::ONE;
+                let from_all = net
+                    .client_send_or_king_receive(&[net.party_id() as u8], sid)
+                    .await
+                    .unwrap()
+                    .map(|ids| vec![Bytes::from(ids.concat()); N_PARTIES]);
+                let sum = net
+                    .client_receive_or_king_send(from_all, sid)
+                    .await
+                    .unwrap();
+                // Dropping the king before the peers have read what it sent
+                // would cut them off
+                (net, sum)
+            })
+        });
+        let mut nets = vec![];
+        for round in rounds.collect::<Vec<_>>() {
+            let (net, sum) = round.await.unwrap();
+            assert_eq!(sum[..], [0, 1, 2]);
+            nets.push(net);
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
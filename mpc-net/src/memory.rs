@@ -0,0 +1,731 @@
+//! A network whose parties live in the same process and talk over tokio
+//! channels. There are no sockets, no TLS and no handshake, so a cluster is
+//! ready as soon as it is created.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures::stream::FuturesOrdered;
+use futures::StreamExt;
+use tokio::sync::mpsc::{
+    unbounded_channel, UnboundedReceiver, UnboundedSender,
+};
+use tokio::sync::Mutex as TokioMutex;
+use tokio_util::bytes::Bytes;
+
+use crate::compress::CompressionConfig;
+use crate::multi::{check_n_streams, MULTIPLEXED_STREAMS};
+use crate::{
+    MpcNet, MpcNetError, MultiplexedStreamID, NetStats, SessionTracker,
+    WireCodec,
+};
+
+/// One party of an in-memory cluster
+#[derive(Debug)]
+pub struct MemoryNet {
+    id: u32,
+    n_parties: usize,
+    n_streams: usize,
+    king: u32,
+    wire_codec: WireCodec,
+    session: SessionTracker,
+    /// Per peer, one sender for each stream
+    outboxes: HashMap<u32, Vec<UnboundedSender<Bytes>>>,
+    /// Per peer, one receiver for each stream
+    inboxes: HashMap<u32, Vec<TokioMutex<UnboundedReceiver<Bytes>>>>,
+}
+
+impl MemoryNet {
+    /// Connects `n_parties` parties to each other, ordered by party ID
+    pub fn new_cluster(n_parties: usize) -> Vec<Self> {
+        Self::connect(n_parties, MULTIPLEXED_STREAMS)
+    }
+
+    /// Like [`MemoryNet::new_cluster`], but opens `n_streams` streams between
+    /// each pair of parties
+    pub fn new_cluster_with_streams(
+        n_parties: usize,
+        n_streams: usize,
+    ) -> Result<Vec<Self>, MpcNetError> {
+        check_n_streams(n_streams)?;
+        Ok(Self::connect(n_parties, n_streams))
+    }
+
+    fn connect(n_parties: usize, n_streams: usize) -> Vec<Self> {
+        let mut nodes = (0..n_parties as u32)
+            .map(|id| MemoryNet {
+                id,
+                n_parties,
+                n_streams,
+                king: 0,
+                wire_codec: WireCodec::default(),
+                session: SessionTracker::default(),
+                outboxes: HashMap::new(),
+                inboxes: HashMap::new(),
+            })
+            .collect::<Vec<_>>();
+
+        for from in 0..n_parties {
+            for to in (0..n_parties).filter(|to| *to != from) {
+                let (senders, receivers): (Vec<_>, Vec<_>) = (0..n_streams)
+                    .map(|_| {
+                        let (tx, rx) = unbounded_channel();
+                        (tx, TokioMutex::new(rx))
+                    })
+                    .unzip();
+                nodes[from].outboxes.insert(to as u32, senders);
+                nodes[to].inboxes.insert(from as u32, receivers);
+            }
+        }
+
+        nodes
+    }
+
+    /// Make `king` the king instead of party 0. Every party must agree on it.
+    pub fn set_king(&mut self, king: u32) -> Result<(), MpcNetError> {
+        if king as usize >= self.n_parties {
+            return Err(MpcNetError::BadInput {
+                err: "The king is not one of the parties",
+            });
+        }
+        self.king = king;
+        Ok(())
+    }
+
+    pub fn set_wire_codec(&mut self, codec: WireCodec) {
+        self.wire_codec = codec;
+    }
+
+    /// Set the session ID every frame is tagged with
+    pub fn set_session_id(&mut self, session_id: u64) {
+        self.session.set_session_id(session_id);
+    }
+
+    /// Bound every single send and receive by `timeout`
+    pub fn set_op_timeout(&mut self, timeout: Duration) {
+        self.session.set_op_timeout(Some(timeout));
+    }
+
+    /// Fail every send and receive once `timeout` has passed
+    pub fn set_session_timeout(&mut self, timeout: Duration) {
+        self.session
+            .set_deadline(Some(tokio::time::Instant::now() + timeout));
+    }
+
+    /// Log a trace of every frame sent and received
+    pub fn set_trace(&mut self, trace: bool) {
+        self.session.set_trace(trace);
+    }
+
+    /// Compress payloads sent on every stream with `config`
+    pub fn set_compression(&mut self, config: Option<CompressionConfig>) {
+        self.session.set_default_compression(config);
+    }
+
+    /// Compress payloads sent on `sid` with `config`, whatever is set for the
+    /// other streams
+    pub fn set_stream_compression(
+        &mut self,
+        sid: MultiplexedStreamID,
+        config: Option<CompressionConfig>,
+    ) {
+        self.session.set_compression(sid, config);
+    }
+
+    fn outbox(
+        &self,
+        id: u32,
+        sid: MultiplexedStreamID,
+    ) -> Result<&UnboundedSender<Bytes>, MpcNetError> {
+        self.outboxes
+            .get(&id)
+            .ok_or_else(|| {
+                MpcNetError::Generic(format!("Peer {} not found", id))
+            })?
+            .get(sid.index())
+            .ok_or_else(|| MpcNetError::Generic("Stream is None".to_string()))
+    }
+
+    /// The next frame from `id` on `sid`, still sealed
+    async fn recv_frame(
+        &self,
+        id: u32,
+        sid: MultiplexedStreamID,
+    ) -> Result<Bytes, MpcNetError> {
+        let inbox = self
+            .inboxes
+            .get(&id)
+            .ok_or_else(|| {
+                MpcNetError::Generic(format!("Peer {} not found", id))
+            })?
+            .get(sid.index())
+            .ok_or_else(|| {
+                MpcNetError::Generic("Stream is None".to_string())
+            })?;
+        self.session
+            .bounded_recv(id, sid, async {
+                inbox.lock().await.recv().await.ok_or_else(|| {
+                    MpcNetError::Generic("Stream died".to_string())
+                })
+            })
+            .await
+    }
+
+    async fn send_frame(
+        &self,
+        id: u32,
+        outbox: &UnboundedSender<Bytes>,
+        frame: Bytes,
+    ) -> Result<(), MpcNetError> {
+        self.session
+            .bounded(id, async {
+                outbox.send(frame).map_err(|_| MpcNetError::NotConnected)
+            })
+            .await
+    }
+}
+
+#[async_trait]
+impl MpcNet for MemoryNet {
+    fn n_parties(&self) -> usize {
+        self.n_parties
+    }
+
+    fn party_id(&self) -> u32 {
+        self.id
+    }
+
+    fn king_id(&self) -> u32 {
+        self.king
+    }
+
+    fn is_init(&self) -> bool {
+        true
+    }
+
+    fn n_streams(&self) -> usize {
+        self.n_streams
+    }
+
+    fn wire_codec(&self) -> WireCodec {
+        self.wire_codec
+    }
+
+    fn set_protocol_label(&self, sid: MultiplexedStreamID, label: &str) {
+        self.session.set_label(sid, label)
+    }
+
+    fn stats(&self) -> NetStats {
+        self.session.stats(self.id)
+    }
+
+    async fn recv_from(
+        &self,
+        id: u32,
+        sid: MultiplexedStreamID,
+    ) -> Result<Bytes, MpcNetError> {
+        let frame = self.recv_frame(id, sid).await?;
+        self.session.open(self.id, id, sid, frame)
+    }
+
+    async fn send_to(
+        &self,
+        id: u32,
+        bytes: Bytes,
+        sid: MultiplexedStreamID,
+    ) -> Result<(), MpcNetError> {
+        let outbox = self.outbox(id, sid)?;
+        let frame = self.session.seal(self.id, id, sid, bytes)?;
+        self.send_frame(id, outbox, frame).await
+    }
+
+    async fn recv_sealed(
+        &self,
+        id: u32,
+        sid: MultiplexedStreamID,
+    ) -> Result<Bytes, MpcNetError> {
+        let frame = self.recv_frame(id, sid).await?;
+        self.session.pass_on(self.id, id, sid, frame)
+    }
+
+    async fn send_sealed(
+        &self,
+        id: u32,
+        frame: Bytes,
+        sid: MultiplexedStreamID,
+    ) -> Result<(), MpcNetError> {
+        let outbox = self.outbox(id, sid)?;
+        self.send_frame(id, outbox, frame).await
+    }
+
+    async fn abort(&self, reason: &str) {
+        let abort = match self.session.start_abort(self.id, reason) {
+            Some(abort) => abort,
+            None => return,
+        };
+
+        for (peer, outboxes) in &self.outboxes {
+            if *peer == abort.party {
+                continue;
+            }
+            for (sid, outbox) in outboxes.iter().enumerate() {
+                let sid = MultiplexedStreamID::new(sid as u32);
+                if let Ok(frame) =
+                    self.session.seal_abort(self.id, *peer, sid, &abort)
+                {
+                    // Best effort, the peer may be gone already
+                    let _ = outbox.send(frame);
+                }
+            }
+        }
+    }
+
+    async fn shutdown(&self) -> Result<(), MpcNetError> {
+        if !self.session.start_shutdown(self.id) {
+            return Ok(());
+        }
+
+        for (peer, outboxes) in &self.outboxes {
+            for (sid, outbox) in outboxes.iter().enumerate() {
+                let sid = MultiplexedStreamID::new(sid as u32);
+                let frame = self.session.seal_close(self.id, *peer, sid)?;
+                // Best effort, the peer may be gone already
+                let _ = outbox.send(frame);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// An in-memory cluster, a drop-in for [`crate::LocalTestNet`] in tests and
+/// single-process simulations
+#[derive(Debug)]
+pub struct MemoryTestNet {
+    nodes: Vec<MemoryNet>,
+}
+
+impl MemoryTestNet {
+    pub fn new(n_parties: usize) -> Self {
+        Self {
+            nodes: MemoryNet::new_cluster(n_parties),
+        }
+    }
+
+    /// Like [`MemoryTestNet::new`], but opens `n_streams` streams between
+    /// each pair of parties
+    pub fn new_with_streams(
+        n_parties: usize,
+        n_streams: usize,
+    ) -> Result<Self, MpcNetError> {
+        Ok(Self {
+            nodes: MemoryNet::new_cluster_with_streams(n_parties, n_streams)?,
+        })
+    }
+
+    /// Runs `f` for every party on its own task, and returns the results
+    /// ordered by party ID. `user_data` is cloned for every party.
+    pub async fn simulate_network_round<
+        F: Future<Output = K> + Send,
+        K: Send + Sync + 'static,
+        U: Clone + Send + Sync + 'static,
+    >(
+        self,
+        user_data: U,
+        f: impl Fn(MemoryNet, U) -> F + Send + Sync + Clone + 'static,
+    ) -> Vec<K> {
+        let mut futures = FuturesOrdered::new();
+        for node in self.nodes {
+            let next_f = f.clone();
+            let next_user_data = user_data.clone();
+            futures.push_back(Box::pin(async move {
+                let task = async move { next_f(node, next_user_data).await };
+                tokio::task::spawn(task).await.unwrap()
+            }));
+        }
+        futures.collect().await
+    }
+
+    /// Get the connection for a given party ID
+    pub fn get_connection(&self, party_id: usize) -> &MemoryNet {
+        &self.nodes[party_id]
+    }
+
+    pub fn get_king(&self) -> &MemoryNet {
+        self.get_connection(self.nodes[0].king as usize)
+    }
+
+    /// Make `king` the king of every node, instead of party 0
+    pub fn set_king(&mut self, king: u32) -> Result<(), MpcNetError> {
+        for node in &mut self.nodes {
+            node.set_king(king)?;
+        }
+        Ok(())
+    }
+
+    /// Set the wire codec used by every node
+    pub fn set_wire_codec(&mut self, codec: WireCodec) {
+        for node in &mut self.nodes {
+            node.set_wire_codec(codec);
+        }
+    }
+
+    /// Set the session ID every frame is tagged with
+    pub fn set_session_id(&mut self, session_id: u64) {
+        for node in &mut self.nodes {
+            node.set_session_id(session_id);
+        }
+    }
+
+    /// Bound every single send and receive of every node by `timeout`
+    pub fn set_op_timeout(&mut self, timeout: Duration) {
+        for node in &mut self.nodes {
+            node.set_op_timeout(timeout);
+        }
+    }
+
+    /// Fail every send and receive of every node once `timeout` has passed
+    pub fn set_session_timeout(&mut self, timeout: Duration) {
+        for node in &mut self.nodes {
+            node.set_session_timeout(timeout);
+        }
+    }
+
+    /// Log a trace of every frame sent and received by every node
+    pub fn set_trace(&mut self, trace: bool) {
+        for node in &mut self.nodes {
+            node.set_trace(trace);
+        }
+    }
+
+    /// Compress payloads sent on every stream by every node with `config`
+    pub fn set_compression(&mut self, config: Option<CompressionConfig>) {
+        for node in &mut self.nodes {
+            node.set_compression(config);
+        }
+    }
+
+    /// Compress payloads sent on `sid` by every node with `config`
+    pub fn set_stream_compression(
+        &mut self,
+        sid: MultiplexedStreamID,
+        config: Option<CompressionConfig>,
+    ) {
+        for node in &mut self.nodes {
+            node.set_stream_compression(sid, config);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adversary::{AdversarialNet, Fault};
+    use crate::multi::MAX_STREAMS;
+
+    #[tokio::test]
+    async fn test_king_gathers_and_scatters() {
+        const N_PARTIES: usize = 4;
+        let testnet = MemoryTestNet::new_with_streams(N_PARTIES, 8).unwrap();
+
+        let sums = testnet
+            .simulate_network_round((), |conn, _| async move {
+                let mut sums = vec![];
+                for sid in
+                    (0..conn.n_streams() as u32).map(MultiplexedStreamID::new)
+                {
+                    let id = [conn.party_id() as u8];
+                    let from_all = conn
+                        .client_send_or_king_receive(&id, sid)
+                        .await
+                        .unwrap()
+                        .map(|ids| {
+                            let sum = ids.iter().map(|id| id[0]).sum::<u8>();
+                            vec![Bytes::from(vec![sum]); N_PARTIES]
+                        });
+                    let sum = conn
+                        .client_receive_or_king_send(from_all, sid)
+                        .await
+                        .unwrap();
+                    sums.push(sum[0]);
+                }
+                sums
+            })
+            .await;
+
+        for party_sums in sums {
+            assert_eq!(party_sums, vec![6; 8]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_any_party_can_be_the_king() {
+        const N_PARTIES: usize = 4;
+        let mut testnet = MemoryTestNet::new(N_PARTIES);
+        assert!(testnet.set_king(N_PARTIES as u32).is_err());
+        testnet.set_king(2).unwrap();
+
+        let results = testnet
+            .simulate_network_round((), |conn, _| async move {
+                let sid = MultiplexedStreamID::ZERO;
+                let id = [conn.party_id() as u8];
+                let from_all =
+                    conn.client_send_or_king_receive(&id, sid).await.unwrap();
+                let gathered = from_all.is_some();
+                let from_all = from_all.map(|ids| {
+                    let sum = ids.iter().map(|id| id[0]).sum::<u8>();
+                    vec![Bytes::from(vec![sum]); N_PARTIES]
+                });
+                let sum = conn
+                    .client_receive_or_king_send(from_all, sid)
+                    .await
+                    .unwrap();
+                (conn.party_id(), gathered, sum[0])
+            })
+            .await;
+
+        for (id, gathered, sum) in results {
+            assert_eq!(gathered, id == 2);
+            assert_eq!(sum, 6);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_every_party_talks_to_every_other() {
+        const N_PARTIES: usize = 4;
+        let results = MemoryTestNet::new(N_PARTIES)
+            .simulate_network_round((), |conn, _| async move {
+                let sid = MultiplexedStreamID::ONE;
+                let id = conn.party_id() as u8;
+                let ids = conn.broadcast(&[id], sid).await.unwrap();
+                // Party i sends party j the byte 10 * i + j
+                let bytes_out = (0..N_PARTIES as u8)
+                    .map(|to| Bytes::from(vec![10 * id + to]))
+                    .collect();
+                let from_all = conn.all_to_all(bytes_out, sid).await.unwrap();
+                (ids, from_all)
+            })
+            .await;
+
+        for (me, (ids, from_all)) in results.into_iter().enumerate() {
+            let ids = ids.iter().map(|id| id[0]).collect::<Vec<_>>();
+            assert_eq!(ids, vec![0, 1, 2, 3]);
+            let from_all = from_all.iter().map(|b| b[0]).collect::<Vec<_>>();
+            let expected = (0..N_PARTIES as u8)
+                .map(|from| 10 * from + me as u8)
+                .collect::<Vec<_>>();
+            assert_eq!(from_all, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reliable_broadcast_catches_equivocation() {
+        const N_PARTIES: usize = 4;
+        const SENDER: u32 = 2;
+
+        async fn run(equivocate: bool) -> Vec<Result<Bytes, MpcNetError>> {
+            MemoryTestNet::new(N_PARTIES)
+                .simulate_network_round(
+                    equivocate,
+                    |conn, equivocate| async move {
+                        let sid = MultiplexedStreamID::ZERO;
+                        let mut net = AdversarialNet::new(conn);
+                        if equivocate {
+                            // Only the first message, the echoes are honest
+                            let fault = Fault::equivocate(&[3], |_, _| {
+                                Bytes::from_static(&[2])
+                            });
+                            net = net.with_fault_for(SENDER, fault.nth(0));
+                        }
+                        let bytes = (net.party_id() == SENDER)
+                            .then(|| Bytes::from_static(&[1]));
+                        net.reliable_broadcast(SENDER, bytes, sid).await
+                    },
+                )
+                .await
+        }
+
+        for result in run(false).await {
+            assert_eq!(&result.unwrap()[..], &[1]);
+        }
+        // Everyone else catches it, whether they got the lie or the truth
+        for (id, result) in run(true).await.into_iter().enumerate() {
+            if id as u32 == SENDER {
+                continue;
+            }
+            assert!(
+                matches!(
+                    &result,
+                    Err(MpcNetError::Equivocation { suspects, .. })
+                        if suspects.contains(&SENDER)
+                ) || matches!(result, Err(MpcNetError::Aborted { .. })),
+                "party {}: {:?}",
+                id,
+                result
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_abort_reaches_every_party() {
+        const N_PARTIES: usize = 4;
+        let testnet = MemoryTestNet::new(N_PARTIES);
+
+        let results = testnet
+            .simulate_network_round((), |conn, _| async move {
+                let sid = MultiplexedStreamID::ZERO;
+                if conn.party_id() == 3 {
+                    conn.abort("out of memory").await;
+                    return Ok(None);
+                }
+
+                let from_all =
+                    conn.client_send_or_king_receive(&[1], sid).await?;
+                conn.client_receive_or_king_send(from_all, sid)
+                    .await
+                    .map(Some)
+            })
+            .await;
+
+        for result in results.into_iter().take(3) {
+            assert!(matches!(
+                result,
+                Err(MpcNetError::Aborted { party: 3, .. })
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_traffic_is_counted_per_label() {
+        let stats = MemoryTestNet::new(4)
+            .simulate_network_round((), |conn, _| async move {
+                let sid = MultiplexedStreamID::ONE;
+                conn.set_protocol_label(sid, "d_fft");
+                let from_all = conn
+                    .client_send_or_king_receive(&[0; 100], sid)
+                    .await
+                    .unwrap();
+                conn.client_receive_or_king_send(from_all, sid)
+                    .await
+                    .unwrap();
+                conn.stats()
+            })
+            .await;
+
+        let king = stats[0].by_label()["d_fft"];
+        assert_eq!(king.messages_received, 3);
+        assert_eq!(king.messages_sent, 3);
+        assert!(king.bytes_received > 300);
+        assert!(king.bytes_sent > 300);
+
+        let client = stats[1].by_peer()[&0];
+        assert_eq!(client.messages_sent, 1);
+        assert_eq!(client.messages_received, 1);
+        assert_eq!(stats[1].by_stream().len(), 1);
+    }
+
+    #[cfg(feature = "lz4")]
+    #[tokio::test]
+    async fn test_payloads_are_compressed_once_negotiated() {
+        let mut network = MemoryTestNet::new(4);
+        network.set_compression(Some(CompressionConfig::default()));
+        let stats = network
+            .simulate_network_round((), |conn, _| async move {
+                let sid = MultiplexedStreamID::ONE;
+                let payload = vec![conn.party_id() as u8; 1 << 16];
+                // Clients learn what the king accepts from its first reply,
+                // so only the second round is compressed both ways
+                for _ in 0..2 {
+                    let from_all = conn
+                        .client_send_or_king_receive(&payload, sid)
+                        .await
+                        .unwrap();
+                    if let Some(from_all) = &from_all {
+                        for (id, bytes) in from_all.iter().enumerate() {
+                            assert_eq!(bytes, &vec![id as u8; 1 << 16]);
+                        }
+                    }
+                    let echoed = conn
+                        .client_receive_or_king_send(from_all, sid)
+                        .await
+                        .unwrap();
+                    assert_eq!(echoed, payload);
+                }
+                conn.stats()
+            })
+            .await;
+
+        let king = stats[0].total();
+        assert_eq!(king.compressed_from, 6 << 16);
+        assert!(king.compression_ratio().unwrap() > 10.0);
+        assert!(king.bytes_received < 4 << 16);
+
+        let client = stats[1].total();
+        assert_eq!(client.compressed_from, 1 << 16);
+    }
+
+    #[tokio::test]
+    async fn test_dropped_party_is_detected() {
+        let mut nodes = MemoryNet::new_cluster(2);
+        let peer = nodes.pop().unwrap();
+        let king = nodes.pop().unwrap();
+        drop(peer);
+
+        let sid = MultiplexedStreamID::ZERO;
+        assert!(king.recv_from(1, sid).await.is_err());
+        assert!(matches!(
+            king.send_to(1, Bytes::from_static(&[1]), sid).await,
+            Err(MpcNetError::NotConnected)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_is_told_apart_from_a_crash() {
+        let mut nodes = MemoryNet::new_cluster(3);
+        let crashed = nodes.pop().unwrap();
+        let leaving = nodes.pop().unwrap();
+        let king = nodes.pop().unwrap();
+
+        let sid = MultiplexedStreamID::ZERO;
+        leaving
+            .send_to(0, Bytes::from_static(&[1]), sid)
+            .await
+            .unwrap();
+        leaving.shutdown().await.unwrap();
+        drop(crashed);
+
+        // Frames sent before the shutdown still arrive
+        assert_eq!(&king.recv_from(1, sid).await.unwrap()[..], &[1]);
+        for _ in 0..2 {
+            assert!(matches!(
+                king.recv_from(1, sid).await,
+                Err(MpcNetError::ShutDown { party: 1 })
+            ));
+        }
+        assert!(matches!(
+            king.send_to(1, Bytes::from_static(&[1]), sid).await,
+            Err(MpcNetError::ShutDown { party: 1 })
+        ));
+        assert!(matches!(
+            king.recv_from(2, sid).await,
+            Err(MpcNetError::Generic(_))
+        ));
+
+        // Nothing goes through after shutting down
+        assert!(matches!(
+            leaving.recv_from(0, sid).await,
+            Err(MpcNetError::ShutDown { party: 1 })
+        ));
+    }
+
+    #[test]
+    fn test_stream_count_is_bounded() {
+        for n_streams in [0, MAX_STREAMS + 1] {
+            assert!(matches!(
+                MemoryNet::new_cluster_with_streams(2, n_streams),
+                Err(MpcNetError::BadInput { .. })
+            ));
+        }
+    }
+}
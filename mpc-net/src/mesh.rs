@@ -0,0 +1,560 @@
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::{trace, warn};
+use rustls::RootCertStore;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::TlsStream;
+use tokio_util::bytes::Bytes;
+
+use crate::compress::CompressionConfig;
+use crate::multi::{
+    check_n_streams, multiplex_stream, MpcNetConnection, Peer,
+    MULTIPLEXED_STREAMS,
+};
+use crate::prod::{
+    create_client_mutual_tls_connector, create_server_mutual_tls_acceptor,
+    CertToDer, HasPeerIdentity, PeerIdentity, RustlsCertificate,
+};
+use crate::session::HANDSHAKE_TIMEOUT;
+use crate::{MpcNet, MpcNetError, MultiplexedStreamID, NetStats, WireCodec};
+
+/// How often a party tries to reach a peer that is not listening yet
+const DIAL_ATTEMPTS: usize = 50;
+pub(crate) const DIAL_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Where a party of the mesh listens, and the certificate it authenticates with
+#[derive(Clone, Debug)]
+pub struct MeshParty {
+    pub addr: SocketAddr,
+    pub certificate: rustls::Certificate,
+}
+
+/// Every party of a mesh, including ourselves, numbered from 0 to n-1
+#[derive(Clone, Debug, Default)]
+pub struct MeshRoster {
+    parties: BTreeMap<u32, MeshParty>,
+    ids: HashMap<PeerIdentity, u32>,
+}
+
+impl MeshRoster {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers party `party_id`. Both the ID and the certificate must be
+    /// unused.
+    pub fn insert(
+        &mut self,
+        party_id: u32,
+        addr: SocketAddr,
+        certificate: rustls::Certificate,
+    ) -> Result<(), MpcNetError> {
+        if self.parties.contains_key(&party_id) {
+            return Err(MpcNetError::Generic(format!(
+                "Party ID {party_id} is already in the roster"
+            )));
+        }
+
+        let identity = PeerIdentity::from_certificate(&certificate);
+        if self.ids.contains_key(&identity) {
+            return Err(MpcNetError::Generic(format!(
+                "Identity {identity} is already in the roster"
+            )));
+        }
+
+        self.ids.insert(identity, party_id);
+        self.parties
+            .insert(party_id, MeshParty { addr, certificate });
+        Ok(())
+    }
+
+    pub fn get(&self, party_id: u32) -> Option<&MeshParty> {
+        self.parties.get(&party_id)
+    }
+
+    /// The party ID assigned to `identity`, if any
+    pub fn party_id(&self, identity: &PeerIdentity) -> Option<u32> {
+        self.ids.get(identity).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.parties.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.parties.is_empty()
+    }
+
+    /// A root store trusting every certificate in the roster
+    pub fn root_cert_store(&self) -> Result<RootCertStore, MpcNetError> {
+        let mut store = RootCertStore::empty();
+        for party in self.parties.values() {
+            store.add(&party.certificate)?;
+        }
+        Ok(store)
+    }
+}
+
+/// A production network where every pair of parties is connected over
+/// mutual TLS, so that any party can talk to any other
+pub struct ProdMeshNet {
+    connections: MpcNetConnection<TlsStream<TcpStream>>,
+}
+
+impl ProdMeshNet {
+    /// Listens on our address in the roster and connects to every other
+    /// party. Returns once all parties are connected.
+    pub async fn new<R: CertToDer>(
+        my_id: u32,
+        identity: R,
+        roster: &MeshRoster,
+    ) -> Result<Self, MpcNetError> {
+        Self::new_with_streams(my_id, identity, roster, MULTIPLEXED_STREAMS)
+            .await
+    }
+
+    /// Like [`ProdMeshNet::new`], but opens `n_streams` multiplexed streams
+    /// to every peer. All parties must agree on `n_streams`.
+    pub async fn new_with_streams<R: CertToDer>(
+        my_id: u32,
+        identity: R,
+        roster: &MeshRoster,
+        n_streams: usize,
+    ) -> Result<Self, MpcNetError> {
+        let n_parties = roster.len();
+        if (0..n_parties as u32).any(|id| roster.get(id).is_none()) {
+            return Err(MpcNetError::BadInput {
+                err: "Roster must list parties 0 to n-1",
+            });
+        }
+        let me = roster.get(my_id).ok_or(MpcNetError::BadInput {
+            err: "We are not in the roster",
+        })?;
+        check_n_streams(n_streams)?;
+
+        let identity = RustlsCertificate {
+            cert: rustls::Certificate(identity.serialize_certificate_to_der()?),
+            private_key: rustls::PrivateKey(
+                identity.serialize_private_key_to_der()?,
+            ),
+        };
+        let acceptor = create_server_mutual_tls_acceptor(
+            roster.root_cert_store()?,
+            identity.clone(),
+        )?;
+        let connector = create_client_mutual_tls_connector(
+            roster.root_cert_store()?,
+            identity,
+        )?;
+        let listener = TcpListener::bind(me.addr).await?;
+
+        // Like LocalTestNet, we dial the parties with higher IDs and accept
+        // connections from the ones with lower IDs
+        let server_task = async {
+            let mut peers: Vec<(Peer<_>, _)> = Vec::new();
+            while peers.len() < my_id as usize {
+                let (stream, peer_addr) = listener.accept().await?;
+                let handshake = async {
+                    let mut stream =
+                        TlsStream::Server(acceptor.accept(stream).await?);
+
+                    let announced_id = stream.read_u32().await?;
+                    let peer_id = stream
+                        .peer_identity()
+                        .and_then(|identity| roster.party_id(&identity));
+                    if peer_id != Some(announced_id) || announced_id >= my_id {
+                        return Err(MpcNetError::Protocol {
+                            err: format!(
+                                "Peer announced ID {announced_id} but authenticated as {peer_id:?}"
+                            ),
+                            party: announced_id,
+                        });
+                    }
+                    Ok::<_, MpcNetError>((stream, announced_id))
+                };
+
+                // A party that fails to connect must not keep the others out
+                let (stream, announced_id) =
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake)
+                        .await
+                    {
+                        Ok(Ok(accepted)) => accepted,
+                        Ok(Err(err)) => {
+                            warn!(
+                            "Rejected a connection from {peer_addr}: {err:?}"
+                        );
+                            continue;
+                        }
+                        Err(_) => {
+                            warn!("Handshake with {peer_addr} timed out");
+                            continue;
+                        }
+                    };
+                if peers.iter().any(|(peer, _)| peer.id == announced_id) {
+                    warn!("Party {announced_id} connected twice");
+                    continue;
+                }
+
+                let (muxed, worker) =
+                    multiplex_stream(n_streams, true, stream).await?;
+                let peer = Peer {
+                    id: announced_id,
+                    listen_addr: peer_addr,
+                    streams: Some(muxed),
+                };
+                peers.push((peer, worker));
+                trace!("{my_id} connected to peer {announced_id}");
+            }
+            Ok::<_, MpcNetError>(peers)
+        };
+
+        let client_task = async {
+            let mut peers = Vec::new();
+            for peer_id in my_id + 1..n_parties as u32 {
+                let party = roster.get(peer_id).expect("Checked above");
+                let stream = dial(party.addr).await?;
+                let server_name =
+                    rustls::ServerName::IpAddress(party.addr.ip());
+                let mut stream = TlsStream::Client(
+                    connector.connect(server_name, stream).await?,
+                );
+
+                // Any certificate in the roster passes the TLS handshake, so
+                // check that we reached the party we dialed
+                let expected =
+                    PeerIdentity::from_certificate(&party.certificate);
+                if stream.peer_identity() != Some(expected) {
+                    return Err(MpcNetError::Protocol {
+                        err: "Authenticated as a different party".to_string(),
+                        party: peer_id,
+                    });
+                }
+
+                stream.write_u32(my_id).await?;
+                let (muxed, worker) =
+                    multiplex_stream(n_streams, false, stream).await?;
+                let peer = Peer {
+                    id: peer_id,
+                    listen_addr: party.addr,
+                    streams: Some(muxed),
+                };
+                peers.push((peer, worker));
+                trace!("{my_id} connected to peer {peer_id}");
+            }
+            Ok::<_, MpcNetError>(peers)
+        };
+
+        let (accepted, dialed) = tokio::try_join!(server_task, client_task)?;
+
+        let mut connections = MpcNetConnection {
+            id: my_id,
+            listener: None,
+            peers: Default::default(),
+            n_parties,
+            n_streams,
+            king: 0,
+            wire_codec: WireCodec::default(),
+            session: Default::default(),
+            workers: Default::default(),
+        };
+        for (peer, worker) in accepted.into_iter().chain(dialed) {
+            connections.workers.get_mut().push(worker);
+            if connections.peers.insert(peer.id, peer).is_some() {
+                return Err(MpcNetError::Generic(
+                    "Connected to the same peer twice".to_string(),
+                ));
+            }
+        }
+
+        // Do a round with the king, to be sure everyone is ready
+        let sid = MultiplexedStreamID::ZERO;
+        let from_all = connections
+            .client_send_or_king_receive(&[my_id as u8], sid)
+            .await?;
+        connections
+            .client_receive_or_king_send(from_all, sid)
+            .await?;
+
+        Ok(Self { connections })
+    }
+
+    /// Make `king` the king instead of party 0. Every party is connected to
+    /// every other, so any of them can be, but all must agree on which.
+    pub fn set_king(&mut self, king: u32) -> Result<(), MpcNetError> {
+        if king as usize >= self.connections.n_parties {
+            return Err(MpcNetError::BadInput {
+                err: "The king is not one of the parties",
+            });
+        }
+        self.connections.king = king;
+        Ok(())
+    }
+
+    /// Set the codec used to serialize values sent over this network
+    pub fn set_wire_codec(&mut self, codec: WireCodec) {
+        self.connections.wire_codec = codec;
+    }
+
+    /// Set the session ID every frame is tagged with. All parties must agree
+    /// on it.
+    pub fn set_session_id(&mut self, session_id: u64) {
+        self.connections.session.set_session_id(session_id);
+    }
+
+    /// Log a trace of every frame sent and received
+    pub fn set_trace(&mut self, trace: bool) {
+        self.connections.session.set_trace(trace);
+    }
+
+    /// Compress payloads sent on every stream with `config`
+    pub fn set_compression(&mut self, config: Option<CompressionConfig>) {
+        self.connections.session.set_default_compression(config);
+    }
+
+    /// Compress payloads sent on `sid` with `config`, whatever is set for the
+    /// other streams
+    pub fn set_stream_compression(
+        &mut self,
+        sid: MultiplexedStreamID,
+        config: Option<CompressionConfig>,
+    ) {
+        self.connections.session.set_compression(sid, config);
+    }
+
+    /// Bound every single send and receive by `timeout`
+    pub fn set_op_timeout(&mut self, timeout: Duration) {
+        self.connections.session.set_op_timeout(Some(timeout));
+    }
+
+    /// Fail every send and receive once `timeout` has passed
+    pub fn set_session_timeout(&mut self, timeout: Duration) {
+        let deadline = tokio::time::Instant::now() + timeout;
+        self.connections.session.set_deadline(Some(deadline));
+    }
+}
+
+/// Connects to `addr`, waiting for it to start listening
+async fn dial(addr: SocketAddr) -> Result<TcpStream, MpcNetError> {
+    let mut attempt = 1;
+    loop {
+        match TcpStream::connect(addr).await {
+            Ok(stream) => return Ok(stream),
+            Err(_) if attempt < DIAL_ATTEMPTS => {
+                attempt += 1;
+                tokio::time::sleep(DIAL_RETRY_DELAY).await;
+            }
+            Err(err) => {
+                return Err(MpcNetError::Generic(format!(
+                    "Error connecting to {addr}: {err:?}"
+                )))
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl MpcNet for ProdMeshNet {
+    fn n_parties(&self) -> usize {
+        self.connections.n_parties()
+    }
+
+    fn party_id(&self) -> u32 {
+        self.connections.party_id()
+    }
+
+    fn king_id(&self) -> u32 {
+        self.connections.king_id()
+    }
+
+    fn is_init(&self) -> bool {
+        self.connections.is_init()
+    }
+
+    fn n_streams(&self) -> usize {
+        self.connections.n_streams()
+    }
+
+    fn wire_codec(&self) -> WireCodec {
+        self.connections.wire_codec()
+    }
+
+    fn set_protocol_label(&self, sid: MultiplexedStreamID, label: &str) {
+        self.connections.set_protocol_label(sid, label)
+    }
+
+    fn stats(&self) -> NetStats {
+        self.connections.stats()
+    }
+
+    async fn abort(&self, reason: &str) {
+        self.connections.abort(reason).await
+    }
+
+    async fn shutdown(&self) -> Result<(), MpcNetError> {
+        self.connections.shutdown().await
+    }
+
+    async fn recv_from(
+        &self,
+        id: u32,
+        sid: MultiplexedStreamID,
+    ) -> Result<Bytes, MpcNetError> {
+        self.connections.recv_from(id, sid).await
+    }
+
+    async fn send_to(
+        &self,
+        id: u32,
+        bytes: Bytes,
+        sid: MultiplexedStreamID,
+    ) -> Result<(), MpcNetError> {
+        self.connections.send_to(id, bytes, sid).await
+    }
+
+    async fn recv_sealed(
+        &self,
+        id: u32,
+        sid: MultiplexedStreamID,
+    ) -> Result<Bytes, MpcNetError> {
+        self.connections.recv_sealed(id, sid).await
+    }
+
+    async fn send_sealed(
+        &self,
+        id: u32,
+        frame: Bytes,
+        sid: MultiplexedStreamID,
+    ) -> Result<(), MpcNetError> {
+        self.connections.send_sealed(id, frame, sid).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream::FuturesOrdered;
+    use futures::StreamExt;
+
+    fn generate_identity() -> RustlsCertificate {
+        let identity =
+            rcgen::generate_simple_self_signed(vec!["127.0.0.1".to_string()])
+                .unwrap();
+        RustlsCertificate {
+            cert: rustls::Certificate(identity.serialize_der().unwrap()),
+            private_key: rustls::PrivateKey(
+                identity.serialize_private_key_der(),
+            ),
+        }
+    }
+
+    async fn free_addr() -> SocketAddr {
+        TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+    }
+
+    async fn init_mesh(
+        identities: Vec<RustlsCertificate>,
+        roster: MeshRoster,
+    ) -> Vec<Result<ProdMeshNet, MpcNetError>> {
+        identities
+            .into_iter()
+            .enumerate()
+            .map(|(id, identity)| {
+                let roster = roster.clone();
+                tokio::spawn(async move {
+                    ProdMeshNet::new(id as u32, identity, &roster).await
+                })
+            })
+            .collect::<FuturesOrdered<_>>()
+            .map(|result| result.unwrap())
+            .collect()
+            .await
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_every_pair_can_talk() {
+        const N_PARTIES: usize = 4;
+        let mut roster = MeshRoster::new();
+        let mut identities = Vec::new();
+        for id in 0..N_PARTIES as u32 {
+            let identity = generate_identity();
+            roster
+                .insert(id, free_addr().await, identity.cert.clone())
+                .unwrap();
+            identities.push(identity);
+        }
+
+        let nets = init_mesh(identities, roster).await;
+        nets.into_iter()
+            .map(|net| {
+                tokio::spawn(async move {
+                    let net = net.unwrap();
+                    let my_id = net.party_id();
+                    let sid = MultiplexedStreamID::TWO;
+                    for peer in (0..N_PARTIES as u32).filter(|p| *p != my_id) {
+                        net.send_to(peer, vec![my_id as u8].into(), sid)
+                            .await
+                            .unwrap();
+                    }
+                    for peer in (0..N_PARTIES as u32).filter(|p| *p != my_id) {
+                        let bytes = net.recv_from(peer, sid).await.unwrap();
+                        assert_eq!(&bytes[..], &[peer as u8]);
+                    }
+                })
+            })
+            .collect::<FuturesOrdered<_>>()
+            .for_each(|result| async move { result.unwrap() })
+            .await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_party_with_foreign_certificate_is_rejected() {
+        let mut roster = MeshRoster::new();
+        let mut identities = Vec::new();
+        for id in 0..2 {
+            let identity = generate_identity();
+            roster
+                .insert(id, free_addr().await, identity.cert.clone())
+                .unwrap();
+            identities.push(identity);
+        }
+
+        let party_1 = {
+            let identity = identities.pop().unwrap();
+            let roster = roster.clone();
+            tokio::spawn(
+                async move { ProdMeshNet::new(1, identity, &roster).await },
+            )
+        };
+
+        // Party 0 first presents a certificate that is not in the roster
+        let foreign = ProdMeshNet::new(0, generate_identity(), &roster).await;
+        assert!(foreign.is_err());
+
+        // Party 1 drops that connection and keeps waiting for party 0
+        let identity = identities.pop().unwrap();
+        ProdMeshNet::new(0, identity, &roster).await.unwrap();
+        party_1.await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_roster_rejects_duplicates() {
+        let addr = "127.0.0.1:1".parse().unwrap();
+        let first = generate_identity();
+        let second = generate_identity();
+        let mut roster = MeshRoster::new();
+        roster.insert(0, addr, first.cert.clone()).unwrap();
+
+        assert!(roster.insert(0, addr, second.cert.clone()).is_err());
+        assert!(roster.insert(1, addr, first.cert.clone()).is_err());
+        roster.insert(1, addr, second.cert).unwrap();
+        assert_eq!(roster.len(), 2);
+    }
+}
@@ -2,20 +2,32 @@ use std::collections::HashMap;
 use std::fmt::{Debug, Formatter};
 use std::future::Future;
 use std::net::SocketAddr;
+use std::pin::Pin;
 use std::sync::Arc;
+use std::task::{Context, Poll};
 use std::time::Duration;
-use serde::de;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+#[cfg(unix)]
+use tokio::net::UnixStream;
 use tokio::net::{TcpListener, TcpStream};
 
-use crate::{MpcNetError, MultiplexedStreamID};
+use crate::chunk::{recv_chunked, send_chunked};
+use crate::compress::CompressionConfig;
+#[cfg(unix)]
+use crate::local::{TrustedLocal, LOCAL_ADDR};
+use crate::session::{ABORT_TIMEOUT, SHUTDOWN_TIMEOUT};
+use crate::{
+    MpcNetError, MultiplexedStreamID, NetStats, SessionTracker, WireCodec,
+};
 use async_smux::{MuxBuilder, MuxStream};
 use async_trait::async_trait;
+use futures::future::join_all;
 use futures::stream::{FuturesOrdered, FuturesUnordered};
 use futures::{SinkExt, StreamExt, TryStreamExt};
 use log::trace;
 use parking_lot::Mutex;
 use tokio::sync::Mutex as TokioMutex;
+use tokio::task::JoinHandle;
 use tokio_util::bytes::Bytes;
 use tokio_util::codec::{Framed, LengthDelimitedCodec};
 
@@ -58,43 +70,106 @@ impl<IO: AsyncRead + AsyncWrite + Unpin> Clone for Peer<IO> {
     }
 }
 
-pub type WrappedMuxStream<T> = Framed<MuxStream<T>, LengthDelimitedCodec>;
+pub type WrappedMuxStream<T> =
+    Framed<FlushedMuxStream<T>, LengthDelimitedCodec>;
+
+/// A [`MuxStream`] whose flush is done once the peer closed the stream. The
+/// mux fails a flush as soon as the peer's close arrives, even if what was
+/// written went out before, so our last message to a peer that reads it and
+/// leaves right away would fail at random. Like on a socket, writing after
+/// the peer closed still fails.
+pub struct FlushedMuxStream<T: AsyncRead + AsyncWrite + Unpin>(MuxStream<T>);
+
+impl<T: AsyncRead + AsyncWrite + Unpin> AsyncRead for FlushedMuxStream<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> AsyncWrite for FlushedMuxStream<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match Pin::new(&mut self.0).poll_flush(cx) {
+            Poll::Ready(Err(_)) if self.0.is_closed() => Poll::Ready(Ok(())),
+            flushed => flushed,
+        }
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+/// The number of streams opened per connection unless configured otherwise
 pub const MULTIPLEXED_STREAMS: usize = 3;
+/// The most streams a connection may be asked to open
+pub const MAX_STREAMS: usize = 256;
 
-/// Should be called immediately after making a connection to a peer.
+pub(crate) fn check_n_streams(n_streams: usize) -> Result<(), MpcNetError> {
+    if !(1..=MAX_STREAMS).contains(&n_streams) {
+        return Err(MpcNetError::BadInput {
+            err: "Must open between 1 and MAX_STREAMS streams per connection",
+        });
+    }
+    Ok(())
+}
+
+/// The multiplexed streams over a connection
+pub type MuxStreams<T> = Vec<TokioMutex<WrappedMuxStream<T>>>;
+
+/// Should be called immediately after making a connection to a peer. Returns
+/// the streams, and the task that drives the connection underneath them.
 pub async fn multiplex_stream<
     T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
 >(
     channels: usize,
     is_server: bool,
     stream: T,
-) -> Result<Vec<TokioMutex<WrappedMuxStream<T>>>, MpcNetError> {
+) -> Result<(MuxStreams<T>, JoinHandle<()>), MpcNetError> {
     if is_server {
         let (_connector, mut acceptor, worker) =
             MuxBuilder::server().with_connection(stream).build();
-        tokio::spawn(worker);
+        let worker = tokio::spawn(async move {
+            let _ = worker.await;
+        });
         let mut ret = Vec::new();
         for _ in 0..channels {
-            ret.push(TokioMutex::new(wrap_stream(
-                acceptor.accept().await.ok_or_else(|| {
-                    MpcNetError::Generic(
-                        "Error accepting connection".to_string(),
-                    )
-                })?,
-            )));
+            let stream = acceptor.accept().await.ok_or_else(|| {
+                MpcNetError::Generic("Error accepting connection".to_string())
+            })?;
+            ret.push(TokioMutex::new(wrap_stream(FlushedMuxStream(stream))));
         }
 
-        Ok(ret)
+        Ok((ret, worker))
     } else {
         let (connector, _acceptor, worker) =
             MuxBuilder::client().with_connection(stream).build();
-        tokio::spawn(worker);
+        let worker = tokio::spawn(async move {
+            let _ = worker.await;
+        });
         let mut ret = Vec::new();
         for _ in 0..channels {
-            ret.push(TokioMutex::new(wrap_stream(connector.connect()?)));
+            let stream = FlushedMuxStream(connector.connect()?);
+            ret.push(TokioMutex::new(wrap_stream(stream)));
         }
 
-        Ok(ret)
+        Ok((ret, worker))
     }
 }
 
@@ -104,11 +179,19 @@ pub struct MpcNetConnection<IO: AsyncRead + AsyncWrite + Unpin> {
     pub listener: Option<TcpListener>,
     pub peers: HashMap<u32, Peer<IO>>,
     pub n_parties: usize,
+    pub n_streams: usize,
+    /// The party the king-centric helpers gather at
+    pub king: u32,
+    pub wire_codec: WireCodec,
+    pub session: SessionTracker,
+    /// The tasks driving the connections to the peers
+    pub workers: Mutex<Vec<JoinHandle<()>>>,
 }
 
 impl MpcNetConnection<TcpStream> {
     async fn connect_to_all(&mut self) -> Result<(), MpcNetError> {
         let n_minus_1 = self.n_parties() - 1;
+        let n_streams = self.n_streams;
         let my_id = self.id;
 
         let peer_addrs = self
@@ -132,6 +215,7 @@ impl MpcNetConnection<TcpStream> {
         let inbound_connections_i_will_make = my_id as usize;
 
         let server_task = async move {
+            let mut workers = Vec::new();
             for _ in 0..inbound_connections_i_will_make {
                 let (mut stream, _peer_addr) =
                     listener.accept().await.map_err(|err| {
@@ -142,20 +226,21 @@ impl MpcNetConnection<TcpStream> {
 
                 let peer_id = stream.read_u32().await?;
                 // Now, multiplex the stream
-                let muxed =
-                    multiplex_stream(MULTIPLEXED_STREAMS, true, stream).await?;
+                let (muxed, worker) =
+                    multiplex_stream(n_streams, true, stream).await?;
                 new_peers_server.lock().get_mut(&peer_id).unwrap().streams =
                     Some(muxed);
+                workers.push(worker);
                 trace!("{my_id} connected to peer {peer_id}")
             }
 
-            Ok::<_, MpcNetError>(())
+            Ok::<_, MpcNetError>(workers)
         };
 
         let client_task = async move {
-            // Wait some time for the server tasks to boot up
-            tokio::time::sleep(Duration::from_millis(200)).await;
-            // Listeners are all active, now, connect us to n-1 peers
+            let mut workers = Vec::new();
+            // Every listener was bound before any party got here, so the
+            // connections queue up even if the peer is not accepting yet
             for conns_made in 0..outbound_connections_i_will_make {
                 // If I am 0, I will connect to 1 and 2
                 // If I am 1, I will connect to 2
@@ -171,67 +256,56 @@ impl MpcNetConnection<TcpStream> {
                     })?;
                 stream.write_u32(my_id).await.unwrap();
 
-                let muxed =
-                    multiplex_stream(MULTIPLEXED_STREAMS, false, stream)
-                        .await?;
+                let (muxed, worker) =
+                    multiplex_stream(n_streams, false, stream).await?;
                 new_peers_client
                     .lock()
                     .get_mut(&next_peer_to_connect_to)
                     .unwrap()
                     .streams = Some(muxed);
+                workers.push(worker);
                 trace!("{my_id} connected to peer {next_peer_to_connect_to}")
             }
 
-            Ok::<_, MpcNetError>(())
+            Ok::<_, MpcNetError>(workers)
         };
 
         trace!("Awaiting on client and server task to finish");
 
-        tokio::try_join!(server_task, client_task)?;
+        let (accepted, dialed) = tokio::try_join!(server_task, client_task)?;
+        self.workers
+            .get_mut()
+            .extend(accepted.into_iter().chain(dialed));
         self.peers = Arc::try_unwrap(new_peers).unwrap().into_inner();
 
         trace!("All connected");
-
-        // Every party will use this channel for genesis
-        let genesis_round_channel = MultiplexedStreamID::Zero;
-
-        // Do a round with the king, to be sure everyone is ready
-        let from_all = self
-            .client_send_or_king_receive(
-                &[self.id as u8] as &[u8],
-                genesis_round_channel,
-            )
-            .await?;
-        self.client_receive_or_king_send(from_all, genesis_round_channel)
-            .await?;
-
-        for peer in &self.peers {
-            if peer.0 == &self.id {
-                continue;
-            }
-
-            if peer.1.streams.is_none() {
-                return Err(MpcNetError::Generic(format!(
-                    "Peer {} has no stream",
-                    peer.0
-                )));
-            }
-        }
-
-        trace!("Done with recv_from_king");
-        Ok(())
+        self.synchronize().await
     }
 }
 
+/// Every party of a cluster in one process, over loopback TCP unless built
+/// with [`LocalTestNet::new_local_unix_testnet`]
 #[derive(Debug)]
-pub struct LocalTestNet {
-    nodes: HashMap<usize, MpcNetConnection<TcpStream>>,
+pub struct LocalTestNet<IO: AsyncRead + AsyncWrite + Unpin = TcpStream> {
+    nodes: HashMap<usize, MpcNetConnection<IO>>,
 }
 
 impl LocalTestNet {
     pub async fn new_local_testnet(
         n_parties: usize,
     ) -> Result<Self, MpcNetError> {
+        Self::new_local_testnet_with_streams(n_parties, MULTIPLEXED_STREAMS)
+            .await
+    }
+
+    /// Like [`LocalTestNet::new_local_testnet`], but opens `n_streams`
+    /// multiplexed streams between each pair of parties
+    pub async fn new_local_testnet_with_streams(
+        n_parties: usize,
+        n_streams: usize,
+    ) -> Result<Self, MpcNetError> {
+        check_n_streams(n_streams)?;
+
         // Step 1: Generate all the Listeners for each node
         let mut listeners = HashMap::new();
         let mut listen_addrs = HashMap::new();
@@ -249,6 +323,11 @@ impl LocalTestNet {
                 listener: Some(my_listener),
                 peers: Default::default(),
                 n_parties,
+                n_streams,
+                king: 0,
+                wire_codec: WireCodec::default(),
+                session: SessionTracker::default(),
+                workers: Default::default(),
             };
             for peer_id in 0..n_parties {
                 // NOTE: this is the listen addr
@@ -280,7 +359,88 @@ impl LocalTestNet {
 
         Ok(Self { nodes })
     }
+}
+
+#[cfg(unix)]
+impl LocalTestNet<TrustedLocal<UnixStream>> {
+    /// Like [`LocalTestNet::new_local_testnet`], but connects every pair of
+    /// parties with a Unix socket pair instead of loopback TCP
+    pub async fn new_local_unix_testnet(
+        n_parties: usize,
+    ) -> Result<Self, MpcNetError> {
+        Self::new_local_unix_testnet_with_streams(
+            n_parties,
+            MULTIPLEXED_STREAMS,
+        )
+        .await
+    }
+
+    /// Like [`LocalTestNet::new_local_unix_testnet`], but opens `n_streams`
+    /// multiplexed streams between each pair of parties
+    pub async fn new_local_unix_testnet_with_streams(
+        n_parties: usize,
+        n_streams: usize,
+    ) -> Result<Self, MpcNetError> {
+        check_n_streams(n_streams)?;
+
+        let mut sockets = (0..n_parties).map(|_| vec![]).collect::<Vec<_>>();
+        for i in 0..n_parties as u32 {
+            for j in (i + 1)..n_parties as u32 {
+                let (a, b) = UnixStream::pair()?;
+                sockets[i as usize].push((j, TrustedLocal::new(a)));
+                sockets[j as usize].push((i, TrustedLocal::new(b)));
+            }
+        }
+
+        let futures = FuturesUnordered::new();
+        for (my_id, sockets) in sockets.into_iter().enumerate() {
+            futures.push(Box::pin(async move {
+                let mut connections = MpcNetConnection {
+                    id: my_id as u32,
+                    listener: None,
+                    peers: Default::default(),
+                    n_parties,
+                    n_streams,
+                    king: 0,
+                    wire_codec: WireCodec::default(),
+                    session: SessionTracker::default(),
+                    workers: Default::default(),
+                };
+                connections.peers.insert(
+                    my_id as u32,
+                    Peer {
+                        id: my_id as u32,
+                        listen_addr: LOCAL_ADDR,
+                        streams: None,
+                    },
+                );
+                // As over TCP, the party with the lower ID is the client
+                for (peer_id, socket) in sockets {
+                    let is_server = peer_id < my_id as u32;
+                    let (muxed, worker) =
+                        multiplex_stream(n_streams, is_server, socket).await?;
+                    connections.workers.get_mut().push(worker);
+                    connections.peers.insert(
+                        peer_id,
+                        Peer {
+                            id: peer_id,
+                            listen_addr: LOCAL_ADDR,
+                            streams: Some(muxed),
+                        },
+                    );
+                }
+                connections.synchronize().await?;
+                Ok::<_, MpcNetError>((my_id, connections))
+            }));
+        }
+
+        let nodes = futures.try_collect().await?;
+
+        Ok(Self { nodes })
+    }
+}
 
+impl<IO: AsyncRead + AsyncWrite + Unpin + Send + 'static> LocalTestNet<IO> {
     /// For each node, run a function (a Future) provided by the parameter that accepts the node's Connection.
     /// Then, run all these futures in a FuturesOrdered.
     ///
@@ -293,15 +453,11 @@ impl LocalTestNet {
     >(
         self,
         user_data: U,
-        f: impl Fn(MpcNetConnection<TcpStream>, U) -> F
-            + Send
-            + Sync
-            + Clone
-            + 'static,
+        f: impl Fn(MpcNetConnection<IO>, U) -> F + Send + Sync + Clone + 'static,
     ) -> Vec<K> {
         let mut futures = FuturesOrdered::new();
         let mut sorted_nodes = self.nodes.into_iter().collect::<Vec<_>>();
-        sorted_nodes.sort_by(|a, b| a.0.cmp(&b.0));
+        sorted_nodes.sort_by_key(|node| node.0);
         for (_, connections) in sorted_nodes {
             let next_f = f.clone();
             let next_user_data = user_data.clone();
@@ -315,16 +471,195 @@ impl LocalTestNet {
         futures.collect().await
     }
 
+    /// The connection of every node, ordered by party ID
+    pub(crate) fn into_nodes(self) -> Vec<MpcNetConnection<IO>> {
+        let mut nodes = self.nodes.into_iter().collect::<Vec<_>>();
+        nodes.sort_by_key(|(id, _)| *id);
+        nodes.into_iter().map(|(_, node)| node).collect()
+    }
+
     /// Get the connection for a given party ID
-    pub fn get_connection(
-        &self,
-        party_id: usize,
-    ) -> &MpcNetConnection<TcpStream> {
+    pub fn get_connection(&self, party_id: usize) -> &MpcNetConnection<IO> {
         self.nodes.get(&party_id).unwrap()
     }
 
-    pub fn get_king(&self) -> &MpcNetConnection<TcpStream> {
-        self.get_connection(0)
+    pub fn get_king(&self) -> &MpcNetConnection<IO> {
+        let king = self.get_connection(0).king;
+        self.get_connection(king as usize)
+    }
+
+    /// Make `king` the king of every node, instead of party 0
+    pub fn set_king(&mut self, king: u32) -> Result<(), MpcNetError> {
+        if king as usize >= self.nodes.len() {
+            return Err(MpcNetError::BadInput {
+                err: "The king is not one of the parties",
+            });
+        }
+        for node in self.nodes.values_mut() {
+            node.king = king;
+        }
+        Ok(())
+    }
+
+    /// Set the wire codec used by every node
+    pub fn set_wire_codec(&mut self, codec: WireCodec) {
+        for node in self.nodes.values_mut() {
+            node.wire_codec = codec;
+        }
+    }
+
+    /// Set the session ID every frame is tagged with
+    pub fn set_session_id(&mut self, session_id: u64) {
+        for node in self.nodes.values_mut() {
+            node.session.set_session_id(session_id);
+        }
+    }
+
+    /// Bound every single send and receive of every node by `timeout`
+    pub fn set_op_timeout(&mut self, timeout: Duration) {
+        for node in self.nodes.values_mut() {
+            node.session.set_op_timeout(Some(timeout));
+        }
+    }
+
+    /// Fail every send and receive of every node once `timeout` has passed
+    pub fn set_session_timeout(&mut self, timeout: Duration) {
+        let deadline = tokio::time::Instant::now() + timeout;
+        for node in self.nodes.values_mut() {
+            node.session.set_deadline(Some(deadline));
+        }
+    }
+
+    /// Log a trace of every frame sent and received by every node
+    pub fn set_trace(&mut self, trace: bool) {
+        for node in self.nodes.values_mut() {
+            node.session.set_trace(trace);
+        }
+    }
+
+    /// Compress payloads sent on every stream by every node with `config`
+    pub fn set_compression(&mut self, config: Option<CompressionConfig>) {
+        for node in self.nodes.values_mut() {
+            node.session.set_default_compression(config);
+        }
+    }
+
+    /// Compress payloads sent on `sid` by every node with `config`
+    pub fn set_stream_compression(
+        &mut self,
+        sid: MultiplexedStreamID,
+        config: Option<CompressionConfig>,
+    ) {
+        for node in self.nodes.values_mut() {
+            node.session.set_compression(sid, config);
+        }
+    }
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin + Send> MpcNetConnection<IO> {
+    /// Runs a round with the king once every connection is made, so that
+    /// no party starts before the others are ready
+    async fn synchronize(&self) -> Result<(), MpcNetError> {
+        // Every party will use this channel for genesis
+        let genesis_round_channel = MultiplexedStreamID::ZERO;
+
+        // Do a round with the king, to be sure everyone is ready
+        let from_all = self
+            .client_send_or_king_receive(
+                &[self.id as u8] as &[u8],
+                genesis_round_channel,
+            )
+            .await?;
+        self.client_receive_or_king_send(from_all, genesis_round_channel)
+            .await?;
+
+        for peer in &self.peers {
+            if peer.0 == &self.id {
+                continue;
+            }
+
+            if peer.1.streams.is_none() {
+                return Err(MpcNetError::Generic(format!(
+                    "Peer {} has no stream",
+                    peer.0
+                )));
+            }
+        }
+
+        trace!("Done with recv_from_king");
+        Ok(())
+    }
+
+    fn peer(&self, id: u32) -> Result<&Peer<IO>, MpcNetError> {
+        self.peers.get(&id).ok_or_else(|| {
+            MpcNetError::Generic(format!("Peer {} not found", id))
+        })
+    }
+
+    /// The next frame from `id` on `sid`, still sealed
+    async fn recv_frame(
+        &self,
+        id: u32,
+        sid: MultiplexedStreamID,
+    ) -> Result<Bytes, MpcNetError> {
+        let peer = self.peer(id)?;
+        self.session
+            .bounded_recv(id, sid, recv_stream(peer.streams.as_ref(), sid))
+            .await
+    }
+
+    /// Sends the close notification as the last frame on every stream, after
+    /// `prefix`, and closes the streams and the connections underneath
+    pub(crate) async fn close_streams(&self, prefix: &[u8]) {
+        let streams = self
+            .peers
+            .values()
+            .filter(|peer| peer.id != self.id)
+            .filter_map(|peer| Some((peer.id, peer.streams.as_ref()?)))
+            .flat_map(|(peer, streams)| {
+                streams.iter().enumerate().map(move |(sid, stream)| {
+                    (peer, MultiplexedStreamID::new(sid as u32), stream)
+                })
+            });
+        join_all(streams.map(|(peer, sid, stream)| async move {
+            let frame = match self.session.seal_close(self.id, peer, sid) {
+                Ok(frame) => frame,
+                Err(_) => return,
+            };
+            // Best effort, the peer may be gone already
+            let _ = tokio::time::timeout(SHUTDOWN_TIMEOUT, async {
+                let mut stream = stream.lock().await;
+                send_chunked(&mut *stream, prefix, &frame).await?;
+                SinkExt::<Bytes>::close(&mut *stream)
+                    .await
+                    .map_err(MpcNetError::from)
+            })
+            .await;
+        }))
+        .await;
+
+        // Every stream is flushed and closed, so the workers only have the
+        // last frames left to hand to the connections. Those that take too
+        // long are aborted.
+        let workers = std::mem::take(&mut *self.workers.lock());
+        join_all(workers.into_iter().map(|mut worker| async move {
+            if tokio::time::timeout(SHUTDOWN_TIMEOUT, &mut worker)
+                .await
+                .is_err()
+            {
+                worker.abort();
+                let _ = worker.await;
+            }
+        }))
+        .await;
+    }
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin> Drop for MpcNetConnection<IO> {
+    fn drop(&mut self) {
+        for worker in self.workers.get_mut().iter() {
+            worker.abort();
+        }
     }
 }
 
@@ -340,19 +675,37 @@ impl<IO: AsyncRead + AsyncWrite + Unpin + Send> MpcNet
         self.id
     }
 
+    fn king_id(&self) -> u32 {
+        self.king
+    }
+
     fn is_init(&self) -> bool {
         self.peers.iter().all(|r| r.1.streams.is_some())
     }
 
+    fn n_streams(&self) -> usize {
+        self.n_streams
+    }
+
+    fn wire_codec(&self) -> WireCodec {
+        self.wire_codec
+    }
+
+    fn set_protocol_label(&self, sid: MultiplexedStreamID, label: &str) {
+        self.session.set_label(sid, label)
+    }
+
+    fn stats(&self) -> NetStats {
+        self.session.stats(self.id)
+    }
+
     async fn recv_from(
         &self,
         id: u32,
         sid: MultiplexedStreamID,
     ) -> Result<Bytes, MpcNetError> {
-        let peer = self.peers.get(&id).ok_or_else(|| {
-            MpcNetError::Generic(format!("Peer {} not found", id))
-        })?;
-        recv_stream(peer.streams.as_ref(), sid).await
+        let frame = self.recv_frame(id, sid).await?;
+        self.session.open(self.id, id, sid, frame)
     }
 
     async fn send_to(
@@ -361,10 +714,68 @@ impl<IO: AsyncRead + AsyncWrite + Unpin + Send> MpcNet
         bytes: Bytes,
         sid: MultiplexedStreamID,
     ) -> Result<(), MpcNetError> {
-        let peer = self.peers.get(&id).ok_or_else(|| {
-            MpcNetError::Generic(format!("Peer {} not found", id))
-        })?;
-        send_stream(peer.streams.as_ref(), bytes, sid).await
+        let peer = self.peer(id)?;
+        let frame = self.session.seal(self.id, id, sid, bytes)?;
+        self.session
+            .bounded(id, send_stream(peer.streams.as_ref(), frame, sid))
+            .await
+    }
+
+    async fn recv_sealed(
+        &self,
+        id: u32,
+        sid: MultiplexedStreamID,
+    ) -> Result<Bytes, MpcNetError> {
+        let frame = self.recv_frame(id, sid).await?;
+        self.session.pass_on(self.id, id, sid, frame)
+    }
+
+    async fn send_sealed(
+        &self,
+        id: u32,
+        frame: Bytes,
+        sid: MultiplexedStreamID,
+    ) -> Result<(), MpcNetError> {
+        let peer = self.peer(id)?;
+        self.session
+            .bounded(id, send_stream(peer.streams.as_ref(), frame, sid))
+            .await
+    }
+
+    async fn abort(&self, reason: &str) {
+        let abort = match self.session.start_abort(self.id, reason) {
+            Some(abort) => abort,
+            None => return,
+        };
+
+        for peer in self.peers.values() {
+            if peer.id == self.id || peer.id == abort.party {
+                continue;
+            }
+            for sid in (0..self.n_streams as u32).map(MultiplexedStreamID::new)
+            {
+                let frame = match self
+                    .session
+                    .seal_abort(self.id, peer.id, sid, &abort)
+                {
+                    Ok(frame) => frame,
+                    Err(_) => continue,
+                };
+                // Best effort, the peer may be gone already
+                let _ = tokio::time::timeout(
+                    ABORT_TIMEOUT,
+                    send_stream(peer.streams.as_ref(), frame, sid),
+                )
+                .await;
+            }
+        }
+    }
+
+    async fn shutdown(&self) -> Result<(), MpcNetError> {
+        if self.session.start_shutdown(self.id) {
+            self.close_streams(&[]).await;
+        }
+        Ok(())
     }
 }
 
@@ -373,8 +784,8 @@ async fn send_stream<T: AsyncRead + AsyncWrite + Unpin>(
     bytes: Bytes,
     sid: MultiplexedStreamID,
 ) -> Result<(), MpcNetError> {
-    if let Some(stream) = stream.and_then(|r| r.get(sid as usize)) {
-        Ok(stream.lock().await.send(bytes).await?)
+    if let Some(stream) = stream.and_then(|r| r.get(sid.index())) {
+        send_chunked(&mut *stream.lock().await, &[], &bytes).await
     } else {
         Err(MpcNetError::Generic("Stream is None".to_string()))
     }
@@ -384,14 +795,8 @@ async fn recv_stream<T: AsyncRead + AsyncWrite + Unpin>(
     stream: Option<&Vec<TokioMutex<WrappedStream<T>>>>,
     sid: MultiplexedStreamID,
 ) -> Result<Bytes, MpcNetError> {
-    if let Some(stream) = stream.and_then(|r| r.get(sid as usize)) {
-        Ok(stream
-            .lock()
-            .await
-            .next()
-            .await
-            .ok_or_else(|| MpcNetError::Generic("Stream died".to_string()))??
-            .freeze())
+    if let Some(stream) = stream.and_then(|r| r.get(sid.index())) {
+        recv_chunked(&mut *stream.lock().await).await
     } else {
         Err(MpcNetError::Generic("Stream is None".to_string()))
     }
@@ -399,30 +804,242 @@ async fn recv_stream<T: AsyncRead + AsyncWrite + Unpin>(
 
 #[cfg(test)]
 mod tests {
-    use crate::multi::{recv_stream, send_stream};
-    use crate::{LocalTestNet, MultiplexedStreamID};
+    use crate::adversary::assert_king_timed_out;
+    use crate::multi::{recv_stream, send_stream, MAX_STREAMS};
+    use crate::{LocalTestNet, MpcNet, MpcNetError, MultiplexedStreamID};
     use std::collections::HashMap;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_flush_outlasts_a_peer_that_left() {
+        use crate::multi::multiplex_stream;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (a, b) = tokio::io::duplex(1 << 16);
+        let (king, peer) = tokio::join!(
+            multiplex_stream(1, true, a),
+            multiplex_stream(1, false, b)
+        );
+        let (mut king, _king_worker) = king.unwrap();
+        let (mut peer, _peer_worker) = peer.unwrap();
+        let mut king = king.pop().unwrap().into_inner().into_inner();
+        let mut peer = peer.pop().unwrap().into_inner().into_inner();
+
+        // The peer reads the last message and leaves before the king flushes
+        king.write_all(b"last").await.unwrap();
+        let mut message = [0; 4];
+        peer.read_exact(&mut message).await.unwrap();
+        drop(peer);
+        while !king.0.is_closed() {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        king.flush().await.unwrap();
+        assert!(king.write_all(b"more").await.is_err());
+    }
 
     #[tokio::test]
     async fn test_multiplexing() {
         const N_PARTIES: usize = 4;
         let testnet = LocalTestNet::new_local_testnet(N_PARTIES).await.unwrap();
+        multiplexing_inner(testnet).await;
+    }
+
+    #[tokio::test]
+    async fn test_multiplexing_many_streams() {
+        const N_PARTIES: usize = 4;
+        let testnet =
+            LocalTestNet::new_local_testnet_with_streams(N_PARTIES, 8)
+                .await
+                .unwrap();
+        multiplexing_inner(testnet).await;
+    }
+
+    #[tokio::test]
+    async fn test_stream_count_is_bounded() {
+        for n_streams in [0, MAX_STREAMS + 1] {
+            assert!(matches!(
+                LocalTestNet::new_local_testnet_with_streams(2, n_streams)
+                    .await,
+                Err(MpcNetError::BadInput { .. })
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_messages_larger_than_a_frame() {
+        const N_PARTIES: usize = 4;
+        const LEN: usize = 20 << 20;
+        let testnet = LocalTestNet::new_local_testnet(N_PARTIES).await.unwrap();
+
+        testnet
+            .simulate_network_round((), |conn, _| async move {
+                let sid = MultiplexedStreamID::ZERO;
+                let bytes = vec![conn.party_id() as u8; LEN];
+                let from_all = conn
+                    .client_send_or_king_receive_bytes(bytes.into(), sid)
+                    .await
+                    .unwrap();
+                if let Some(from_all) = &from_all {
+                    for (id, bytes) in from_all.iter().enumerate() {
+                        assert_eq!(bytes.len(), LEN);
+                        assert!(bytes.iter().all(|b| *b == id as u8));
+                    }
+                }
+
+                let back = conn
+                    .client_receive_or_king_send(from_all, sid)
+                    .await
+                    .unwrap();
+                assert_eq!(back.len(), LEN);
+                assert_eq!(back[LEN - 1], conn.party_id() as u8);
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_king_rejects_mislabeled_frames() {
+        const N_PARTIES: usize = 4;
+        let mut testnet =
+            LocalTestNet::new_local_testnet(N_PARTIES).await.unwrap();
+        testnet.set_trace(true);
+
+        testnet
+            .simulate_network_round((), |conn, _| async move {
+                let sid = MultiplexedStreamID::ONE;
+                let label = if conn.party_id() == 2 {
+                    "d_fft"
+                } else {
+                    "d_msm"
+                };
+                conn.set_protocol_label(sid, label);
+
+                let result = conn.client_send_or_king_receive(&[1], sid).await;
+                if conn.is_king() {
+                    match result {
+                        Err(MpcNetError::Protocol { party, .. }) => {
+                            assert_eq!(party, 2)
+                        }
+                        other => {
+                            panic!("Expected a protocol error, got {:?}", other)
+                        }
+                    }
+                }
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_abort_reaches_every_party() {
+        const N_PARTIES: usize = 4;
+        let testnet = LocalTestNet::new_local_testnet(N_PARTIES).await.unwrap();
+
+        let results = testnet
+            .simulate_network_round((), |conn, _| async move {
+                let sid = MultiplexedStreamID::ZERO;
+                if conn.party_id() == 3 {
+                    conn.abort("out of memory").await;
+                    // Stay connected until the others have read the abort
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    return Ok(None);
+                }
+
+                // Everyone else waits for party 3, then for the king
+                let from_all =
+                    conn.client_send_or_king_receive(&[1], sid).await?;
+                conn.client_receive_or_king_send(from_all, sid)
+                    .await
+                    .map(Some)
+            })
+            .await;
+
+        for result in results.into_iter().take(3) {
+            assert!(matches!(
+                result,
+                Err(MpcNetError::Aborted { party: 3, .. })
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_king_times_out_on_silent_party() {
+        const N_PARTIES: usize = 4;
+        let mut testnet =
+            LocalTestNet::new_local_testnet(N_PARTIES).await.unwrap();
+        testnet.set_op_timeout(Duration::from_millis(200));
+
+        let results = testnet
+            .simulate_network_round((), |conn, _| async move {
+                let sid = MultiplexedStreamID::ZERO;
+                if conn.party_id() == 2 {
+                    // Never sends its share, but waits for the king
+                    return conn.client_receive_or_king_send(None, sid).await;
+                }
+
+                let from_all =
+                    conn.client_send_or_king_receive(&[1], sid).await?;
+                conn.client_receive_or_king_send(from_all, sid).await
+            })
+            .await;
+
+        assert_king_timed_out(&results, 2);
+    }
+
+    #[tokio::test]
+    async fn test_peers_see_a_clean_shutdown() {
+        const N_PARTIES: usize = 4;
+        let testnet = LocalTestNet::new_local_testnet(N_PARTIES).await.unwrap();
+
+        let results = testnet
+            .simulate_network_round((), |conn, _| async move {
+                let sid = MultiplexedStreamID::ZERO;
+                let from_all =
+                    conn.client_send_or_king_receive(&[1], sid).await?;
+                match conn.party_id() {
+                    0 => {
+                        // Party 2's share arrives before its goodbye
+                        assert_eq!(from_all.unwrap().len(), N_PARTIES);
+                        assert!(matches!(
+                            conn.recv_from(2, sid).await,
+                            Err(MpcNetError::ShutDown { party: 2 })
+                        ));
+
+                        conn.shutdown().await?;
+                        assert!(conn.workers.lock().is_empty());
+                        assert!(matches!(
+                            conn.send_to(1, vec![1].into(), sid).await,
+                            Err(MpcNetError::ShutDown { party: 0 })
+                        ));
+                        Ok(())
+                    }
+                    2 => conn.shutdown().await,
+                    _ => conn.recv_from(0, sid).await.map(|_| ()),
+                }
+            })
+            .await;
+
+        assert!(results[0].is_ok());
+        assert!(results[2].is_ok());
+        for result in [&results[1], &results[3]] {
+            assert!(matches!(result, Err(MpcNetError::ShutDown { party: 0 })));
+        }
+    }
+
+    async fn multiplexing_inner(testnet: LocalTestNet) {
         let expected_sum = (0..4).sum::<u32>();
 
         testnet
             .simulate_network_round((), move |conn, _| async move {
-                let sids = [
-                    MultiplexedStreamID::Zero,
-                    MultiplexedStreamID::One,
-                    MultiplexedStreamID::Two,
-                ];
+                let sids = (0..conn.n_streams() as u32)
+                    .map(MultiplexedStreamID::new)
+                    .collect::<Vec<_>>();
                 // Broadcast our ID to everyone
                 let my_id = conn.id;
                 for peer in &mut conn.peers.values() {
                     if peer.id == my_id {
                         continue;
                     }
-                    for sid in sids {
+                    for sid in sids.iter().copied() {
                         send_stream(
                             peer.streams.as_ref(),
                             vec![my_id as u8].into(),
@@ -439,7 +1056,7 @@ mod tests {
                     if peer.id == my_id {
                         continue;
                     }
-                    for sid in sids {
+                    for sid in sids.iter().copied() {
                         let recv_bytes =
                             recv_stream(peer.streams.as_ref(), sid)
                                 .await
@@ -0,0 +1,471 @@
+//! An encrypted transport that authenticates parties by raw X25519 static
+//! keys, using the Noise XX handshake, instead of X.509 certificates.
+//!
+//! A [`NoiseStream`] plugs into
+//! [`ProdNet::new_from_pre_existing_connection_with_roster`], where the king
+//! looks up each peer's static key in a [`PeerRoster`].
+//!
+//! [`ProdNet::new_from_pre_existing_connection_with_roster`]: crate::prod::ProdNet::new_from_pre_existing_connection_with_roster
+//! [`PeerRoster`]: crate::prod::PeerRoster
+
+use std::convert::TryInto;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use snow::params::DHChoice;
+use snow::resolvers::{CryptoResolver, DefaultResolver};
+use snow::{Builder, HandshakeState, TransportState};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+
+use crate::prod::{
+    HasPeerAddr, HasPeerIdentity, IsTransportEncrypted, PeerIdentity,
+};
+use crate::MpcNetError;
+
+/// The handshake pattern and primitives every party uses
+pub const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+
+/// The most bytes of a single Noise message
+const MAX_MESSAGE_LEN: usize = 65535;
+/// Every transport message carries an authentication tag of this size
+const TAG_LEN: usize = 16;
+const MAX_PLAINTEXT_LEN: usize = MAX_MESSAGE_LEN - TAG_LEN;
+/// Every message on the wire is prefixed by its length, as a u16
+const LEN_PREFIX: usize = 2;
+
+/// A static X25519 key pair that identifies a party
+#[derive(Clone)]
+pub struct NoiseKeypair {
+    pub public: [u8; 32],
+    private: [u8; 32],
+}
+
+impl NoiseKeypair {
+    pub fn generate() -> Result<Self, MpcNetError> {
+        let keypair = Builder::new(NOISE_PARAMS.parse()?).generate_keypair()?;
+        Ok(Self {
+            public: to_key(&keypair.public)?,
+            private: to_key(&keypair.private)?,
+        })
+    }
+
+    /// Recovers the key pair from its private key
+    pub fn from_private_key(private: [u8; 32]) -> Result<Self, MpcNetError> {
+        let mut dh = DefaultResolver
+            .resolve_dh(&DHChoice::Curve25519)
+            .ok_or_else(|| {
+                MpcNetError::Generic("X25519 is not available".to_string())
+            })?;
+        dh.set(&private);
+        Ok(Self {
+            public: to_key(dh.pubkey())?,
+            private,
+        })
+    }
+
+    pub fn private_key(&self) -> &[u8; 32] {
+        &self.private
+    }
+
+    /// The identity peers know this key pair by
+    pub fn identity(&self) -> PeerIdentity {
+        PeerIdentity::StaticKey(self.public)
+    }
+}
+
+/// A connection encrypted with the keys agreed on in a Noise handshake
+pub struct NoiseStream<S> {
+    inner: S,
+    transport: TransportState,
+    remote_key: [u8; 32],
+    /// The message being read, including its length prefix
+    incoming: Box<[u8]>,
+    incoming_filled: usize,
+    /// The decrypted message, read up to `plaintext_pos`
+    plaintext: Box<[u8]>,
+    plaintext_pos: usize,
+    plaintext_len: usize,
+    /// The encrypted message being written, written up to `outgoing_pos`
+    outgoing: Vec<u8>,
+    outgoing_pos: usize,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> NoiseStream<S> {
+    /// Runs the handshake as the party that dials, usually a peer dialing
+    /// the king. If `remote_key` is given, the other end must prove it owns
+    /// that key.
+    pub async fn connect(
+        mut inner: S,
+        keypair: &NoiseKeypair,
+        remote_key: Option<&[u8; 32]>,
+    ) -> Result<Self, MpcNetError> {
+        let state = Builder::new(NOISE_PARAMS.parse()?)
+            .local_private_key(&keypair.private)
+            .build_initiator()?;
+        let (transport, remote) = handshake(&mut inner, state).await?;
+        if let Some(expected) = remote_key {
+            if *expected != remote {
+                return Err(MpcNetError::Generic(format!(
+                    "Expected {} but the remote end authenticated as {}",
+                    PeerIdentity::StaticKey(*expected),
+                    PeerIdentity::StaticKey(remote)
+                )));
+            }
+        }
+        Ok(Self::new(inner, transport, remote))
+    }
+
+    /// Runs the handshake as the party that was dialed, usually the king.
+    /// Who is on the other end is left to the caller to check, with
+    /// [`HasPeerIdentity::peer_identity`].
+    pub async fn accept(
+        mut inner: S,
+        keypair: &NoiseKeypair,
+    ) -> Result<Self, MpcNetError> {
+        let state = Builder::new(NOISE_PARAMS.parse()?)
+            .local_private_key(&keypair.private)
+            .build_responder()?;
+        let (transport, remote) = handshake(&mut inner, state).await?;
+        Ok(Self::new(inner, transport, remote))
+    }
+
+    fn new(inner: S, transport: TransportState, remote_key: [u8; 32]) -> Self {
+        Self {
+            inner,
+            transport,
+            remote_key,
+            incoming: vec![0; LEN_PREFIX + MAX_MESSAGE_LEN].into(),
+            incoming_filled: 0,
+            plaintext: vec![0; MAX_MESSAGE_LEN].into(),
+            plaintext_pos: 0,
+            plaintext_len: 0,
+            outgoing: Vec::with_capacity(LEN_PREFIX + MAX_MESSAGE_LEN),
+            outgoing_pos: 0,
+        }
+    }
+
+    /// The static key the remote end authenticated with
+    pub fn remote_key(&self) -> &[u8; 32] {
+        &self.remote_key
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Reads and decrypts the next message. Returns false if the connection
+    /// was closed between messages.
+    fn poll_read_message(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<bool>> {
+        loop {
+            let needed = if self.incoming_filled < LEN_PREFIX {
+                LEN_PREFIX
+            } else {
+                let len =
+                    u16::from_be_bytes([self.incoming[0], self.incoming[1]]);
+                LEN_PREFIX + len as usize
+            };
+
+            if self.incoming_filled >= LEN_PREFIX
+                && self.incoming_filled == needed
+            {
+                self.plaintext_len = self
+                    .transport
+                    .read_message(
+                        &self.incoming[LEN_PREFIX..needed],
+                        &mut self.plaintext,
+                    )
+                    .map_err(|err| {
+                        io::Error::new(io::ErrorKind::InvalidData, err)
+                    })?;
+                self.plaintext_pos = 0;
+                self.incoming_filled = 0;
+                return Poll::Ready(Ok(true));
+            }
+
+            let mut buf =
+                ReadBuf::new(&mut self.incoming[self.incoming_filled..needed]);
+            ready!(Pin::new(&mut self.inner).poll_read(cx, &mut buf))?;
+            let n = buf.filled().len();
+            if n == 0 {
+                if self.incoming_filled == 0 {
+                    return Poll::Ready(Ok(false));
+                }
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+            self.incoming_filled += n;
+        }
+    }
+
+    /// Writes out what is left of the last encrypted message
+    fn poll_write_outgoing(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        while self.outgoing_pos < self.outgoing.len() {
+            let n = ready!(Pin::new(&mut self.inner)
+                .poll_write(cx, &self.outgoing[self.outgoing_pos..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.outgoing_pos += n;
+        }
+        self.outgoing.clear();
+        self.outgoing_pos = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for NoiseStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        while this.plaintext_pos == this.plaintext_len {
+            if !ready!(this.poll_read_message(cx))? {
+                return Poll::Ready(Ok(()));
+            }
+        }
+
+        let n = buf.remaining().min(this.plaintext_len - this.plaintext_pos);
+        buf.put_slice(
+            &this.plaintext[this.plaintext_pos..this.plaintext_pos + n],
+        );
+        this.plaintext_pos += n;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for NoiseStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_outgoing(cx))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let n = buf.len().min(MAX_PLAINTEXT_LEN);
+        this.outgoing.resize(LEN_PREFIX + MAX_MESSAGE_LEN, 0);
+        let len = this
+            .transport
+            .write_message(&buf[..n], &mut this.outgoing[LEN_PREFIX..])
+            .map_err(io::Error::other)?;
+        this.outgoing[..LEN_PREFIX]
+            .copy_from_slice(&(len as u16).to_be_bytes());
+        this.outgoing.truncate(LEN_PREFIX + len);
+
+        // Start sending right away, like a TLS stream does, since callers
+        // don't always flush before waiting for a reply
+        if let Poll::Ready(Err(err)) = this.poll_write_outgoing(cx) {
+            return Poll::Ready(Err(err));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_outgoing(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_outgoing(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+impl HasPeerAddr for NoiseStream<TcpStream> {
+    fn peer_addr(&self) -> Result<SocketAddr, MpcNetError> {
+        self.inner
+            .peer_addr()
+            .map_err(|err| MpcNetError::Generic(err.to_string()))
+    }
+}
+
+impl<S> HasPeerIdentity for NoiseStream<S> {
+    fn peer_identity(&self) -> Option<PeerIdentity> {
+        Some(PeerIdentity::StaticKey(self.remote_key))
+    }
+}
+
+impl<S> IsTransportEncrypted for NoiseStream<S> {}
+
+/// Exchanges handshake messages until both ends have authenticated each
+/// other, and returns the transport keys and the remote static key
+async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    mut state: HandshakeState,
+) -> Result<(TransportState, [u8; 32]), MpcNetError> {
+    let mut buf = vec![0u8; MAX_MESSAGE_LEN];
+    while !state.is_handshake_finished() {
+        if state.is_my_turn() {
+            let len = state.write_message(&[], &mut buf)?;
+            stream.write_u16(len as u16).await?;
+            stream.write_all(&buf[..len]).await?;
+            stream.flush().await?;
+        } else {
+            let len = stream.read_u16().await? as usize;
+            let mut message = vec![0u8; len];
+            stream.read_exact(&mut message).await?;
+            state.read_message(&message, &mut buf)?;
+        }
+    }
+
+    let remote = to_key(state.get_remote_static().ok_or_else(|| {
+        MpcNetError::Generic("Remote end sent no static key".to_string())
+    })?)?;
+    Ok((state.into_transport_mode()?, remote))
+}
+
+fn to_key(bytes: &[u8]) -> Result<[u8; 32], MpcNetError> {
+    bytes.try_into().map_err(|_| MpcNetError::BadInput {
+        err: "Static keys must be 32 bytes",
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prod::{PeerRoster, ProdNet};
+    use crate::{MpcNet, MultiplexedStreamID};
+    use futures::future::try_join_all;
+    use tokio::net::TcpListener;
+    use tokio_util::bytes::Bytes;
+
+    #[tokio::test]
+    async fn test_handshake_authenticates_and_encrypts() {
+        let (a, b) = tokio::io::duplex(1 << 16);
+        let (king, peer) = (
+            NoiseKeypair::generate().unwrap(),
+            NoiseKeypair::generate().unwrap(),
+        );
+
+        let (king_stream, peer_stream) = tokio::join!(
+            NoiseStream::accept(a, &king),
+            NoiseStream::connect(b, &peer, Some(&king.public))
+        );
+        let (mut king_stream, mut peer_stream) =
+            (king_stream.unwrap(), peer_stream.unwrap());
+        assert_eq!(king_stream.peer_identity(), Some(peer.identity()));
+        assert_eq!(peer_stream.remote_key(), &king.public);
+
+        // Spans many Noise messages
+        let sent = (0..200_000).map(|i| i as u8).collect::<Vec<_>>();
+        let mut received = vec![0; sent.len()];
+        let (written, read) = tokio::join!(
+            async {
+                peer_stream.write_all(&sent).await?;
+                peer_stream.flush().await
+            },
+            king_stream.read_exact(&mut received)
+        );
+        written.unwrap();
+        read.unwrap();
+        assert_eq!(received, sent);
+    }
+
+    #[tokio::test]
+    async fn test_unexpected_remote_key_is_rejected() {
+        let (a, b) = tokio::io::duplex(1 << 16);
+        let (king, peer, impostor) = (
+            NoiseKeypair::generate().unwrap(),
+            NoiseKeypair::generate().unwrap(),
+            NoiseKeypair::generate().unwrap(),
+        );
+
+        let (_, peer_stream) = tokio::join!(
+            NoiseStream::accept(a, &impostor),
+            NoiseStream::connect(b, &peer, Some(&king.public))
+        );
+        assert!(peer_stream.is_err());
+    }
+
+    #[test]
+    fn test_public_key_is_recovered_from_private_key() {
+        let keypair = NoiseKeypair::generate().unwrap();
+        let recovered =
+            NoiseKeypair::from_private_key(*keypair.private_key()).unwrap();
+        assert_eq!(recovered.public, keypair.public);
+    }
+
+    #[tokio::test]
+    async fn test_prod_net_over_noise() {
+        let n_parties = 4;
+        let keypairs = (0..n_parties)
+            .map(|_| NoiseKeypair::generate().unwrap())
+            .collect::<Vec<_>>();
+        let mut roster = PeerRoster::new();
+        for (id, keypair) in keypairs.iter().enumerate().skip(1) {
+            roster.insert(keypair.identity(), id as u32).unwrap();
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let king_addr = listener.local_addr().unwrap();
+        let king_keypair = keypairs[0].clone();
+        let king = tokio::spawn(async move {
+            let mut streams = vec![];
+            for _ in 1..n_parties {
+                let (stream, _) = listener.accept().await?;
+                streams.push(NoiseStream::accept(stream, &king_keypair).await?);
+            }
+            ProdNet::new_from_pre_existing_connection_with_roster(
+                0, n_parties, streams, &roster,
+            )
+            .await
+        });
+
+        let king_key = keypairs[0].public;
+        let peers = try_join_all(keypairs.iter().enumerate().skip(1).map(
+            |(id, keypair)| async move {
+                let stream = TcpStream::connect(king_addr).await?;
+                let stream =
+                    NoiseStream::connect(stream, keypair, Some(&king_key))
+                        .await?;
+                ProdNet::new_from_pre_existing_connection(
+                    id as u32,
+                    n_parties,
+                    vec![stream],
+                )
+                .await
+            },
+        ))
+        .await
+        .unwrap();
+
+        let mut nets = vec![king.await.unwrap().unwrap()];
+        nets.extend(peers);
+
+        let sums = try_join_all(nets.iter().map(|net| async move {
+            let sid = MultiplexedStreamID::ONE;
+            let from_all = net
+                .client_send_or_king_receive(&[net.party_id() as u8], sid)
+                .await?;
+            let sums = from_all.map(|from_all| {
+                let sum = from_all.iter().map(|bytes| bytes[0]).sum::<u8>();
+                vec![Bytes::from(vec![sum]); from_all.len()]
+            });
+            net.client_receive_or_king_send(sums, sid).await
+        }))
+        .await
+        .unwrap();
+        assert!(sums.iter().all(|sum| sum[..] == [6]));
+    }
+}
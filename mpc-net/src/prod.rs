@@ -1,18 +1,37 @@
+mod resume;
+
+pub use resume::{Dialer, ResumeConfig};
+
+use crate::chunk::{recv_chunked, send_chunked};
+use crate::compress::CompressionConfig;
+use crate::identity::ReloadableIdentity;
+use crate::mesh::DIAL_RETRY_DELAY;
 use crate::multi::{
-    multiplex_stream, MpcNetConnection, Peer, WrappedMuxStream,
-    MULTIPLEXED_STREAMS,
+    check_n_streams, multiplex_stream, MpcNetConnection, Peer,
+    WrappedMuxStream, MAX_STREAMS, MULTIPLEXED_STREAMS,
+};
+use crate::session::{ABORT_TIMEOUT, HANDSHAKE_TIMEOUT};
+use crate::{
+    MpcNet, MpcNetError, MultiplexedStreamID, NetStats, SessionTracker,
+    WireCodec,
 };
-use crate::{MpcNet, MpcNetError, MultiplexedStreamID};
 use async_trait::async_trait;
-use futures::SinkExt;
-use futures::StreamExt;
-use rustls::server::AllowAnyAuthenticatedClient;
-use rustls::{RootCertStore, ServerConfig};
+use futures::FutureExt;
+use log::warn;
+use rustls::server::{
+    AllowAnyAuthenticatedClient, ClientCertVerified, ClientCertVerifier,
+    ResolvesServerCert,
+};
+use rustls::{DistinguishedName, RootCertStore, ServerConfig};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
-use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
 use tokio::sync::Mutex;
 use tokio_rustls::{TlsAcceptor, TlsStream};
 use tokio_util::bytes::Bytes;
@@ -42,10 +61,19 @@ pub fn create_server_mutual_tls_acceptor<T: CertToDer>(
     client_certs: RootCertStore,
     server_certificate: T,
 ) -> Result<TlsAcceptor, MpcNetError> {
-    let client_auth = AllowAnyAuthenticatedClient::new(client_certs);
+    create_server_tls_acceptor(
+        AllowAnyAuthenticatedClient::new(client_certs).boxed(),
+        server_certificate,
+    )
+}
+
+fn create_server_tls_acceptor<T: CertToDer>(
+    client_auth: Arc<dyn ClientCertVerifier>,
+    server_certificate: T,
+) -> Result<TlsAcceptor, MpcNetError> {
     let server_config = ServerConfig::builder()
         .with_safe_defaults()
-        .with_client_cert_verifier(client_auth.boxed())
+        .with_client_cert_verifier(client_auth)
         .with_single_cert(
             vec![rustls::Certificate(
                 server_certificate.serialize_certificate_to_der()?,
@@ -58,6 +86,29 @@ pub fn create_server_mutual_tls_acceptor<T: CertToDer>(
     Ok(TlsAcceptor::from(Arc::new(server_config)))
 }
 
+/// Like [`create_server_mutual_tls_acceptor`], but asks `resolver` for the
+/// certificate to present on every handshake, so that it can be rotated
+pub fn create_server_mutual_tls_acceptor_with_resolver(
+    client_certs: RootCertStore,
+    resolver: Arc<dyn ResolvesServerCert>,
+) -> Result<TlsAcceptor, MpcNetError> {
+    create_server_tls_acceptor_with_resolver(
+        AllowAnyAuthenticatedClient::new(client_certs).boxed(),
+        resolver,
+    )
+}
+
+fn create_server_tls_acceptor_with_resolver(
+    client_auth: Arc<dyn ClientCertVerifier>,
+    resolver: Arc<dyn ResolvesServerCert>,
+) -> Result<TlsAcceptor, MpcNetError> {
+    let server_config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(client_auth)
+        .with_cert_resolver(resolver);
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
 pub fn create_client_mutual_tls_connector<T: CertToDer>(
     server_certs: RootCertStore,
     client_certificate: T,
@@ -94,10 +145,213 @@ impl HasPeerAddr for TlsStream<TcpStream> {
 pub trait IsTransportEncrypted {}
 impl IsTransportEncrypted for TlsStream<TcpStream> {}
 
+/// How many parties, the king included, must have joined a king by
+/// `deadline`. Only one king can gather more than half of the parties, so
+/// two coordinators that each think they lead don't both keep waiting.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Quorum {
+    pub(crate) parties: usize,
+    pub(crate) deadline: tokio::time::Instant,
+}
+
+impl Quorum {
+    /// More than half of `n_parties` within `timeout`
+    pub(crate) fn majority(n_parties: usize, timeout: Duration) -> Self {
+        Self {
+            parties: n_parties / 2 + 1,
+            deadline: tokio::time::Instant::now() + timeout,
+        }
+    }
+
+    /// Waits for `accept`, but only until the deadline if fewer than the
+    /// quorum of parties have `joined` king `king` so far
+    pub(crate) async fn accept<F: std::future::Future>(
+        quorum: Option<Self>,
+        king: u32,
+        joined: usize,
+        accept: F,
+    ) -> Result<F::Output, MpcNetError> {
+        match quorum {
+            Some(quorum) if joined < quorum.parties => {
+                tokio::time::timeout_at(quorum.deadline, accept)
+                    .await
+                    .map_err(|_| {
+                        MpcNetError::Generic(format!(
+                            "Only {joined} of the {} parties king {king} needs joined it in time, another coordinator may lead",
+                            quorum.parties
+                        ))
+                    })
+            }
+            _ => Ok(accept.await),
+        }
+    }
+}
+
+/// An identity of the remote end of a connection, authenticated by the transport
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum PeerIdentity {
+    /// SHA-256 fingerprint of the peer's DER encoded certificate
+    CertificateFingerprint([u8; 32]),
+    /// X25519 static public key of a peer on a Noise transport
+    StaticKey([u8; 32]),
+}
+
+impl PeerIdentity {
+    pub fn from_certificate(cert: &rustls::Certificate) -> Self {
+        PeerIdentity::CertificateFingerprint(Sha256::digest(&cert.0).into())
+    }
+}
+
+impl Display for PeerIdentity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let (scheme, bytes) = match self {
+            PeerIdentity::CertificateFingerprint(fingerprint) => {
+                ("sha256", fingerprint)
+            }
+            PeerIdentity::StaticKey(key) => ("x25519", key),
+        };
+        write!(f, "{scheme}:")?;
+        for b in bytes {
+            write!(f, "{b:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Implemented by transports that authenticate the remote end. Transports
+/// that don't can rely on the default, which reports no identity.
+pub trait HasPeerIdentity {
+    fn peer_identity(&self) -> Option<PeerIdentity> {
+        None
+    }
+}
+
+impl HasPeerIdentity for TlsStream<TcpStream> {
+    fn peer_identity(&self) -> Option<PeerIdentity> {
+        self.get_ref()
+            .1
+            .peer_certificates()?
+            .first()
+            .map(PeerIdentity::from_certificate)
+    }
+}
+
+/// Maps the identities the king is willing to accept to party IDs
+#[derive(Clone, Debug, Default)]
+pub struct PeerRoster {
+    ids: HashMap<PeerIdentity, u32>,
+    certificates: Vec<rustls::Certificate>,
+}
+
+impl PeerRoster {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers `identity` as party `party_id`. Both must be unused. The
+    /// king is not in its own roster, so a peer that claims the king's ID is
+    /// rejected when it connects.
+    pub fn insert(
+        &mut self,
+        identity: PeerIdentity,
+        party_id: u32,
+    ) -> Result<(), MpcNetError> {
+        if self.ids.values().any(|id| *id == party_id) {
+            return Err(MpcNetError::Generic(format!(
+                "Party ID {party_id} is already in the roster"
+            )));
+        }
+
+        if self.ids.contains_key(&identity) {
+            return Err(MpcNetError::Generic(format!(
+                "Identity {identity} is already in the roster"
+            )));
+        }
+
+        self.ids.insert(identity, party_id);
+        Ok(())
+    }
+
+    /// Registers the client certificate of party `party_id`
+    pub fn add_certificate(
+        &mut self,
+        party_id: u32,
+        cert: rustls::Certificate,
+    ) -> Result<(), MpcNetError> {
+        self.insert(PeerIdentity::from_certificate(&cert), party_id)?;
+        self.certificates.push(cert);
+        Ok(())
+    }
+
+    /// The party ID assigned to `identity`, if any
+    pub fn party_id(&self, identity: &PeerIdentity) -> Option<u32> {
+        self.ids.get(identity).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    /// A root store trusting every certificate in the roster
+    pub fn root_cert_store(&self) -> Result<RootCertStore, MpcNetError> {
+        let mut store = RootCertStore::empty();
+        for cert in &self.certificates {
+            store.add(cert)?;
+        }
+        Ok(store)
+    }
+
+    /// A verifier accepting any client certificate that is in the roster,
+    /// either in full or by fingerprint, or that chains to one that is
+    pub fn client_verifier(
+        &self,
+    ) -> Result<Arc<dyn ClientCertVerifier>, MpcNetError> {
+        Ok(Arc::new(RosterClientVerifier {
+            roots: AllowAnyAuthenticatedClient::new(self.root_cert_store()?),
+            ids: self.ids.clone(),
+        }))
+    }
+}
+
+/// Lets a party that is only known by the fingerprint of its certificate,
+/// which is usually self-signed, through the TLS handshake. The handshake
+/// still checks that the client holds the certificate's private key.
+struct RosterClientVerifier {
+    roots: AllowAnyAuthenticatedClient,
+    ids: HashMap<PeerIdentity, u32>,
+}
+
+impl ClientCertVerifier for RosterClientVerifier {
+    fn client_auth_root_subjects(&self) -> &[DistinguishedName] {
+        self.roots.client_auth_root_subjects()
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &rustls::Certificate,
+        intermediates: &[rustls::Certificate],
+        now: SystemTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        if self
+            .ids
+            .contains_key(&PeerIdentity::from_certificate(end_entity))
+        {
+            return Ok(ClientCertVerified::assertion());
+        }
+        self.roots
+            .verify_client_cert(end_entity, intermediates, now)
+    }
+}
+
 pub trait IOStream:
     AsyncWrite
     + AsyncRead
     + HasPeerAddr
+    + HasPeerIdentity
     + IsTransportEncrypted
     + Unpin
     + Send
@@ -108,6 +362,7 @@ impl<
         T: AsyncWrite
             + AsyncRead
             + HasPeerAddr
+            + HasPeerIdentity
             + IsTransportEncrypted
             + Unpin
             + Send
@@ -119,41 +374,268 @@ impl<
 pub struct ProdNet<T: IOStream> {
     /// The king will have a connection to each party, and each party will have a connection to the king.
     /// Thus, if this node is a king, there will be n_parties connections below. If this node is not a king,
-    /// then, where will be only a single connection to the thing with ID 0
+    /// then, where will be only a single connection to the king
     connections: MpcNetConnection<T>,
+    /// Used by the king to authenticate peers, including when they reconnect
+    roster: Option<PeerRoster>,
+    resumer: Option<resume::Resumer<T>>,
 }
 
 #[derive(Serialize, Deserialize, Eq, PartialEq, Debug)]
 pub enum ProtocolPacket {
     Syn,
     SynAck,
-    Packet(Vec<u8>),
+    /// Sent over a new connection, with how many frames were received on
+    /// each stream
+    Resume {
+        session_id: u64,
+        received: Vec<u64>,
+    },
+    /// Acknowledges frames on its own, with how many frames were received on
+    /// each stream, for peers that only send
+    Ack {
+        session_id: u64,
+        received: Vec<(u32, u64)>,
+    },
 }
 
 impl ProdNet<TlsStream<TcpStream>> {
     /// Returns when all the parties have connected.
+    ///
+    /// Each peer's party ID is taken from the roster entry of the certificate
+    /// it authenticated with.
     pub async fn new_king_tls<V: ToSocketAddrs, R: CertToDer>(
         bind_addr: V,
         identity: R,
-        root_cert_store: RootCertStore,
+        roster: PeerRoster,
+    ) -> Result<ProdNet<TlsStream<TcpStream>>, MpcNetError> {
+        Self::new_king_tls_with_streams(
+            bind_addr,
+            identity,
+            roster,
+            MULTIPLEXED_STREAMS,
+        )
+        .await
+    }
+
+    /// Like [`ProdNet::new_king_tls`], but opens `n_streams` multiplexed
+    /// streams to every peer. Peers adopt the king's stream count.
+    pub async fn new_king_tls_with_streams<V: ToSocketAddrs, R: CertToDer>(
+        bind_addr: V,
+        identity: R,
+        roster: PeerRoster,
+        n_streams: usize,
+    ) -> Result<ProdNet<TlsStream<TcpStream>>, MpcNetError> {
+        Self::new_king_tls_with_id(0, bind_addr, identity, roster, n_streams)
+            .await
+    }
+
+    /// Like [`ProdNet::new_king_tls_with_streams`], for a king with party ID
+    /// `id`. Its peers learn the king's ID during the handshake.
+    pub async fn new_king_tls_with_id<V: ToSocketAddrs, R: CertToDer>(
+        id: u32,
+        bind_addr: V,
+        identity: R,
+        roster: PeerRoster,
+        n_streams: usize,
+    ) -> Result<ProdNet<TlsStream<TcpStream>>, MpcNetError> {
+        let tcp_listener = TcpListener::bind(bind_addr).await?;
+        Self::new_king_tls_with_listener(
+            id,
+            tcp_listener,
+            identity,
+            roster,
+            n_streams,
+        )
+        .await
+    }
+
+    /// Like [`ProdNet::new_king_tls_with_id`], accepting peers on a listener
+    /// that is already bound
+    pub async fn new_king_tls_with_listener<R: CertToDer>(
+        id: u32,
+        tcp_listener: TcpListener,
+        identity: R,
+        roster: PeerRoster,
+        n_streams: usize,
+    ) -> Result<ProdNet<TlsStream<TcpStream>>, MpcNetError> {
+        Self::new_king_tls_with_quorum(
+            id,
+            tcp_listener,
+            identity,
+            roster,
+            n_streams,
+            None,
+            None,
+        )
+        .await
+    }
+
+    /// Like [`ProdNet::new_king_tls_with_listener`], but lets peers that lose
+    /// their connection reconnect and resume the session, as set by `config`.
+    /// Until they acknowledge them, frames sent to each peer are kept for
+    /// replay, up to [`ResumeConfig::replay_limit`] per stream. Peers must be
+    /// resumable too, see [`ProdNet::new_peer_tls_with_king`].
+    pub async fn new_king_tls_resumable<R: CertToDer>(
+        id: u32,
+        tcp_listener: TcpListener,
+        identity: R,
+        roster: PeerRoster,
+        n_streams: usize,
+        config: ResumeConfig,
     ) -> Result<ProdNet<TlsStream<TcpStream>>, MpcNetError> {
-        let tcp_listener = tokio::net::TcpListener::bind(bind_addr).await?;
-        let n_peers = root_cert_store.roots.len();
+        Self::new_king_tls_with_quorum(
+            id,
+            tcp_listener,
+            identity,
+            roster,
+            n_streams,
+            None,
+            Some(config),
+        )
+        .await
+    }
 
+    /// Like [`ProdNet::new_king_tls_with_listener`], but gives up unless a
+    /// `quorum` of the parties joins in time, and resumes with peers if
+    /// `resume` is set
+    pub(crate) async fn new_king_tls_with_quorum<R: CertToDer>(
+        id: u32,
+        tcp_listener: TcpListener,
+        identity: R,
+        roster: PeerRoster,
+        n_streams: usize,
+        quorum: Option<Quorum>,
+        resume: Option<ResumeConfig>,
+    ) -> Result<ProdNet<TlsStream<TcpStream>>, MpcNetError> {
         let tls_acceptor =
-            create_server_mutual_tls_acceptor(root_cert_store, identity)?;
+            create_server_tls_acceptor(roster.client_verifier()?, identity)?;
+        Self::new_king_with_acceptor(
+            id,
+            tcp_listener,
+            tls_acceptor,
+            roster,
+            n_streams,
+            quorum,
+            resume,
+        )
+        .await
+    }
 
-        let mut tls_conns = vec![];
+    /// Like [`ProdNet::new_king_tls_with_id`], but presents whatever
+    /// certificate `identity` holds when a peer connects or reconnects, and
+    /// resumes with peers if `resume` is set. After
+    /// [`ReloadableIdentity::reload`], reconnecting peers see the rotated
+    /// certificate without the king restarting.
+    ///
+    /// Only the king's own certificate rotates. The roster the king checks
+    /// peers against stays fixed, and peers keep trusting whichever king
+    /// certificates they were given, so they must be handed the new one
+    /// before the king reloads.
+    pub async fn new_king_tls_reloadable<V: ToSocketAddrs>(
+        id: u32,
+        bind_addr: V,
+        identity: Arc<ReloadableIdentity>,
+        roster: PeerRoster,
+        n_streams: usize,
+        resume: Option<ResumeConfig>,
+    ) -> Result<ProdNet<TlsStream<TcpStream>>, MpcNetError> {
+        let tls_acceptor = create_server_tls_acceptor_with_resolver(
+            roster.client_verifier()?,
+            identity,
+        )?;
+        let tcp_listener = TcpListener::bind(bind_addr).await?;
+        Self::new_king_with_acceptor(
+            id,
+            tcp_listener,
+            tls_acceptor,
+            roster,
+            n_streams,
+            None,
+            resume,
+        )
+        .await
+    }
 
-        for _ in 0..n_peers {
-            let (stream, _) = tcp_listener.accept().await?;
-            let stream = TlsStream::Server(tls_acceptor.accept(stream).await?);
-            tls_conns.push(stream);
+    async fn new_king_with_acceptor(
+        id: u32,
+        tcp_listener: TcpListener,
+        tls_acceptor: TlsAcceptor,
+        roster: PeerRoster,
+        n_streams: usize,
+        quorum: Option<Quorum>,
+        resume: Option<ResumeConfig>,
+    ) -> Result<ProdNet<TlsStream<TcpStream>>, MpcNetError> {
+        let n_peers = roster.len();
+        let n_parties = n_peers + 1;
+        check_n_streams(n_streams)?;
+
+        let mut greeted: Vec<(u32, TlsStream<TcpStream>)> = vec![];
+
+        while greeted.len() < n_peers {
+            let joined = greeted.len() + 1;
+            let (stream, addr) =
+                Quorum::accept(quorum, id, joined, tcp_listener.accept())
+                    .await??;
+            let known =
+                greeted.iter().map(|(peer, _)| *peer).collect::<Vec<_>>();
+            let handshake = async {
+                let mut stream =
+                    TlsStream::Server(tls_acceptor.accept(stream).await?);
+                let peer_id = greet_peer(
+                    &mut stream,
+                    id,
+                    n_parties,
+                    n_streams,
+                    Some(&roster),
+                    &known,
+                )
+                .await?;
+                Ok::<_, MpcNetError>((peer_id, stream))
+            };
+            // A dial that gave up halfway, e.g. while looking for the king
+            // during an election, a connection that never starts the
+            // handshake or a peer that claims the wrong ID must not keep the
+            // others out
+            match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake).await {
+                Ok(Ok(peer)) => greeted.push(peer),
+                Ok(Err(err)) => {
+                    warn!("Rejected a connection from {addr}: {err:?}")
+                }
+                Err(_) => warn!("Handshake with {addr} timed out"),
+            }
         }
 
-        let n_parties = n_peers + 1;
+        let mut net = ProdNet::new_from_greeted_peers(
+            id,
+            n_parties,
+            greeted,
+            Some(&roster),
+            n_streams,
+        )
+        .await?;
+
+        let config = match resume {
+            Some(config) => config,
+            None => return Ok(net),
+        };
+
+        // Listen again whenever a peer loses its connection
+        let addr = tcp_listener.local_addr()?;
+        drop(tcp_listener);
+        let (tx, rx) = tokio::sync::mpsc::channel(n_peers.max(1));
+        net.resume_by_accepting(rx, config)?;
+        if let Some(resumer) = &mut net.resumer {
+            let acceptor = tokio::spawn(resume::accept_tls_connections(
+                addr,
+                tls_acceptor,
+                tx,
+                resumer.resuming(),
+            ));
+            resumer.tasks.push(acceptor);
+        }
 
-        ProdNet::new_from_pre_existing_connection(0, n_parties, tls_conns).await
+        Ok(net)
     }
 
     pub async fn new_peer_tls<R: CertToDer, V: std::net::ToSocketAddrs>(
@@ -162,6 +644,38 @@ impl ProdNet<TlsStream<TcpStream>> {
         identity: R,
         server_cert: RootCertStore,
         n_parties: usize,
+    ) -> Result<ProdNet<TlsStream<TcpStream>>, MpcNetError> {
+        Self::new_peer_tls_with_king(
+            id,
+            0,
+            king,
+            identity,
+            server_cert,
+            n_parties,
+            None,
+            None,
+        )
+        .await
+    }
+
+    /// Like [`ProdNet::new_peer_tls`], for a king with party ID `king_id`.
+    /// If `dial_timeout` is given, keeps dialing the king until it has passed,
+    /// in case the king is not listening yet. If `resume` is set, dials the
+    /// king again when the connection is lost and resumes the session, which
+    /// the king must be set up for too.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn new_peer_tls_with_king<
+        R: CertToDer,
+        V: std::net::ToSocketAddrs,
+    >(
+        id: u32,
+        king_id: u32,
+        king: V,
+        identity: R,
+        server_cert: RootCertStore,
+        n_parties: usize,
+        dial_timeout: Option<Duration>,
+        resume: Option<ResumeConfig>,
     ) -> Result<ProdNet<TlsStream<TcpStream>>, MpcNetError> {
         let king_addr: SocketAddr =
             king.to_socket_addrs()?
@@ -170,78 +684,403 @@ impl ProdNet<TlsStream<TcpStream>> {
                     err: "King socket addr invalid",
                 })?;
 
-        let stream = TcpStream::connect(king_addr).await?;
         let tls_connector =
             create_client_mutual_tls_connector(server_cert, identity)?;
-        let stream = TlsStream::Client(
-            tls_connector
-                .connect(rustls::ServerName::IpAddress(king_addr.ip()), stream)
-                .await?,
-        );
+        let dialer: Dialer<TlsStream<TcpStream>> = Arc::new(move || {
+            let tls_connector = tls_connector.clone();
+            async move {
+                let stream = TcpStream::connect(king_addr).await?;
+                let server_name = rustls::ServerName::IpAddress(king_addr.ip());
+                Ok::<_, MpcNetError>(TlsStream::Client(
+                    tls_connector.connect(server_name, stream).await?,
+                ))
+            }
+            .boxed()
+        });
+
+        let stream = match dial_timeout {
+            Some(dial_timeout) => {
+                let deadline = tokio::time::Instant::now() + dial_timeout;
+                loop {
+                    match tokio::time::timeout_at(deadline, dialer()).await {
+                        Ok(Ok(stream)) => break stream,
+                        Ok(Err(_))
+                            if tokio::time::Instant::now() < deadline =>
+                        {
+                            tokio::time::sleep(DIAL_RETRY_DELAY).await
+                        }
+                        Ok(Err(err)) => return Err(err),
+                        Err(_) => {
+                            return Err(MpcNetError::Timeout { party: king_id })
+                        }
+                    }
+                }
+            }
+            None => dialer().await?,
+        };
+        let mut net = ProdNet::new_from_connections(
+            id,
+            king_id,
+            n_parties,
+            vec![stream],
+            None,
+            MULTIPLEXED_STREAMS,
+        )
+        .await?;
+        if let Some(config) = resume {
+            net.resume_by_dialing(dialer, config)?;
+        }
 
-        ProdNet::new_from_pre_existing_connection(id, n_parties, vec![stream])
-            .await
+        Ok(net)
     }
 }
 
 impl<T: IOStream> ProdNet<T> {
     /// Must pass a list of connections to all the peers if king, otherwise a single connection
-    /// if a peer
+    /// if a peer. Party 0 is the king.
+    ///
+    /// The king trusts the party ID each peer announces, so this should only be
+    /// used with transports that already pin which party is on each connection.
+    /// Otherwise use [`ProdNet::new_from_pre_existing_connection_with_roster`].
     pub async fn new_from_pre_existing_connection(
         id: u32,
         n_parties: usize,
+        ios: Vec<T>,
+    ) -> Result<Self, MpcNetError> {
+        Self::new_from_connections(
+            id,
+            0,
+            n_parties,
+            ios,
+            None,
+            MULTIPLEXED_STREAMS,
+        )
+        .await
+    }
+
+    /// Like [`ProdNet::new_from_pre_existing_connection`], but the king derives
+    /// each peer's party ID from the identity its transport authenticated.
+    /// Peers that are not in the roster, or that announce a different ID, are
+    /// rejected.
+    pub async fn new_from_pre_existing_connection_with_roster(
+        id: u32,
+        n_parties: usize,
+        ios: Vec<T>,
+        roster: &PeerRoster,
+    ) -> Result<Self, MpcNetError> {
+        Self::new_from_connections(
+            id,
+            0,
+            n_parties,
+            ios,
+            Some(roster),
+            MULTIPLEXED_STREAMS,
+        )
+        .await
+    }
+
+    /// The general form of [`ProdNet::new_from_pre_existing_connection`].
+    ///
+    /// Party `king` is the king, and we are it if `id` is `king`. If a
+    /// `roster` is given, the king derives each peer's party ID from it. The
+    /// king opens `n_streams` multiplexed streams to every peer and tells the
+    /// peers during the handshake, along with its ID, so a peer's `n_streams`
+    /// is ignored and a peer that expects a different king gives up. The king
+    /// drops a connection whose peer announces an invalid or taken ID, and
+    /// fails only once it turns out that not every peer joined.
+    pub async fn new_from_connections(
+        id: u32,
+        king: u32,
+        n_parties: usize,
         mut ios: Vec<T>,
+        roster: Option<&PeerRoster>,
+        n_streams: usize,
     ) -> Result<Self, MpcNetError> {
-        if id != 0 && ios.len() != 1 {
+        if id == king {
+            check_n_streams(n_streams)?;
+            let mut greeted: Vec<(u32, T)> = Vec::with_capacity(ios.len());
+            for mut stream in ios {
+                let known =
+                    greeted.iter().map(|(peer, _)| *peer).collect::<Vec<_>>();
+                // A peer that fails to identify itself must not keep the
+                // others out
+                let greeting = greet_peer(
+                    &mut stream,
+                    king,
+                    n_parties,
+                    n_streams,
+                    roster,
+                    &known,
+                );
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, greeting).await {
+                    Ok(Ok(peer_id)) => greeted.push((peer_id, stream)),
+                    Ok(Err(err)) => warn!("Rejected a connection: {err:?}"),
+                    Err(_) => warn!("A peer did not announce its ID in time"),
+                }
+            }
+            if greeted.len() + 1 != n_parties {
+                return Err(MpcNetError::Generic(format!(
+                    "Only {} of {} peers identified themselves",
+                    greeted.len(),
+                    n_parties - 1
+                )));
+            }
+            return Self::new_from_greeted_peers(
+                king, n_parties, greeted, roster, n_streams,
+            )
+            .await;
+        }
+
+        if ios.len() != 1 {
             return Err(MpcNetError::BadInput {
                 err: "Must pass a single connection to the king if you are a peer",
             });
         }
 
-        let mut connections = MpcNetConnection {
-            id,
-            listener: None,
-            peers: Default::default(),
-            n_parties,
-        };
+        let mut connections = unconnected(id, king, n_parties, n_streams);
+        let mut stream = ios.pop().expect("Should exist");
+        let oeer_addr = stream.peer_addr()?;
+        stream.write_u32(id).await?;
+        let n_streams = stream.read_u32().await? as usize;
+        let announced_king = stream.read_u32().await?;
+        if announced_king != king {
+            return Err(MpcNetError::Protocol {
+                err: format!(
+                    "Expected party {king} to be the king, but party {announced_king} is"
+                ),
+                party: announced_king,
+            });
+        }
+        if !(1..=MAX_STREAMS).contains(&n_streams) {
+            return Err(MpcNetError::Protocol {
+                err: format!("Asked to open {n_streams} streams"),
+                party: king,
+            });
+        }
+        connections.n_streams = n_streams;
+        let (muxed, worker) =
+            multiplex_stream(n_streams, false, stream).await?;
+        connections.workers.get_mut().push(worker);
+        connections.peers.insert(
+            king,
+            Peer {
+                id: king,
+                listen_addr: oeer_addr,
+                streams: Some(muxed),
+            },
+        );
 
-        if id == 0 {
-            for mut stream in ios.into_iter() {
-                let peer_id = stream.read_u32().await?;
-                let peer_addr = stream.peer_addr()?;
-                let muxed =
-                    multiplex_stream(MULTIPLEXED_STREAMS, true, stream).await?;
-                connections.peers.insert(
-                    peer_id,
-                    Peer {
-                        id: peer_id,
-                        listen_addr: peer_addr,
-                        streams: Some(muxed),
-                    },
-                );
-            }
-        } else {
-            let mut stream = ios.pop().expect("Should exist");
-            let oeer_addr = stream.peer_addr()?;
-            stream.write_u32(id).await?;
-            let muxed =
-                multiplex_stream(MULTIPLEXED_STREAMS, false, stream).await?;
+        Self::from_connections(connections, roster).await
+    }
+
+    /// Like [`ProdNet::new_from_connections`] for the king, with connections
+    /// to peers that [`greet_peer`] already identified
+    pub(crate) async fn new_from_greeted_peers(
+        king: u32,
+        n_parties: usize,
+        greeted: Vec<(u32, T)>,
+        roster: Option<&PeerRoster>,
+        n_streams: usize,
+    ) -> Result<Self, MpcNetError> {
+        check_n_streams(n_streams)?;
+        let mut connections = unconnected(king, king, n_parties, n_streams);
+        for (peer_id, stream) in greeted {
+            let peer_addr = stream.peer_addr()?;
+            let (muxed, worker) =
+                multiplex_stream(n_streams, true, stream).await?;
+            connections.workers.get_mut().push(worker);
             connections.peers.insert(
-                0,
+                peer_id,
                 Peer {
-                    id: 0,
-                    listen_addr: oeer_addr,
+                    id: peer_id,
+                    listen_addr: peer_addr,
                     streams: Some(muxed),
                 },
             );
         }
+        Self::from_connections(connections, roster).await
+    }
 
-        let this = Self { connections };
+    async fn from_connections(
+        connections: MpcNetConnection<T>,
+        roster: Option<&PeerRoster>,
+    ) -> Result<Self, MpcNetError> {
+        let this = Self {
+            connections,
+            roster: roster.cloned(),
+            resumer: None,
+        };
         this.synchronize().await?;
 
         Ok(this)
     }
 
+    /// Set the codec used to serialize values sent over this network
+    pub fn set_wire_codec(&mut self, codec: WireCodec) {
+        self.connections.wire_codec = codec;
+    }
+
+    /// Set the session ID every frame is tagged with. All parties must agree
+    /// on it.
+    pub fn set_session_id(&mut self, session_id: u64) {
+        self.connections.session.set_session_id(session_id);
+    }
+
+    /// Bound every single send and receive by `timeout`
+    pub fn set_op_timeout(&mut self, timeout: Duration) {
+        self.connections.session.set_op_timeout(Some(timeout));
+    }
+
+    /// Fail every send and receive once `timeout` has passed
+    pub fn set_session_timeout(&mut self, timeout: Duration) {
+        let deadline = tokio::time::Instant::now() + timeout;
+        self.connections.session.set_deadline(Some(deadline));
+    }
+
+    /// Log a trace of every frame sent and received
+    pub fn set_trace(&mut self, trace: bool) {
+        self.connections.session.set_trace(trace);
+    }
+
+    /// Compress payloads sent on every stream with `config`
+    pub fn set_compression(&mut self, config: Option<CompressionConfig>) {
+        self.connections.session.set_default_compression(config);
+    }
+
+    /// Compress payloads sent on `sid` with `config`, whatever is set for the
+    /// other streams
+    pub fn set_stream_compression(
+        &mut self,
+        sid: MultiplexedStreamID,
+        config: Option<CompressionConfig>,
+    ) {
+        self.connections.session.set_compression(sid, config);
+    }
+
+    /// Sends a sealed frame, unless the connection was replaced since it was
+    /// sealed at `generation`, in which case it was replayed already
+    async fn send_frame(
+        &self,
+        peer: &Peer<T>,
+        sid: MultiplexedStreamID,
+        frame: &Bytes,
+        generation: u64,
+    ) -> Result<(), MpcNetError> {
+        let streams = peer.streams.as_ref().ok_or(MpcNetError::NotConnected)?;
+        let stream =
+            streams.get(sid.index()).ok_or(MpcNetError::NotConnected)?;
+        let send = async {
+            let mut stream = stream.lock().await;
+            if self.generation(peer.id) != generation {
+                return Ok(());
+            }
+            send_chunked(&mut *stream, &[FRAME], frame).await
+        };
+        // Let go of the stream if the connection is being replaced, which
+        // replays the frame
+        tokio::select! {
+            sent = send => sent,
+            _ = self.replacing(peer.id) => Err(MpcNetError::NotConnected),
+        }
+    }
+
+    /// Receives the next frame from `peer` on `sid`, taking in the
+    /// acknowledgements that arrive before it
+    async fn next_frame(
+        &self,
+        peer: &Peer<T>,
+        sid: MultiplexedStreamID,
+    ) -> Result<Incoming, MpcNetError> {
+        let stream = stream_of(peer.streams.as_ref(), sid)?;
+        // Stashed frames are taken under the lock, so none is overtaken
+        let mut stream = stream.lock().await;
+        if let Some(frame) = self.connections.session.unstash(peer.id, sid) {
+            return Ok(Incoming::Stashed(frame));
+        }
+
+        loop {
+            match read_message(&mut stream).await? {
+                Message::Frame(frame) => return Ok(Incoming::Fresh(frame)),
+                Message::Control(packet) => self.take_ack(peer.id, packet)?,
+            }
+        }
+    }
+
+    /// Waits until we keep little enough for replay to `peer` on `sid` to
+    /// seal another frame. Frames that arrive in the meantime are stashed,
+    /// and acknowledged if due, so that two parties that both wait make
+    /// progress.
+    async fn wait_for_acks(
+        &self,
+        peer: &Peer<T>,
+        sid: MultiplexedStreamID,
+    ) -> Result<(), MpcNetError> {
+        let session = &self.connections.session;
+        if !session.replay_full(peer.id, sid) {
+            return Ok(());
+        }
+
+        let stream = stream_of(peer.streams.as_ref(), sid)?;
+        let mut stream = stream.lock().await;
+        while session.replay_full(peer.id, sid) {
+            match read_message(&mut stream).await? {
+                Message::Frame(frame) => session.stash(peer.id, sid, frame),
+                Message::Control(packet) => self.take_ack(peer.id, packet)?,
+            }
+            if let Some(received) = session.take_ack(peer.id) {
+                let packet = ProtocolPacket::Ack {
+                    session_id: session.session_id(),
+                    received,
+                };
+                write_packet(&mut stream, packet).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Takes in an acknowledgement from `peer_id`, the only packet expected
+    /// once the session started
+    fn take_ack(
+        &self,
+        peer_id: u32,
+        packet: ProtocolPacket,
+    ) -> Result<(), MpcNetError> {
+        let session = &self.connections.session;
+        match packet {
+            ProtocolPacket::Ack {
+                session_id,
+                received,
+            } => {
+                if session_id == session.session_id() {
+                    session.acknowledge(peer_id, &received);
+                }
+                Ok(())
+            }
+            packet => Err(MpcNetError::Generic(format!(
+                "Unexpected packet, got {packet:?}"
+            ))),
+        }
+    }
+
+    /// Acknowledges the frames received from `peer` so far, if it keeps
+    /// enough of them for replay to need it. Best effort, as the peer learns
+    /// what we received when the connection is resumed anyway.
+    async fn send_ack(&self, peer: &Peer<T>, sid: MultiplexedStreamID) {
+        let session = &self.connections.session;
+        let received = match session.take_ack(peer.id) {
+            Some(received) => received,
+            None => return,
+        };
+        let packet = ProtocolPacket::Ack {
+            session_id: session.session_id(),
+            received,
+        };
+        tokio::select! {
+            _ = send_packet(peer.streams.as_ref(), sid, packet) => {}
+            _ = self.replacing(peer.id) => {}
+        }
+    }
+
     /// Ensure all peers are connected to the king
     async fn synchronize(&self) -> Result<(), MpcNetError> {
         if self.is_king() {
@@ -249,7 +1088,7 @@ impl<T: IOStream> ProdNet<T> {
             for conn in self.connections.peers.values() {
                 send_packet(
                     conn.streams.as_ref(),
-                    MultiplexedStreamID::Zero,
+                    MultiplexedStreamID::ZERO,
                     ProtocolPacket::Syn,
                 )
                 .await?;
@@ -259,7 +1098,7 @@ impl<T: IOStream> ProdNet<T> {
             for conn in self.connections.peers.values() {
                 let packet = recv_packet(
                     conn.streams.as_ref(),
-                    MultiplexedStreamID::Zero,
+                    MultiplexedStreamID::ZERO,
                 )
                 .await?;
                 if packet != ProtocolPacket::SynAck {
@@ -271,22 +1110,23 @@ impl<T: IOStream> ProdNet<T> {
             }
         } else {
             // Wait for a Syn packet
+            let king = self.king_id();
             let packet = recv_packet(
-                self.connections.peers.get(&0).unwrap().streams.as_ref(),
-                MultiplexedStreamID::Zero,
+                self.connections.peers.get(&king).unwrap().streams.as_ref(),
+                MultiplexedStreamID::ZERO,
             )
             .await?;
             if packet != ProtocolPacket::Syn {
                 return Err(MpcNetError::Protocol {
                     err: "Did not receive Syn".to_string(),
-                    party: 0,
+                    party: king,
                 });
             }
 
-            // Send a SynAck packet to party_id=0
+            // Send a SynAck packet to the king
             send_packet(
-                self.connections.peers.get(&0).unwrap().streams.as_ref(),
-                MultiplexedStreamID::Zero,
+                self.connections.peers.get(&king).unwrap().streams.as_ref(),
+                MultiplexedStreamID::ZERO,
                 ProtocolPacket::SynAck,
             )
             .await?;
@@ -306,10 +1146,30 @@ impl<T: IOStream> MpcNet for ProdNet<T> {
         self.connections.party_id()
     }
 
+    fn king_id(&self) -> u32 {
+        self.connections.king_id()
+    }
+
     fn is_init(&self) -> bool {
         self.connections.is_init()
     }
 
+    fn n_streams(&self) -> usize {
+        self.connections.n_streams()
+    }
+
+    fn wire_codec(&self) -> WireCodec {
+        self.connections.wire_codec()
+    }
+
+    fn set_protocol_label(&self, sid: MultiplexedStreamID, label: &str) {
+        self.connections.set_protocol_label(sid, label)
+    }
+
+    fn stats(&self) -> NetStats {
+        self.connections.stats()
+    }
+
     async fn recv_from(
         &self,
         id: u32,
@@ -319,15 +1179,33 @@ impl<T: IOStream> MpcNet for ProdNet<T> {
             MpcNetError::Generic(format!("Peer {} not found", id))
         })?;
 
-        recv_packet(peer.streams.as_ref(), sid)
-            .await
-            .map(|r| match r {
-                ProtocolPacket::Packet(packet) => Ok(Bytes::from(packet)),
+        let session = &self.connections.session;
+        let frame = session
+            .bounded_recv(id, sid, async {
+                loop {
+                    let generation = self.generation(id);
+                    let received = tokio::select! {
+                        received = self.next_frame(peer, sid) => received,
+                        _ = self.replacing(id) => Err(MpcNetError::NotConnected),
+                    };
+                    match received {
+                        Ok(frame) => return Ok(frame),
+                        Err(err) => self.resume(id, generation, err).await?,
+                    }
+                }
+            })
+            .await?;
 
-                _ => Err(MpcNetError::Generic(format!(
-                    "Unexpected packet, got {r:?}"
-                ))),
-            })?
+        let payload = match frame {
+            Incoming::Fresh(frame) => {
+                session.open(self.party_id(), id, sid, frame)?
+            }
+            Incoming::Stashed(frame) => {
+                session.open_stashed(self.party_id(), id, sid, frame)?
+            }
+        };
+        self.send_ack(peer, sid).await;
+        Ok(payload)
     }
 
     async fn send_to(
@@ -340,13 +1218,193 @@ impl<T: IOStream> MpcNet for ProdNet<T> {
             MpcNetError::Generic(format!("Peer {} not found", id))
         })?;
 
-        send_packet(
-            peer.streams.as_ref(),
-            sid,
-            ProtocolPacket::Packet(bytes.to_vec()),
-        )
-        .await
+        let session = &self.connections.session;
+        // Frames are kept for replay until the peer acknowledges them, and
+        // only so many of them
+        session
+            .bounded(id, async {
+                loop {
+                    let generation = self.generation(id);
+                    let waited = tokio::select! {
+                        waited = self.wait_for_acks(peer, sid) => waited,
+                        _ = self.replacing(id) => Err(MpcNetError::NotConnected),
+                    };
+                    match waited {
+                        Ok(()) => return Ok(()),
+                        // Resuming drops the frames the peer received
+                        Err(err) => self.resume(id, generation, err).await?,
+                    }
+                }
+            })
+            .await?;
+
+        let generation = self.generation(id);
+        let frame = session.seal(self.party_id(), id, sid, bytes)?;
+        session
+            .bounded(id, async {
+                match self.send_frame(peer, sid, &frame, generation).await {
+                    Ok(()) => Ok(()),
+                    // The frame is replayed over the new connection if needed
+                    Err(err) => self.resume(id, generation, err).await,
+                }
+            })
+            .await
+    }
+
+    async fn abort(&self, reason: &str) {
+        let session = &self.connections.session;
+        let abort = match session.start_abort(self.party_id(), reason) {
+            Some(abort) => abort,
+            None => return,
+        };
+
+        for peer in self.connections.peers.values() {
+            if peer.id == abort.party {
+                continue;
+            }
+            for sid in
+                (0..self.n_streams() as u32).map(MultiplexedStreamID::new)
+            {
+                let frame = match session.seal_abort(
+                    self.party_id(),
+                    peer.id,
+                    sid,
+                    &abort,
+                ) {
+                    Ok(frame) => frame,
+                    Err(_) => continue,
+                };
+                // Best effort, the peer may be gone already
+                let _ = tokio::time::timeout(ABORT_TIMEOUT, async {
+                    let stream = peer
+                        .streams
+                        .as_ref()
+                        .and_then(|streams| streams.get(sid.index()))
+                        .ok_or(MpcNetError::NotConnected)?;
+                    send_chunked(&mut *stream.lock().await, &[FRAME], &frame)
+                        .await
+                })
+                .await;
+            }
+        }
+    }
+
+    async fn shutdown(&self) -> Result<(), MpcNetError> {
+        if !self.connections.session.start_shutdown(self.party_id()) {
+            return Ok(());
+        }
+
+        // Nobody may resume a connection we are about to close
+        if let Some(resumer) = &self.resumer {
+            resumer.stop();
+        }
+        self.connections.close_streams(&[FRAME]).await;
+        Ok(())
+    }
+}
+
+/// Connections of party `id` to none of its peers yet
+fn unconnected<T: IOStream>(
+    id: u32,
+    king: u32,
+    n_parties: usize,
+    n_streams: usize,
+) -> MpcNetConnection<T> {
+    MpcNetConnection {
+        id,
+        listener: None,
+        peers: Default::default(),
+        n_parties,
+        n_streams,
+        king,
+        wire_codec: WireCodec::default(),
+        session: SessionTracker::default(),
+        workers: Default::default(),
+    }
+}
+
+/// Reads the party ID the peer on `stream` announces and checks it against
+/// the `roster`, if any, and the peers that are `known` already. Then tells the
+/// peer how many streams to open, and who the king is.
+pub(crate) async fn greet_peer<T: IOStream>(
+    stream: &mut T,
+    king: u32,
+    n_parties: usize,
+    n_streams: usize,
+    roster: Option<&PeerRoster>,
+    known: &[u32],
+) -> Result<u32, MpcNetError> {
+    let announced_id = stream.read_u32().await?;
+    let peer_id = match roster {
+        Some(roster) => authenticated_party_id(stream, announced_id, roster)?,
+        None => announced_id,
+    };
+
+    if peer_id == king
+        || peer_id as usize >= n_parties
+        || known.contains(&peer_id)
+    {
+        return Err(MpcNetError::Protocol {
+            err: format!("Invalid or duplicate party ID {peer_id}"),
+            party: peer_id,
+        });
+    }
+
+    stream.write_u32(n_streams as u32).await?;
+    stream.write_u32(king).await?;
+    Ok(peer_id)
+}
+
+/// Looks up the party ID of the peer on `stream` and checks that it matches the
+/// ID the peer announced
+fn authenticated_party_id<T: IOStream>(
+    stream: &T,
+    announced_id: u32,
+    roster: &PeerRoster,
+) -> Result<u32, MpcNetError> {
+    let identity =
+        stream
+            .peer_identity()
+            .ok_or_else(|| MpcNetError::Protocol {
+                err: "Peer did not present an identity".to_string(),
+                party: announced_id,
+            })?;
+
+    let peer_id =
+        roster
+            .party_id(&identity)
+            .ok_or_else(|| MpcNetError::Protocol {
+                err: format!("Identity {identity} is not in the roster"),
+                party: announced_id,
+            })?;
+
+    if peer_id != announced_id {
+        return Err(MpcNetError::Protocol {
+            err: format!(
+                "Peer announced ID {announced_id} but authenticated as {peer_id}"
+            ),
+            party: peer_id,
+        });
     }
+
+    Ok(peer_id)
+}
+
+/// Leads a message that carries a [`ProtocolPacket`]
+const CONTROL: u8 = 0;
+/// Leads a message that carries a sealed frame, which is sent as is
+const FRAME: u8 = 1;
+
+/// A message received on a stream
+enum Message {
+    Control(ProtocolPacket),
+    Frame(Bytes),
+}
+
+/// A frame to open, fresh off the connection or stashed earlier
+enum Incoming {
+    Fresh(Bytes),
+    Stashed(Bytes),
 }
 
 async fn send_packet<T: IOStream>(
@@ -354,41 +1412,75 @@ async fn send_packet<T: IOStream>(
     sid: MultiplexedStreamID,
     packet: ProtocolPacket,
 ) -> Result<(), MpcNetError> {
-    let stream = streams.ok_or(MpcNetError::NotConnected)?;
-    let stream = stream.get(sid as usize).ok_or(MpcNetError::NotConnected)?;
+    let stream = stream_of(streams, sid)?;
+    write_packet(&mut *stream.lock().await, packet).await
+}
+
+async fn write_packet<T: IOStream>(
+    stream: &mut WrappedMuxStream<T>,
+    packet: ProtocolPacket,
+) -> Result<(), MpcNetError> {
     let packet = bincode2::serialize(&packet)?;
-    stream.lock().await.send(Bytes::from(packet)).await?;
-    Ok(())
+    send_chunked(stream, &[CONTROL], &packet).await
+}
+
+fn stream_of<T: IOStream>(
+    streams: Option<&Vec<Mutex<WrappedMuxStream<T>>>>,
+    sid: MultiplexedStreamID,
+) -> Result<&Mutex<WrappedMuxStream<T>>, MpcNetError> {
+    let stream = streams.ok_or(MpcNetError::NotConnected)?;
+    stream.get(sid.index()).ok_or(MpcNetError::NotConnected)
+}
+
+async fn recv_message<T: IOStream>(
+    streams: Option<&Vec<Mutex<WrappedMuxStream<T>>>>,
+    sid: MultiplexedStreamID,
+) -> Result<Message, MpcNetError> {
+    let stream = stream_of(streams, sid)?;
+    read_message(&mut *stream.lock().await).await
+}
+
+async fn read_message<T: IOStream>(
+    stream: &mut WrappedMuxStream<T>,
+) -> Result<Message, MpcNetError> {
+    let mut message = recv_chunked(stream).await?;
+    if message.is_empty() {
+        return Err(MpcNetError::Generic("Message is empty".to_string()));
+    }
+
+    match message.split_to(1)[0] {
+        CONTROL => Ok(Message::Control(bincode2::deserialize(&message)?)),
+        FRAME => Ok(Message::Frame(message)),
+        kind => Err(MpcNetError::Generic(format!(
+            "Unknown kind of message {kind}"
+        ))),
+    }
 }
 
 async fn recv_packet<T: IOStream>(
     streams: Option<&Vec<Mutex<WrappedMuxStream<T>>>>,
     sid: MultiplexedStreamID,
 ) -> Result<ProtocolPacket, MpcNetError> {
-    let stream = streams.ok_or(MpcNetError::NotConnected)?;
-    let stream = stream.get(sid as usize).ok_or(MpcNetError::NotConnected)?;
-    let packet = stream
-        .lock()
-        .await
-        .next()
-        .await
-        .ok_or(MpcNetError::NotConnected)??;
-    let packet = bincode2::deserialize(&packet)?;
-    Ok(packet)
+    match recv_message(streams, sid).await? {
+        Message::Control(packet) => Ok(packet),
+        Message::Frame(_) => Err(MpcNetError::Generic(
+            "Expected a control packet, got a frame".to_string(),
+        )),
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::session::ReplayLimit;
     use futures::stream::{FuturesOrdered, FuturesUnordered};
-    use futures::{TryFutureExt, TryStreamExt};
+    use futures::{StreamExt, TryFutureExt, TryStreamExt};
     use std::future::Future;
     use std::io::Error;
     use std::pin::Pin;
     use std::str::FromStr;
     use std::task::{Context, Poll};
     use std::time::Duration;
-    use tokio::net::TcpListener;
 
     use rcgen::{Certificate, RcgenError};
     use tokio::io::ReadBuf;
@@ -406,6 +1498,16 @@ mod test {
         rcgen::generate_simple_self_signed(vec!["127.0.0.1".to_string()])
     }
 
+    fn generate_rustls_identity() -> RustlsCertificate {
+        let identity = generate_self_signed_cert().unwrap();
+        RustlsCertificate {
+            cert: rustls::Certificate(identity.serialize_der().unwrap()),
+            private_key: rustls::PrivateKey(
+                identity.serialize_private_key_der(),
+            ),
+        }
+    }
+
     struct LocalTestNetProd<T: IOStream> {
         nodes: Vec<ProdNet<T>>,
     }
@@ -437,6 +1539,7 @@ mod test {
     }
 
     impl IsTransportEncrypted for ChannelIO {}
+    impl HasPeerIdentity for ChannelIO {}
 
     impl HasPeerAddr for ChannelIO {
         fn peer_addr(&self) -> Result<SocketAddr, MpcNetError> {
@@ -451,8 +1554,13 @@ mod test {
             buf: &[u8],
         ) -> Poll<Result<usize, Error>> {
             let len = buf.len();
-            self.tx.send(buf.into()).unwrap();
-            Poll::Ready(Ok(len))
+            match self.tx.send(buf.into()) {
+                Ok(()) => Poll::Ready(Ok(len)),
+                Err(_) => Poll::Ready(Err(Error::new(
+                    std::io::ErrorKind::BrokenPipe,
+                    "Channel closed",
+                ))),
+            }
         }
 
         fn poll_flush(
@@ -481,15 +1589,278 @@ mod test {
                     buf.put_slice(&bytes);
                     Poll::Ready(Ok(()))
                 }
-                Poll::Ready(None) => Poll::Ready(Err(Error::new(
-                    std::io::ErrorKind::Other,
-                    "Channel closed",
-                ))),
+                Poll::Ready(None) => {
+                    Poll::Ready(Err(Error::other("Channel closed")))
+                }
                 Poll::Pending => Poll::Pending,
             }
         }
     }
 
+    /// Two ends of a connection, and the task relaying between them. Aborting
+    /// the task breaks the connection.
+    fn breakable_channel_pair(
+    ) -> (ChannelIO, ChannelIO, tokio::task::JoinHandle<()>) {
+        let (a_tx, mut from_a) = tokio::sync::mpsc::unbounded_channel();
+        let (to_a, a_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (b_tx, mut from_b) = tokio::sync::mpsc::unbounded_channel();
+        let (to_b, b_rx) = tokio::sync::mpsc::unbounded_channel();
+        let relay = tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    Some(bytes) = from_a.recv() => { let _ = to_b.send(bytes); }
+                    Some(bytes) = from_b.recv() => { let _ = to_a.send(bytes); }
+                    else => return,
+                }
+            }
+        });
+
+        let a = ChannelIO { tx: a_tx, rx: a_rx };
+        let b = ChannelIO { tx: b_tx, rx: b_rx };
+        (a, b, relay)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_session_resumes_after_connection_loss() {
+        const N_PEERS: usize = 2;
+        let n_parties = N_PEERS + 1;
+
+        let mut king_conns = vec![];
+        let mut peer_conns = vec![];
+        let mut relays = vec![];
+        for _ in 0..N_PEERS {
+            let (king, peer, relay) = breakable_channel_pair();
+            king_conns.push(king);
+            peer_conns.push(peer);
+            relays.push(relay);
+        }
+
+        let king = tokio::spawn(ProdNet::new_from_connections(
+            0,
+            0,
+            n_parties,
+            king_conns,
+            None,
+            MULTIPLEXED_STREAMS,
+        ))
+        .map_err(|err| MpcNetError::Generic(err.to_string()));
+        let peers = peer_conns
+            .into_iter()
+            .enumerate()
+            .map(|(i, io)| {
+                Box::pin(ProdNet::new_from_pre_existing_connection(
+                    (i + 1) as u32,
+                    n_parties,
+                    vec![io],
+                ))
+            })
+            .collect::<FuturesOrdered<_>>()
+            .try_collect::<Vec<_>>();
+        let (king, mut nodes) = tokio::try_join!(king, peers).unwrap();
+
+        let config = ResumeConfig {
+            retry_delay: Duration::from_millis(10),
+            ..Default::default()
+        };
+        let (incoming, incoming_rx) = tokio::sync::mpsc::channel(N_PEERS);
+        for peer in &mut nodes {
+            let incoming = incoming.clone();
+            let dialer: Dialer<ChannelIO> = Arc::new(move || {
+                let incoming = incoming.clone();
+                async move {
+                    let (king_end, peer_end, _relay) = breakable_channel_pair();
+                    incoming
+                        .send(king_end)
+                        .await
+                        .map_err(|_| MpcNetError::NotConnected)?;
+                    Ok(peer_end)
+                }
+                .boxed()
+            });
+            peer.resume_by_dialing(dialer, config).unwrap();
+        }
+        let mut king = king.unwrap();
+        king.resume_by_accepting(incoming_rx, config).unwrap();
+        nodes.push(king);
+
+        // A connection that never announces itself gets in ahead of the one
+        // party 1 resumes over
+        let (silent, _silent_peer, _) = breakable_channel_pair();
+        incoming.send(silent).await.unwrap();
+
+        // Party 1 loses its connection after the first round
+        let relay = Arc::new(relays.remove(0));
+        let expected_sum: u32 = (0..n_parties as u32).sum();
+        let testnet = LocalTestNetProd { nodes };
+        testnet
+            .simulate_network_round(move |net| {
+                let relay = relay.clone();
+                async move {
+                    let sid = MultiplexedStreamID::ONE;
+                    for round in 0..3 {
+                        if round == 1 && net.party_id() == 1 {
+                            relay.abort();
+                        }
+
+                        let bytes =
+                            bincode2::serialize(&net.party_id()).unwrap();
+                        let sums = net
+                            .client_send_or_king_receive(&bytes, sid)
+                            .await
+                            .unwrap()
+                            .map(|ids| {
+                                let sum = ids
+                                    .iter()
+                                    .map(|id| {
+                                        bincode2::deserialize::<u32>(id)
+                                            .unwrap()
+                                    })
+                                    .sum::<u32>();
+                                let sum: Bytes =
+                                    bincode2::serialize(&sum).unwrap().into();
+                                vec![sum; n_parties]
+                            });
+                        let sum = net
+                            .client_receive_or_king_send(sums, sid)
+                            .await
+                            .unwrap();
+                        let sum: u32 = bincode2::deserialize(&sum).unwrap();
+                        assert_eq!(sum, expected_sum);
+                    }
+                }
+            })
+            .await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_resuming_outlasts_attempts_within_the_op_timeout() {
+        let (king_io, peer_io, relay) = breakable_channel_pair();
+        let (king, peer) = tokio::try_join!(
+            ProdNet::new_from_connections(
+                0,
+                0,
+                2,
+                vec![king_io],
+                None,
+                MULTIPLEXED_STREAMS
+            ),
+            ProdNet::new_from_pre_existing_connection(1, 2, vec![peer_io]),
+        )
+        .unwrap();
+        let (mut king, mut peer) = (king, peer);
+
+        // A single attempt is over long before the king is reachable again,
+        // but the operations may take much longer
+        let config = ResumeConfig {
+            max_attempts: 1,
+            retry_delay: Duration::from_millis(10),
+            ..Default::default()
+        };
+        let (incoming, incoming_rx) = tokio::sync::mpsc::channel(1);
+        let dials = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let dialer: Dialer<ChannelIO> = Arc::new(move || {
+            let incoming = incoming.clone();
+            let dials = dials.clone();
+            async move {
+                if dials.fetch_add(1, std::sync::atomic::Ordering::SeqCst) < 20
+                {
+                    return Err(MpcNetError::NotConnected);
+                }
+                let (king_end, peer_end, _relay) = breakable_channel_pair();
+                incoming
+                    .send(king_end)
+                    .await
+                    .map_err(|_| MpcNetError::NotConnected)?;
+                Ok(peer_end)
+            }
+            .boxed()
+        });
+        peer.resume_by_dialing(dialer, config).unwrap();
+        king.resume_by_accepting(incoming_rx, config).unwrap();
+        for net in [&mut king, &mut peer] {
+            net.set_op_timeout(Duration::from_secs(30));
+        }
+
+        // The king only listens on this stream, so the frames the peer sends
+        // on it are only acknowledged on their own or when resuming
+        let sid = MultiplexedStreamID::ONE;
+        let king_task = async {
+            for i in 0..200u32 {
+                let bytes = king.recv_from(1, sid).await.unwrap();
+                assert_eq!(bincode2::deserialize::<u32>(&bytes).unwrap(), i);
+            }
+            king.send_to(1, Bytes::new(), sid).await.unwrap();
+        };
+        let peer_task = async {
+            for i in 0..200u32 {
+                if i == 100 {
+                    relay.abort();
+                }
+                let bytes = bincode2::serialize(&i).unwrap().into();
+                peer.send_to(0, bytes, sid).await.unwrap();
+            }
+            peer.recv_from(0, sid).await.unwrap();
+        };
+        tokio::join!(king_task, peer_task);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_replay_buffer_stays_bounded_when_only_sending() {
+        let (king_io, peer_io, _relay) = breakable_channel_pair();
+        let (king, peer) = tokio::try_join!(
+            ProdNet::new_from_connections(
+                0,
+                0,
+                2,
+                vec![king_io],
+                None,
+                MULTIPLEXED_STREAMS
+            ),
+            ProdNet::new_from_pre_existing_connection(1, 2, vec![peer_io]),
+        )
+        .unwrap();
+        let (mut king, mut peer) = (king, peer);
+
+        let limit = ReplayLimit {
+            frames: 8,
+            ..Default::default()
+        };
+        let config = ResumeConfig {
+            retry_delay: Duration::from_millis(10),
+            replay_limit: limit,
+            ..Default::default()
+        };
+        let (_incoming, incoming_rx) = tokio::sync::mpsc::channel(1);
+        let dialer: Dialer<ChannelIO> = Arc::new(|| {
+            async { Err::<ChannelIO, _>(MpcNetError::NotConnected) }.boxed()
+        });
+        peer.resume_by_dialing(dialer, config).unwrap();
+        king.resume_by_accepting(incoming_rx, config).unwrap();
+        for net in [&mut king, &mut peer] {
+            net.set_op_timeout(Duration::from_secs(30));
+        }
+
+        // The king receives slowly and never sends a frame back, so the peer
+        // has to wait for acknowledgements sent on their own
+        let sid = MultiplexedStreamID::ONE;
+        let king_task = async {
+            for i in 0..100u32 {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+                let bytes = king.recv_from(1, sid).await.unwrap();
+                assert_eq!(bincode2::deserialize::<u32>(&bytes).unwrap(), i);
+            }
+        };
+        let peer_task = async {
+            for i in 0..100u32 {
+                let bytes = bincode2::serialize(&i).unwrap().into();
+                peer.send_to(0, bytes, sid).await.unwrap();
+                let kept = peer.connections.session.unacknowledged(0, sid);
+                assert!(kept <= limit.frames, "{kept} frames kept for replay");
+            }
+        };
+        tokio::join!(king_task, peer_task);
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     async fn test_network_init() {
         let _ = init_network(3).await;
@@ -513,6 +1884,258 @@ mod test {
         add_protocol_inner(testnet, expected_result, N_PEERS).await;
     }
 
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_king_rejects_impersonation() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let king_addr = listener.local_addr().unwrap();
+        let server_identity = generate_rustls_identity();
+        let mut server_cert = RootCertStore::empty();
+        server_cert.add(&server_identity.cert).unwrap();
+
+        let honest = generate_rustls_identity();
+        let impostor = generate_rustls_identity();
+        let mut roster = PeerRoster::new();
+        roster.add_certificate(1, honest.cert.clone()).unwrap();
+        roster.add_certificate(2, impostor.cert.clone()).unwrap();
+
+        let king = tokio::spawn(ProdNet::new_king_tls_with_listener(
+            0,
+            listener,
+            server_identity,
+            roster,
+            MULTIPLEXED_STREAMS,
+        ));
+
+        // Party 2 claims to be party 1, and is turned away
+        let rejected = ProdNet::new_peer_tls(
+            1,
+            king_addr,
+            impostor.clone(),
+            server_cert.clone(),
+            3,
+        )
+        .await;
+        assert!(rejected.is_err());
+
+        // The king keeps accepting, so both parties can still join
+        let peers = [(1, honest), (2, impostor)].map(|(id, identity)| {
+            tokio::spawn(ProdNet::new_peer_tls(
+                id,
+                king_addr,
+                identity,
+                server_cert.clone(),
+                3,
+            ))
+        });
+        let king = king.await.unwrap().unwrap();
+        assert_eq!(king.n_parties(), 3);
+        // Nothing is kept for replay unless asked for
+        assert!(king.resumer.is_none());
+        for peer in peers {
+            let peer = peer.await.unwrap().unwrap();
+            assert_eq!(peer.king_id(), 0);
+            assert!(peer.resumer.is_none());
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_king_without_a_quorum_gives_up() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut roster = PeerRoster::new();
+        for id in 1..=2 {
+            roster
+                .add_certificate(id, generate_rustls_identity().cert)
+                .unwrap();
+        }
+
+        let quorum = Quorum::majority(3, Duration::from_millis(50));
+        let result = ProdNet::new_king_tls_with_quorum(
+            0,
+            listener,
+            generate_rustls_identity(),
+            roster,
+            MULTIPLEXED_STREAMS,
+            Some(quorum),
+            None,
+        )
+        .await;
+        assert!(matches!(result, Err(MpcNetError::Generic(_))));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_king_listens_only_while_resuming() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let king_addr = listener.local_addr().unwrap();
+        let server_identity = generate_rustls_identity();
+        let mut server_cert = RootCertStore::empty();
+        server_cert.add(&server_identity.cert).unwrap();
+        let peer_identity = generate_rustls_identity();
+        let mut roster = PeerRoster::new();
+        roster
+            .add_certificate(1, peer_identity.cert.clone())
+            .unwrap();
+
+        let config = ResumeConfig::default();
+        let king = ProdNet::new_king_tls_resumable(
+            0,
+            listener,
+            server_identity,
+            roster,
+            MULTIPLEXED_STREAMS,
+            config,
+        );
+        let peer = ProdNet::new_peer_tls_with_king(
+            1,
+            0,
+            king_addr,
+            peer_identity,
+            server_cert,
+            2,
+            None,
+            Some(config),
+        );
+        let (king, peer) = tokio::try_join!(king, peer).unwrap();
+        assert!(king.resumer.is_some() && peer.resumer.is_some());
+
+        // Every peer is connected, so there is nothing to accept
+        assert!(TcpStream::connect(king_addr).await.is_err());
+    }
+
+    #[test]
+    fn test_roster_rejects_duplicates() {
+        let first = generate_rustls_identity();
+        let second = generate_rustls_identity();
+        let mut roster = PeerRoster::new();
+        roster.add_certificate(1, first.cert.clone()).unwrap();
+
+        assert!(roster.add_certificate(1, second.cert.clone()).is_err());
+        assert!(roster.add_certificate(2, first.cert.clone()).is_err());
+        roster.add_certificate(2, second.cert.clone()).unwrap();
+
+        assert_eq!(roster.len(), 2);
+        assert_eq!(
+            roster.party_id(&PeerIdentity::from_certificate(&second.cert)),
+            Some(2)
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_peers_adopt_king_stream_count() {
+        const N_PEERS: usize = 3;
+        const N_STREAMS: usize = 6;
+        let nodes =
+            init_network_channels_with_streams(N_PEERS, N_STREAMS).await;
+        let testnet = LocalTestNetProd { nodes };
+
+        testnet
+            .simulate_network_round(|net| async move {
+                assert_eq!(net.n_streams(), N_STREAMS);
+                let sid = MultiplexedStreamID::new(N_STREAMS as u32 - 1);
+                let bytes = bincode2::serialize(&net.party_id()).unwrap();
+                let from_all =
+                    net.client_send_or_king_receive(&bytes, sid).await.unwrap();
+                net.client_receive_or_king_send(from_all, sid)
+                    .await
+                    .unwrap();
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_peer_rejects_a_bad_stream_count() {
+        let (mut king_io, peer_io, _relay) = breakable_channel_pair();
+        let king = tokio::spawn(async move {
+            king_io.read_u32().await.unwrap();
+            king_io.write_u32(0).await.unwrap();
+            king_io.write_u32(0).await.unwrap();
+            king_io
+        });
+        let peer =
+            ProdNet::new_from_pre_existing_connection(1, 2, vec![peer_io]);
+
+        match peer.await {
+            Err(MpcNetError::Protocol { party: 0, .. }) => {}
+            Err(err) => panic!("Unexpected error: {:?}", err),
+            Ok(_) => panic!("Peer opened no streams"),
+        }
+        king.await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_any_party_can_be_the_king() {
+        const N_PARTIES: usize = 4;
+        const KING: u32 = 2;
+
+        let mut king_conns = vec![];
+        let mut peers = FuturesOrdered::new();
+        for id in (0..N_PARTIES as u32).filter(|id| *id != KING) {
+            let (king_io, peer_io, _relay) = breakable_channel_pair();
+            king_conns.push(king_io);
+            peers.push_back(Box::pin(ProdNet::new_from_connections(
+                id,
+                KING,
+                N_PARTIES,
+                vec![peer_io],
+                None,
+                MULTIPLEXED_STREAMS,
+            )));
+        }
+        let king = tokio::spawn(ProdNet::new_from_connections(
+            KING,
+            KING,
+            N_PARTIES,
+            king_conns,
+            None,
+            MULTIPLEXED_STREAMS,
+        ))
+        .map_err(|err| MpcNetError::Generic(err.to_string()));
+        let (king, mut nodes) =
+            tokio::try_join!(king, peers.try_collect::<Vec<_>>()).unwrap();
+        nodes.push(king.unwrap());
+
+        let sums = LocalTestNetProd { nodes }
+            .simulate_network_round(|net| async move {
+                assert_eq!(net.king_id(), KING);
+                let sid = MultiplexedStreamID::ONE;
+                let from_all = net
+                    .client_send_or_king_receive(&[net.party_id() as u8], sid)
+                    .await
+                    .unwrap()
+                    .map(|ids| {
+                        let sum = ids.iter().map(|id| id[0]).sum::<u8>();
+                        vec![Bytes::from(vec![sum]); ids.len()]
+                    });
+                net.client_receive_or_king_send(from_all, sid)
+                    .await
+                    .unwrap()
+            })
+            .await;
+        assert!(sums.iter().all(|sum| sum[..] == [6]));
+    }
+
+    #[tokio::test]
+    async fn test_peer_rejects_an_unexpected_king() {
+        let (king_io, peer_io, _relay) = breakable_channel_pair();
+        let king = tokio::spawn(ProdNet::new_from_connections(
+            1,
+            1,
+            3,
+            vec![king_io],
+            None,
+            MULTIPLEXED_STREAMS,
+        ));
+        // Expects party 0 to be the king
+        let peer =
+            ProdNet::new_from_pre_existing_connection(2, 3, vec![peer_io]);
+
+        match peer.await {
+            Err(MpcNetError::Protocol { party: 1, .. }) => {}
+            Err(err) => panic!("Unexpected error: {:?}", err),
+            Ok(_) => panic!("Peer accepted party 1 as the king"),
+        }
+        king.abort();
+    }
+
     async fn add_protocol_inner<T: IOStream>(
         testnet: LocalTestNetProd<T>,
         expected_result: u32,
@@ -525,12 +2148,12 @@ mod test {
                 if let Some(king_recv) = net
                     .client_send_or_king_receive(
                         &bytes,
-                        MultiplexedStreamID::Zero,
+                        MultiplexedStreamID::ZERO,
                     )
                     .await
                     .unwrap()
                 {
-                    assert_eq!(my_id, 0);
+                    assert_eq!(my_id, net.king_id());
                     // convert each bytes into a u32, and sum
                     let mut sum = 0;
                     for bytes in king_recv {
@@ -545,17 +2168,17 @@ mod test {
                         .collect::<Vec<Bytes>>();
                     net.client_receive_or_king_send(
                         Some(send),
-                        MultiplexedStreamID::Zero,
+                        MultiplexedStreamID::ZERO,
                     )
                     .await
                     .unwrap();
                     sum
                 } else {
-                    assert_ne!(my_id, 0);
+                    assert_ne!(my_id, net.king_id());
                     let bytes = net
                         .client_receive_or_king_send(
                             None,
-                            MultiplexedStreamID::Zero,
+                            MultiplexedStreamID::ZERO,
                         )
                         .await
                         .unwrap();
@@ -573,11 +2196,8 @@ mod test {
     async fn init_network(
         n_peers: usize,
     ) -> Vec<ProdNet<TlsStream<TcpStream>>> {
-        let king_addr = TcpListener::bind("127.0.0.1:0")
-            .await
-            .unwrap()
-            .local_addr()
-            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let king_addr = listener.local_addr().unwrap();
         let server_identity = generate_self_signed_cert().unwrap();
         let server_identity = RustlsCertificate {
             cert: rustls::Certificate(server_identity.serialize_der().unwrap()),
@@ -589,31 +2209,25 @@ mod test {
         let mut server_cert = RootCertStore::empty();
         server_cert.add(&server_identity.cert).unwrap();
 
-        let mut client_certs = RootCertStore::empty();
+        let mut roster = PeerRoster::new();
         let mut client_identities = Vec::new();
-        for _ in 0..n_peers {
-            let peer_identity = generate_self_signed_cert().unwrap();
-            let peer_identity = RustlsCertificate {
-                cert: rustls::Certificate(
-                    peer_identity.serialize_der().unwrap(),
-                ),
-                private_key: rustls::PrivateKey(
-                    peer_identity.serialize_private_key_der(),
-                ),
-            };
-            client_certs.add(&peer_identity.cert).unwrap();
+        for i in 0..n_peers {
+            let peer_identity = generate_rustls_identity();
+            roster
+                .add_certificate((i + 1) as u32, peer_identity.cert.clone())
+                .unwrap();
             client_identities.push(peer_identity);
         }
 
-        let king = tokio::spawn(ProdNet::<TlsStream<TcpStream>>::new_king_tls(
-            king_addr,
+        let king = tokio::spawn(ProdNet::new_king_tls_with_listener(
+            0,
+            listener,
             server_identity.clone(),
-            client_certs.clone(),
+            roster,
+            MULTIPLEXED_STREAMS,
         ))
         .map_err(|err| MpcNetError::Generic(err.to_string()));
 
-        tokio::time::sleep(Duration::from_millis(200)).await;
-
         let peers = FuturesUnordered::new();
         for (i, identity) in client_identities.into_iter().enumerate() {
             let peer = ProdNet::new_peer_tls(
@@ -634,6 +2248,13 @@ mod test {
     }
 
     async fn init_network_channels(n_peers: usize) -> Vec<ProdNet<ChannelIO>> {
+        init_network_channels_with_streams(n_peers, MULTIPLEXED_STREAMS).await
+    }
+
+    async fn init_network_channels_with_streams(
+        n_peers: usize,
+        n_streams: usize,
+    ) -> Vec<ProdNet<ChannelIO>> {
         let n_parties = n_peers + 1;
         let mut king_conns = vec![];
         let mut peer_nets = vec![];
@@ -653,8 +2274,8 @@ mod test {
             peer_nets.push(peer);
         }
 
-        let king = tokio::spawn(ProdNet::new_from_pre_existing_connection(
-            0, n_parties, king_conns,
+        let king = tokio::spawn(ProdNet::new_from_connections(
+            0, 0, n_parties, king_conns, None, n_streams,
         ))
         .map_err(|err| MpcNetError::Generic(err.to_string()));
 
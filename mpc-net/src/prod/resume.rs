@@ -0,0 +1,516 @@
+//! Resuming a [`ProdNet`] session over a new connection.
+//!
+//! Every frame is kept in the session's replay buffer until the peer
+//! acknowledges it, which it does on every frame it sends back, and on its
+//! own once enough frames are unacknowledged. When a connection dies, the
+//! peer dials the king again (or the king waits for it to), both exchange
+//! how many frames they have received on each stream, and replay the rest
+//! before continuing.
+
+use super::*;
+use crate::session::{ReplayLimit, HANDSHAKE_TIMEOUT};
+use futures::future::BoxFuture;
+use futures::stream::{FuturesUnordered, StreamExt};
+use log::{info, warn};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+
+/// Opens a new connection to the king, for peers
+pub type Dialer<T> =
+    Arc<dyn Fn() -> BoxFuture<'static, Result<T, MpcNetError>> + Send + Sync>;
+
+/// A peer keeps dialing for `max_attempts * retry_delay`, and the king waits
+/// up to `retry_delay` for the peer on each of its attempts. Either keeps
+/// trying for longer if the operation that lost the connection may take
+/// longer, as set by [`ProdNet::set_op_timeout`] and
+/// [`ProdNet::set_session_timeout`]. The king only listens for reconnections
+/// while it is resuming with a peer, so a peer that notices the loss first is
+/// refused until the king does too.
+#[derive(Clone, Copy, Debug)]
+pub struct ResumeConfig {
+    /// How often to try reconnecting at least before giving up
+    pub max_attempts: usize,
+    /// How long to wait between attempts
+    pub retry_delay: Duration,
+    /// How much to keep for replay on each stream, the same for all parties
+    pub replay_limit: ReplayLimit,
+}
+
+impl Default for ResumeConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 10,
+            retry_delay: Duration::from_millis(500),
+            replay_limit: ReplayLimit::default(),
+        }
+    }
+}
+
+pub(super) enum Reconnect<T> {
+    Dial(Dialer<T>),
+    /// Authenticated connections, routed to the party they belong to
+    Accept(HashMap<u32, Mutex<mpsc::Receiver<T>>>),
+}
+
+pub(super) struct Link {
+    /// Bumped every time the connection is replaced
+    generation: AtomicU64,
+    resuming: Mutex<()>,
+    /// Set while the connection is being replaced
+    replacing: watch::Sender<bool>,
+}
+
+impl Default for Link {
+    fn default() -> Self {
+        Self {
+            generation: Default::default(),
+            resuming: Default::default(),
+            replacing: watch::channel(false).0,
+        }
+    }
+}
+
+pub(super) struct Resumer<T> {
+    pub(super) config: ResumeConfig,
+    reconnect: Reconnect<T>,
+    links: HashMap<u32, Link>,
+    /// How many peers are being resumed with
+    resuming: watch::Sender<usize>,
+    pub(super) tasks: Vec<JoinHandle<()>>,
+}
+
+impl<T> Resumer<T> {
+    /// Follows how many peers are being resumed with
+    pub(super) fn resuming(&self) -> watch::Receiver<usize> {
+        self.resuming.subscribe()
+    }
+
+    /// Stops accepting connections to resume with
+    pub(super) fn stop(&self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+impl<T> Drop for Resumer<T> {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+impl<T: IOStream> ProdNet<T> {
+    /// Lets a peer survive the loss of its connection to the king, by calling
+    /// `dialer` for a new one and resuming the session over it
+    pub fn resume_by_dialing(
+        &mut self,
+        dialer: Dialer<T>,
+        config: ResumeConfig,
+    ) -> Result<(), MpcNetError> {
+        if self.is_king() {
+            return Err(MpcNetError::BadInput {
+                err: "The king can not dial its peers",
+            });
+        }
+
+        self.enable_resume(Reconnect::Dial(dialer), vec![], config);
+        Ok(())
+    }
+
+    /// Lets the king survive the loss of a connection to a peer, by waiting
+    /// for the peer to reconnect through `incoming` and resuming the session
+    /// over the new connection. If the network was set up with a roster, new
+    /// connections are authenticated against it.
+    pub fn resume_by_accepting(
+        &mut self,
+        incoming: mpsc::Receiver<T>,
+        config: ResumeConfig,
+    ) -> Result<(), MpcNetError> {
+        if !self.is_king() {
+            return Err(MpcNetError::BadInput {
+                err: "Only the king accepts connections",
+            });
+        }
+
+        let mut routes = HashMap::new();
+        let mut receivers = HashMap::new();
+        for id in self.connections.peers.keys() {
+            let (tx, rx) = mpsc::channel(4);
+            routes.insert(*id, tx);
+            receivers.insert(*id, Mutex::new(rx));
+        }
+
+        let router = tokio::spawn(route_connections(
+            incoming,
+            routes,
+            self.roster.clone(),
+            self.n_streams(),
+        ));
+        self.enable_resume(Reconnect::Accept(receivers), vec![router], config);
+        Ok(())
+    }
+
+    /// Changes the retry policy of a resumable network
+    pub fn set_resume_config(&mut self, config: ResumeConfig) {
+        if let Some(resumer) = &mut self.resumer {
+            resumer.config = config;
+            let limit = Some(config.replay_limit);
+            self.connections.session.set_replay(limit);
+        }
+    }
+
+    pub(super) fn enable_resume(
+        &mut self,
+        reconnect: Reconnect<T>,
+        tasks: Vec<JoinHandle<()>>,
+        config: ResumeConfig,
+    ) {
+        let links = self
+            .connections
+            .peers
+            .keys()
+            .map(|id| (*id, Link::default()))
+            .collect();
+        self.connections
+            .session
+            .set_replay(Some(config.replay_limit));
+        self.resumer = Some(Resumer {
+            config,
+            reconnect,
+            links,
+            resuming: watch::channel(0).0,
+            tasks,
+        });
+    }
+
+    /// How often the connection to `peer_id` has been replaced
+    pub(super) fn generation(&self, peer_id: u32) -> u64 {
+        self.resumer
+            .as_ref()
+            .and_then(|resumer| resumer.links.get(&peer_id))
+            .map_or(0, |link| link.generation.load(Ordering::SeqCst))
+    }
+
+    /// Resolves once the connection to `peer_id` is being replaced, so that
+    /// an operation blocked on the old connection can let go of its stream
+    pub(super) async fn replacing(&self, peer_id: u32) {
+        let link = self
+            .resumer
+            .as_ref()
+            .and_then(|resumer| resumer.links.get(&peer_id));
+        if let Some(link) = link {
+            let mut replacing = link.replacing.subscribe();
+            // We hold the sender, so the channel cannot close
+            let _ = replacing.wait_for(|replacing| *replacing).await;
+        }
+        futures::future::pending().await
+    }
+
+    /// Called after an operation on the connection to `peer_id` failed with
+    /// `err`. Replaces the connection, unless that has happened since the
+    /// connection was at `generation`. Fails with `err` if the network is not
+    /// resumable.
+    pub(super) async fn resume(
+        &self,
+        peer_id: u32,
+        generation: u64,
+        err: MpcNetError,
+    ) -> Result<(), MpcNetError> {
+        // A connection the peer closed on purpose is not coming back
+        let session = &self.connections.session;
+        if session.is_shut_down() || session.peer_shut_down(peer_id) {
+            return Err(err);
+        }
+
+        let (resumer, link) = match &self.resumer {
+            Some(resumer) => match resumer.links.get(&peer_id) {
+                Some(link) => (resumer, link),
+                None => return Err(err),
+            },
+            None => return Err(err),
+        };
+
+        let _resuming = link.resuming.lock().await;
+        if link.generation.load(Ordering::SeqCst) != generation {
+            return Ok(());
+        }
+
+        warn!("Lost connection to party {peer_id}: {err:?}, resuming");
+        resumer.resuming.send_modify(|n| *n += 1);
+        let _resumed = Resumed(&resumer.resuming);
+        link.replacing.send_replace(true);
+        let _replaced = Replaced(&link.replacing);
+
+        // Keep trying for as long as the operation that lost the connection
+        // may take
+        let config = resumer.config;
+        let give_up_at = session
+            .time_left()
+            .map(|time_left| tokio::time::Instant::now() + time_left);
+        let may_retry = |attempt| {
+            attempt < config.max_attempts
+                || give_up_at.is_some_and(|give_up_at| {
+                    tokio::time::Instant::now() + config.retry_delay
+                        < give_up_at
+                })
+        };
+        let mut attempt = 1;
+        loop {
+            match self.try_resume(resumer, link, peer_id).await {
+                Ok(()) => break,
+                Err(err) if may_retry(attempt) => {
+                    warn!("Attempt {attempt} to resume with party {peer_id} failed: {err:?}");
+                    attempt += 1;
+                    tokio::time::sleep(config.retry_delay).await;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        info!("Resumed session with party {peer_id}");
+        Ok(())
+    }
+
+    async fn try_resume(
+        &self,
+        resumer: &Resumer<T>,
+        link: &Link,
+        peer_id: u32,
+    ) -> Result<(), MpcNetError> {
+        let n_streams = self.n_streams();
+        let stream = match &resumer.reconnect {
+            Reconnect::Dial(dialer) => {
+                let mut stream = dialer().await?;
+                stream.write_u32(self.party_id()).await?;
+                let king_streams = stream.read_u32().await? as usize;
+                if king_streams != n_streams {
+                    return Err(MpcNetError::Protocol {
+                        err: format!(
+                            "King now wants {king_streams} streams instead of {n_streams}"
+                        ),
+                        party: peer_id,
+                    });
+                }
+                stream
+            }
+            Reconnect::Accept(receivers) => {
+                let mut receiver = receivers
+                    .get(&peer_id)
+                    .ok_or(MpcNetError::NotConnected)?
+                    .lock()
+                    .await;
+                tokio::time::timeout(
+                    resumer.config.retry_delay,
+                    receiver.recv(),
+                )
+                .await
+                .map_err(|_| MpcNetError::Timeout { party: peer_id })?
+                .ok_or(MpcNetError::NotConnected)?
+            }
+        };
+
+        let (new_streams, worker) =
+            multiplex_stream(n_streams, self.is_king(), stream).await?;
+        self.connections.workers.lock().push(worker);
+
+        // Tell each other how far we got
+        let session = &self.connections.session;
+        let received = session.received(peer_id, n_streams);
+        send_packet(
+            Some(&new_streams),
+            MultiplexedStreamID::ZERO,
+            ProtocolPacket::Resume {
+                session_id: session.session_id(),
+                received,
+            },
+        )
+        .await?;
+        let their_received =
+            match recv_packet(Some(&new_streams), MultiplexedStreamID::ZERO)
+                .await?
+            {
+                ProtocolPacket::Resume {
+                    session_id,
+                    received,
+                } if session_id == session.session_id()
+                    && received.len() == n_streams =>
+                {
+                    received
+                }
+                packet => {
+                    return Err(MpcNetError::Protocol {
+                        err: format!(
+                            "Can not resume the session from {packet:?}"
+                        ),
+                        party: peer_id,
+                    })
+                }
+            };
+
+        // Swap in the new streams, and replay what the peer has missed before
+        // anyone else gets to use them. Operations still waiting on the old
+        // streams were cancelled when we started, so they don't hold them.
+        let old_streams = self
+            .connections
+            .peers
+            .get(&peer_id)
+            .and_then(|peer| peer.streams.as_ref())
+            .ok_or(MpcNetError::NotConnected)?;
+        let mut guards = Vec::with_capacity(n_streams);
+        for (old, new) in old_streams.iter().zip(new_streams) {
+            let mut guard = old.lock().await;
+            *guard = new.into_inner();
+            guards.push(guard);
+        }
+
+        for (sid, (guard, from)) in
+            guards.iter_mut().zip(their_received).enumerate()
+        {
+            let sid = MultiplexedStreamID::new(sid as u32);
+            for frame in session.replay(peer_id, sid, from)? {
+                send_chunked(&mut **guard, &[FRAME], &frame).await?;
+            }
+        }
+
+        link.generation.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+/// Reads the party ID announced on every new connection, authenticates it if
+/// there is a roster, and hands the connection to the party's resumption.
+/// Connections are greeted side by side, each within [`HANDSHAKE_TIMEOUT`], so
+/// one that never announces itself does not hold up the others.
+async fn route_connections<T: IOStream>(
+    mut incoming: mpsc::Receiver<T>,
+    routes: HashMap<u32, mpsc::Sender<T>>,
+    roster: Option<PeerRoster>,
+    n_streams: usize,
+) {
+    let mut greetings = FuturesUnordered::new();
+    loop {
+        let greeted = tokio::select! {
+            stream = incoming.recv() => match stream {
+                Some(stream) => {
+                    let greeting =
+                        greet_reconnection(stream, &routes, &roster, n_streams);
+                    greetings.push(tokio::time::timeout(
+                        HANDSHAKE_TIMEOUT,
+                        greeting,
+                    ));
+                    continue;
+                }
+                None => return,
+            },
+            Some(greeted) = greetings.next() => greeted,
+        };
+
+        match greeted {
+            Ok(Ok((peer_id, stream))) => {
+                if let Some(route) = routes.get(&peer_id) {
+                    if route.try_send(stream).is_err() {
+                        warn!("Dropped a reconnection from party {peer_id}");
+                    }
+                }
+            }
+            Ok(Err(err)) => warn!("Rejected a reconnection: {err:?}"),
+            Err(_) => warn!("Handshake of a reconnection timed out"),
+        }
+    }
+}
+
+/// Reads the party ID `stream` announces and checks that it may resume
+async fn greet_reconnection<T: IOStream>(
+    mut stream: T,
+    routes: &HashMap<u32, mpsc::Sender<T>>,
+    roster: &Option<PeerRoster>,
+    n_streams: usize,
+) -> Result<(u32, T), MpcNetError> {
+    let announced_id = stream.read_u32().await?;
+    let peer_id = match roster {
+        Some(roster) => authenticated_party_id(&stream, announced_id, roster)?,
+        None => announced_id,
+    };
+
+    // Only peers of the king resume, never the king itself
+    if !routes.contains_key(&peer_id) {
+        return Err(MpcNetError::Protocol {
+            err: format!("Invalid party ID {peer_id}"),
+            party: peer_id,
+        });
+    }
+
+    stream.write_u32(n_streams as u32).await?;
+    Ok((peer_id, stream))
+}
+
+/// Counts a resumption as finished when dropped
+struct Resumed<'a>(&'a watch::Sender<usize>);
+
+impl Drop for Resumed<'_> {
+    fn drop(&mut self) {
+        self.0.send_modify(|n| *n -= 1);
+    }
+}
+
+/// Marks a connection as no longer being replaced when dropped
+struct Replaced<'a>(&'a watch::Sender<bool>);
+
+impl Drop for Replaced<'_> {
+    fn drop(&mut self) {
+        self.0.send_replace(false);
+    }
+}
+
+/// Listens on `addr` while any peer is being resumed with, and passes the TLS
+/// connections it accepts on to `incoming`. The listener is closed again once
+/// every peer is back.
+pub(super) async fn accept_tls_connections(
+    addr: SocketAddr,
+    acceptor: TlsAcceptor,
+    incoming: mpsc::Sender<TlsStream<TcpStream>>,
+    mut resuming: watch::Receiver<usize>,
+) {
+    while resuming.wait_for(|n| *n > 0).await.is_ok() {
+        let listener = match TcpListener::bind(addr).await {
+            Ok(listener) => listener,
+            Err(err) => {
+                warn!("Can not listen for reconnections on {addr}: {err:?}");
+                tokio::time::sleep(DIAL_RETRY_DELAY).await;
+                continue;
+            }
+        };
+
+        loop {
+            let stream = tokio::select! {
+                _ = resuming.wait_for(|n| *n == 0) => break,
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, _)) => stream,
+                    Err(err) => {
+                        warn!("Error accepting a reconnection: {err:?}");
+                        continue;
+                    }
+                },
+            };
+
+            match tokio::time::timeout(
+                HANDSHAKE_TIMEOUT,
+                acceptor.accept(stream),
+            )
+            .await
+            {
+                Ok(Ok(stream)) => {
+                    if incoming.send(TlsStream::Server(stream)).await.is_err() {
+                        return;
+                    }
+                }
+                Ok(Err(err)) => {
+                    warn!("TLS handshake of a reconnection failed: {err:?}")
+                }
+                Err(_) => warn!("TLS handshake of a reconnection timed out"),
+            }
+        }
+    }
+}
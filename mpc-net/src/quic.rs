@@ -0,0 +1,669 @@
+//! A star network over QUIC, authenticated with the same certificates as
+//! [`crate::prod::ProdNet`].
+//!
+//! Every [`MultiplexedStreamID`] is its own QUIC stream, so a message that is
+//! held up on one stream does not hold up the others the way it does on a
+//! single TCP connection.
+
+use std::collections::HashMap;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::warn;
+use quinn::{
+    Connection, ConnectionError, Endpoint, EndpointConfig, RecvStream,
+    SendStream, TokioRuntime, TransportConfig, VarInt,
+};
+use rustls::RootCertStore;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::Mutex;
+use tokio_util::bytes::Bytes;
+use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
+
+use crate::chunk::{recv_chunked, send_chunked};
+use crate::compress::CompressionConfig;
+use crate::multi::{MAX_STREAMS, MULTIPLEXED_STREAMS};
+use crate::prod::{CertToDer, PeerIdentity, PeerRoster};
+use crate::session::{ABORT_TIMEOUT, HANDSHAKE_TIMEOUT, SHUTDOWN_TIMEOUT};
+use crate::{
+    MpcNet, MpcNetError, MultiplexedStreamID, NetStats, SessionTracker,
+    WireCodec,
+};
+
+/// How often an idle connection is kept alive, so that it survives long
+/// stretches of local computation
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The application error code of connections closed by
+/// [`MpcNet::shutdown`], as opposed to a dropped network
+const SHUTDOWN_CODE: VarInt = VarInt::from_u32(1);
+
+/// One direction of a QUIC stream is written while the other is read, so
+/// each has its own lock
+#[derive(Debug)]
+struct QuicStream {
+    send: Mutex<FramedWrite<SendStream, LengthDelimitedCodec>>,
+    recv: Mutex<FramedRead<RecvStream, LengthDelimitedCodec>>,
+}
+
+impl QuicStream {
+    fn new(send: SendStream, recv: RecvStream) -> Self {
+        Self {
+            send: Mutex::new(FramedWrite::new(send, codec())),
+            recv: Mutex::new(FramedRead::new(recv, codec())),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct QuicPeer {
+    connection: Connection,
+    /// Indexed by stream ID
+    streams: Vec<QuicStream>,
+}
+
+/// One party of a star network over QUIC
+#[derive(Debug)]
+pub struct QuicNet {
+    id: u32,
+    n_parties: usize,
+    n_streams: usize,
+    wire_codec: WireCodec,
+    session: SessionTracker,
+    endpoint: Endpoint,
+    peers: HashMap<u32, QuicPeer>,
+}
+
+impl QuicNet {
+    /// Returns when all the parties in the roster have connected
+    pub async fn new_king<R: CertToDer>(
+        bind_addr: SocketAddr,
+        identity: R,
+        roster: PeerRoster,
+    ) -> Result<Self, MpcNetError> {
+        Self::new_king_with_socket(
+            UdpSocket::bind(bind_addr)?,
+            identity,
+            roster,
+            MULTIPLEXED_STREAMS,
+        )
+        .await
+    }
+
+    /// Like [`QuicNet::new_king`], but listens on an already bound socket
+    /// and opens `n_streams` streams to every peer. Peers adopt the king's
+    /// stream count.
+    pub async fn new_king_with_socket<R: CertToDer>(
+        socket: UdpSocket,
+        identity: R,
+        roster: PeerRoster,
+        n_streams: usize,
+    ) -> Result<Self, MpcNetError> {
+        if !(1..=MAX_STREAMS).contains(&n_streams) {
+            return Err(MpcNetError::BadInput {
+                err:
+                    "Must open between 1 and MAX_STREAMS streams per connection",
+            });
+        }
+
+        let crypto = rustls::ServerConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&[&rustls::version::TLS13])?
+            .with_client_cert_verifier(roster.client_verifier()?)
+            .with_single_cert(
+                vec![rustls::Certificate(
+                    identity.serialize_certificate_to_der()?,
+                )],
+                rustls::PrivateKey(identity.serialize_private_key_to_der()?),
+            )?;
+        let mut server_config =
+            quinn::ServerConfig::with_crypto(Arc::new(crypto));
+        let mut transport = transport_config();
+        // Peers open every stream, plus one for the handshake
+        transport.max_concurrent_bidi_streams((n_streams as u32 + 1).into());
+        server_config.transport_config(Arc::new(transport));
+
+        let endpoint = Endpoint::new(
+            EndpointConfig::default(),
+            Some(server_config),
+            socket,
+            Arc::new(TokioRuntime),
+        )?;
+
+        let n_parties = roster.len() + 1;
+        let mut peers = HashMap::new();
+        while peers.len() < roster.len() {
+            let connecting = endpoint.accept().await.ok_or_else(|| {
+                MpcNetError::Generic("Endpoint was closed".to_string())
+            })?;
+            let peer_addr = connecting.remote_address();
+            let handshake = async {
+                let connection = connecting.await?;
+                accept_peer(connection, &roster, n_parties, n_streams).await
+            };
+
+            // A party that fails to connect must not keep the others out
+            let (peer_id, peer) = match tokio::time::timeout(
+                HANDSHAKE_TIMEOUT,
+                handshake,
+            )
+            .await
+            {
+                Ok(Ok(accepted)) => accepted,
+                Ok(Err(err)) => {
+                    warn!("Rejected a connection from {peer_addr}: {err:?}");
+                    continue;
+                }
+                Err(_) => {
+                    warn!("Handshake with {peer_addr} timed out");
+                    continue;
+                }
+            };
+            if peers.contains_key(&peer_id) {
+                warn!("Party {peer_id} connected twice");
+                continue;
+            }
+            peers.insert(peer_id, peer);
+        }
+
+        Ok(Self {
+            id: 0,
+            n_parties,
+            n_streams,
+            wire_codec: WireCodec::default(),
+            session: SessionTracker::default(),
+            endpoint,
+            peers,
+        })
+    }
+
+    /// Connects to the king at `king_addr`, which must present a certificate
+    /// in `server_certs`
+    pub async fn new_peer<R: CertToDer>(
+        id: u32,
+        king_addr: SocketAddr,
+        identity: R,
+        server_certs: RootCertStore,
+        n_parties: usize,
+    ) -> Result<Self, MpcNetError> {
+        if id == 0 {
+            return Err(MpcNetError::BadInput {
+                err: "Party ID 0 is reserved for the king",
+            });
+        }
+
+        let crypto = rustls::ClientConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&[&rustls::version::TLS13])?
+            .with_root_certificates(server_certs)
+            .with_client_auth_cert(
+                vec![rustls::Certificate(
+                    identity.serialize_certificate_to_der()?,
+                )],
+                rustls::PrivateKey(identity.serialize_private_key_to_der()?),
+            )?;
+        let mut client_config = quinn::ClientConfig::new(Arc::new(crypto));
+        client_config.transport_config(Arc::new(transport_config()));
+
+        let bind_addr: SocketAddr = if king_addr.is_ipv6() {
+            "[::]:0".parse()?
+        } else {
+            "0.0.0.0:0".parse()?
+        };
+        let endpoint = Endpoint::client(bind_addr)?;
+        let connection = endpoint
+            .connect_with(
+                client_config,
+                king_addr,
+                &king_addr.ip().to_string(),
+            )?
+            .await?;
+
+        // Announce our ID and learn how many streams to open
+        let (mut send, mut recv) = connection.open_bi().await?;
+        send.write_u32(id).await?;
+        let n_streams = recv.read_u32().await? as usize;
+        send.finish().await?;
+        if !(1..=MAX_STREAMS).contains(&n_streams) {
+            return Err(MpcNetError::Protocol {
+                err: format!("Asked to open {n_streams} streams"),
+                party: 0,
+            });
+        }
+
+        let mut streams = Vec::with_capacity(n_streams);
+        for sid in 0..n_streams as u32 {
+            let (mut send, recv) = connection.open_bi().await?;
+            // The king only learns about the stream once it carries data
+            send.write_u32(sid).await?;
+            streams.push(QuicStream::new(send, recv));
+        }
+
+        let peers = HashMap::from([(
+            0,
+            QuicPeer {
+                connection,
+                streams,
+            },
+        )]);
+        Ok(Self {
+            id,
+            n_parties,
+            n_streams,
+            wire_codec: WireCodec::default(),
+            session: SessionTracker::default(),
+            endpoint,
+            peers,
+        })
+    }
+
+    /// The address the endpoint is bound to
+    pub fn local_addr(&self) -> Result<SocketAddr, MpcNetError> {
+        Ok(self.endpoint.local_addr()?)
+    }
+
+    /// Set the codec used to serialize values sent over this network
+    pub fn set_wire_codec(&mut self, codec: WireCodec) {
+        self.wire_codec = codec;
+    }
+
+    /// Set the session ID every frame is tagged with. All parties must agree
+    /// on it.
+    pub fn set_session_id(&mut self, session_id: u64) {
+        self.session.set_session_id(session_id);
+    }
+
+    /// Bound every single send and receive by `timeout`
+    pub fn set_op_timeout(&mut self, timeout: Duration) {
+        self.session.set_op_timeout(Some(timeout));
+    }
+
+    /// Fail every send and receive once `timeout` has passed
+    pub fn set_session_timeout(&mut self, timeout: Duration) {
+        self.session
+            .set_deadline(Some(tokio::time::Instant::now() + timeout));
+    }
+
+    /// Log a trace of every frame sent and received
+    pub fn set_trace(&mut self, trace: bool) {
+        self.session.set_trace(trace);
+    }
+
+    /// Compress payloads sent on every stream with `config`
+    pub fn set_compression(&mut self, config: Option<CompressionConfig>) {
+        self.session.set_default_compression(config);
+    }
+
+    /// Compress payloads sent on `sid` with `config`, whatever is set for the
+    /// other streams
+    pub fn set_stream_compression(
+        &mut self,
+        sid: MultiplexedStreamID,
+        config: Option<CompressionConfig>,
+    ) {
+        self.session.set_compression(sid, config);
+    }
+
+    fn stream(
+        &self,
+        id: u32,
+        sid: MultiplexedStreamID,
+    ) -> Result<&QuicStream, MpcNetError> {
+        self.peers
+            .get(&id)
+            .ok_or_else(|| {
+                MpcNetError::Generic(format!("Peer {} not found", id))
+            })?
+            .streams
+            .get(sid.index())
+            .ok_or_else(|| MpcNetError::Generic("Stream is None".to_string()))
+    }
+
+    /// The next frame from `id` on `sid`, still sealed
+    async fn recv_frame(
+        &self,
+        id: u32,
+        sid: MultiplexedStreamID,
+    ) -> Result<Bytes, MpcNetError> {
+        let stream = self.stream(id, sid)?;
+        self.session
+            .bounded_recv(id, sid, async {
+                recv_chunked(&mut *stream.recv.lock().await)
+                    .await
+                    .map_err(|err| self.closed_by(id).unwrap_or(err))
+            })
+            .await
+    }
+
+    async fn send_frame(
+        &self,
+        id: u32,
+        stream: &QuicStream,
+        frame: Bytes,
+    ) -> Result<(), MpcNetError> {
+        self.session
+            .bounded(id, async {
+                send_chunked(&mut *stream.send.lock().await, &[], &frame).await
+            })
+            .await
+    }
+
+    /// The error for operations with `id`, if it closed the connection on
+    /// shutdown
+    fn closed_by(&self, id: u32) -> Option<MpcNetError> {
+        match self.peers.get(&id)?.connection.close_reason()? {
+            ConnectionError::ApplicationClosed(close)
+                if close.error_code == SHUTDOWN_CODE =>
+            {
+                Some(MpcNetError::ShutDown { party: id })
+            }
+            _ => None,
+        }
+    }
+}
+
+#[async_trait]
+impl MpcNet for QuicNet {
+    fn n_parties(&self) -> usize {
+        self.n_parties
+    }
+
+    fn party_id(&self) -> u32 {
+        self.id
+    }
+
+    fn is_init(&self) -> bool {
+        self.peers
+            .values()
+            .all(|peer| peer.connection.close_reason().is_none())
+    }
+
+    fn n_streams(&self) -> usize {
+        self.n_streams
+    }
+
+    fn wire_codec(&self) -> WireCodec {
+        self.wire_codec
+    }
+
+    fn set_protocol_label(&self, sid: MultiplexedStreamID, label: &str) {
+        self.session.set_label(sid, label)
+    }
+
+    fn stats(&self) -> NetStats {
+        self.session.stats(self.id)
+    }
+
+    async fn recv_from(
+        &self,
+        id: u32,
+        sid: MultiplexedStreamID,
+    ) -> Result<Bytes, MpcNetError> {
+        let frame = self.recv_frame(id, sid).await?;
+        self.session.open(self.id, id, sid, frame)
+    }
+
+    async fn send_to(
+        &self,
+        id: u32,
+        bytes: Bytes,
+        sid: MultiplexedStreamID,
+    ) -> Result<(), MpcNetError> {
+        let stream = self.stream(id, sid)?;
+        let frame = self.session.seal(self.id, id, sid, bytes)?;
+        self.send_frame(id, stream, frame).await
+    }
+
+    async fn recv_sealed(
+        &self,
+        id: u32,
+        sid: MultiplexedStreamID,
+    ) -> Result<Bytes, MpcNetError> {
+        let frame = self.recv_frame(id, sid).await?;
+        self.session.pass_on(self.id, id, sid, frame)
+    }
+
+    async fn send_sealed(
+        &self,
+        id: u32,
+        frame: Bytes,
+        sid: MultiplexedStreamID,
+    ) -> Result<(), MpcNetError> {
+        let stream = self.stream(id, sid)?;
+        self.send_frame(id, stream, frame).await
+    }
+
+    async fn abort(&self, reason: &str) {
+        let abort = match self.session.start_abort(self.id, reason) {
+            Some(abort) => abort,
+            None => return,
+        };
+
+        for (peer, quic_peer) in &self.peers {
+            if *peer == abort.party {
+                continue;
+            }
+            for (sid, stream) in quic_peer.streams.iter().enumerate() {
+                let sid = MultiplexedStreamID::new(sid as u32);
+                let frame = match self
+                    .session
+                    .seal_abort(self.id, *peer, sid, &abort)
+                {
+                    Ok(frame) => frame,
+                    Err(_) => continue,
+                };
+                // Best effort, the peer may be gone already
+                let _ = tokio::time::timeout(ABORT_TIMEOUT, async {
+                    send_chunked(&mut *stream.send.lock().await, &[], &frame)
+                        .await
+                })
+                .await;
+            }
+        }
+    }
+
+    async fn shutdown(&self) -> Result<(), MpcNetError> {
+        if !self.session.start_shutdown(self.id) {
+            return Ok(());
+        }
+
+        for (peer, quic_peer) in &self.peers {
+            for (sid, stream) in quic_peer.streams.iter().enumerate() {
+                let sid = MultiplexedStreamID::new(sid as u32);
+                let frame = self.session.seal_close(self.id, *peer, sid)?;
+                // Best effort, the peer may be gone already
+                let _ = tokio::time::timeout(SHUTDOWN_TIMEOUT, async {
+                    let mut send = stream.send.lock().await;
+                    send_chunked(&mut *send, &[], &frame).await?;
+                    // Returns once the peer has received everything we sent
+                    send.get_mut().finish().await.map_err(MpcNetError::from)
+                })
+                .await;
+            }
+            quic_peer.connection.close(SHUTDOWN_CODE, b"shutdown");
+        }
+
+        let _ =
+            tokio::time::timeout(SHUTDOWN_TIMEOUT, self.endpoint.wait_idle())
+                .await;
+        Ok(())
+    }
+}
+
+/// Runs the king's side of the handshake with a new connection
+async fn accept_peer(
+    connection: Connection,
+    roster: &PeerRoster,
+    n_parties: usize,
+    n_streams: usize,
+) -> Result<(u32, QuicPeer), MpcNetError> {
+    let (mut send, mut recv) = connection.accept_bi().await?;
+    let announced_id = recv.read_u32().await?;
+
+    let identity = connection
+        .peer_identity()
+        .and_then(|identity| {
+            identity.downcast::<Vec<rustls::Certificate>>().ok()
+        })
+        .and_then(|certs| certs.first().map(PeerIdentity::from_certificate))
+        .ok_or_else(|| MpcNetError::Protocol {
+            err: "Peer did not present an identity".to_string(),
+            party: announced_id,
+        })?;
+    let peer_id =
+        roster
+            .party_id(&identity)
+            .ok_or_else(|| MpcNetError::Protocol {
+                err: format!("Identity {identity} is not in the roster"),
+                party: announced_id,
+            })?;
+    if peer_id != announced_id || peer_id as usize >= n_parties {
+        return Err(MpcNetError::Protocol {
+            err: format!(
+                "Peer announced ID {announced_id} but authenticated as {peer_id}"
+            ),
+            party: peer_id,
+        });
+    }
+
+    send.write_u32(n_streams as u32).await?;
+    send.finish().await?;
+
+    let mut streams = (0..n_streams).map(|_| None).collect::<Vec<_>>();
+    for _ in 0..n_streams {
+        let (send, mut recv) = connection.accept_bi().await?;
+        let sid = recv.read_u32().await? as usize;
+        match streams.get_mut(sid) {
+            Some(slot) if slot.is_none() => {
+                *slot = Some(QuicStream::new(send, recv))
+            }
+            _ => {
+                return Err(MpcNetError::Protocol {
+                    err: format!("Invalid or duplicate stream {sid}"),
+                    party: peer_id,
+                })
+            }
+        }
+    }
+
+    let streams = streams.into_iter().map(Option::unwrap).collect();
+    Ok((
+        peer_id,
+        QuicPeer {
+            connection,
+            streams,
+        },
+    ))
+}
+
+fn transport_config() -> TransportConfig {
+    let mut transport = TransportConfig::default();
+    transport.keep_alive_interval(Some(KEEP_ALIVE_INTERVAL));
+    transport
+}
+
+fn codec() -> LengthDelimitedCodec {
+    LengthDelimitedCodec::builder()
+        .big_endian()
+        .length_field_type::<u32>()
+        .new_codec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::try_join_all;
+
+    fn identity() -> crate::prod::RustlsCertificate {
+        let cert =
+            rcgen::generate_simple_self_signed(vec!["127.0.0.1".to_string()])
+                .unwrap();
+        crate::prod::RustlsCertificate {
+            cert: rustls::Certificate(cert.serialize_der().unwrap()),
+            private_key: rustls::PrivateKey(cert.serialize_private_key_der()),
+        }
+    }
+
+    /// Connects a king and `n_parties - 1` peers on localhost
+    async fn cluster(n_parties: usize) -> Vec<QuicNet> {
+        let identities = (0..n_parties).map(|_| identity()).collect::<Vec<_>>();
+        let mut roster = PeerRoster::new();
+        for (id, identity) in identities.iter().enumerate().skip(1) {
+            roster
+                .add_certificate(id as u32, identity.cert.clone())
+                .unwrap();
+        }
+        let mut king_certs = RootCertStore::empty();
+        king_certs.add(&identities[0].cert).unwrap();
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let king_addr = socket.local_addr().unwrap();
+        let king = tokio::spawn(QuicNet::new_king_with_socket(
+            socket,
+            identities[0].clone(),
+            roster,
+            MULTIPLEXED_STREAMS,
+        ));
+        let peers = try_join_all(identities.iter().enumerate().skip(1).map(
+            |(id, identity)| {
+                QuicNet::new_peer(
+                    id as u32,
+                    king_addr,
+                    identity.clone(),
+                    king_certs.clone(),
+                    n_parties,
+                )
+            },
+        ))
+        .await
+        .unwrap();
+
+        let mut nets = vec![king.await.unwrap().unwrap()];
+        nets.extend(peers);
+        nets
+    }
+
+    #[tokio::test]
+    async fn test_king_gathers_and_scatters_over_quic() {
+        let nets = cluster(4).await;
+        let results = try_join_all(nets.iter().map(|net| async move {
+            let sid = MultiplexedStreamID::TWO;
+            let from_all = net
+                .client_send_or_king_receive(&[net.party_id() as u8], sid)
+                .await?;
+            let sums = from_all.map(|from_all| {
+                let sum = from_all.iter().map(|bytes| bytes[0]).sum::<u8>();
+                vec![Bytes::from(vec![sum]); from_all.len()]
+            });
+            net.client_receive_or_king_send(sums, sid).await
+        }))
+        .await
+        .unwrap();
+
+        assert!(results.iter().all(|sum| sum[..] == [6]));
+    }
+
+    #[tokio::test]
+    async fn test_streams_are_independent() {
+        let nets = cluster(2).await;
+        let (king, peer) = (&nets[0], &nets[1]);
+
+        // Nothing is ever sent on stream one, which must not hold up stream
+        // zero
+        let waiting = peer.recv_from(0, MultiplexedStreamID::ONE);
+        let sid = MultiplexedStreamID::ZERO;
+        king.send_to(1, Bytes::from_static(b"zero"), sid)
+            .await
+            .unwrap();
+        tokio::select! {
+            _ = waiting => panic!("Nothing was sent on stream one"),
+            received = peer.recv_from(0, sid) => {
+                assert_eq!(&received.unwrap()[..], b"zero");
+            }
+        }
+    }
+}
@@ -0,0 +1,1161 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use log::debug;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+use tokio::time::Instant;
+use tokio_util::bytes::{BufMut, Bytes, BytesMut};
+
+use crate::chunk::MAX_MESSAGE_LEN;
+use crate::compress::{self, Algorithm, CompressionConfig};
+use crate::stats::{NetStats, StatsRecorder};
+use crate::{MpcNetError, MultiplexedStreamID};
+
+/// Appended to every frame, followed by its length as a u32, so that the
+/// receiver can detect frames that belong to another session, another
+/// protocol, or another round. It goes after the payload so that the payload
+/// need not be copied to make room for it.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct FrameTag {
+    /// The session the sender is in
+    pub session_id: u64,
+    /// The protocol the sender is running on this stream, e.g. "d_msm"
+    pub label: String,
+    /// How many frames the sender has sent to us on this stream before
+    pub round: u64,
+    /// How many frames the sender has received from us on each stream, so
+    /// that streams we only send on are acknowledged too
+    pub acks: Vec<(u32, u64)>,
+    /// Set if the session was aborted, by the sender or a party it heard from
+    pub abort: Option<Abort>,
+    /// Set on the last frame the sender sends on this stream, when it shuts
+    /// down
+    pub close: bool,
+    /// How the payload is compressed, if it is
+    pub compression: Option<Algorithm>,
+    /// The algorithms the sender can decompress
+    pub accepts: Vec<Algorithm>,
+}
+
+/// How long to wait when delivering an abort to a single peer
+pub const ABORT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long to wait when closing a single stream on shutdown
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long a party that connects may take to complete its handshake
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Room to leave behind a payload for the [`FrameTag`] that seals it, so that
+/// sealing does not move the payload. Most tags take less.
+pub const TAG_ROOM: usize = 256;
+
+/// How much is kept for replay to a peer on a single stream. Once either
+/// limit is reached, sending on the stream waits for the peer to acknowledge
+/// frames, which it does at the latest when half of either limit is
+/// unacknowledged. All parties must use the same limits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReplayLimit {
+    /// How many frames
+    pub frames: usize,
+    /// How many bytes of frames, where a single frame may be larger
+    pub bytes: usize,
+}
+
+impl Default for ReplayLimit {
+    fn default() -> Self {
+        Self {
+            frames: 32,
+            bytes: 4 << 20,
+        }
+    }
+}
+
+/// Frames sent to a peer on a stream, with their round numbers, until the
+/// peer acknowledges them
+#[derive(Debug, Default)]
+struct ReplayBuffer {
+    frames: VecDeque<(u64, Bytes)>,
+    bytes: usize,
+}
+
+impl ReplayBuffer {
+    fn push(&mut self, round: u64, frame: Bytes) {
+        self.bytes += frame.len();
+        self.frames.push_back((round, frame));
+    }
+
+    /// Drops the frames before round `from`
+    fn acknowledge(&mut self, from: u64) {
+        while let Some((round, frame)) = self.frames.front() {
+            if *round >= from {
+                break;
+            }
+            self.bytes -= frame.len();
+            self.frames.pop_front();
+        }
+    }
+
+    fn is_full(&self, limit: &ReplayLimit) -> bool {
+        self.frames.len() >= limit.frames || self.bytes >= limit.bytes
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+struct Rounds {
+    sent: u64,
+    received: u64,
+    /// Frames taken off the connection but not opened yet
+    stashed: VecDeque<Bytes>,
+    /// Frames received since we last acknowledged them, and their bytes
+    unacked_frames: usize,
+    unacked_bytes: usize,
+}
+
+impl Rounds {
+    /// Frames received off the connection, opened or not
+    fn delivered(&self) -> u64 {
+        self.received + self.stashed.len() as u64
+    }
+
+    fn count_unacked(&mut self, frame: &Bytes) {
+        self.unacked_frames += 1;
+        self.unacked_bytes += frame.len();
+    }
+}
+
+/// How many frames we have received from `peer` on each stream
+fn delivered_from(
+    rounds: &HashMap<(u32, MultiplexedStreamID), Rounds>,
+    peer: u32,
+) -> Vec<(u32, u64)> {
+    let mut acks = rounds
+        .iter()
+        .filter(|((from, _), r)| *from == peer && r.delivered() > 0)
+        .map(|((_, sid), r)| (sid.0, r.delivered()))
+        .collect::<Vec<_>>();
+    acks.sort_unstable();
+    acks
+}
+
+/// Notes that `peer` was sent `acks`. Streams on which more frames arrived
+/// since still count them as unacknowledged.
+fn mark_acknowledged(
+    rounds: &mut HashMap<(u32, MultiplexedStreamID), Rounds>,
+    peer: u32,
+    acks: &[(u32, u64)],
+) {
+    for &(sid, delivered) in acks {
+        let key = (peer, MultiplexedStreamID::new(sid));
+        if let Some(r) = rounds.get_mut(&key) {
+            if r.delivered() == delivered {
+                r.unacked_frames = 0;
+                r.unacked_bytes = 0;
+            }
+        }
+    }
+}
+
+/// How many frames we have received from `peer` on each stream, which
+/// acknowledges all of them
+fn acknowledge_all(
+    rounds: &mut HashMap<(u32, MultiplexedStreamID), Rounds>,
+    peer: u32,
+) -> Vec<(u32, u64)> {
+    let acks = delivered_from(rounds, peer);
+    mark_acknowledged(rounds, peer, &acks);
+    acks
+}
+
+/// The party that aborted the session first, and why
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Abort {
+    pub party: u32,
+    pub reason: String,
+}
+
+/// Tags outgoing frames and checks the tags of incoming ones. It also tracks
+/// the deadlines of the session and whether it was aborted.
+///
+/// Rounds are counted per peer and per stream, labels are set per stream.
+#[derive(Debug)]
+pub struct SessionTracker {
+    session_id: u64,
+    trace: bool,
+    labels: Mutex<HashMap<MultiplexedStreamID, String>>,
+    rounds: Mutex<HashMap<(u32, MultiplexedStreamID), Rounds>>,
+    op_timeout: Option<Duration>,
+    deadline: Option<Instant>,
+    abort: watch::Sender<Option<Abort>>,
+    abort_forwarded: AtomicBool,
+    /// Our party ID, once we shut down
+    shut_down: watch::Sender<Option<u32>>,
+    /// The streams on which peers told us they shut down
+    closed: Mutex<HashSet<(u32, MultiplexedStreamID)>>,
+    /// Set if frames are kept for replay
+    replay_limit: Option<ReplayLimit>,
+    replay: Mutex<HashMap<(u32, MultiplexedStreamID), ReplayBuffer>>,
+    stats: StatsRecorder,
+    default_compression: Option<CompressionConfig>,
+    compression: HashMap<MultiplexedStreamID, Option<CompressionConfig>>,
+    /// The algorithms each peer told us it can decompress
+    peer_accepts: Mutex<HashMap<u32, Vec<Algorithm>>>,
+}
+
+impl Default for SessionTracker {
+    fn default() -> Self {
+        Self {
+            session_id: 0,
+            trace: false,
+            labels: Default::default(),
+            rounds: Default::default(),
+            op_timeout: None,
+            deadline: None,
+            abort: watch::channel(None).0,
+            abort_forwarded: AtomicBool::new(false),
+            shut_down: watch::channel(None).0,
+            closed: Default::default(),
+            replay_limit: None,
+            replay: Default::default(),
+            stats: Default::default(),
+            default_compression: None,
+            compression: Default::default(),
+            peer_accepts: Default::default(),
+        }
+    }
+}
+
+impl SessionTracker {
+    pub fn new(session_id: u64) -> Self {
+        Self {
+            session_id,
+            ..Default::default()
+        }
+    }
+
+    pub fn session_id(&self) -> u64 {
+        self.session_id
+    }
+
+    pub fn set_session_id(&mut self, session_id: u64) {
+        self.session_id = session_id;
+    }
+
+    /// Log every frame sent and received, with its tag
+    pub fn set_trace(&mut self, trace: bool) {
+        self.trace = trace;
+    }
+
+    /// Keep every frame until the peer acknowledges it, so that it can be
+    /// replayed over a new connection, within `limit`
+    pub fn set_replay(&mut self, limit: Option<ReplayLimit>) {
+        self.replay_limit = limit;
+    }
+
+    /// Whether we keep as much for replay to `peer` on `sid` as we may, so
+    /// the peer has to acknowledge frames before we seal another
+    pub fn replay_full(&self, peer: u32, sid: MultiplexedStreamID) -> bool {
+        let limit = match &self.replay_limit {
+            Some(limit) => limit,
+            None => return false,
+        };
+        self.replay
+            .lock()
+            .get(&(peer, sid))
+            .is_some_and(|buffered| buffered.is_full(limit))
+    }
+
+    /// How many frames to `peer` on `sid` are kept for replay
+    pub fn unacknowledged(&self, peer: u32, sid: MultiplexedStreamID) -> usize {
+        self.replay
+            .lock()
+            .get(&(peer, sid))
+            .map_or(0, |buffered| buffered.frames.len())
+    }
+
+    /// Drops the frames `peer` acknowledged with `acks`, how many frames it
+    /// received on each stream
+    pub fn acknowledge(&self, peer: u32, acks: &[(u32, u64)]) {
+        if self.replay_limit.is_none() {
+            return;
+        }
+        let mut replay = self.replay.lock();
+        for (acked_sid, ack) in acks {
+            let key = (peer, MultiplexedStreamID::new(*acked_sid));
+            if let Some(buffered) = replay.get_mut(&key) {
+                buffered.acknowledge(*ack);
+            }
+        }
+    }
+
+    /// The acknowledgement to send `peer` on its own, if half as much as the
+    /// peer may keep for replay on a stream is unacknowledged
+    pub fn take_ack(&self, peer: u32) -> Option<Vec<(u32, u64)>> {
+        let limit = self.replay_limit?;
+        let mut rounds = self.rounds.lock();
+        let due = rounds.iter().any(|((from, _), r)| {
+            *from == peer
+                && (r.unacked_frames * 2 >= limit.frames
+                    || r.unacked_bytes * 2 >= limit.bytes)
+        });
+        due.then(|| acknowledge_all(&mut rounds, peer))
+    }
+
+    /// Compress payloads sent on every stream with `config`, unless set
+    /// otherwise for the stream
+    pub fn set_default_compression(
+        &mut self,
+        config: Option<CompressionConfig>,
+    ) {
+        self.default_compression = config;
+    }
+
+    /// Compress payloads sent on `sid` with `config`
+    pub fn set_compression(
+        &mut self,
+        sid: MultiplexedStreamID,
+        config: Option<CompressionConfig>,
+    ) {
+        self.compression.insert(sid, config);
+    }
+
+    /// Compresses a payload for `peer` on `sid`, if that is configured, the
+    /// peer can decompress it and it gets smaller
+    fn compress(
+        &self,
+        peer: u32,
+        sid: MultiplexedStreamID,
+        payload: &[u8],
+    ) -> Result<Option<(Algorithm, Vec<u8>)>, MpcNetError> {
+        let config = match self
+            .compression
+            .get(&sid)
+            .copied()
+            .unwrap_or(self.default_compression)
+        {
+            Some(config) if payload.len() >= config.threshold => config,
+            _ => return Ok(None),
+        };
+
+        let peer_accepts = self
+            .peer_accepts
+            .lock()
+            .get(&peer)
+            .is_some_and(|accepts| accepts.contains(&config.algorithm));
+        if !peer_accepts || !config.algorithm.is_supported() {
+            return Ok(None);
+        }
+
+        let compressed = compress::compress(&config, payload)?;
+        if compressed.len() >= payload.len() {
+            return Ok(None);
+        }
+        Ok(Some((config.algorithm, compressed)))
+    }
+
+    /// How many frames we have received from `peer` on each stream, opened
+    /// or stashed
+    pub fn received(&self, peer: u32, n_streams: usize) -> Vec<u64> {
+        let rounds = self.rounds.lock();
+        (0..n_streams as u32)
+            .map(|sid| {
+                rounds
+                    .get(&(peer, MultiplexedStreamID::new(sid)))
+                    .map_or(0, Rounds::delivered)
+            })
+            .collect()
+    }
+
+    /// Keeps a frame received from `peer` on `sid` before anyone asked for
+    /// it, which counts as received. Frames are stashed in order and must be
+    /// opened with [`SessionTracker::open_stashed`] before any frame that
+    /// arrives after them.
+    pub fn stash(&self, peer: u32, sid: MultiplexedStreamID, frame: Bytes) {
+        let mut rounds = self.rounds.lock();
+        let entry = rounds.entry((peer, sid)).or_default();
+        entry.count_unacked(&frame);
+        entry.stashed.push_back(frame);
+    }
+
+    /// The first frame stashed from `peer` on `sid`, if any
+    pub fn unstash(
+        &self,
+        peer: u32,
+        sid: MultiplexedStreamID,
+    ) -> Option<Bytes> {
+        self.rounds
+            .lock()
+            .get_mut(&(peer, sid))
+            .and_then(|rounds| rounds.stashed.pop_front())
+    }
+
+    /// The frames sent to `peer` on `sid` from round `from` on, for a peer
+    /// that has received `from` frames so far
+    pub fn replay(
+        &self,
+        peer: u32,
+        sid: MultiplexedStreamID,
+        from: u64,
+    ) -> Result<Vec<Bytes>, MpcNetError> {
+        let sent = self.rounds.lock().get(&(peer, sid)).map_or(0, |r| r.sent);
+        if from > sent {
+            return Err(MpcNetError::Protocol {
+                err: format!(
+                    "Claims to have received {from} frames on stream {}, but only {sent} were sent",
+                    sid.0
+                ),
+                party: peer,
+            });
+        }
+
+        let mut replay = self.replay.lock();
+        let buffered = replay.entry((peer, sid)).or_default();
+        buffered.acknowledge(from);
+        if buffered.frames.len() as u64 != sent - from {
+            return Err(MpcNetError::Generic(format!(
+                "Frames to party {peer} on stream {} were evicted from the replay buffer",
+                sid.0
+            )));
+        }
+
+        Ok(buffered
+            .frames
+            .iter()
+            .map(|(_, frame)| frame.clone())
+            .collect())
+    }
+
+    /// Bound every single send and receive by `timeout`
+    pub fn set_op_timeout(&mut self, timeout: Option<Duration>) {
+        self.op_timeout = timeout;
+    }
+
+    /// Fail every send and receive after `deadline`
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+    }
+
+    /// How long the next operation may take, if it is bounded at all
+    pub fn time_left(&self) -> Option<Duration> {
+        let until_deadline = self
+            .deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        match (self.op_timeout, until_deadline) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// Runs an operation with `party`, failing with [`MpcNetError::Timeout`]
+    /// once time is up and with [`MpcNetError::Aborted`] as soon as the
+    /// session is aborted. Fails with [`MpcNetError::ShutDown`] if either
+    /// side has shut down.
+    pub async fn bounded<T>(
+        &self,
+        party: u32,
+        op: impl Future<Output = Result<T, MpcNetError>>,
+    ) -> Result<T, MpcNetError> {
+        if let Some(err) = self.shut_down_err(party, None) {
+            return Err(err);
+        }
+        self.run(party, op).await
+    }
+
+    async fn run<T>(
+        &self,
+        party: u32,
+        op: impl Future<Output = Result<T, MpcNetError>>,
+    ) -> Result<T, MpcNetError> {
+        if let Some(err) = self.aborted() {
+            return Err(err);
+        }
+
+        let op = async {
+            match self.time_left() {
+                Some(limit) => tokio::time::timeout(limit, op)
+                    .await
+                    .map_err(|_| MpcNetError::Timeout { party })?,
+                None => op.await,
+            }
+        };
+
+        let result = tokio::select! {
+            result = op => result,
+            err = self.wait_aborted() => Err(err),
+        };
+
+        // A connection that dies after the peer said goodbye was closed by it
+        match result {
+            Err(MpcNetError::Aborted { .. }) => result,
+            Err(_) if self.peer_shut_down(party) => {
+                Err(MpcNetError::ShutDown { party })
+            }
+            result => result,
+        }
+    }
+
+    /// Like [`SessionTracker::bounded`], for receiving from `party` on `sid`.
+    /// Records how long we waited. Frames the peer sent on `sid` before it
+    /// shut down can still be received, and a pending receive fails as soon as
+    /// we shut down.
+    pub async fn bounded_recv<T>(
+        &self,
+        party: u32,
+        sid: MultiplexedStreamID,
+        op: impl Future<Output = Result<T, MpcNetError>>,
+    ) -> Result<T, MpcNetError> {
+        if let Some(err) = self.shut_down_err(party, Some(sid)) {
+            return Err(err);
+        }
+
+        let started = Instant::now();
+        let result = tokio::select! {
+            result = self.run(party, op) => result,
+            err = self.wait_shut_down() => Err(err),
+        };
+        let waited = started.elapsed();
+        self.stats
+            .record(party, sid, &self.label(sid), |s| s.recv_wait += waited);
+        result
+    }
+
+    /// A snapshot of the traffic so far, on behalf of `my_id`
+    pub fn stats(&self, my_id: u32) -> NetStats {
+        self.stats.snapshot(my_id)
+    }
+
+    /// The error every operation fails with once the session is aborted
+    pub fn aborted(&self) -> Option<MpcNetError> {
+        self.abort
+            .borrow()
+            .as_ref()
+            .map(|abort| MpcNetError::Aborted {
+                err: abort.reason.clone(),
+                party: abort.party,
+            })
+    }
+
+    async fn wait_aborted(&self) -> MpcNetError {
+        let mut receiver = self.abort.subscribe();
+        loop {
+            if let Some(err) = self.aborted() {
+                return err;
+            }
+            // We hold the sender, so the channel cannot close
+            let _ = receiver.changed().await;
+        }
+    }
+
+    /// Records that `party` aborted the session, unless it already was
+    pub fn mark_aborted(&self, party: u32, reason: &str) {
+        self.abort.send_if_modified(|abort| {
+            if abort.is_some() {
+                return false;
+            }
+            *abort = Some(Abort {
+                party,
+                reason: reason.to_string(),
+            });
+            true
+        });
+    }
+
+    /// Aborts the session on behalf of `my_id`. Returns the abort to forward
+    /// to the other parties, or `None` if that was done already.
+    pub fn start_abort(&self, my_id: u32, reason: &str) -> Option<Abort> {
+        self.mark_aborted(my_id, reason);
+        if self.abort_forwarded.swap(true, Ordering::SeqCst) {
+            return None;
+        }
+        self.abort.borrow().clone()
+    }
+
+    /// Shuts the session down on behalf of `my_id`. Returns false if that was
+    /// done already.
+    pub fn start_shutdown(&self, my_id: u32) -> bool {
+        self.shut_down.send_if_modified(|shut_down| {
+            if shut_down.is_some() {
+                return false;
+            }
+            *shut_down = Some(my_id);
+            true
+        })
+    }
+
+    /// Whether we shut down
+    pub fn is_shut_down(&self) -> bool {
+        self.shut_down.borrow().is_some()
+    }
+
+    /// Whether `peer` told us it shut down, on any stream
+    pub fn peer_shut_down(&self, peer: u32) -> bool {
+        self.closed.lock().iter().any(|(party, _)| *party == peer)
+    }
+
+    /// The error an operation with `party` on `sid`, or on any stream if
+    /// `None`, fails with after either side shut down
+    fn shut_down_err(
+        &self,
+        party: u32,
+        sid: Option<MultiplexedStreamID>,
+    ) -> Option<MpcNetError> {
+        if let Some(my_id) = *self.shut_down.borrow() {
+            return Some(MpcNetError::ShutDown { party: my_id });
+        }
+        let closed = match sid {
+            Some(sid) => self.closed.lock().contains(&(party, sid)),
+            None => self.peer_shut_down(party),
+        };
+        closed.then_some(MpcNetError::ShutDown { party })
+    }
+
+    async fn wait_shut_down(&self) -> MpcNetError {
+        let mut receiver = self.shut_down.subscribe();
+        loop {
+            if let Some(my_id) = *receiver.borrow_and_update() {
+                return MpcNetError::ShutDown { party: my_id };
+            }
+            // We hold the sender, so the channel cannot close
+            let _ = receiver.changed().await;
+        }
+    }
+
+    /// Label the protocol that is about to run on `sid`
+    pub fn set_label(&self, sid: MultiplexedStreamID, label: &str) {
+        self.labels.lock().insert(sid, label.to_string());
+    }
+
+    fn label(&self, sid: MultiplexedStreamID) -> String {
+        self.labels.lock().get(&sid).cloned().unwrap_or_default()
+    }
+
+    /// Appends the tag for the next frame to `peer` on `sid`, in place if
+    /// nobody else holds `payload`. Fails if as much as we may keep for replay
+    /// to the peer on `sid` is unacknowledged, see
+    /// [`SessionTracker::replay_full`].
+    pub fn seal(
+        &self,
+        my_id: u32,
+        peer: u32,
+        sid: MultiplexedStreamID,
+        payload: Bytes,
+    ) -> Result<Bytes, MpcNetError> {
+        if self.replay_full(peer, sid) {
+            return Err(MpcNetError::Generic(format!(
+                "Party {peer} has not acknowledged the frames kept for replay on stream {}",
+                sid.0
+            )));
+        }
+
+        // The frame only takes its round once it is sealed, so that one that
+        // fails to seal leaves no gap in the rounds the peer sees
+        let (round, acks) = {
+            let rounds = self.rounds.lock();
+            let round = rounds.get(&(peer, sid)).map_or(0, |r| r.sent);
+            (round, delivered_from(&rounds, peer))
+        };
+        let label = self.label(sid);
+        let compressed = self.compress(peer, sid, &payload)?;
+        let tag = FrameTag {
+            session_id: self.session_id,
+            label: label.clone(),
+            round,
+            acks: acks.clone(),
+            abort: None,
+            close: false,
+            compression: compressed.as_ref().map(|(algorithm, _)| *algorithm),
+            accepts: Algorithm::supported(),
+        };
+        let payload_len = payload.len();
+        let (wire_payload, compressed_to) = match compressed {
+            Some((_, compressed)) => {
+                let len = compressed.len();
+                (Bytes::from(compressed), Some(len))
+            }
+            None => (payload, None),
+        };
+        let frame = self.encode(my_id, peer, sid, tag, wire_payload)?;
+        {
+            let mut rounds = self.rounds.lock();
+            rounds.entry((peer, sid)).or_default().sent += 1;
+            mark_acknowledged(&mut rounds, peer, &acks);
+        }
+        self.stats.record(peer, sid, &label, |s| {
+            s.bytes_sent += frame.len() as u64;
+            s.messages_sent += 1;
+            if let Some(compressed_to) = compressed_to {
+                s.compressed_from += payload_len as u64;
+                s.compressed_to += compressed_to as u64;
+            }
+        });
+
+        if self.replay_limit.is_some() {
+            let mut replay = self.replay.lock();
+            let buffered = replay.entry((peer, sid)).or_default();
+            buffered.push(round, frame.clone());
+        }
+
+        Ok(frame)
+    }
+
+    /// Builds a frame telling `peer` that the session was aborted
+    pub fn seal_abort(
+        &self,
+        my_id: u32,
+        peer: u32,
+        sid: MultiplexedStreamID,
+        abort: &Abort,
+    ) -> Result<Bytes, MpcNetError> {
+        let tag = FrameTag {
+            session_id: self.session_id,
+            label: self.label(sid),
+            round: 0,
+            acks: vec![],
+            abort: Some(abort.clone()),
+            close: false,
+            compression: None,
+            accepts: Algorithm::supported(),
+        };
+        self.encode(my_id, peer, sid, tag, Bytes::new())
+    }
+
+    /// Builds the last frame to `peer` on `sid`, telling it that we shut down
+    pub fn seal_close(
+        &self,
+        my_id: u32,
+        peer: u32,
+        sid: MultiplexedStreamID,
+    ) -> Result<Bytes, MpcNetError> {
+        let tag = FrameTag {
+            session_id: self.session_id,
+            label: self.label(sid),
+            round: 0,
+            acks: vec![],
+            abort: None,
+            close: true,
+            compression: None,
+            accepts: Algorithm::supported(),
+        };
+        self.encode(my_id, peer, sid, tag, Bytes::new())
+    }
+
+    fn encode(
+        &self,
+        my_id: u32,
+        peer: u32,
+        sid: MultiplexedStreamID,
+        tag: FrameTag,
+        payload: Bytes,
+    ) -> Result<Bytes, MpcNetError> {
+        if self.trace {
+            debug!(
+                "{my_id} -> {peer} on stream {}: {tag:?}, {} bytes",
+                sid.0,
+                payload.len()
+            );
+        }
+
+        let tag = bincode2::serialize(&tag)?;
+        // A payload shared with someone else, like one the king sends to
+        // every peer, is copied
+        let mut frame = payload
+            .try_into_mut()
+            .unwrap_or_else(|shared| BytesMut::from(&shared[..]));
+        frame.reserve(tag.len() + 4);
+        frame.put_slice(&tag);
+        frame.put_u32(tag.len() as u32);
+        Ok(frame.freeze())
+    }
+
+    /// The session `frame` was sealed in, if it has a tag
+    pub fn session_of(frame: &[u8]) -> Option<u64> {
+        Self::tag_of(frame).map(|tag| tag.session_id)
+    }
+
+    fn tag_of(frame: &[u8]) -> Option<FrameTag> {
+        let payload_len = Self::payload_len(frame).ok()?;
+        bincode2::deserialize(&frame[payload_len..frame.len() - 4]).ok()
+    }
+
+    /// Where the tag of `frame` starts
+    fn payload_len(frame: &[u8]) -> Result<usize, String> {
+        let (rest, tag_len) = frame
+            .split_last_chunk::<4>()
+            .ok_or_else(|| "Frame is missing its tag".to_string())?;
+        rest.len()
+            .checked_sub(u32::from_be_bytes(*tag_len) as usize)
+            .ok_or_else(|| "Frame tag is truncated".to_string())
+    }
+
+    /// Hands on a frame received from `peer` on `sid` that the caller opens
+    /// itself, unless it aborts or closes our own session, which fails like
+    /// it does in [`SessionTracker::open`]
+    pub fn pass_on(
+        &self,
+        my_id: u32,
+        peer: u32,
+        sid: MultiplexedStreamID,
+        frame: Bytes,
+    ) -> Result<Bytes, MpcNetError> {
+        match Self::tag_of(&frame) {
+            Some(tag)
+                if tag.session_id == self.session_id
+                    && (tag.abort.is_some() || tag.close) =>
+            {
+                self.open(my_id, peer, sid, frame)
+            }
+            _ => Ok(frame),
+        }
+    }
+
+    /// Strips the tag from a frame received from `peer` on `sid`, returning
+    /// an error if it is not the frame we expect next
+    pub fn open(
+        &self,
+        my_id: u32,
+        peer: u32,
+        sid: MultiplexedStreamID,
+        frame: Bytes,
+    ) -> Result<Bytes, MpcNetError> {
+        self.unseal(my_id, peer, sid, frame, true)
+    }
+
+    /// Like [`SessionTracker::open`], for a frame taken from
+    /// [`SessionTracker::unstash`], which was counted as received already
+    pub fn open_stashed(
+        &self,
+        my_id: u32,
+        peer: u32,
+        sid: MultiplexedStreamID,
+        frame: Bytes,
+    ) -> Result<Bytes, MpcNetError> {
+        self.unseal(my_id, peer, sid, frame, false)
+    }
+
+    fn unseal(
+        &self,
+        my_id: u32,
+        peer: u32,
+        sid: MultiplexedStreamID,
+        frame: Bytes,
+        fresh: bool,
+    ) -> Result<Bytes, MpcNetError> {
+        let protocol_err =
+            |err: String| MpcNetError::Protocol { err, party: peer };
+
+        let payload_len = Self::payload_len(&frame).map_err(protocol_err)?;
+        let tag: FrameTag =
+            bincode2::deserialize(&frame[payload_len..frame.len() - 4])
+                .map_err(|err| {
+                    protocol_err(format!("Malformed frame tag: {err}"))
+                })?;
+        let payload = frame.slice(..payload_len);
+
+        if self.trace {
+            debug!(
+                "{my_id} <- {peer} on stream {}: {tag:?}, {} bytes",
+                sid.0,
+                payload.len()
+            );
+        }
+
+        if let Some(abort) = &tag.abort {
+            if tag.session_id == self.session_id {
+                self.mark_aborted(abort.party, &abort.reason);
+                return Err(self.aborted().expect("Session was just aborted"));
+            }
+        }
+
+        if tag.close && tag.session_id == self.session_id {
+            self.closed.lock().insert((peer, sid));
+            return Err(MpcNetError::ShutDown { party: peer });
+        }
+
+        let expected = FrameTag {
+            session_id: self.session_id,
+            label: self.label(sid),
+            round: self.rounds.lock().entry((peer, sid)).or_default().received,
+            acks: tag.acks.clone(),
+            abort: None,
+            close: false,
+            compression: tag.compression,
+            accepts: tag.accepts.clone(),
+        };
+
+        if tag != expected {
+            return Err(protocol_err(format!(
+                "Frame on stream {} is out of place, got {tag:?} but expected {expected:?}",
+                sid.0
+            )));
+        }
+
+        let payload = match tag.compression {
+            Some(algorithm) => {
+                compress::decompress(algorithm, &payload, MAX_MESSAGE_LEN)
+                    .map(Bytes::from)
+                    .map_err(|err| {
+                        protocol_err(format!(
+                            "Malformed compressed payload: {err:?}"
+                        ))
+                    })?
+            }
+            None => payload,
+        };
+
+        {
+            let mut rounds = self.rounds.lock();
+            let entry = rounds.entry((peer, sid)).or_default();
+            entry.received += 1;
+            if fresh {
+                entry.count_unacked(&frame);
+            }
+        }
+        self.stats.record(peer, sid, &tag.label, |s| {
+            s.bytes_received += frame.len() as u64;
+            s.messages_received += 1;
+        });
+        self.acknowledge(peer, &tag.acks);
+        self.peer_accepts.lock().insert(peer, tag.accepts);
+        Ok(payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SID: MultiplexedStreamID = MultiplexedStreamID::ZERO;
+
+    #[test]
+    fn test_frames_name_their_session() {
+        let sender = SessionTracker::new(6);
+        let frame = sender.seal(1, 0, SID, Bytes::from(vec![0])).unwrap();
+
+        assert_eq!(SessionTracker::session_of(&frame), Some(6));
+        assert_eq!(SessionTracker::session_of(&[0, 0, 0, 9, 1]), None);
+    }
+
+    #[test]
+    fn test_only_our_own_goodbye_is_not_passed_on() {
+        let receiver = SessionTracker::new(7);
+        let job = SessionTracker::new(8)
+            .seal(1, 0, SID, Bytes::from(vec![0]))
+            .unwrap();
+        assert_eq!(receiver.pass_on(0, 1, SID, job.clone()).unwrap(), job);
+
+        let sender = SessionTracker::new(7);
+        let close = sender.seal_close(1, 0, SID).unwrap();
+        assert!(matches!(
+            receiver.pass_on(0, 1, SID, close),
+            Err(MpcNetError::ShutDown { party: 1 })
+        ));
+    }
+
+    #[test]
+    fn test_frames_open_in_order() {
+        let sender = SessionTracker::new(7);
+        let receiver = SessionTracker::new(7);
+        sender.set_label(SID, "d_msm");
+        receiver.set_label(SID, "d_msm");
+
+        for i in 0..3u8 {
+            let frame = sender.seal(1, 0, SID, Bytes::from(vec![i])).unwrap();
+            let payload = receiver.open(0, 1, SID, frame).unwrap();
+            assert_eq!(&payload[..], &[i]);
+        }
+    }
+
+    #[test]
+    fn test_sealing_keeps_the_payload_in_place() {
+        let sender = SessionTracker::new(7);
+        let mut payload = Vec::with_capacity(1 + TAG_ROOM);
+        payload.push(1);
+        let payload = Bytes::from(payload);
+        let at = payload.as_ptr();
+
+        let frame = sender.seal(1, 0, SID, payload).unwrap();
+        assert_eq!(frame.as_ptr(), at);
+    }
+
+    fn first_frame(session_id: u64, label: &str) -> Bytes {
+        let sender = SessionTracker::new(session_id);
+        sender.set_label(SID, label);
+        sender.seal(1, 0, SID, Bytes::from(vec![0])).unwrap()
+    }
+
+    #[test]
+    fn test_mismatched_frames_are_rejected() {
+        let receiver = SessionTracker::new(7);
+        receiver.set_label(SID, "d_fft");
+
+        // Wrong session
+        assert!(matches!(
+            receiver.open(0, 1, SID, first_frame(8, "d_fft")),
+            Err(MpcNetError::Protocol { party: 1, .. })
+        ));
+
+        // Wrong protocol
+        assert!(receiver.open(0, 1, SID, first_frame(7, "d_msm")).is_err());
+
+        // Replayed round
+        receiver.open(0, 1, SID, first_frame(7, "d_fft")).unwrap();
+        assert!(receiver.open(0, 1, SID, first_frame(7, "d_fft")).is_err());
+
+        // Abort, which sticks
+        let sender = SessionTracker::new(7);
+        let abort = sender.start_abort(1, "out of memory").unwrap();
+        assert!(sender.start_abort(1, "again").is_none());
+        let frame = sender.seal_abort(1, 0, SID, &abort).unwrap();
+        assert!(matches!(
+            receiver.open(0, 1, SID, frame),
+            Err(MpcNetError::Aborted { party: 1, .. })
+        ));
+        assert!(receiver.aborted().is_some());
+
+        // Truncated tag
+        assert!(receiver
+            .open(0, 1, SID, Bytes::from_static(&[0, 0]))
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_close_ends_only_its_stream() {
+        let sender = SessionTracker::new(7);
+        let receiver = SessionTracker::new(7);
+        let other = MultiplexedStreamID::ONE;
+
+        let frame = sender.seal_close(1, 0, SID).unwrap();
+        assert!(matches!(
+            receiver.open(0, 1, SID, frame),
+            Err(MpcNetError::ShutDown { party: 1 })
+        ));
+        assert!(receiver.peer_shut_down(1));
+
+        // Nothing more arrives on the closed stream
+        let result = receiver
+            .bounded_recv(1, SID, futures::future::pending::<Result<(), _>>())
+            .await;
+        assert!(matches!(result, Err(MpcNetError::ShutDown { party: 1 })));
+
+        // Frames sent on other streams before the close still do
+        let result = receiver.bounded_recv(1, other, async { Ok(()) }).await;
+        assert!(result.is_ok());
+
+        // Once we shut down ourselves, everything fails
+        assert!(receiver.start_shutdown(0));
+        assert!(!receiver.start_shutdown(0));
+        let result = receiver.bounded_recv(2, other, async { Ok(()) }).await;
+        assert!(matches!(result, Err(MpcNetError::ShutDown { party: 0 })));
+    }
+
+    #[tokio::test]
+    async fn test_operations_time_out() {
+        let mut session = SessionTracker::new(0);
+        session.set_op_timeout(Some(Duration::from_millis(10)));
+
+        let result = session
+            .bounded(3, futures::future::pending::<Result<(), MpcNetError>>())
+            .await;
+        assert!(matches!(result, Err(MpcNetError::Timeout { party: 3 })));
+
+        // A passed deadline fails operations right away
+        session.set_op_timeout(None);
+        session.set_deadline(Some(Instant::now()));
+        let result = session
+            .bounded(3, futures::future::pending::<Result<(), MpcNetError>>())
+            .await;
+        assert!(matches!(result, Err(MpcNetError::Timeout { party: 3 })));
+    }
+
+    #[test]
+    fn test_unacknowledged_frames_are_replayed() {
+        let mut sender = SessionTracker::new(0);
+        sender.set_replay(Some(ReplayLimit::default()));
+        let receiver = SessionTracker::new(0);
+
+        // The first frame arrives, the next two are lost
+        let frame = sender.seal(1, 0, SID, Bytes::from(vec![0])).unwrap();
+        receiver.open(0, 1, SID, frame).unwrap();
+        sender.seal(1, 0, SID, Bytes::from(vec![1])).unwrap();
+        sender.seal(1, 0, SID, Bytes::from(vec![2])).unwrap();
+
+        let received = receiver.received(1, 1);
+        assert_eq!(received, vec![1]);
+        for (i, frame) in sender
+            .replay(0, SID, received[0])
+            .unwrap()
+            .into_iter()
+            .enumerate()
+        {
+            let payload = receiver.open(0, 1, SID, frame).unwrap();
+            assert_eq!(&payload[..], &[i as u8 + 1]);
+        }
+
+        // Lost frames are kept for as long as they are not acknowledged
+        for i in 3..30 {
+            sender.seal(1, 0, SID, Bytes::from(vec![i])).unwrap();
+        }
+        assert_eq!(sender.replay(0, SID, 1).unwrap().len(), 29);
+        assert!(sender.replay(0, SID, 31).is_err());
+
+        // A frame on another stream acknowledges the frames on this one
+        let other = MultiplexedStreamID::ONE;
+        let frame = receiver.seal(0, 1, other, Bytes::new()).unwrap();
+        sender.open(1, 0, other, frame).unwrap();
+        assert!(sender.replay(0, SID, 1).is_err());
+        assert_eq!(sender.replay(0, SID, 3).unwrap().len(), 27);
+    }
+
+    #[test]
+    fn test_replay_buffer_is_bounded() {
+        let limit = ReplayLimit {
+            frames: 4,
+            bytes: usize::MAX,
+        };
+        let mut sender = SessionTracker::new(0);
+        sender.set_replay(Some(limit));
+        let mut receiver = SessionTracker::new(0);
+        receiver.set_replay(Some(limit));
+
+        // The receiver acknowledges on its own once half the limit is reached
+        for i in 0..2u8 {
+            assert!(receiver.take_ack(1).is_none());
+            let frame = sender.seal(1, 0, SID, Bytes::from(vec![i])).unwrap();
+            receiver.open(0, 1, SID, frame).unwrap();
+        }
+        let ack = receiver.take_ack(1).unwrap();
+        assert!(receiver.take_ack(1).is_none());
+
+        // Without the acknowledgement, the sender may not seal more frames
+        for i in 2..4u8 {
+            sender.seal(1, 0, SID, Bytes::from(vec![i])).unwrap();
+        }
+        assert!(sender.replay_full(0, SID));
+        assert!(sender.seal(1, 0, SID, Bytes::from(vec![4])).is_err());
+
+        sender.acknowledge(0, &ack);
+        assert_eq!(sender.unacknowledged(0, SID), 2);
+        assert!(!sender.replay_full(0, SID));
+        sender.seal(1, 0, SID, Bytes::from(vec![4])).unwrap();
+    }
+
+    #[test]
+    fn test_stashed_frames_count_as_received() {
+        let sender = SessionTracker::new(0);
+        let receiver = SessionTracker::new(0);
+        let first = sender.seal(1, 0, SID, Bytes::from(vec![1])).unwrap();
+        let second = sender.seal(1, 0, SID, Bytes::from(vec![2])).unwrap();
+
+        receiver.stash(1, SID, first);
+        assert_eq!(receiver.received(1, 1), vec![1]);
+        let stashed = receiver.unstash(1, SID).unwrap();
+        assert_eq!(receiver.open_stashed(0, 1, SID, stashed).unwrap()[..], [1]);
+        assert!(receiver.unstash(1, SID).is_none());
+        assert_eq!(receiver.open(0, 1, SID, second).unwrap()[..], [2]);
+        assert_eq!(receiver.received(1, 1), vec![2]);
+    }
+}
@@ -0,0 +1,419 @@
+//! A network simulator that wraps any [`MpcNet`], usually a
+//! [`crate::memory::MemoryNet`], and models the links between the parties.
+//!
+//! Nothing is slowed down for real. Every party keeps a simulated clock
+//! instead: sending stamps a frame with the simulated time it would arrive
+//! at, receiving moves the receiver's clock forward to that time, and time
+//! spent computing between network operations is added as measured. The
+//! clock of the last party to finish is the simulated wall-clock time of the
+//! whole run.
+//!
+//! Frames on a stream still arrive in order, as over TCP. Lost and reordered
+//! segments are modelled by the delay they cause; only dropped frames are
+//! actually not delivered.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use parking_lot::Mutex;
+use tokio_util::bytes::{BufMut, Bytes, BytesMut};
+
+use crate::{MpcNet, MpcNetError, MultiplexedStreamID, NetStats, WireCodec};
+
+/// Bytes prepended to every frame, holding its simulated arrival time
+const HEADER_LEN: usize = 8;
+
+/// How a link from one party to another behaves
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LinkProfile {
+    /// One-way delay of every frame
+    pub latency: Duration,
+    /// Extra delay, uniformly distributed between zero and this
+    pub jitter: Duration,
+    /// In bits per second, or unlimited if `None`
+    pub bandwidth: Option<u64>,
+    /// Probability that a frame has to be retransmitted, which delays it by
+    /// `retransmit_timeout`
+    pub loss_rate: f64,
+    pub retransmit_timeout: Duration,
+    /// Probability that a frame is held up by a reordered segment, which
+    /// delays it by another `latency`
+    pub reorder_rate: f64,
+    /// Probability that a frame is dropped and never delivered
+    pub drop_rate: f64,
+}
+
+impl LinkProfile {
+    /// A link with the given one-way latency and bandwidth in bits per second
+    pub fn new(latency: Duration, bandwidth: u64) -> Self {
+        Self {
+            latency,
+            bandwidth: Some(bandwidth),
+            ..Default::default()
+        }
+    }
+}
+
+/// The links of a whole cluster
+#[derive(Clone, Debug, Default)]
+pub struct NetworkProfile {
+    /// Used for every link that is not set explicitly
+    pub default_link: LinkProfile,
+    links: HashMap<(u32, u32), LinkProfile>,
+    /// Outgoing bandwidth of each party in bits per second, shared by all of
+    /// its links, or unlimited if `None`
+    pub uplink: Option<u64>,
+    /// Whether to add the time spent computing between network operations
+    /// to the simulated clock. This measures real time, so the parties should
+    /// not be competing for cores.
+    pub measure_compute: bool,
+    /// Seeds the random delays and drops, which are reproducible
+    pub seed: u64,
+}
+
+impl NetworkProfile {
+    /// Every link behaves like `link`
+    pub fn uniform(link: LinkProfile) -> Self {
+        Self {
+            default_link: link,
+            measure_compute: true,
+            ..Default::default()
+        }
+    }
+
+    /// Sets the link from `from` to `to`
+    pub fn set_link(&mut self, from: u32, to: u32, link: LinkProfile) {
+        self.links.insert((from, to), link);
+    }
+
+    /// Sets the links between `a` and `b` in both directions
+    pub fn set_links(&mut self, a: u32, b: u32, link: LinkProfile) {
+        self.set_link(a, b, link);
+        self.set_link(b, a, link);
+    }
+
+    pub fn link(&self, from: u32, to: u32) -> &LinkProfile {
+        self.links.get(&(from, to)).unwrap_or(&self.default_link)
+    }
+}
+
+/// A small, seedable generator, so that runs are reproducible
+#[derive(Debug)]
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in [0, 1)
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && self.next_f64() < probability
+    }
+}
+
+#[derive(Debug)]
+struct SimState {
+    /// Simulated time of this party
+    now: Duration,
+    /// When this party last finished a network operation, in real time
+    last_op: Instant,
+    /// When our uplink is free again
+    uplink_free_at: Duration,
+    /// When the link to each peer is free again
+    link_free_at: HashMap<u32, Duration>,
+    /// When the last frame to each peer and stream arrives
+    last_arrival: HashMap<(u32, MultiplexedStreamID), Duration>,
+    rng: SplitMix64,
+}
+
+/// Wraps a network and simulates the links of `profile` on top of it. All
+/// parties of a cluster must be wrapped.
+pub struct SimNet<N: MpcNet> {
+    inner: N,
+    profile: NetworkProfile,
+    state: Mutex<SimState>,
+}
+
+impl<N: MpcNet> SimNet<N> {
+    pub fn new(inner: N, profile: NetworkProfile) -> Self {
+        let seed = profile.seed ^ u64::from(inner.party_id());
+        Self {
+            inner,
+            profile,
+            state: Mutex::new(SimState {
+                now: Duration::ZERO,
+                last_op: Instant::now(),
+                uplink_free_at: Duration::ZERO,
+                link_free_at: HashMap::new(),
+                last_arrival: HashMap::new(),
+                rng: SplitMix64(seed),
+            }),
+        }
+    }
+
+    pub fn inner(&self) -> &N {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> N {
+        self.inner
+    }
+
+    /// The simulated time that has passed for this party
+    pub fn elapsed(&self) -> Duration {
+        let mut state = self.state.lock();
+        self.tick(&mut state);
+        state.now
+    }
+
+    /// Adds the time spent computing since the last network operation
+    fn tick(&self, state: &mut SimState) {
+        let now = Instant::now();
+        if self.profile.measure_compute {
+            state.now += now - state.last_op;
+        }
+        state.last_op = now;
+    }
+
+    /// Computes when a frame of `len` bytes to `id` on `sid` arrives, or
+    /// `None` if it is dropped
+    fn schedule(
+        &self,
+        id: u32,
+        sid: MultiplexedStreamID,
+        len: usize,
+    ) -> Option<Duration> {
+        let link = self.profile.link(self.party_id(), id);
+        let mut state = self.state.lock();
+        self.tick(&mut state);
+
+        let transmit = |bandwidth: Option<u64>| {
+            bandwidth.map_or(Duration::ZERO, |bps| {
+                Duration::from_secs_f64(len as f64 * 8.0 / bps as f64)
+            })
+        };
+
+        // Wait for the link and our uplink to be free
+        let link_free_at =
+            state.link_free_at.get(&id).copied().unwrap_or_default();
+        let departure = state.now.max(link_free_at).max(state.uplink_free_at);
+        let uplink_free_at = departure + transmit(self.profile.uplink);
+        let sent = uplink_free_at.max(departure + transmit(link.bandwidth));
+        state.link_free_at.insert(id, sent);
+        state.uplink_free_at = uplink_free_at;
+
+        if state.rng.chance(link.drop_rate) {
+            return None;
+        }
+
+        let mut arrival = sent + link.latency;
+        arrival += link.jitter.mul_f64(state.rng.next_f64());
+        if state.rng.chance(link.loss_rate) {
+            arrival += link.retransmit_timeout;
+        }
+        if state.rng.chance(link.reorder_rate) {
+            arrival += link.latency;
+        }
+
+        // Frames on a stream can not overtake each other
+        let last_arrival = state.last_arrival.entry((id, sid)).or_default();
+        arrival = arrival.max(*last_arrival);
+        *last_arrival = arrival;
+        Some(arrival)
+    }
+}
+
+#[async_trait]
+impl<N: MpcNet> MpcNet for SimNet<N> {
+    fn n_parties(&self) -> usize {
+        self.inner.n_parties()
+    }
+
+    fn party_id(&self) -> u32 {
+        self.inner.party_id()
+    }
+
+    fn king_id(&self) -> u32 {
+        self.inner.king_id()
+    }
+
+    fn is_init(&self) -> bool {
+        self.inner.is_init()
+    }
+
+    fn n_streams(&self) -> usize {
+        self.inner.n_streams()
+    }
+
+    fn wire_codec(&self) -> WireCodec {
+        self.inner.wire_codec()
+    }
+
+    fn set_protocol_label(&self, sid: MultiplexedStreamID, label: &str) {
+        self.inner.set_protocol_label(sid, label)
+    }
+
+    fn stats(&self) -> NetStats {
+        self.inner.stats()
+    }
+
+    async fn recv_from(
+        &self,
+        id: u32,
+        sid: MultiplexedStreamID,
+    ) -> Result<Bytes, MpcNetError> {
+        self.tick(&mut self.state.lock());
+        let mut frame = self.inner.recv_from(id, sid).await?;
+        if frame.len() < HEADER_LEN {
+            return Err(MpcNetError::Protocol {
+                err: "Frame is missing its simulated arrival time".to_string(),
+                party: id,
+            });
+        }
+        let payload = frame.split_off(HEADER_LEN);
+        let mut arrival = [0u8; HEADER_LEN];
+        arrival.copy_from_slice(&frame);
+        let arrival = Duration::from_nanos(u64::from_be_bytes(arrival));
+
+        // Time spent blocked is replaced by the simulated wait
+        let mut state = self.state.lock();
+        state.now = state.now.max(arrival);
+        state.last_op = Instant::now();
+        Ok(payload)
+    }
+
+    async fn send_to(
+        &self,
+        id: u32,
+        bytes: Bytes,
+        sid: MultiplexedStreamID,
+    ) -> Result<(), MpcNetError> {
+        let arrival = match self.schedule(id, sid, bytes.len()) {
+            Some(arrival) => arrival,
+            None => return Ok(()),
+        };
+
+        let mut frame = BytesMut::with_capacity(HEADER_LEN + bytes.len());
+        frame.put_u64(arrival.as_nanos() as u64);
+        frame.put_slice(&bytes);
+        let result = self.inner.send_to(id, frame.freeze(), sid).await;
+        self.state.lock().last_op = Instant::now();
+        result
+    }
+
+    async fn abort(&self, reason: &str) {
+        self.inner.abort(reason).await
+    }
+
+    async fn shutdown(&self) -> Result<(), MpcNetError> {
+        self.inner.shutdown().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryTestNet;
+
+    fn profile(link: LinkProfile) -> NetworkProfile {
+        NetworkProfile {
+            measure_compute: false,
+            ..NetworkProfile::uniform(link)
+        }
+    }
+
+    /// Runs a gather and scatter through the king and returns the simulated
+    /// time every party took
+    async fn king_round(profile: NetworkProfile, len: usize) -> Vec<Duration> {
+        MemoryTestNet::new(4)
+            .simulate_network_round(profile, move |conn, profile| async move {
+                let net = SimNet::new(conn, profile);
+                let sid = MultiplexedStreamID::ZERO;
+                let from_all = net
+                    .client_send_or_king_receive(&vec![0; len], sid)
+                    .await
+                    .unwrap();
+                net.client_receive_or_king_send(from_all, sid)
+                    .await
+                    .unwrap();
+                net.elapsed()
+            })
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_latency_adds_up_per_round_trip() {
+        let latency = Duration::from_millis(50);
+        let elapsed = king_round(
+            profile(LinkProfile {
+                latency,
+                ..Default::default()
+            }),
+            1,
+        )
+        .await;
+
+        assert_eq!(elapsed[0], latency);
+        for client in &elapsed[1..] {
+            assert_eq!(*client, latency * 2);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_king_uplink_is_shared() {
+        // 1 KB at 8 Kbit/s takes a second per peer
+        let mut profile = profile(LinkProfile::default());
+        profile.uplink = Some(8_000);
+        let elapsed = king_round(profile, 1_000).await;
+
+        // The clients send in parallel, the king sends to one after another
+        assert_eq!(elapsed[0], Duration::from_secs(1));
+        let mut clients = elapsed[1..].to_vec();
+        clients.sort();
+        assert_eq!(
+            clients,
+            vec![
+                Duration::from_secs(2),
+                Duration::from_secs(3),
+                Duration::from_secs(4)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dropped_frames_time_out() {
+        let mut testnet = MemoryTestNet::new(2);
+        testnet.set_op_timeout(Duration::from_millis(100));
+        let link = LinkProfile {
+            drop_rate: 1.0,
+            ..Default::default()
+        };
+
+        let results = testnet
+            .simulate_network_round(profile(link), |conn, profile| async move {
+                let net = SimNet::new(conn, profile);
+                let sid = MultiplexedStreamID::ZERO;
+                let peer = 1 - net.party_id();
+                net.send_to(peer, Bytes::from_static(&[1]), sid)
+                    .await
+                    .unwrap();
+                // Hand back the net, so that nobody disconnects early
+                (net.recv_from(peer, sid).await, net)
+            })
+            .await;
+
+        for (result, _) in results {
+            assert!(matches!(result, Err(MpcNetError::Timeout { .. })));
+        }
+    }
+}
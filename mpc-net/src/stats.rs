@@ -0,0 +1,235 @@
+//! Traffic and timing metrics, recorded by the [`crate::SessionTracker`] of a
+//! network and broken down per peer, stream and protocol label.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::ops::AddAssign;
+use std::time::Duration;
+
+use parking_lot::Mutex;
+
+use crate::MultiplexedStreamID;
+
+/// What the traffic with one peer on one stream under one label is
+/// accounted to
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StatsKey {
+    pub peer: u32,
+    pub sid: MultiplexedStreamID,
+    pub label: String,
+}
+
+/// Counters for some part of the traffic. Bytes are counted as they are on
+/// the wire, including frame tags but not the length prefix.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TrafficStats {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub messages_sent: u64,
+    pub messages_received: u64,
+    /// Time spent waiting in `recv_from`
+    pub recv_wait: Duration,
+    /// Size of the payloads that were sent compressed, before compression
+    pub compressed_from: u64,
+    /// Size of the same payloads after compression
+    pub compressed_to: u64,
+}
+
+impl TrafficStats {
+    /// How much smaller compression made the payloads it was applied to,
+    /// e.g. 4.0 if they shrank to a quarter
+    pub fn compression_ratio(&self) -> Option<f64> {
+        if self.compressed_to == 0 {
+            return None;
+        }
+        Some(self.compressed_from as f64 / self.compressed_to as f64)
+    }
+}
+
+impl AddAssign for TrafficStats {
+    fn add_assign(&mut self, other: Self) {
+        self.bytes_sent += other.bytes_sent;
+        self.bytes_received += other.bytes_received;
+        self.messages_sent += other.messages_sent;
+        self.messages_received += other.messages_received;
+        self.recv_wait += other.recv_wait;
+        self.compressed_from += other.compressed_from;
+        self.compressed_to += other.compressed_to;
+    }
+}
+
+/// A snapshot of the traffic of one party
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NetStats {
+    pub party_id: u32,
+    pub traffic: BTreeMap<StatsKey, TrafficStats>,
+}
+
+impl NetStats {
+    pub fn new(party_id: u32) -> Self {
+        Self {
+            party_id,
+            traffic: BTreeMap::new(),
+        }
+    }
+
+    pub fn total(&self) -> TrafficStats {
+        let mut total = TrafficStats::default();
+        for stats in self.traffic.values() {
+            total += *stats;
+        }
+        total
+    }
+
+    pub fn by_peer(&self) -> BTreeMap<u32, TrafficStats> {
+        self.group_by(|key| key.peer)
+    }
+
+    pub fn by_stream(&self) -> BTreeMap<MultiplexedStreamID, TrafficStats> {
+        self.group_by(|key| key.sid)
+    }
+
+    /// The traffic of each protocol, e.g. how many bytes "d_fft" sent
+    pub fn by_label(&self) -> BTreeMap<String, TrafficStats> {
+        self.group_by(|key| key.label.clone())
+    }
+
+    fn group_by<K: Ord>(
+        &self,
+        group: impl Fn(&StatsKey) -> K,
+    ) -> BTreeMap<K, TrafficStats> {
+        let mut groups = BTreeMap::<_, TrafficStats>::new();
+        for (key, stats) in &self.traffic {
+            *groups.entry(group(key)).or_default() += *stats;
+        }
+        groups
+    }
+
+    /// Renders the counters in the Prometheus text exposition format, to be
+    /// served on a metrics endpoint
+    pub fn to_prometheus(&self) -> String {
+        type Metric = (&'static str, &'static str, fn(&TrafficStats) -> f64);
+        let metrics: [Metric; 7] = [
+            ("mpc_net_bytes_sent_total", "Bytes sent", |s| {
+                s.bytes_sent as f64
+            }),
+            ("mpc_net_bytes_received_total", "Bytes received", |s| {
+                s.bytes_received as f64
+            }),
+            ("mpc_net_messages_sent_total", "Messages sent", |s| {
+                s.messages_sent as f64
+            }),
+            (
+                "mpc_net_messages_received_total",
+                "Messages received",
+                |s| s.messages_received as f64,
+            ),
+            (
+                "mpc_net_recv_wait_seconds_total",
+                "Time spent waiting for messages",
+                |s| s.recv_wait.as_secs_f64(),
+            ),
+            (
+                "mpc_net_compressed_from_bytes_total",
+                "Payload bytes sent compressed, before compression",
+                |s| s.compressed_from as f64,
+            ),
+            (
+                "mpc_net_compressed_to_bytes_total",
+                "Payload bytes sent compressed, after compression",
+                |s| s.compressed_to as f64,
+            ),
+        ];
+
+        let mut out = String::new();
+        for (name, help, value) in metrics {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} counter");
+            for (key, stats) in &self.traffic {
+                let _ = writeln!(
+                    out,
+                    "{name}{{party=\"{}\",peer=\"{}\",stream=\"{}\",protocol=\"{}\"}} {}",
+                    self.party_id,
+                    key.peer,
+                    key.sid.0,
+                    escape_label(&key.label),
+                    value(stats)
+                );
+            }
+        }
+        out
+    }
+}
+
+fn escape_label(label: &str) -> String {
+    label
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Accumulates the counters of a [`NetStats`]
+#[derive(Debug, Default)]
+pub(crate) struct StatsRecorder {
+    traffic: Mutex<BTreeMap<StatsKey, TrafficStats>>,
+}
+
+impl StatsRecorder {
+    pub(crate) fn record(
+        &self,
+        peer: u32,
+        sid: MultiplexedStreamID,
+        label: &str,
+        update: impl FnOnce(&mut TrafficStats),
+    ) {
+        let key = StatsKey {
+            peer,
+            sid,
+            label: label.to_string(),
+        };
+        update(self.traffic.lock().entry(key).or_default());
+    }
+
+    pub(crate) fn snapshot(&self, party_id: u32) -> NetStats {
+        NetStats {
+            party_id,
+            traffic: self.traffic.lock().clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stats_group_and_export() {
+        let recorder = StatsRecorder::default();
+        let sid = MultiplexedStreamID::ONE;
+        recorder.record(1, sid, "d_fft", |s| {
+            s.bytes_sent += 100;
+            s.messages_sent += 1;
+        });
+        recorder.record(2, sid, "d_fft", |s| {
+            s.bytes_sent += 50;
+            s.messages_sent += 1;
+        });
+        recorder.record(1, sid, "d_msm", |s| {
+            s.recv_wait += Duration::from_millis(1500)
+        });
+
+        let stats = recorder.snapshot(0);
+        assert_eq!(stats.total().bytes_sent, 150);
+        assert_eq!(stats.by_label()["d_fft"].messages_sent, 2);
+        assert_eq!(stats.by_peer()[&1].bytes_sent, 100);
+        assert_eq!(stats.by_stream()[&sid].bytes_sent, 150);
+
+        let text = stats.to_prometheus();
+        assert!(text.contains(
+            "mpc_net_bytes_sent_total{party=\"0\",peer=\"2\",stream=\"1\",protocol=\"d_fft\"} 50"
+        ));
+        assert!(text.contains(
+            "mpc_net_recv_wait_seconds_total{party=\"0\",peer=\"1\",stream=\"1\",protocol=\"d_msm\"} 1.5"
+        ));
+    }
+}
@@ -0,0 +1,426 @@
+//! Recording what a party sends and receives, and replaying it.
+//!
+//! [`RecordingNet`] wraps any [`MpcNet`] and appends every message that goes
+//! through it to a transcript file. [`ReplayNet`] reads one party's transcript
+//! back: it hands out the recorded messages instead of receiving them, and
+//! checks that the party sends exactly what it sent before. That reruns a
+//! single party offline, e.g. under a debugger, without the rest of the
+//! cluster.
+//!
+//! A transcript is a sequence of records, each a big-endian `u32` length
+//! followed by a bincode-encoded value: a [`TranscriptHeader`], then one
+//! [`TranscriptEntry`] per message.
+
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::Path;
+
+use async_trait::async_trait;
+use parking_lot::Mutex;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tokio_util::bytes::Bytes;
+
+use crate::{MpcNet, MpcNetError, MultiplexedStreamID, NetStats, WireCodec};
+
+/// Which way a message went
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// The party a transcript was recorded by, and its network
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct TranscriptHeader {
+    pub party_id: u32,
+    pub king_id: u32,
+    pub n_parties: usize,
+    pub n_streams: usize,
+    pub wire_codec: WireCodec,
+}
+
+/// One message sent or received
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct TranscriptEntry {
+    pub direction: Direction,
+    /// The party the message was sent to or received from
+    pub party: u32,
+    pub sid: MultiplexedStreamID,
+    /// How many messages went the same way, with the same party and on the
+    /// same stream, before this one
+    pub seq: u64,
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Transcript {
+    pub header: TranscriptHeader,
+    pub entries: Vec<TranscriptEntry>,
+}
+
+impl Transcript {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, MpcNetError> {
+        Self::read(BufReader::new(File::open(path)?))
+    }
+
+    pub fn read(mut reader: impl Read) -> Result<Self, MpcNetError> {
+        let header = read_record(&mut reader)?.ok_or_else(|| {
+            MpcNetError::Generic("Transcript is empty".to_string())
+        })?;
+        let mut entries = Vec::new();
+        while let Some(entry) = read_record(&mut reader)? {
+            entries.push(entry);
+        }
+        Ok(Self { header, entries })
+    }
+}
+
+fn write_record<T: Serialize>(
+    writer: &mut impl Write,
+    value: &T,
+) -> Result<(), MpcNetError> {
+    let record = bincode2::serialize(value)?;
+    writer.write_all(&(record.len() as u32).to_be_bytes())?;
+    writer.write_all(&record)?;
+    Ok(())
+}
+
+/// Reads the next record, or `None` at the end of the transcript
+fn read_record<T: DeserializeOwned>(
+    reader: &mut impl Read,
+) -> Result<Option<T>, MpcNetError> {
+    let mut len = [0u8; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    }
+
+    let mut record = vec![0u8; u32::from_be_bytes(len) as usize];
+    reader.read_exact(&mut record).map_err(|err| {
+        MpcNetError::Generic(format!("Transcript is truncated: {err}"))
+    })?;
+    Ok(Some(bincode2::deserialize(&record)?))
+}
+
+struct Recorder {
+    writer: Box<dyn Write + Send>,
+    seqs: HashMap<(Direction, u32, MultiplexedStreamID), u64>,
+}
+
+/// Wraps a network and records every message sent and received through it.
+/// Every message is flushed as it is recorded, so the transcript of a party
+/// that crashes is complete up to the crash.
+pub struct RecordingNet<N> {
+    inner: N,
+    recorder: Mutex<Recorder>,
+}
+
+impl<N: MpcNet> RecordingNet<N> {
+    /// Records to a new file at `path`
+    pub fn create(
+        inner: N,
+        path: impl AsRef<Path>,
+    ) -> Result<Self, MpcNetError> {
+        Self::new(inner, BufWriter::new(File::create(path)?))
+    }
+
+    /// Records to `writer`
+    pub fn new(
+        inner: N,
+        mut writer: impl Write + Send + 'static,
+    ) -> Result<Self, MpcNetError> {
+        let header = TranscriptHeader {
+            party_id: inner.party_id(),
+            king_id: inner.king_id(),
+            n_parties: inner.n_parties(),
+            n_streams: inner.n_streams(),
+            wire_codec: inner.wire_codec(),
+        };
+        write_record(&mut writer, &header)?;
+        writer.flush()?;
+
+        Ok(Self {
+            inner,
+            recorder: Mutex::new(Recorder {
+                writer: Box::new(writer),
+                seqs: HashMap::new(),
+            }),
+        })
+    }
+
+    pub fn inner(&self) -> &N {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> N {
+        self.inner
+    }
+
+    fn record(
+        &self,
+        direction: Direction,
+        party: u32,
+        sid: MultiplexedStreamID,
+        bytes: &[u8],
+    ) -> Result<(), MpcNetError> {
+        let mut recorder = self.recorder.lock();
+        let seq = recorder.seqs.entry((direction, party, sid)).or_default();
+        let entry = TranscriptEntry {
+            direction,
+            party,
+            sid,
+            seq: *seq,
+            bytes: bytes.to_vec(),
+        };
+        *seq += 1;
+
+        write_record(&mut recorder.writer, &entry)?;
+        recorder.writer.flush()?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<N: MpcNet> MpcNet for RecordingNet<N> {
+    fn n_parties(&self) -> usize {
+        self.inner.n_parties()
+    }
+
+    fn party_id(&self) -> u32 {
+        self.inner.party_id()
+    }
+
+    fn king_id(&self) -> u32 {
+        self.inner.king_id()
+    }
+
+    fn is_init(&self) -> bool {
+        self.inner.is_init()
+    }
+
+    fn n_streams(&self) -> usize {
+        self.inner.n_streams()
+    }
+
+    fn wire_codec(&self) -> WireCodec {
+        self.inner.wire_codec()
+    }
+
+    fn set_protocol_label(&self, sid: MultiplexedStreamID, label: &str) {
+        self.inner.set_protocol_label(sid, label)
+    }
+
+    fn stats(&self) -> NetStats {
+        self.inner.stats()
+    }
+
+    async fn recv_from(
+        &self,
+        id: u32,
+        sid: MultiplexedStreamID,
+    ) -> Result<Bytes, MpcNetError> {
+        let bytes = self.inner.recv_from(id, sid).await?;
+        self.record(Direction::Received, id, sid, &bytes)?;
+        Ok(bytes)
+    }
+
+    async fn send_to(
+        &self,
+        id: u32,
+        bytes: Bytes,
+        sid: MultiplexedStreamID,
+    ) -> Result<(), MpcNetError> {
+        self.inner.send_to(id, bytes.clone(), sid).await?;
+        self.record(Direction::Sent, id, sid, &bytes)
+    }
+
+    async fn abort(&self, reason: &str) {
+        self.inner.abort(reason).await
+    }
+
+    async fn shutdown(&self) -> Result<(), MpcNetError> {
+        self.inner.shutdown().await
+    }
+}
+
+type Queues = Mutex<HashMap<(u32, MultiplexedStreamID), VecDeque<Bytes>>>;
+
+/// Plays back the network of the party that recorded a [`Transcript`].
+/// Receiving returns the recorded messages, and sending fails if the message
+/// differs from the recorded one.
+#[derive(Debug)]
+pub struct ReplayNet {
+    header: TranscriptHeader,
+    received: Queues,
+    sent: Queues,
+}
+
+impl ReplayNet {
+    pub fn new(transcript: Transcript) -> Self {
+        let mut entries = transcript.entries;
+        entries.sort_by_key(|entry| entry.seq);
+
+        let mut received = HashMap::<_, VecDeque<_>>::new();
+        let mut sent = HashMap::<_, VecDeque<_>>::new();
+        for entry in entries {
+            let queues = match entry.direction {
+                Direction::Received => &mut received,
+                Direction::Sent => &mut sent,
+            };
+            queues
+                .entry((entry.party, entry.sid))
+                .or_default()
+                .push_back(Bytes::from(entry.bytes));
+        }
+
+        Self {
+            header: transcript.header,
+            received: Mutex::new(received),
+            sent: Mutex::new(sent),
+        }
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, MpcNetError> {
+        Ok(Self::new(Transcript::load(path)?))
+    }
+
+    /// Whether every recorded message was received and sent again
+    pub fn is_finished(&self) -> bool {
+        let drained = |queues: &Queues| {
+            queues.lock().values().all(|queue| queue.is_empty())
+        };
+        drained(&self.received) && drained(&self.sent)
+    }
+}
+
+#[async_trait]
+impl MpcNet for ReplayNet {
+    fn n_parties(&self) -> usize {
+        self.header.n_parties
+    }
+
+    fn party_id(&self) -> u32 {
+        self.header.party_id
+    }
+
+    fn king_id(&self) -> u32 {
+        self.header.king_id
+    }
+
+    fn is_init(&self) -> bool {
+        true
+    }
+
+    fn n_streams(&self) -> usize {
+        self.header.n_streams
+    }
+
+    fn wire_codec(&self) -> WireCodec {
+        self.header.wire_codec
+    }
+
+    async fn recv_from(
+        &self,
+        id: u32,
+        sid: MultiplexedStreamID,
+    ) -> Result<Bytes, MpcNetError> {
+        self.received
+            .lock()
+            .get_mut(&(id, sid))
+            .and_then(|queue| queue.pop_front())
+            .ok_or_else(|| {
+                MpcNetError::Generic(format!(
+                    "The transcript has no more messages from party {id} on stream {}",
+                    sid.0
+                ))
+            })
+    }
+
+    async fn send_to(
+        &self,
+        id: u32,
+        bytes: Bytes,
+        sid: MultiplexedStreamID,
+    ) -> Result<(), MpcNetError> {
+        let recorded = self
+            .sent
+            .lock()
+            .get_mut(&(id, sid))
+            .and_then(|queue| queue.pop_front());
+        match recorded {
+            Some(recorded) if recorded == bytes => Ok(()),
+            Some(_) => Err(MpcNetError::Generic(format!(
+                "Message to party {id} on stream {} differs from the transcript",
+                sid.0
+            ))),
+            None => Err(MpcNetError::Generic(format!(
+                "The transcript has no more messages to party {id} on stream {}",
+                sid.0
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryTestNet;
+    use std::path::PathBuf;
+
+    fn transcript_path(party_id: u32) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "mpc-net-transcript-{}-{party_id}",
+            std::process::id()
+        ))
+    }
+
+    /// Every party sends its ID plus `offset`, the king sends back the sum
+    async fn sum_ids<N: MpcNet>(
+        net: &N,
+        offset: u8,
+    ) -> Result<u8, MpcNetError> {
+        let sid = MultiplexedStreamID::ONE;
+        let from_all = net
+            .client_send_or_king_receive(&[net.party_id() as u8 + offset], sid)
+            .await?
+            .map(|ids| {
+                let sum = ids.iter().map(|id| id[0]).sum::<u8>();
+                vec![Bytes::from(vec![sum]); ids.len()]
+            });
+        let sum = net.client_receive_or_king_send(from_all, sid).await?;
+        Ok(sum[0])
+    }
+
+    #[tokio::test]
+    async fn test_party_is_replayed_from_its_transcript() {
+        let sums = MemoryTestNet::new(4)
+            .simulate_network_round((), |net, _| async move {
+                let path = transcript_path(net.party_id());
+                let net = RecordingNet::create(net, path).unwrap();
+                sum_ids(&net, 0).await.unwrap()
+            })
+            .await;
+        assert_eq!(sums, vec![6; 4]);
+
+        for party_id in 0..4 {
+            let path = transcript_path(party_id);
+            let transcript = Transcript::load(&path).unwrap();
+            assert_eq!(transcript.header.party_id, party_id);
+            let n_entries = if party_id == 0 { 6 } else { 2 };
+            assert_eq!(transcript.entries.len(), n_entries);
+
+            let replay = ReplayNet::new(transcript.clone());
+            assert_eq!(sum_ids(&replay, 0).await.unwrap(), 6);
+            assert!(replay.is_finished());
+
+            // A party that behaves differently is caught
+            let replay = ReplayNet::new(transcript);
+            assert!(sum_ids(&replay, 1).await.is_err());
+
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+}